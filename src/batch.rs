@@ -0,0 +1,217 @@
+// Bulk inserts of key-ordered input, e.g. sorted runs emitted by ETL jobs.
+//
+// Records are encoded into a buffer and appended with a single write per
+// chunk, instead of one write (and one seek) per record.
+
+use super::cancel::CancellationToken;
+use super::deadline::Deadline;
+use super::error::*;
+use super::record::*;
+use super::trace::TraceOp;
+use super::{encoded_record_len, IndexEntry, Segment, SunsetDB};
+
+// Encoded bytes buffered before they are written out.
+const CHUNK_BYTES: usize = 1 << 20;
+
+// Writes accepted but not applied to the database yet.
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct Pending {
+    pub(crate) keys: u64,
+    pub(crate) bytes: u64,
+}
+
+#[derive(Default)]
+struct Chunk<'a> {
+    buffer: Vec<u8>,
+    // Offsets are relative to the start of `buffer`.
+    entries: Vec<(&'a str, IndexEntry)>,
+    values: Vec<&'a str>,
+    // See `SunsetDB::current_record`.
+    previous: Vec<Option<(usize, u64)>>,
+    pending: Pending,
+}
+
+impl SunsetDB {
+    /// Inserts `items`, whose keys must be strictly ascending, a chunk of
+    /// records at a time. Returns the number of keys inserted.
+    ///
+    /// Every item is checked like [`insert`](SunsetDB::insert) would,
+    /// keys out of order fail with [`ErrorKind::InvalidInput`](crate::ErrorKind::InvalidInput).
+    /// Chunks written before an error stay written; the rest is dropped.
+    pub fn insert_sorted_batch<'a>(
+        &mut self,
+        items: impl IntoIterator<Item = (&'a str, &'a str)>,
+    ) -> Result<u64, Error> {
+        self.insert_sorted_batch_with(items, &CancellationToken::default())
+    }
+
+    /// Like [`insert_sorted_batch`](SunsetDB::insert_sorted_batch), checking
+    /// `cancel` before writing each chunk.
+    pub fn insert_sorted_batch_with<'a>(
+        &mut self,
+        items: impl IntoIterator<Item = (&'a str, &'a str)>,
+        cancel: &CancellationToken,
+    ) -> Result<u64, Error> {
+        let deadline = self.deadline();
+        self.check_writable()?;
+        self.maybe_compact();
+        let mut chunk = Chunk::default();
+        let mut last_key: Option<&str> = None;
+        let mut inserted = 0;
+
+        for (key, value) in items {
+            if last_key.is_some_and(|last| key <= last) {
+                return Err(Error::from(InsertError::UnsortedBatch).with_key(key));
+            }
+            last_key = Some(key);
+
+            // See `Segment::insert`.
+            if value.len() as u64 >= TOMBSTONE {
+                return Err(Error::from(InsertError::ValueExceedsMaxSize).with_key(key));
+            }
+            // Keys are unique within the batch, so the database alone tells
+            // whether they are new.
+            let is_new_key = !self.contains(key);
+            self.check_quotas(key, value, is_new_key, chunk.pending)
+                .map_err(|e| Error::from(e).with_key(key))?;
+
+            let offset = chunk.buffer.len() as u64;
+            encode_string(&mut chunk.buffer, key);
+            encode_string(&mut chunk.buffer, value);
+            let value_len = value.len() as u64;
+            chunk.entries.push((key, IndexEntry { offset, value_len }));
+            chunk.values.push(value);
+            chunk.previous.push(self.current_record(key));
+            chunk.pending.keys += is_new_key as u64;
+            chunk.pending.bytes += encoded_record_len(key, Some(value));
+
+            if chunk.buffer.len() >= CHUNK_BYTES {
+                cancel.check()?;
+                inserted += self.write_chunk(&mut chunk, deadline)?;
+            }
+        }
+
+        cancel.check()?;
+        inserted += self.write_chunk(&mut chunk, deadline)?;
+        Ok(inserted)
+    }
+
+    // `deadline` covers the whole batch.
+    fn write_chunk(&mut self, chunk: &mut Chunk, deadline: Deadline) -> Result<u64, Error> {
+        if chunk.entries.is_empty() {
+            return Ok(0);
+        }
+
+        let bytes = chunk.buffer.len() as u64;
+        self.check_disk_space(bytes)?;
+        self.throttle(bytes, deadline)?;
+
+        // Only the items written are traced.
+        for ((key, _), value) in chunk.entries.iter().zip(&chunk.values) {
+            self.record(TraceOp::Insert, key, Some(value))?;
+            self.forget_miss(key);
+        }
+        self.maybe_rotate()?;
+        deadline.check(&self.options.clock)?;
+        let segment = self.segments.last_mut().ok_or(InsertError::NoSegments)?; // Created in `::new`
+        segment
+            .append_chunk(&chunk.buffer, &chunk.entries)
+            .map_err(|e| segment.error(e))?;
+        self.live_keys += chunk.pending.keys;
+        for previous in chunk.previous.drain(..) {
+            self.kill(previous);
+        }
+        self.maybe_checkpoint();
+
+        let inserted = chunk.entries.len() as u64;
+        chunk.buffer.clear();
+        chunk.entries.clear();
+        chunk.values.clear();
+        chunk.pending = Pending::default();
+        Ok(inserted)
+    }
+}
+
+impl Segment {
+    // Appends the encoded records in `buffer` with a single write.
+    fn append_chunk(
+        &mut self,
+        buffer: &[u8],
+        entries: &[(&str, IndexEntry)],
+    ) -> Result<(), InsertError> {
+        self.append(buffer)?;
+
+        let base = self.len;
+        self.len += buffer.len() as u64;
+        self.index.reserve(entries.len());
+        for (key, entry) in entries {
+            let entry = IndexEntry {
+                offset: base + entry.offset,
+                ..*entry
+            };
+            self.index.insert(key.to_string(), entry);
+            self.deleted.remove(*key);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error;
+
+    use crate::{ErrorKind, Options, Quotas, SunsetDB};
+    use tempfile::tempdir;
+
+    type TestResult = Result<(), Box<dyn Error>>;
+
+    #[test]
+    fn insert_sorted_batch_test() -> TestResult {
+        let base_dir = tempdir()?;
+        let mut s = SunsetDB::new(base_dir.path())?;
+        s.insert("b", "old")?;
+        s.force_delete("d")?;
+
+        let keys: Vec<String> = (0..1000).map(|i| format!("k{:04}", i)).collect();
+        let value = "v".repeat(2000); // Spans a few chunks.
+        let batch = ["a", "b", "c", "d"]
+            .into_iter()
+            .chain(keys.iter().map(String::as_str))
+            .map(|k| (k, value.as_str()));
+        assert_eq!(s.insert_sorted_batch(batch)?, 1004);
+        assert_eq!(s.get("b")?.as_deref(), Some(value.as_str()));
+        assert_eq!(s.get("k0999")?.as_deref(), Some(value.as_str()));
+        assert!(s.segment_stats()[0].dead_bytes > 0);
+
+        let err = s
+            .insert_sorted_batch([("x", "1"), ("y", "2"), ("y", "3")])
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        assert_eq!(err.key(), Some("y"));
+        assert_eq!(s.get("x")?, None);
+
+        drop(s);
+        let mut s = SunsetDB::new(base_dir.path())?;
+        assert_eq!(s.get("d")?.as_deref(), Some(value.as_str()));
+        assert_eq!(s.live_keys, 1004);
+
+        // Quotas account for the records buffered so far.
+        let base_dir = tempdir()?;
+        let options = Options {
+            quotas: Quotas {
+                max_keys: Some(2),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let mut s = SunsetDB::with_options(base_dir.path(), options)?;
+        let err = s
+            .insert_sorted_batch([("a", "1"), ("b", "2"), ("c", "3")])
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::QuotaExceeded(crate::Quota::MaxKeys));
+        assert_eq!(s.get("a")?, None);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,109 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use super::error::SunsetDBError;
+
+/// Stops a long running operation from another thread, e.g.
+/// [`check_with`](crate::check_with),
+/// [`SunsetDB::fold_with`](crate::SunsetDB::fold_with) or
+/// [`SunsetDB::compact_with`](crate::SunsetDB::compact_with).
+///
+/// Operations check the token between units of work and then fail with
+/// [`ErrorKind::Cancelled`](crate::ErrorKind::Cancelled), leaving the
+/// database consistent. Clones share the same state.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> CancellationToken {
+        CancellationToken::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    // For code that only sees a flag, such as merges.
+    pub(crate) fn flag(&self) -> &AtomicBool {
+        &self.0
+    }
+
+    pub(crate) fn check(&self) -> Result<(), SunsetDBError> {
+        if self.is_cancelled() {
+            return Err(SunsetDBError::Cancelled);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error;
+
+    use super::*;
+    use crate::{check_with, ConflictPolicy, ErrorKind, ImportFormat, SunsetDB};
+    use tempfile::tempdir;
+
+    type TestResult = Result<(), Box<dyn Error>>;
+
+    #[test]
+    fn cancellation_test() -> TestResult {
+        let base_dir = tempdir()?;
+        let other_dir = tempdir()?;
+        SunsetDB::new(other_dir.path())?.insert("o", "1")?;
+        let mut s = SunsetDB::new(base_dir.path())?;
+        let cancel = CancellationToken::new();
+        assert_eq!(s.insert_sorted_batch_with([("a", "0")], &cancel)?, 1);
+        s.add_new_segment()?;
+        s.insert("a", "1")?;
+
+        cancel.clone().cancel();
+        let e = s.insert_sorted_batch_with([("b", "2"), ("c", "3")], &cancel);
+        assert_eq!(e.unwrap_err().kind(), ErrorKind::Cancelled);
+        assert_eq!(s.get("b")?, None);
+
+        let e = s.fold_with(.., 0, |n, _, _| n + 1, &cancel).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::Cancelled);
+        let e = s.scan_filtered_with(.., |_, _| true, &cancel).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::Cancelled);
+        let mut cursor = s.cursor(..);
+        let e = cursor.next_batch_with(&mut s, 10, &cancel).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::Cancelled);
+        assert_eq!(cursor.next_batch(&mut s, 10)?, [("a".into(), "1".into())]);
+
+        let e = s.import_with(
+            "b,2\n".as_bytes(),
+            ImportFormat::Csv,
+            ConflictPolicy::Fail,
+            Box::new(|key, value| Some((key, value))),
+            |_| {},
+            &cancel,
+        );
+        assert_eq!(e.unwrap_err().kind(), ErrorKind::Cancelled);
+        assert_eq!(s.get("b")?, None);
+        let e = s.absorb_with(other_dir.path(), ConflictPolicy::Fail, &cancel);
+        assert_eq!(e.unwrap_err().kind(), ErrorKind::Cancelled);
+        assert_eq!(s.get("o")?, None);
+        let copy = other_dir.path().join("copy");
+        let e = s.clone_to_with(&copy, Box::new(|key, value| Some((key, value))), &cancel);
+        assert_eq!(e.unwrap_err().kind(), ErrorKind::Cancelled);
+
+        let e = s.compact_with(&cancel).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::Cancelled);
+        let e = s.compact_segment_with(0, &cancel).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::Cancelled);
+        assert_eq!(s.segment_stats()[0].dead_bytes, s.segment_stats()[0].len);
+        assert_eq!(s.compact()?.segments_rewritten, [0, 1]);
+        assert_eq!(s.get("a")?.as_deref(), Some("1"));
+        drop(s);
+
+        let e = check_with(base_dir.path(), &cancel);
+        assert_eq!(e.unwrap_err().kind(), ErrorKind::Cancelled);
+        assert!(check_with(base_dir.path(), &CancellationToken::new())?.is_ok());
+        Ok(())
+    }
+}
@@ -0,0 +1,435 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::fs::{read_dir, File};
+use std::io::{self, BufReader, Read};
+use std::path::{Path, PathBuf};
+
+use super::cancel::CancellationToken;
+use super::checkpoint;
+use super::compact::{marker_path, COMPACTING_EXT};
+use super::error::*;
+use super::record::*;
+use super::{SegmentID, SEGMENT_EXT};
+
+/// Outcome of an offline consistency check of a database directory.
+#[derive(Debug, Default)]
+pub struct CheckReport {
+    pub segments: Vec<SegmentReport>,
+    pub issues: Vec<Issue>,
+}
+
+impl CheckReport {
+    pub fn is_ok(&self) -> bool {
+        self.issues.is_empty()
+    }
+}
+
+#[derive(Debug)]
+pub struct SegmentReport {
+    pub id: u64,
+    pub path: PathBuf,
+    pub len: u64,
+    pub records: u64,
+    pub tombstones: u64,
+    pub live_keys: u64,
+}
+
+#[derive(Debug)]
+pub struct Issue {
+    pub path: PathBuf,
+    pub offset: Option<u64>,
+    pub kind: IssueKind,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum IssueKind {
+    InvalidSegmentName,
+    DuplicateSegmentID(u64),
+    MissingSegmentIDs {
+        from: u64,
+        to: u64,
+    },
+    Truncated,
+    MisplacedTombstone,
+    InvalidChecksum {
+        expected: u32,
+        found: u32,
+    },
+    InvalidString,
+    /// The index checkpoint covers more than the segment holds.
+    CheckpointPastEnd {
+        watermark: u64,
+        len: u64,
+    },
+    /// Left by an interrupted compaction, which opening the database for
+    /// writing finishes or rolls back.
+    InterruptedCompaction,
+    /// Left by an interrupted atomic write.
+    TemporaryFile,
+    IOError(String),
+}
+
+impl fmt::Display for IssueKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IssueKind::InvalidSegmentName => write!(f, "segment name is not a valid ID"),
+            IssueKind::DuplicateSegmentID(id) => write!(f, "duplicate segment ID {}", id),
+            IssueKind::MissingSegmentIDs { from, to } => {
+                write!(f, "missing segment IDs {}..={}", from, to)
+            }
+            IssueKind::Truncated => write!(f, "truncated record"),
+            IssueKind::MisplacedTombstone => write!(f, "tombstone in key position"),
+            IssueKind::InvalidChecksum { expected, found } => write!(
+                f,
+                "invalid checksum (expected {:?}, found {:?})",
+                expected, found
+            ),
+            IssueKind::InvalidString => write!(f, "invalid string"),
+            IssueKind::CheckpointPastEnd { watermark, len } => write!(
+                f,
+                "index checkpoint covers {} bytes, past the segment's {}",
+                watermark, len
+            ),
+            IssueKind::InterruptedCompaction => write!(f, "interrupted compaction"),
+            IssueKind::TemporaryFile => write!(f, "leftover temporary file"),
+            IssueKind::IOError(e) => write!(f, "IO error: {}", e),
+        }
+    }
+}
+
+/// Verifies every segment in `base_path` without opening the database:
+/// segment IDs must be unique and contiguous, and every record must decode
+/// with a valid checksum. Index checkpoints must not go past their segment,
+/// and no compaction or atomic write may have been left unfinished.
+pub fn check(base_path: &Path) -> Result<CheckReport, Error> {
+    check_with(base_path, &CancellationToken::default())
+}
+
+/// Like [`check`], stopping early once `cancel` is cancelled.
+pub fn check_with(base_path: &Path, cancel: &CancellationToken) -> Result<CheckReport, Error> {
+    let mut report = CheckReport::default();
+
+    let mut segments: Vec<(u64, PathBuf)> = Vec::new();
+    let entries = read_dir(base_path).map_err(|e| Error::from(e).with_path(base_path))?;
+    for entry in entries {
+        let path = entry
+            .map_err(|e| Error::from(e).with_path(base_path))?
+            .path();
+        let extension = path.extension().and_then(|e| e.to_str());
+        let sidecar = if path == marker_path(base_path) || extension == Some(COMPACTING_EXT) {
+            Some(IssueKind::InterruptedCompaction)
+        } else if extension == Some("tmp") {
+            Some(IssueKind::TemporaryFile)
+        } else {
+            None
+        };
+        if let Some(kind) = sidecar {
+            report.issues.push(Issue {
+                path,
+                offset: None,
+                kind,
+            });
+            continue;
+        }
+        if extension != Some(SEGMENT_EXT) {
+            continue;
+        }
+
+        match SegmentID::try_from(path.as_path()) {
+            Ok(id) => segments.push((id.0, path)),
+            Err(_) => report.issues.push(Issue {
+                path,
+                offset: None,
+                kind: IssueKind::InvalidSegmentName,
+            }),
+        }
+    }
+
+    // NOTE: Sort by ID, not by path: "10.segment" < "2.segment".
+    segments.sort();
+
+    for pair in segments.windows(2) {
+        let ((previous, _), (id, path)) = (&pair[0], &pair[1]);
+        if previous == id {
+            report.issues.push(Issue {
+                path: path.clone(),
+                offset: None,
+                kind: IssueKind::DuplicateSegmentID(*id),
+            });
+        } else if previous + 1 != *id {
+            report.issues.push(Issue {
+                path: path.clone(),
+                offset: None,
+                kind: IssueKind::MissingSegmentIDs {
+                    from: previous + 1,
+                    to: id - 1,
+                },
+            });
+        }
+    }
+
+    for (id, path) in segments {
+        match check_segment(id, &path, &mut report.issues, cancel) {
+            Ok(segment_report) => report.segments.push(segment_report),
+            Err(_) if cancel.is_cancelled() => {
+                return Err(Error::from(SunsetDBError::Cancelled).with_path(&path))
+            }
+            Err(e) => report.issues.push(Issue {
+                path,
+                offset: None,
+                kind: IssueKind::IOError(e.to_string()),
+            }),
+        }
+    }
+
+    Ok(report)
+}
+
+fn check_segment(
+    id: u64,
+    path: &Path,
+    issues: &mut Vec<Issue>,
+    cancel: &CancellationToken,
+) -> io::Result<SegmentReport> {
+    let file = File::open(path)?;
+    let len = file.metadata()?.len();
+    if let Some(c) = checkpoint::read(path).filter(|c| c.watermark > len) {
+        issues.push(Issue {
+            path: checkpoint::checkpoint_path(path),
+            offset: None,
+            kind: IssueKind::CheckpointPastEnd {
+                watermark: c.watermark,
+                len,
+            },
+        });
+    }
+    check_records(id, path, len, &mut BufReader::new(file), issues, cancel)
+}
+
+// Validates the `len` bytes of segment `id` read from `reader`. Fails with
+// `Interrupted` once `cancel` is cancelled.
+pub(crate) fn check_records(
+    id: u64,
+    path: &Path,
+    len: u64,
+    reader: &mut impl Read,
+    issues: &mut Vec<Issue>,
+    cancel: &CancellationToken,
+) -> io::Result<SegmentReport> {
+    let mut report = SegmentReport {
+        id,
+        path: path.to_path_buf(),
+        len,
+        records: 0,
+        tombstones: 0,
+        live_keys: 0,
+    };
+    let mut live_keys = HashSet::new();
+
+    let mut offset = 0;
+    while offset < len {
+        if cancel.is_cancelled() {
+            return Err(io::Error::new(io::ErrorKind::Interrupted, "cancelled"));
+        }
+        let record_offset = offset;
+        let mut issue = |kind| {
+            issues.push(Issue {
+                path: path.to_path_buf(),
+                offset: Some(record_offset),
+                kind,
+            })
+        };
+
+        let key = match read_checked(reader, &mut offset, len)? {
+            Checked::String(key) => key,
+            Checked::Tombstone => {
+                // Without a key length there's no way to find the next record.
+                issue(IssueKind::MisplacedTombstone);
+                break;
+            }
+            Checked::Invalid(kind) => {
+                issue(kind);
+                None
+            }
+            Checked::Truncated => {
+                issue(IssueKind::Truncated);
+                break;
+            }
+        };
+
+        match read_checked(reader, &mut offset, len)? {
+            Checked::String(_) => {
+                if let Some(key) = key {
+                    live_keys.insert(key);
+                }
+            }
+            Checked::Tombstone => {
+                report.tombstones += 1;
+                if let Some(key) = key {
+                    live_keys.remove(&key);
+                }
+            }
+            Checked::Invalid(kind) => issue(kind),
+            Checked::Truncated => {
+                issue(IssueKind::Truncated);
+                break;
+            }
+        }
+        report.records += 1;
+    }
+
+    report.live_keys = live_keys.len() as u64;
+    Ok(report)
+}
+
+enum Checked {
+    String(Option<String>),
+    Tombstone,
+    Invalid(IssueKind),
+    Truncated,
+}
+
+// Like `read_check_string`, but keeps going past bad checksums as long as
+// the encoded lengths allow finding the next record.
+fn read_checked(reader: &mut impl Read, offset: &mut u64, len: u64) -> io::Result<Checked> {
+    if len - *offset < ENCODED_LEN_SIZE as u64 {
+        return Ok(Checked::Truncated);
+    }
+
+    let mut encoded_len = [0; ENCODED_LEN_SIZE];
+    reader.read_exact(&mut encoded_len)?;
+    *offset += ENCODED_LEN_SIZE as u64;
+    let string_len = match decode_len(encoded_len) {
+        EncodedLen::Tombstone => return Ok(Checked::Tombstone),
+        EncodedLen::Len(len) => len,
+    };
+    if len - *offset < string_len.saturating_add(CRC32_SIZE as u64) {
+        return Ok(Checked::Truncated);
+    }
+
+    let mut encoded_string = vec![0; string_len as usize];
+    reader.read_exact(&mut encoded_string)?;
+    let mut encoded_checksum = [0; CRC32_SIZE];
+    reader.read_exact(&mut encoded_checksum)?;
+    *offset += string_len + CRC32_SIZE as u64;
+
+    match decode_string(encoded_string, encoded_checksum) {
+        Ok(s) => Ok(Checked::String(Some(s))),
+        Err(RecordError::InvalidChecksum { expected, found }) => {
+            Ok(Checked::Invalid(IssueKind::InvalidChecksum {
+                expected,
+                found,
+            }))
+        }
+        Err(RecordError::InvalidString(_)) => Ok(Checked::Invalid(IssueKind::InvalidString)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error;
+    use std::fs::OpenOptions;
+    use std::io::Write;
+    use std::num::NonZeroU64;
+
+    use super::*;
+    use crate::{Options, SunsetDB};
+    use tempfile::tempdir;
+
+    type TestResult = Result<(), Box<dyn Error>>;
+
+    #[test]
+    fn check_clean_db_test() -> TestResult {
+        let base_dir = tempdir()?;
+        let mut s = SunsetDB::new(base_dir.path())?;
+        s.insert("k", "v")?;
+        s.insert("j", "w")?;
+        s.delete("k")?;
+
+        let report = check(base_dir.path())?;
+        assert!(report.is_ok(), "{:?}", report.issues);
+        assert_eq!(report.segments.len(), 1);
+        assert_eq!(report.segments[0].records, 3);
+        assert_eq!(report.segments[0].tombstones, 1);
+        assert_eq!(report.segments[0].live_keys, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn check_missing_and_duplicate_ids_test() -> TestResult {
+        let base_dir = tempdir()?;
+        for name in ["0", "3", "03", "foo"] {
+            File::create(base_dir.path().join(format!("{}.{}", name, SEGMENT_EXT)))?;
+        }
+
+        let report = check(base_dir.path())?;
+        let kinds: Vec<_> = report.issues.iter().map(|i| &i.kind).collect();
+        assert!(kinds.contains(&&IssueKind::InvalidSegmentName));
+        assert!(kinds.contains(&&IssueKind::DuplicateSegmentID(3)));
+        assert!(kinds.contains(&&IssueKind::MissingSegmentIDs { from: 1, to: 2 }));
+        Ok(())
+    }
+
+    #[test]
+    fn check_sidecars_test() -> TestResult {
+        let base_dir = tempdir()?;
+        let options = Options {
+            index_checkpoint_bytes: NonZeroU64::new(1),
+            ..Default::default()
+        };
+        let mut s = SunsetDB::with_options(base_dir.path(), options)?;
+        s.insert("k", "v")?;
+        drop(s);
+        assert!(check(base_dir.path())?.is_ok());
+
+        let path = base_dir.path().join(format!("0.{}", SEGMENT_EXT));
+        let len = path.metadata()?.len();
+        OpenOptions::new()
+            .write(true)
+            .open(&path)?
+            .set_len(len - 1)?;
+        for name in ["compaction", "0.compacting", "0.index.tmp"] {
+            File::create(base_dir.path().join(name))?;
+        }
+
+        let report = check(base_dir.path())?;
+        let kinds: Vec<_> = report.issues.iter().map(|i| &i.kind).collect();
+        assert!(kinds.contains(&&IssueKind::CheckpointPastEnd {
+            watermark: len,
+            len: len - 1
+        }));
+        let count = |kind| kinds.iter().filter(|k| ***k == kind).count();
+        assert_eq!(count(IssueKind::InterruptedCompaction), 2);
+        assert_eq!(count(IssueKind::TemporaryFile), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn check_corrupted_segment_test() -> TestResult {
+        let base_dir = tempdir()?;
+        let mut s = SunsetDB::new(base_dir.path())?;
+        s.insert("k", "v")?;
+        s.insert("j", "w")?;
+        drop(s);
+
+        let path = base_dir.path().join(format!("0.{}", SEGMENT_EXT));
+        let mut f = OpenOptions::new().read(true).write(true).open(&path)?;
+
+        // Flip the value of the first record, then truncate the last one.
+        let value_offset = (ENCODED_LEN_SIZE + 1 + CRC32_SIZE + ENCODED_LEN_SIZE) as u64;
+        std::io::Seek::seek(&mut f, io::SeekFrom::Start(value_offset))?;
+        f.write_all(b"x")?;
+        f.set_len(f.metadata()?.len() - 1)?;
+
+        let report = check(base_dir.path())?;
+        assert!(!report.is_ok());
+        assert!(matches!(
+            report.issues[0].kind,
+            IssueKind::InvalidChecksum { .. }
+        ));
+        assert_eq!(report.issues[0].offset, Some(0));
+        assert_eq!(report.issues[1].kind, IssueKind::Truncated);
+        assert_eq!(report.segments[0].records, 1);
+        Ok(())
+    }
+}
@@ -0,0 +1,132 @@
+// Checkpoints of a segment's in-memory index, so that opening the database
+// only replays the records appended after the last one.
+//
+// -- <version> || <watermark> || <dead bytes> || <#keys> || (<key> || <offset> || <value len>)*
+//    || <#deleted> || <key>* || <checksum> --
+//
+// Keys use the record framing; the trailing checksum covers everything before
+// it. The segment itself stays the source of truth: an invalid or stale
+// checkpoint is ignored and the whole segment is replayed instead.
+
+use std::collections::HashSet;
+use std::fs::{rename, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use super::failpoints;
+use super::record::*;
+use super::{Index, IndexEntry};
+
+const CHECKPOINT_EXT: &str = "index";
+
+// Checkpoints of another version are ignored.
+pub(crate) const VERSION: u64 = 2;
+
+#[derive(Default)]
+pub(crate) struct Checkpoint {
+    /// Length of the segment covered by the checkpoint.
+    pub(crate) watermark: u64,
+    pub(crate) index: Index,
+    pub(crate) deleted: HashSet<String>,
+    pub(crate) dead_bytes: u64,
+}
+
+pub(crate) fn checkpoint_path(segment_path: &Path) -> PathBuf {
+    segment_path.with_extension(CHECKPOINT_EXT)
+}
+
+/// Atomically replaces the checkpoint of the segment at `segment_path`.
+pub(crate) fn write(
+    segment_path: &Path,
+    watermark: u64,
+    index: &Index,
+    deleted: &HashSet<String>,
+    dead_bytes: u64,
+) -> io::Result<()> {
+    let mut buffer = Vec::new();
+    buffer.extend_from_slice(&VERSION.to_be_bytes());
+    buffer.extend_from_slice(&watermark.to_be_bytes());
+    buffer.extend_from_slice(&dead_bytes.to_be_bytes());
+    buffer.extend_from_slice(&(index.len() as u64).to_be_bytes());
+    for (key, entry) in index {
+        encode_string(&mut buffer, key);
+        buffer.extend_from_slice(&entry.offset.to_be_bytes());
+        buffer.extend_from_slice(&entry.value_len.to_be_bytes());
+    }
+    buffer.extend_from_slice(&(deleted.len() as u64).to_be_bytes());
+    for key in deleted {
+        encode_string(&mut buffer, key);
+    }
+    let checksum = crc32fast::hash(&buffer);
+    buffer.extend_from_slice(&checksum.to_be_bytes());
+
+    failpoints::fail_point!(failpoints::CHECKPOINT);
+    let path = checkpoint_path(segment_path);
+    let tmp_path = path.with_extension(format!("{}.tmp", CHECKPOINT_EXT));
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(&buffer)?;
+    file.sync_all()?;
+    rename(tmp_path, path)
+}
+
+/// Reads the checkpoint of the segment at `segment_path`, if there is a valid one.
+pub(crate) fn read(segment_path: &Path) -> Option<Checkpoint> {
+    let buffer = std::fs::read(checkpoint_path(segment_path)).ok()?;
+    let (data, checksum) = buffer.split_at(buffer.len().checked_sub(CRC32_SIZE)?);
+    if crc32fast::hash(data).to_be_bytes() != checksum {
+        return None;
+    }
+
+    let mut reader = Reader(data);
+    if reader.u64()? != VERSION {
+        return None;
+    }
+    let watermark = reader.u64()?;
+    let dead_bytes = reader.u64()?;
+    let mut index = Index::new();
+    for _ in 0..reader.u64()? {
+        let key = reader.string()?;
+        let (offset, value_len) = (reader.u64()?, reader.u64()?);
+        index.insert(key, IndexEntry { offset, value_len });
+    }
+    let mut deleted = HashSet::new();
+    for _ in 0..reader.u64()? {
+        deleted.insert(reader.string()?);
+    }
+
+    Some(Checkpoint {
+        watermark,
+        index,
+        deleted,
+        dead_bytes,
+    })
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl Reader<'_> {
+    fn take(&mut self, n: usize) -> Option<&[u8]> {
+        if self.0.len() < n {
+            return None;
+        }
+        let (head, tail) = self.0.split_at(n);
+        self.0 = tail;
+        Some(head)
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        Some(u64::from_be_bytes(
+            self.take(ENCODED_LEN_SIZE)?.try_into().ok()?,
+        ))
+    }
+
+    fn string(&mut self) -> Option<String> {
+        let len = match decode_len(self.take(ENCODED_LEN_SIZE)?.try_into().ok()?) {
+            EncodedLen::Len(len) => usize::try_from(len).ok()?,
+            EncodedLen::Tombstone => return None,
+        };
+        let encoded_string = self.take(len)?.to_vec();
+        let encoded_checksum = self.take(CRC32_SIZE)?.try_into().ok()?;
+        decode_string(encoded_string, encoded_checksum).ok()
+    }
+}
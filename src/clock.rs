@@ -0,0 +1,188 @@
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+/// The source of time of a database, used for trace timestamps, rate
+/// limiting and how often free disk space is re-read, see
+/// [`Options::clock`](crate::Options::clock).
+///
+/// Time is allowed to go backwards: intervals then count as empty.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> SystemTime;
+
+    /// Blocks for `duration`, e.g. while a write is throttled.
+    fn sleep(&self, duration: Duration) {
+        thread::sleep(duration)
+    }
+}
+
+/// The operating system's wall clock.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// A clock that only moves when told to, so that time-dependent behavior
+/// can be tested deterministically. Sleeping advances it instead of
+/// blocking. Clones share the same time.
+#[derive(Debug, Clone)]
+pub struct MockClock(Arc<Mutex<SystemTime>>);
+
+impl MockClock {
+    pub fn new(now: SystemTime) -> MockClock {
+        MockClock(Arc::new(Mutex::new(now)))
+    }
+
+    pub fn set(&self, now: SystemTime) {
+        if let Ok(mut time) = self.0.lock() {
+            *time = now;
+        }
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        if let Ok(mut time) = self.0.lock() {
+            *time += duration;
+        }
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> SystemTime {
+        self.0.lock().map_or(SystemTime::UNIX_EPOCH, |time| *time)
+    }
+
+    fn sleep(&self, duration: Duration) {
+        self.advance(duration)
+    }
+}
+
+/// The [`Clock`] of a database, the [`SystemClock`] by default.
+#[derive(Clone)]
+pub struct SharedClock(Arc<dyn Clock>);
+
+impl SharedClock {
+    pub fn new(clock: impl Clock + 'static) -> SharedClock {
+        SharedClock(Arc::new(clock))
+    }
+
+    pub(crate) fn now(&self) -> SystemTime {
+        self.0.now()
+    }
+
+    pub(crate) fn sleep(&self, duration: Duration) {
+        self.0.sleep(duration)
+    }
+}
+
+impl Default for SharedClock {
+    fn default() -> Self {
+        SharedClock::new(SystemClock)
+    }
+}
+
+impl fmt::Debug for SharedClock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SharedClock({:?})", self.now())
+    }
+}
+
+// Time elapsed from `earlier` to `now`, empty if the clock went backwards.
+pub(crate) fn elapsed(earlier: SystemTime, now: SystemTime) -> Duration {
+    now.duration_since(earlier).unwrap_or(Duration::ZERO)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error;
+    use std::num::NonZeroU64;
+    use std::time::UNIX_EPOCH;
+
+    use super::*;
+    use crate::{read_trace, CompactionOptions, Event, Options, RateLimits, SunsetDB};
+    use tempfile::tempdir;
+
+    type TestResult = Result<(), Box<dyn Error>>;
+
+    #[test]
+    fn mock_clock_test() -> TestResult {
+        let base_dir = tempdir()?;
+        let clock = MockClock::new(UNIX_EPOCH + Duration::from_secs(1000));
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let mut options = Options {
+            clock: SharedClock::new(clock.clone()),
+            rate_limits: RateLimits {
+                ops_per_sec: NonZeroU64::new(1),
+                bytes_per_sec: None,
+                max_stall: None,
+            },
+            ..Default::default()
+        };
+        {
+            let events = events.clone();
+            options
+                .listeners
+                .push(move |e: &Event| events.lock().unwrap().push(e.clone()));
+        }
+
+        let mut s = SunsetDB::with_options(base_dir.path(), options)?;
+        let trace_path = base_dir.path().join("trace");
+        s.start_trace(&trace_path)?;
+        s.insert("a", "1")?;
+        // Out of ops: the throttled write sleeps on the mock clock.
+        s.insert("b", "2")?;
+        s.stop_trace()?;
+
+        assert_eq!(clock.now(), UNIX_EPOCH + Duration::from_secs(1001));
+        let stalls: Vec<_> = events
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|e| match e {
+                Event::WriteStalled { duration } => Some(*duration),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(stalls, [Duration::from_secs(1)]);
+        let timestamps: Vec<_> = read_trace(&trace_path)?
+            .iter()
+            .map(|e| e.timestamp)
+            .collect();
+        assert_eq!(timestamps, [1_000_000_000, 1_000_000_000]);
+
+        // Going backwards refills nothing: the limiter is still in debt
+        // for the throttled write.
+        clock.set(UNIX_EPOCH);
+        s.insert("c", "3")?;
+        assert_eq!(clock.now(), UNIX_EPOCH + Duration::from_secs(2));
+        Ok(())
+    }
+
+    #[test]
+    fn deterministic_options_test() -> TestResult {
+        let base_dir = tempdir()?;
+        let clock = MockClock::new(UNIX_EPOCH);
+        let options = Options {
+            compaction: Some(CompactionOptions {
+                min_dead_ratio: 0.0,
+                max_sealed_segments: 1,
+                ..Default::default()
+            }),
+            max_segment_size: NonZeroU64::new(1),
+            ..Options::deterministic(clock.clone())
+        };
+        let mut s = SunsetDB::with_options(base_dir.path(), options)?;
+        for i in 0..4 {
+            s.insert("k", &i.to_string())?;
+        }
+        // Nothing runs behind the test's back.
+        assert_eq!(s.segment_stats().len(), 4);
+        assert_eq!(s.compact()?.segments_rewritten, [0, 1, 2, 3]);
+        assert_eq!(clock.now(), UNIX_EPOCH);
+        Ok(())
+    }
+}
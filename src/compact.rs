@@ -0,0 +1,1339 @@
+// Merges the sealed segments into one, keeping only the records still live:
+// overwritten values, deleted keys and tombstones are dropped. A tombstone
+// is kept while a segment older than the merged ones holds the key, so
+// that no older record can resurface once it is gone, and for
+// `tombstone_grace_period` after the delete.
+//
+// The owning thread picks the records and installs the result; background
+// threads copy them, so that writes don't wait for the copy. With more than
+// one thread, the sealed segments are split into runs merged side by side.
+// `SunsetDB::compact` and `compact_segment` copy them in place instead.
+// Merges only start once the disk has room for their whole output.
+// A single segment can also be rewritten on its own, keeping its
+// tombstones unless it is the oldest. The output replaces the newest
+// input, under its ID:
+//
+// 1. records are copied, in key order, to `<id>.compacting` and synced;
+// 2. a `compaction` marker lists the inputs;
+// 3. the output is renamed over `<id>.segment`;
+// 4. the other inputs are removed, then the marker.
+//
+// A compaction filter sees every live record copied in 1, and can drop it
+// or change its value. A dropped record leaves a tombstone behind unless
+// the oldest segment is merged.
+//
+// Opening the database finishes a compaction interrupted after 3, and
+// drops one interrupted before. Before 2, the newest input ID is recorded
+// in `compacted-through`: log positions up to it no longer point at records.
+
+use std::collections::HashSet;
+use std::fmt;
+use std::fs::{read_dir, remove_file, rename, File};
+use std::io::{self, BufWriter, Write};
+use std::ops::Range;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime};
+
+use super::cancel::CancellationToken;
+use super::checkpoint::checkpoint_path;
+use super::disk_space::DiskSpace;
+use super::error::*;
+use super::events::Event;
+use super::failpoints;
+use super::info::{self, LAST_COMPACTION};
+use super::logging::{log_info, log_warn};
+use super::record::{encode_deletion, encode_string, CRC32_SIZE, ENCODED_LEN_SIZE};
+use super::scrub::join_within;
+use super::{
+    decode_value, encoded_record_len, read_check_string, Index, IndexEntry, Segment, SegmentID,
+    SunsetDB, SEGMENT_EXT,
+};
+
+pub(crate) const COMPACTING_EXT: &str = "compacting";
+const MARKER: &str = "compaction";
+const COMPACTED_THROUGH: &str = "compacted-through";
+
+/// When the background compactor merges the sealed segments, see
+/// [`Options::compaction`](crate::Options::compaction).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CompactionOptions {
+    /// Merge once overwritten and deleted values make up this share of the
+    /// sealed segments.
+    pub min_dead_ratio: f64,
+    /// Merge once there are this many sealed segments.
+    pub max_sealed_segments: usize,
+    /// Split the sealed segments into up to this many runs of at least two
+    /// segments, merged side by side on as many threads. Runs after the
+    /// oldest one keep the tombstones of keys that older runs hold.
+    pub max_parallel_merges: usize,
+}
+
+impl Default for CompactionOptions {
+    fn default() -> Self {
+        CompactionOptions {
+            min_dead_ratio: 0.5,
+            max_sealed_segments: 8,
+            max_parallel_merges: 1,
+        }
+    }
+}
+
+/// What a [`CompactionFilter`] does with a record.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FilterDecision {
+    Keep,
+    /// Delete the key.
+    Drop,
+    /// Keep the key with this value instead.
+    Rewrite(String),
+}
+
+/// Sees every live record a compaction copies, e.g. to expire or redact
+/// values, see [`Options::compaction_filter`](crate::Options::compaction_filter).
+///
+/// Called from the thread running the merge, which may be the background
+/// compactor. Records written since the merge was planned are not seen.
+pub trait CompactionFilter: Send + Sync {
+    fn filter(&self, key: &str, value: &str) -> FilterDecision;
+}
+
+impl<F: Fn(&str, &str) -> FilterDecision + Send + Sync> CompactionFilter for F {
+    fn filter(&self, key: &str, value: &str) -> FilterDecision {
+        self(key, value)
+    }
+}
+
+/// The [`CompactionFilter`] of a database.
+#[derive(Clone)]
+pub struct SharedCompactionFilter(Arc<dyn CompactionFilter>);
+
+impl SharedCompactionFilter {
+    pub fn new(filter: impl CompactionFilter + 'static) -> SharedCompactionFilter {
+        SharedCompactionFilter(Arc::new(filter))
+    }
+}
+
+impl fmt::Debug for SharedCompactionFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SharedCompactionFilter")
+    }
+}
+
+/// Outcome of [`SunsetDB::compact`] and [`SunsetDB::compact_segment`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CompactionReport {
+    /// IDs of the segments rewritten into the newest of them, empty if
+    /// there was nothing to reclaim.
+    pub segments_rewritten: Vec<u64>,
+    pub bytes_reclaimed: u64,
+}
+
+// The live records of consecutive sealed segments, to copy into `output`.
+struct Job {
+    // Position of the oldest input among the segments, when planned.
+    first: usize,
+    // IDs and paths of the inputs, oldest first.
+    inputs: Vec<(u64, PathBuf)>,
+    // Key, position of the segment holding it, and its entry there.
+    records: Vec<(String, usize, IndexEntry)>,
+    // Deleted keys that older segments may still hold, or deleted within
+    // the grace period.
+    tombstones: Vec<String>,
+    // Deleted keys whose tombstones are dropped.
+    expired: Vec<String>,
+    output: PathBuf,
+    // Planned length of the output.
+    len: u64,
+    filter: Option<SharedCompactionFilter>,
+}
+
+struct Merged {
+    job: Job,
+    // Entries of the copied records, in the order of `job.records`, `None`
+    // for the ones the filter dropped.
+    entries: Vec<Option<IndexEntry>>,
+    file: File,
+    len: u64,
+}
+
+/// Background threads running the merges planned by
+/// [`SunsetDB::maybe_compact`], one at a time each.
+///
+/// Started through [`Options::compaction`](crate::Options::compaction) and
+/// stopped when the database is dropped.
+pub(crate) struct Compactor {
+    options: CompactionOptions,
+    jobs: Option<Sender<Job>>,
+    // Outcome of each merge, along with the IDs of its inputs.
+    results: Receiver<(Vec<u64>, Result<Merged, Error>)>,
+    stop: Arc<AtomicBool>,
+    handles: Vec<JoinHandle<()>>,
+    // IDs of the inputs of each running merge.
+    running: Vec<Vec<u64>>,
+    // Same, for the last merge that failed: it is only retried once more
+    // segments are sealed.
+    failed: Option<u64>,
+    pid: u32,
+}
+
+impl Compactor {
+    pub(crate) fn start(options: CompactionOptions, name: String) -> io::Result<Compactor> {
+        let stop = Arc::new(AtomicBool::new(false));
+        let (jobs, job_receiver) = mpsc::channel::<Job>();
+        let job_receiver = Arc::new(Mutex::new(job_receiver));
+        let (result_sender, results) = mpsc::channel();
+
+        let mut handles = Vec::new();
+        for _ in 0..options.max_parallel_merges.max(1) {
+            let stopped = stop.clone();
+            let job_receiver = job_receiver.clone();
+            let result_sender = result_sender.clone();
+            let handle = thread::Builder::new()
+                .name(name.clone())
+                .spawn(move || loop {
+                    // Only held while waiting for the next job.
+                    let job = match job_receiver.lock() {
+                        Ok(receiver) => receiver.recv(),
+                        Err(_) => break,
+                    };
+                    let Ok(job) = job else {
+                        break;
+                    };
+                    let ids = job.inputs.iter().map(|(id, _)| *id).collect();
+                    let output = job.output.clone();
+                    let result = merge(job, &stopped);
+                    if result.is_err() {
+                        let _ = remove_file(output);
+                    }
+                    if result_sender.send((ids, result)).is_err() {
+                        break;
+                    }
+                })?;
+            handles.push(handle);
+        }
+
+        Ok(Compactor {
+            options,
+            jobs: Some(jobs),
+            results,
+            stop,
+            handles,
+            running: Vec::new(),
+            failed: None,
+            pid: std::process::id(),
+        })
+    }
+
+    /// Stops the threads, abandoning running merges, and waits up to
+    /// `timeout` for them to exit, returning whether they did.
+    pub(crate) fn stop(mut self, timeout: Duration) -> bool {
+        self.stop.store(true, Ordering::Relaxed);
+        self.jobs.take();
+        let deadline = Instant::now() + timeout;
+        let mut exited = true;
+        for handle in self.handles.drain(..) {
+            exited &= join_within(handle, deadline.saturating_duration_since(Instant::now()));
+        }
+        exited
+    }
+}
+
+impl Drop for Compactor {
+    fn drop(&mut self) {
+        if self.pid != std::process::id() {
+            // Forked: the threads only exist in the parent.
+            std::mem::forget(std::mem::take(&mut self.handles));
+            return;
+        }
+        self.stop.store(true, Ordering::Relaxed);
+        self.jobs.take();
+        for handle in self.handles.drain(..) {
+            let _ = handle.join();
+        }
+    }
+}
+
+impl SunsetDB {
+    /// Merges every segment into one, keeping only the live records, and
+    /// returns what was reclaimed. The active segment is sealed first,
+    /// so that writes continue in a new one, unless it is empty or there is
+    /// nothing to reclaim. With a
+    /// [`compaction_filter`](crate::Options::compaction_filter), every
+    /// record may be.
+    ///
+    /// Runs on the calling thread, even while maintenance is paused or
+    /// without [`Options::compaction`](crate::Options::compaction). A merge
+    /// running in the background is installed first. Fails with
+    /// [`ErrorKind::OutOfSpace`] if the disk can't hold the merged segment.
+    pub fn compact(&mut self) -> Result<CompactionReport, Error> {
+        self.compact_with(&CancellationToken::default())
+    }
+
+    /// Like [`compact`](SunsetDB::compact), checking `cancel` before each
+    /// record copied. A cancelled compaction installs nothing.
+    pub fn compact_with(&mut self, cancel: &CancellationToken) -> Result<CompactionReport, Error> {
+        self.check_writable()?;
+        self.install_merged(true);
+        // The segments merged, including the active one unless empty.
+        // Checked before sealing, like in `compact_segment_with`.
+        let active_is_empty = self.segments.last().map_or(true, |s| s.len == 0);
+        let inputs = self.segments.len() - active_is_empty as usize;
+        // A filter may find some in any record.
+        let now = self.options.clock.now();
+        let garbage = self.options.compaction_filter.is_some()
+            || self.segments[..inputs].iter().any(|s| {
+                s.dead_bytes > 0
+                    || s.deleted
+                        .iter()
+                        .any(|key| !self.keeps_tombstone(key, 0, now))
+            });
+        if inputs == 0 || inputs < 2 && !garbage {
+            return Ok(CompactionReport::default());
+        }
+        if !active_is_empty {
+            self.seal_active()?;
+        }
+        self.compact_now(0..inputs, cancel)
+    }
+
+    /// Rewrites segment `id` alone, dropping its overwritten and deleted
+    /// records, and returns what was reclaimed. Tombstones are kept while
+    /// older segments hold the key, or within the
+    /// [`tombstone_grace_period`](crate::Options::tombstone_grace_period).
+    /// The active segment is sealed first, unless there is nothing to reclaim.
+    ///
+    /// Runs on the calling thread, like [`compact`](SunsetDB::compact).
+    pub fn compact_segment(&mut self, id: u64) -> Result<CompactionReport, Error> {
+        self.compact_segment_with(id, &CancellationToken::default())
+    }
+
+    /// Like [`compact_segment`](SunsetDB::compact_segment), checking
+    /// `cancel` like [`compact_with`](SunsetDB::compact_with).
+    pub fn compact_segment_with(
+        &mut self,
+        id: u64,
+        cancel: &CancellationToken,
+    ) -> Result<CompactionReport, Error> {
+        self.check_writable()?;
+        self.install_merged(true);
+        let position = self
+            .segments
+            .iter()
+            .position(|s| s.id.0 == id)
+            .ok_or(SunsetDBError::UnknownSegment(id))?;
+        // Checked first, so that an active segment without garbage, e.g. an
+        // empty one, is left active.
+        let segment = &self.segments[position];
+        let filtered = self.options.compaction_filter.is_some() && segment.len > 0;
+        let now = self.options.clock.now();
+        let expired = segment
+            .deleted
+            .iter()
+            .any(|key| !self.keeps_tombstone(key, position, now));
+        if !filtered && segment.dead_bytes == 0 && !expired {
+            return Ok(CompactionReport::default());
+        }
+        if position == self.segments.len() - 1 {
+            self.seal_active()?;
+        }
+        self.compact_now(position..position + 1, cancel)
+    }
+
+    fn compact_now(
+        &mut self,
+        inputs: Range<usize>,
+        cancel: &CancellationToken,
+    ) -> Result<CompactionReport, Error> {
+        cancel.check()?;
+        let job = self.plan(inputs);
+        self.check_merge_space(std::slice::from_ref(&job), false)?;
+        let ids: Vec<u64> = job.inputs.iter().map(|(id, _)| *id).collect();
+        self.options.listeners.emit(Event::CompactionStarted {
+            inputs: ids.clone(),
+        });
+        let output = job.output.clone();
+        let report = merge(job, cancel.flag())
+            .map_err(|e| {
+                let _ = remove_file(&output);
+                e
+            })
+            .and_then(|merged| self.install(merged))
+            .map_err(|e| {
+                self.options.listeners.emit(Event::CompactionAbandoned {
+                    inputs: ids,
+                    error: Some(e.kind()),
+                });
+                e
+            })?;
+        Ok(report.unwrap_or_default())
+    }
+
+    // Installs the merge that finished, if any, then starts the next one if
+    // the sealed segments call for it. Called before writes, while no
+    // segment position is held. Failures are logged and leave the segments
+    // as they were.
+    pub(crate) fn maybe_compact(&mut self) {
+        self.install_merged(false);
+
+        if self.maintenance_paused {
+            return;
+        }
+        let jobs = self.plan_compaction();
+        if jobs.is_empty() {
+            return;
+        }
+        let fits = self.check_merge_space(&jobs, true).is_ok();
+        let Some(compactor) = self.compactor.as_mut() else {
+            return;
+        };
+        if !fits {
+            // Retried once more segments are sealed, like a failed merge.
+            compactor.failed = self.segments.iter().rev().nth(1).map(|s| s.id.0);
+            return;
+        }
+        for job in jobs {
+            let inputs: Vec<u64> = job.inputs.iter().map(|(id, _)| *id).collect();
+            let sent = compactor.jobs.as_ref().map(|jobs| jobs.send(job).is_ok());
+            if let Some(true) = sent {
+                compactor.running.push(inputs.clone());
+                self.options
+                    .listeners
+                    .emit(Event::CompactionStarted { inputs });
+            }
+        }
+    }
+
+    // Installs the merges running in the background once they are done,
+    // waiting for them if `wait`.
+    fn install_merged(&mut self, wait: bool) {
+        loop {
+            let Some(compactor) = self.compactor.as_mut() else {
+                return;
+            };
+            let Some(oldest) = compactor.running.first().cloned() else {
+                return;
+            };
+            let received = if wait {
+                compactor
+                    .results
+                    .recv()
+                    .map_err(|_| TryRecvError::Disconnected)
+            } else {
+                compactor.results.try_recv()
+            };
+            let (inputs, merged) = match received {
+                Ok(result) => result,
+                Err(TryRecvError::Empty) => return,
+                Err(TryRecvError::Disconnected) => {
+                    let e = io::Error::new(io::ErrorKind::Other, "compactor thread exited");
+                    (oldest, Err(e.into()))
+                }
+            };
+            compactor.running.retain(|running| *running != inputs);
+            self.install_result(inputs, merged);
+        }
+    }
+
+    #[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+    fn install_result(&mut self, inputs: Vec<u64>, merged: Result<Merged, Error>) {
+        let id = inputs.last().copied().unwrap_or_default();
+        if let Err(e) = merged.and_then(|merged| self.install(merged)) {
+            log_warn!(segment_id = id, error = %e, "compaction failed");
+            if e.kind() == ErrorKind::Corruption {
+                self.options.listeners.emit(Event::corruption(&e));
+            }
+            self.options.listeners.emit(Event::CompactionAbandoned {
+                inputs,
+                error: Some(e.kind()),
+            });
+            // Whichever run failed, merges wait for the next sealed segment.
+            let newest = self.segments.iter().rev().nth(1).map(|s| s.id.0);
+            if let Some(compactor) = self.compactor.as_mut() {
+                compactor.failed = newest;
+            }
+        }
+    }
+
+    // One job per run of sealed segments, none while merges are running.
+    fn plan_compaction(&self) -> Vec<Job> {
+        let Some(compactor) = self.compactor.as_ref() else {
+            return Vec::new();
+        };
+        let options = compactor.options;
+        let sealed = &self.segments[..self.segments.len().saturating_sub(1)];
+        let Some(newest) = sealed.last().map(|s| s.id.0) else {
+            return Vec::new();
+        };
+        if compactor.failed == Some(newest) || !compactor.running.is_empty() {
+            return Vec::new();
+        }
+
+        let len: u64 = sealed.iter().map(|s| s.len).sum();
+        let dead_bytes: u64 = sealed.iter().map(|s| s.dead_bytes).sum();
+        let dead_ratio = dead_bytes as f64 / len.max(1) as f64;
+        // A lone segment without dead bytes would be merged into itself.
+        let too_many = sealed.len() > 1 && sealed.len() >= options.max_sealed_segments;
+        let too_dead = dead_bytes > 0 && dead_ratio >= options.min_dead_ratio;
+        if !too_many && !too_dead {
+            return Vec::new();
+        }
+        log_info!(
+            segments = sealed.len(),
+            len,
+            dead_bytes,
+            dead_ratio,
+            min_dead_ratio = options.min_dead_ratio,
+            max_sealed_segments = options.max_sealed_segments,
+            "compacting the sealed segments"
+        );
+
+        // Runs of at least two segments, so that each one shrinks.
+        let runs = options.max_parallel_merges.min(sealed.len() / 2).max(1);
+        (0..runs)
+            .map(|run| self.plan(run * sealed.len() / runs..(run + 1) * sealed.len() / runs))
+            .collect()
+    }
+
+    // Merges write their whole output before removing their inputs. The
+    // `background` ones pause while space is low, see `DiskSpaceLimits`.
+    fn check_merge_space(&mut self, jobs: &[Job], background: bool) -> Result<(), Error> {
+        let needed = jobs.iter().map(|job| job.len).sum();
+        let written = self.segments.iter().map(|s| s.len).sum();
+        let space = self
+            .disk_watchdog
+            .check_room(
+                &self.base_path,
+                needed,
+                written,
+                background,
+                self.options.clock.now(),
+            )
+            .map_err(|e| Error::from(e).with_path(&self.base_path))?;
+        if let DiskSpace::Exhausted { available } = space {
+            log_warn!(
+                inputs = ?jobs.iter().flat_map(|job| job.inputs.iter().map(|(id, _)| id)).collect::<Vec<_>>(),
+                needed,
+                available,
+                "not enough disk space to compact"
+            );
+            return Err(
+                Error::from(SunsetDBError::CompactionOutOfSpace { needed, available })
+                    .with_path(&self.base_path),
+            );
+        }
+        Ok(())
+    }
+
+    // Whether merging segments from `first` on must keep the tombstone of
+    // `key`: older segments would serve it again, or it is too recent.
+    fn keeps_tombstone(&self, key: &str, first: usize, now: SystemTime) -> bool {
+        let recent = match (
+            self.options.tombstone_grace_period,
+            self.deleted_at.get(key),
+        ) {
+            (Some(grace), Some(at)) => now.duration_since(*at).unwrap_or_default() < grace,
+            _ => false,
+        };
+        recent || self.segments[..first].iter().any(|s| s.holds(key))
+    }
+
+    // The records of the segments at `inputs` that are still live, and
+    // the tombstones to keep.
+    fn plan(&self, inputs: Range<usize>) -> Job {
+        let segments = &self.segments[inputs.clone()];
+        let mut records: Vec<_> = self
+            .live_keys()
+            .into_iter()
+            .filter(|(i, _)| inputs.contains(i))
+            .filter_map(|(i, key)| {
+                let entry = *self.segments[i].index.get(&key)?;
+                Some((key, i, entry))
+            })
+            .collect();
+        records.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+
+        // Keys written again since their delete shadow older records anyway.
+        let live: HashSet<&str> = records.iter().map(|(key, _, _)| key.as_str()).collect();
+        let now = self.options.clock.now();
+        let (mut tombstones, expired): (Vec<_>, _) = segments
+            .iter()
+            .flat_map(|s| s.deleted.iter().cloned())
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .partition(|key| {
+                !live.contains(key.as_str()) && self.keeps_tombstone(key, inputs.start, now)
+            });
+        tombstones.sort_unstable();
+
+        let len = records
+            .iter()
+            .map(|(key, _, entry)| entry.record_len(key))
+            .chain(tombstones.iter().map(|key| encoded_record_len(key, None)))
+            .sum();
+        let newest = segments.last().map_or(0, |s| s.id.0);
+        Job {
+            first: inputs.start,
+            inputs: segments.iter().map(|s| (s.id.0, s.path.clone())).collect(),
+            records,
+            tombstones,
+            expired,
+            output: self
+                .base_path
+                .join(format!("{}.{}", newest, COMPACTING_EXT)),
+            len,
+            filter: self.options.compaction_filter.clone(),
+        }
+    }
+
+    // Swaps the output in for the inputs, keeping the records that are
+    // still live: keys deleted or overwritten since the merge was planned
+    // become dead bytes.
+    // Returns `None` if the inputs changed in the meantime.
+    fn install(&mut self, merged: Merged) -> Result<Option<CompactionReport>, Error> {
+        let Merged {
+            job,
+            entries,
+            file,
+            len,
+        } = merged;
+        let ids: Vec<u64> = job.inputs.iter().map(|(id, _)| *id).collect();
+        // Merges installed since this one was planned may have moved it.
+        let first = self
+            .segments
+            .iter()
+            .position(|s| ids.first() == Some(&s.id.0))
+            .unwrap_or(self.segments.len());
+        let inputs = first..first + ids.len();
+        let unchanged = self.segments.len() > inputs.end
+            && self.segments[inputs.clone()]
+                .iter()
+                .zip(&ids)
+                .all(|(s, id)| s.id.0 == *id);
+        let Some(&newest) = ids.last().filter(|_| unchanged) else {
+            let _ = remove_file(&job.output);
+            self.options.listeners.emit(Event::CompactionAbandoned {
+                inputs: ids,
+                error: None,
+            });
+            return Ok(None);
+        };
+
+        let mut index = Index::new();
+        let mut deleted: HashSet<String> = job.tombstones.into_iter().collect();
+        let mut hidden = HashSet::new();
+        let mut dead_bytes = 0;
+        for ((key, input, old), entry) in job.records.into_iter().zip(entries) {
+            let input = input - job.first + first;
+            let live = self.current_record(&key).map(|(i, _)| i) == Some(input)
+                && self.segments[input].index.get(&key) == Some(&old);
+            match entry {
+                Some(entry) if live => {
+                    index.insert(key, entry);
+                }
+                Some(entry) => {
+                    dead_bytes += entry.record_len(&key);
+                    // Possibly deleted since.
+                    hidden.insert(key);
+                }
+                // Dropped by the filter.
+                None => {
+                    if live {
+                        self.live_keys -= 1;
+                        // Like a delete, see `append_tombstone`.
+                        for s in &mut self.segments[..first] {
+                            s.hide(&key);
+                        }
+                    }
+                    if job.first > 0 {
+                        deleted.insert(key);
+                    }
+                }
+            }
+        }
+
+        let path = self.path_from_id(newest);
+        if compacted_through(&self.base_path).map_or(true, |id| id < newest) {
+            write_compacted_through(&self.base_path, newest)
+                .map_err(|e| Error::from(e).with_path(&self.base_path))?;
+        }
+        swap_in(&self.base_path, &ids, &job.output, &path)
+            .map_err(|e| Error::from(e).with_segment(newest, &path))?;
+        let segment = Segment {
+            id: SegmentID(newest),
+            path,
+            file,
+            index,
+            deleted,
+            hidden,
+            len,
+            checkpointed: 0,
+            dead_bytes,
+        };
+        let input_len: u64 = self.segments[inputs.clone()].iter().map(|s| s.len).sum();
+        self.segments.splice(inputs, [segment]);
+        for key in job.expired {
+            if !self.segments.iter().any(|s| s.deleted.contains(&key)) {
+                self.deleted_at.remove(&key);
+            }
+        }
+
+        let bytes_reclaimed = input_len.saturating_sub(len);
+        // Only informational, see `info`.
+        let _ = info::write_stamp(&self.base_path, LAST_COMPACTION, self.options.clock.now());
+        log_info!(
+            inputs = ?ids,
+            segment_id = newest,
+            len,
+            bytes_reclaimed,
+            "installed compacted segment"
+        );
+        self.options.listeners.emit(Event::SegmentsCompacted {
+            inputs: ids.clone(),
+            id: newest,
+            bytes_reclaimed,
+        });
+        Ok(Some(CompactionReport {
+            segments_rewritten: ids,
+            bytes_reclaimed,
+        }))
+    }
+}
+
+// Copies the records of `job`, validating their checksums.
+fn merge(job: Job, stop: &AtomicBool) -> Result<Merged, Error> {
+    let output_error = |e: io::Error| Error::from(e).with_path(&job.output);
+    let inputs = job
+        .inputs
+        .iter()
+        .map(|(id, path)| File::open(path).map_err(|e| Error::from(e).with_segment(*id, path)))
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut writer = BufWriter::new(File::create(&job.output).map_err(output_error)?);
+
+    let mut entries = Vec::with_capacity(job.records.len());
+    let mut len = 0;
+    for (key, input, entry) in &job.records {
+        if stop.load(Ordering::Relaxed) {
+            return Err(SunsetDBError::Cancelled.into());
+        }
+        let input = input - job.first;
+        let mut record = read_record(&inputs[input], key, *entry).map_err(|e| {
+            let (id, path) = &job.inputs[input];
+            Error::from(e)
+                .with_segment(*id, path)
+                .with_offset(entry.offset)
+                .with_key(key)
+        })?;
+        let mut value_len = entry.value_len;
+        if let Some(filter) = &job.filter {
+            let value = decode_value(&record[value_offset(key)..], *entry, false)
+                .map_err(|e| Error::from(e).with_key(key))?;
+            match filter.0.filter(key, value) {
+                FilterDecision::Keep => {}
+                FilterDecision::Drop => {
+                    // Hides the key from older segments, see `install`.
+                    if job.first > 0 {
+                        record.truncate(value_offset(key));
+                        encode_deletion(&mut record);
+                        writer.write_all(&record).map_err(output_error)?;
+                        len += record.len() as u64;
+                    }
+                    entries.push(None);
+                    continue;
+                }
+                FilterDecision::Rewrite(value) => {
+                    record.truncate(value_offset(key));
+                    encode_string(&mut record, &value);
+                    value_len = value.len() as u64;
+                }
+            }
+        }
+        writer.write_all(&record).map_err(output_error)?;
+        entries.push(Some(IndexEntry {
+            offset: len,
+            value_len,
+        }));
+        len += record.len() as u64;
+    }
+    for key in &job.tombstones {
+        let mut record = Vec::new();
+        encode_string(&mut record, key);
+        encode_deletion(&mut record);
+        writer.write_all(&record).map_err(output_error)?;
+        len += record.len() as u64;
+    }
+
+    writer
+        .into_inner()
+        .map_err(|e| output_error(e.into_error()))?
+        .sync_all()
+        .map_err(output_error)?;
+    // Sealed segments are read-only; this handle follows the rename.
+    let file = File::open(&job.output).map_err(output_error)?;
+    Ok(Merged {
+        job,
+        entries,
+        file,
+        len,
+    })
+}
+
+// Where the value starts in the record of `key`.
+fn value_offset(key: &str) -> usize {
+    ENCODED_LEN_SIZE + key.len() + CRC32_SIZE
+}
+
+fn read_record(file: &File, key: &str, entry: IndexEntry) -> Result<Vec<u8>, GetError> {
+    let len = usize::try_from(entry.record_len(key)).map_err(ReadError::from)?;
+    let mut record = vec![0; len];
+    file.read_exact_at(&mut record, entry.offset)
+        .map_err(ReadError::from)?;
+
+    let mut value = &record[..];
+    if read_check_string(&mut value)?.as_deref() != Some(key) {
+        return Err(GetError::KeyMismatch);
+    }
+    decode_value(value, entry, true)?;
+    Ok(record)
+}
+
+pub(crate) fn marker_path(base_path: &Path) -> PathBuf {
+    base_path.join(MARKER)
+}
+
+fn segment_path(base_path: &Path, id: u64) -> PathBuf {
+    base_path.join(format!("{}.{}", id, SEGMENT_EXT))
+}
+
+// Steps 2 to 4 above, for the inputs `ids`, the newest at `newest`. Fails
+// without changing any segment unless the output was renamed in.
+fn swap_in(base_path: &Path, ids: &[u64], output: &Path, newest: &Path) -> io::Result<()> {
+    let marker = base_path.join(MARKER);
+
+    let renamed = write_marker(base_path, ids)
+        .and_then(|_| remove_if_exists(&checkpoint_path(newest)))
+        .and_then(|_| {
+            failpoints::fail_point!(failpoints::COMPACTION_SWAP);
+            rename(output, newest)
+        });
+    if let Err(e) = renamed {
+        // The marker goes first: without the output, it would complete
+        // the compaction on the next open.
+        if remove_if_exists(&marker).is_ok() {
+            let _ = remove_file(output);
+        }
+        return Err(e);
+    }
+    // Whatever is left is finished when the database is next opened.
+    let _ = finish(base_path, ids);
+    Ok(())
+}
+
+// Step 4 above.
+fn finish(base_path: &Path, ids: &[u64]) -> io::Result<()> {
+    failpoints::fail_point!(failpoints::COMPACTION_FINISH);
+    for id in ids.split_last().map_or(&[][..], |(_, older)| older) {
+        let path = segment_path(base_path, *id);
+        remove_if_exists(&path)?;
+        remove_if_exists(&checkpoint_path(&path))?;
+    }
+    sync_dir(base_path)?;
+    remove_if_exists(&base_path.join(MARKER))?;
+    sync_dir(base_path)
+}
+
+/// The ID of the newest segment a compaction rewrote, if any.
+pub(crate) fn compacted_through(base_path: &Path) -> Option<u64> {
+    std::fs::read_to_string(base_path.join(COMPACTED_THROUGH))
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+}
+
+// Synced along with the marker, which is written next.
+fn write_compacted_through(base_path: &Path, id: u64) -> io::Result<()> {
+    let tmp_path = base_path.join(format!("{}.tmp", COMPACTED_THROUGH));
+    let mut file = File::create(&tmp_path)?;
+    writeln!(file, "{}", id)?;
+    file.sync_all()?;
+    rename(tmp_path, base_path.join(COMPACTED_THROUGH))
+}
+
+fn write_marker(base_path: &Path, ids: &[u64]) -> io::Result<()> {
+    let ids: Vec<String> = ids.iter().map(u64::to_string).collect();
+    let tmp_path = base_path.join(format!("{}.tmp", MARKER));
+    let mut file = File::create(&tmp_path)?;
+    writeln!(file, "{}", ids.join(" "))?;
+    file.sync_all()?;
+    rename(tmp_path, base_path.join(MARKER))?;
+    sync_dir(base_path)
+}
+
+// The inputs listed by the marker of an interrupted compaction, if any,
+// and whether its output was renamed in.
+fn read_marker(base_path: &Path) -> Result<Option<(Vec<u64>, bool)>, Error> {
+    let marker = base_path.join(MARKER);
+    let ids = match std::fs::read_to_string(&marker) {
+        Ok(ids) => ids,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(Error::from(e).with_path(&marker)),
+    };
+    let ids = ids
+        .split_whitespace()
+        .map(str::parse)
+        .collect::<Result<Vec<u64>, _>>()
+        .ok()
+        .filter(|ids| !ids.is_empty())
+        .ok_or_else(|| Error::from(SunsetDBError::InvalidCompactionMarker).with_path(&marker))?;
+    let newest = ids[ids.len() - 1];
+    let renamed = !base_path
+        .join(format!("{}.{}", newest, COMPACTING_EXT))
+        .exists();
+    Ok(Some((ids, renamed)))
+}
+
+/// Segments that `recover` will remove, and that opening the database
+/// skips: the older inputs of an interrupted compaction whose output
+/// replaced the newest one.
+pub(crate) fn superseded(base_path: &Path) -> Result<Vec<u64>, Error> {
+    Ok(match read_marker(base_path)? {
+        Some((mut ids, true)) => {
+            ids.pop();
+            ids
+        }
+        _ => Vec::new(),
+    })
+}
+
+/// Completes or rolls back a compaction interrupted by a crash, see above.
+/// Called once opening the database replayed the segments, so that
+/// cancelling it leaves the files as they were.
+#[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+pub(crate) fn recover(base_path: &Path) -> Result<(), Error> {
+    let marker = base_path.join(MARKER);
+    let error = |e: io::Error| Error::from(e).with_path(&marker);
+    match read_marker(base_path)? {
+        Some((ids, false)) => {
+            log_warn!(inputs = ?ids, "rolling back an interrupted compaction");
+            remove_file(&marker).map_err(error)?;
+        }
+        Some((ids, true)) => {
+            log_warn!(inputs = ?ids, "finishing an interrupted compaction");
+            finish(base_path, &ids).map_err(error)?;
+        }
+        None => {}
+    }
+
+    // Outputs of merges that were not installed.
+    let entries = read_dir(base_path).map_err(|e| Error::from(e).with_path(base_path))?;
+    for entry in entries.filter_map(io::Result::ok) {
+        let path = entry.path();
+        if path.extension().and_then(|e| e.to_str()) == Some(COMPACTING_EXT) {
+            remove_file(&path).map_err(|e| Error::from(e).with_path(&path))?;
+        }
+    }
+    Ok(())
+}
+
+fn remove_if_exists(path: &Path) -> io::Result<()> {
+    match remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+pub(crate) fn sync_dir(path: &Path) -> io::Result<()> {
+    File::open(path)?.sync_all()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error;
+    use std::num::NonZeroU64;
+    use std::ops::ControlFlow;
+    use std::sync::Mutex;
+    use std::time::Instant;
+
+    use super::*;
+    use crate::{encoded_record_len, MockClock, Options, RecoveryProgress};
+    use tempfile::tempdir;
+
+    type TestResult = Result<(), Box<dyn Error>>;
+
+    #[test]
+    fn background_compaction_test() -> TestResult {
+        let base_dir = tempdir()?;
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let mut options = Options {
+            max_segment_size: NonZeroU64::new(4 * encoded_record_len("k0", Some("v0"))),
+            compaction: Some(CompactionOptions {
+                min_dead_ratio: 0.25,
+                max_sealed_segments: 100,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let listener_events = events.clone();
+        options.listeners.push(move |e: &Event| {
+            if let Event::CompactionStarted { .. } | Event::SegmentsCompacted { .. } = e {
+                listener_events.lock().unwrap().push(e.clone());
+            }
+        });
+        let mut s = SunsetDB::with_options(base_dir.path(), options.clone())?;
+
+        for i in 0..8 {
+            s.insert(&format!("k{}", i), &format!("v{}", i))?;
+        }
+        // Two records of the eight in sealed segments die, reaching the ratio.
+        s.insert("k0", "new")?;
+        s.delete("k1")?;
+        s.insert_sorted_batch([("k8", "v8"), ("k9", "v9")])?;
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while events.lock().unwrap().len() < 2 {
+            assert!(Instant::now() < deadline, "no compaction");
+            thread::sleep(Duration::from_millis(1));
+            s.maybe_compact();
+        }
+        assert_eq!(
+            events.lock().unwrap()[..],
+            [
+                Event::CompactionStarted { inputs: vec![0, 1] },
+                Event::SegmentsCompacted {
+                    inputs: vec![0, 1],
+                    id: 1,
+                    bytes_reclaimed: 2 * encoded_record_len("k0", Some("v0")),
+                }
+            ]
+        );
+        assert!(!s.path_from_id(0).exists() && !base_dir.path().join(MARKER).exists());
+        assert_eq!(s.segments[0].dead_bytes, 0);
+
+        let check = |s: &mut SunsetDB| -> TestResult {
+            assert_eq!(s.get("k0")?.as_deref(), Some("new"));
+            assert_eq!(s.get("k1")?, None);
+            assert_eq!(s.get("k7")?.as_deref(), Some("v7"));
+            let keys: Vec<_> = s
+                .scan_filtered(.., |_, _| true)?
+                .into_iter()
+                .map(|(k, _)| k)
+                .collect();
+            assert_eq!(keys, ["k0", "k2", "k3", "k4", "k5", "k6", "k7", "k8", "k9"]);
+            Ok(())
+        };
+        check(&mut s)?;
+        assert!(s.shutdown(Duration::from_secs(10)));
+        drop(s);
+        check(&mut SunsetDB::new(base_dir.path())?)?;
+        Ok(())
+    }
+
+    #[test]
+    fn parallel_compaction_test() -> TestResult {
+        let base_dir = tempdir()?;
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let mut options = Options {
+            max_segment_size: NonZeroU64::new(2 * encoded_record_len("k0", Some("v0"))),
+            compaction: Some(CompactionOptions {
+                min_dead_ratio: 1.0,
+                max_sealed_segments: 4,
+                max_parallel_merges: 2,
+            }),
+            ..Default::default()
+        };
+        let listener_events = events.clone();
+        options.listeners.push(move |e: &Event| {
+            if let Event::CompactionStarted { .. } | Event::SegmentsCompacted { .. } = e {
+                listener_events.lock().unwrap().push(e.clone());
+            }
+        });
+        let mut s = SunsetDB::with_options(base_dir.path(), options)?;
+
+        for i in 0..6 {
+            s.insert(&format!("k{}", i), &format!("v{}", i))?;
+        }
+        s.delete("k0")?;
+        for i in 6..9 {
+            s.insert(&format!("k{}", i), &format!("v{}", i))?;
+        }
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while events.lock().unwrap().len() < 4 {
+            assert!(Instant::now() < deadline, "no compaction");
+            thread::sleep(Duration::from_millis(1));
+            s.maybe_compact();
+        }
+        let mut events = events.lock().unwrap().clone();
+        events[2..].sort_by_key(|e| format!("{:?}", e));
+        assert_eq!(
+            events[..3],
+            [
+                Event::CompactionStarted { inputs: vec![0, 1] },
+                Event::CompactionStarted { inputs: vec![2, 3] },
+                Event::SegmentsCompacted {
+                    inputs: vec![0, 1],
+                    id: 1,
+                    bytes_reclaimed: encoded_record_len("k0", Some("v0")),
+                },
+            ]
+        );
+        let ids: Vec<_> = s.segment_stats().iter().map(|stats| stats.id).collect();
+        assert_eq!(ids, [1, 3, 4]);
+
+        // The second run kept the tombstone of "k0".
+        let check = |s: &mut SunsetDB| -> TestResult {
+            assert_eq!(s.get("k0")?, None);
+            assert_eq!(s.count(..), 8);
+            assert_eq!(s.get("k5")?.as_deref(), Some("v5"));
+            Ok(())
+        };
+        check(&mut s)?;
+        assert!(s.shutdown(Duration::from_secs(10)));
+        drop(s);
+        check(&mut SunsetDB::new(base_dir.path())?)?;
+        Ok(())
+    }
+
+    #[test]
+    fn compaction_filter_test() -> TestResult {
+        let base_dir = tempdir()?;
+        let options = Options {
+            compaction_filter: Some(SharedCompactionFilter::new(
+                |key: &str, value: &str| match key.split_once('/') {
+                    Some(("tmp", _)) => FilterDecision::Drop,
+                    Some(("pii", _)) => FilterDecision::Rewrite(format!("<{}>", value.len())),
+                    _ => FilterDecision::Keep,
+                },
+            )),
+            ..Default::default()
+        };
+        let mut s = SunsetDB::with_options(base_dir.path(), options.clone())?;
+        s.insert("tmp/a", "old")?;
+        s.insert("k", "v")?;
+        s.add_new_segment()?;
+        s.insert("tmp/a", "new")?;
+        s.insert("pii/b", "secret")?;
+        s.add_new_segment()?;
+        s.insert("tmp/c", "v")?;
+
+        // The older "tmp/a" stays hidden behind a tombstone.
+        assert_eq!(s.compact_segment(1)?.segments_rewritten, [1]);
+        assert_eq!(s.get("tmp/a")?, None);
+        assert_eq!(s.get("pii/b")?.as_deref(), Some("<6>"));
+        assert_eq!(s.live_keys, 3);
+        drop(s);
+
+        let mut s = SunsetDB::with_options(base_dir.path(), options)?;
+        assert_eq!(s.get("tmp/a")?, None);
+        assert_eq!(s.get("pii/b")?.as_deref(), Some("<6>"));
+        s.compact()?;
+        assert_eq!(s.get("tmp/c")?, None);
+        assert_eq!(s.get("k")?.as_deref(), Some("v"));
+        assert_eq!(s.live_keys, 2);
+        assert!(s.segment_stats().iter().all(|stats| stats.dead_bytes == 0));
+        Ok(())
+    }
+
+    #[test]
+    fn explicit_compaction_test() -> TestResult {
+        let base_dir = tempdir()?;
+        let mut s = SunsetDB::new(base_dir.path())?;
+        for i in 0..4 {
+            s.insert(&format!("k{}", i), "v")?;
+        }
+        s.insert("k0", "new")?;
+        s.delete("k1")?;
+        s.add_new_segment()?;
+        s.insert("k4", "v")?;
+        s.insert("k4", "w")?;
+        s.delete("k2")?;
+        s.add_new_segment()?;
+        s.insert("k5", "v")?;
+
+        // Keeps the tombstone of "k2", which segment 0 still holds.
+        let report = s.compact_segment(1)?;
+        assert_eq!(
+            report,
+            CompactionReport {
+                segments_rewritten: vec![1],
+                bytes_reclaimed: encoded_record_len("k4", Some("v")),
+            }
+        );
+        assert_eq!(s.compact_segment(1)?, CompactionReport::default());
+        // The active segment has nothing to reclaim, so it stays active.
+        assert_eq!(s.compact_segment(2)?, CompactionReport::default());
+        assert_eq!(s.segment_stats().len(), 3);
+        let e = s.compact_segment(9).err();
+        assert_eq!(e.map(|e| e.kind()), Some(ErrorKind::InvalidInput));
+        drop(s);
+
+        let mut s = SunsetDB::new(base_dir.path())?;
+        assert_eq!(s.get("k2")?, None);
+        assert_eq!(s.get("k4")?.as_deref(), Some("w"));
+
+        let len: u64 = s.segment_stats().iter().map(|s| s.len).sum();
+        let report = s.compact()?;
+        let live = [("k0", "new"), ("k3", "v"), ("k4", "w"), ("k5", "v")];
+        let live_len: u64 = live
+            .iter()
+            .map(|(k, v)| encoded_record_len(k, Some(v)))
+            .sum();
+        assert_eq!(
+            report,
+            CompactionReport {
+                segments_rewritten: vec![0, 1, 2],
+                bytes_reclaimed: len - live_len,
+            }
+        );
+        assert_eq!(s.compact()?, CompactionReport::default());
+        s.insert("k6", "v")?;
+        drop(s);
+
+        let mut s = SunsetDB::new(base_dir.path())?;
+        let ids: Vec<_> = s.segment_stats().iter().map(|s| s.id).collect();
+        assert_eq!(ids, [2, 3]);
+        let pairs = s.scan_filtered(.., |_, _| true)?;
+        let expected: Vec<_> = live
+            .iter()
+            .chain(&[("k6", "v")])
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        assert_eq!(pairs, expected);
+
+        // A lone active segment without garbage stays active.
+        let base_dir = tempdir()?;
+        let mut s = SunsetDB::new(base_dir.path())?;
+        s.insert("k", "v")?;
+        assert_eq!(s.compact()?, CompactionReport::default());
+        assert_eq!(s.segment_stats().len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn tombstone_gc_test() -> TestResult {
+        let base_dir = tempdir()?;
+        let mut s = SunsetDB::new(base_dir.path())?;
+        s.insert("a", "v")?;
+        s.add_new_segment()?;
+        s.delete("a")?;
+        s.force_delete("x")?;
+        s.add_new_segment()?;
+        s.insert("b", "v")?;
+
+        // Segment 0 still holds "a", but nothing older holds "x".
+        let report = s.compact_segment(1)?;
+        assert_eq!(report.bytes_reclaimed, encoded_record_len("x", None));
+        assert_eq!(s.compact_segment(1)?, CompactionReport::default());
+        drop(s);
+        let mut s = SunsetDB::new(base_dir.path())?;
+        assert_eq!(s.get("a")?, None);
+
+        let clock = MockClock::new(SystemTime::UNIX_EPOCH);
+        let options = Options {
+            tombstone_grace_period: Some(Duration::from_secs(60)),
+            ..Options::deterministic(clock.clone())
+        };
+        let base_dir = tempdir()?;
+        let mut s = SunsetDB::with_options(base_dir.path(), options.clone())?;
+        s.insert("k", "v")?;
+        s.delete("k")?;
+        let report = s.compact()?;
+        assert_eq!(report.bytes_reclaimed, encoded_record_len("k", Some("v")));
+        assert_eq!(s.compact()?, CompactionReport::default());
+        clock.advance(Duration::from_secs(60));
+        drop(s);
+
+        // Counts from the reopen.
+        let mut s = SunsetDB::with_options(base_dir.path(), options)?;
+        assert_eq!(s.compact()?, CompactionReport::default());
+        clock.advance(Duration::from_secs(60));
+        let report = s.compact()?;
+        assert_eq!(report.bytes_reclaimed, encoded_record_len("k", None));
+        assert_eq!(s.get("k")?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn abandoned_compaction_test() -> TestResult {
+        let base_dir = tempdir()?;
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let mut options = Options::default();
+        let listener_events = events.clone();
+        options.listeners.push(move |e: &Event| {
+            if let Event::CompactionStarted { .. } | Event::CompactionAbandoned { .. } = e {
+                listener_events.lock().unwrap().push(e.clone());
+            }
+        });
+        let mut s = SunsetDB::with_options(base_dir.path(), options)?;
+        s.insert("k", "v")?;
+        s.insert("k", "w")?;
+
+        // Corrupt the live value of "k".
+        let f = std::fs::OpenOptions::new()
+            .write(true)
+            .open(s.path_from_id(0))?;
+        f.write_all_at(b"X", 2 * encoded_record_len("k", Some("v")) - 5)?;
+        let e = s.compact().err();
+        assert_eq!(e.map(|e| e.kind()), Some(ErrorKind::Corruption));
+        assert_eq!(
+            events.lock().unwrap()[..],
+            [
+                Event::CompactionStarted { inputs: vec![0] },
+                Event::CompactionAbandoned {
+                    inputs: vec![0],
+                    error: Some(ErrorKind::Corruption),
+                }
+            ]
+        );
+        assert!(s.path_from_id(0).exists());
+        Ok(())
+    }
+
+    #[test]
+    fn interrupted_compaction_test() -> TestResult {
+        let base_dir = tempdir()?;
+        let mut s = SunsetDB::new(base_dir.path())?;
+        for i in 0..3 {
+            s.insert(&format!("k{}", i), "v")?;
+            s.add_new_segment()?;
+        }
+        drop(s);
+        let marker = base_dir.path().join(MARKER);
+        let output = base_dir.path().join(format!("1.{}", COMPACTING_EXT));
+
+        // Cancelling the open leaves it to the next one.
+        std::fs::write(&marker, "0 1\n")?;
+        let mut options = Options::default();
+        options
+            .recovery_listeners
+            .push(|_: &RecoveryProgress| ControlFlow::Break(()));
+        let e = SunsetDB::with_options(base_dir.path(), options).err();
+        assert_eq!(e.map(|e| e.kind()), Some(ErrorKind::Cancelled));
+        assert!(marker.exists() && base_dir.path().join("0.segment").exists());
+
+        // Before the output was renamed in: rolled back.
+        std::fs::write(&output, "")?;
+        let mut s = SunsetDB::new(base_dir.path())?;
+        assert!(!marker.exists() && !output.exists());
+        assert_eq!(s.get("k0")?.as_deref(), Some("v"));
+        drop(s);
+
+        // After: the older inputs are removed.
+        std::fs::write(&marker, "0 1\n")?;
+        let mut s = SunsetDB::new(base_dir.path())?;
+        assert!(!marker.exists() && !s.path_from_id(0).exists());
+        assert_eq!(s.get("k0")?, None);
+        assert_eq!(s.get("k1")?.as_deref(), Some("v"));
+        drop(s);
+
+        std::fs::write(&marker, "0 x\n")?;
+        let e = SunsetDB::new(base_dir.path()).err();
+        assert_eq!(e.map(|e| e.kind()), Some(ErrorKind::Corruption));
+        Ok(())
+    }
+}
@@ -0,0 +1,239 @@
+// Scans that run in batches and can be resumed after a restart. A cursor
+// only remembers its range, narrowed past the last key returned, so its
+// token stays valid across writes and reopens.
+
+use std::ops::{Bound, RangeBounds};
+
+use super::cancel::CancellationToken;
+use super::error::*;
+use super::SunsetDB;
+
+const TOKEN_VERSION: &str = "c1";
+
+/// A scan over a range of keys in key order, see [`SunsetDB::cursor`].
+///
+/// Each batch starts after the last key returned, as the database is then:
+/// keys written ahead of the cursor in the meantime are returned, and keys
+/// deleted are not.
+///
+/// A cursor holds no segment between batches, so compactions in between
+/// neither skip nor repeat keys, and there is no file to keep alive.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cursor {
+    start: Bound<String>,
+    end: Bound<String>,
+}
+
+impl SunsetDB {
+    /// Starts a cursor over the live keys within `range`.
+    pub fn cursor<'r>(&self, range: impl RangeBounds<&'r str>) -> Cursor {
+        Cursor::new(range)
+    }
+
+    /// Resumes the cursor that returned `token` from
+    /// [`save_token`](Cursor::save_token), after the last key it returned.
+    pub fn resume_from_token(&self, token: &str) -> Result<Cursor, Error> {
+        let parse = || {
+            let (crc, fields) = token.split_once('.')?;
+            if u32::from_str_radix(crc, 16).ok()? != crc32fast::hash(fields.as_bytes()) {
+                return None;
+            }
+            let mut fields = fields.split('.');
+            if fields.next()? != TOKEN_VERSION {
+                return None;
+            }
+            let cursor = Cursor {
+                start: decode_bound(fields.next()?)?,
+                end: decode_bound(fields.next()?)?,
+            };
+            fields.next().is_none().then_some(cursor)
+        };
+        parse().ok_or_else(|| SunsetDBError::InvalidCursorToken.into())
+    }
+}
+
+impl Cursor {
+    pub(crate) fn new<'r>(range: impl RangeBounds<&'r str>) -> Cursor {
+        Cursor {
+            start: owned(range.start_bound()),
+            end: owned(range.end_bound()),
+        }
+    }
+
+    /// Reads up to `max_pairs` of the next pairs, returning an empty batch
+    /// once the range is exhausted. Records written next to each other,
+    /// e.g. by [`SunsetDB::insert_sorted_batch`], are read together.
+    ///
+    /// The index is not ordered: finding the next keys goes through every
+    /// key of the database, so each batch takes time linear in their number,
+    /// while only holding `max_pairs` of them.
+    pub fn next_batch(
+        &mut self,
+        db: &mut SunsetDB,
+        max_pairs: usize,
+    ) -> Result<Vec<(String, String)>, Error> {
+        self.next_batch_with(db, max_pairs, &CancellationToken::default())
+    }
+
+    /// Like [`next_batch`](Cursor::next_batch), checking `cancel` before
+    /// reading. A cancelled cursor stays where it was.
+    pub fn next_batch_with(
+        &mut self,
+        db: &mut SunsetDB,
+        max_pairs: usize,
+        cancel: &CancellationToken,
+    ) -> Result<Vec<(String, String)>, Error> {
+        db.check_fork()?;
+        cancel.check()?;
+        let deadline = db.deadline();
+        let range = (borrowed(&self.start), borrowed(&self.end));
+        let keys = db.first_keys_in_range(range, max_pairs);
+        let Some(last) = keys.last().cloned() else {
+            return Ok(Vec::new());
+        };
+        let batch = db.read_batch(keys)?;
+        deadline.check(&db.options.clock)?;
+        self.start = Bound::Excluded(last);
+        Ok(batch
+            .into_iter()
+            .map(|(_, key, value)| (key, value))
+            .collect())
+    }
+
+    /// Encodes where the cursor stands, to resume it with
+    /// [`SunsetDB::resume_from_token`]. Tokens are ASCII.
+    pub fn save_token(&self) -> String {
+        let fields = format!(
+            "{}.{}.{}",
+            TOKEN_VERSION,
+            encode_bound(&self.start),
+            encode_bound(&self.end)
+        );
+        format!("{:08x}.{}", crc32fast::hash(fields.as_bytes()), fields)
+    }
+}
+
+fn owned(bound: Bound<&&str>) -> Bound<String> {
+    match bound {
+        Bound::Included(key) => Bound::Included(key.to_string()),
+        Bound::Excluded(key) => Bound::Excluded(key.to_string()),
+        Bound::Unbounded => Bound::Unbounded,
+    }
+}
+
+fn borrowed(bound: &Bound<String>) -> Bound<&str> {
+    match bound {
+        Bound::Included(key) => Bound::Included(key),
+        Bound::Excluded(key) => Bound::Excluded(key),
+        Bound::Unbounded => Bound::Unbounded,
+    }
+}
+
+// `u` for unbounded, or `i` (included) or `x` (excluded) followed by the
+// key in hex.
+fn encode_bound(bound: &Bound<String>) -> String {
+    let (tag, key) = match bound {
+        Bound::Unbounded => return "u".to_string(),
+        Bound::Included(key) => ('i', key),
+        Bound::Excluded(key) => ('x', key),
+    };
+    let hex: String = key.bytes().map(|b| format!("{:02x}", b)).collect();
+    format!("{}{}", tag, hex)
+}
+
+fn decode_bound(field: &str) -> Option<Bound<String>> {
+    let (tag, hex) = (field.get(..1)?, field.get(1..)?);
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    let bytes = (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect::<Option<Vec<_>>>()?;
+    let key = String::from_utf8(bytes).ok()?;
+    match tag {
+        "u" if key.is_empty() => Some(Bound::Unbounded),
+        "i" => Some(Bound::Included(key)),
+        "x" => Some(Bound::Excluded(key)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error;
+
+    use super::*;
+    use crate::ErrorKind;
+    use tempfile::tempdir;
+
+    type TestResult = Result<(), Box<dyn Error>>;
+
+    #[test]
+    fn cursor_test() -> TestResult {
+        let base_dir = tempdir()?;
+        let mut s = SunsetDB::new(base_dir.path())?;
+        for i in 0..10 {
+            s.insert(&format!("k{}", i), &i.to_string())?;
+        }
+        s.insert("z", "outside")?;
+
+        let mut cursor = s.cursor("k1".."k9");
+        let batch = cursor.next_batch(&mut s, 3)?;
+        assert_eq!(
+            batch.iter().map(|(k, _)| k.as_str()).collect::<Vec<_>>(),
+            ["k1", "k2", "k3"]
+        );
+        let token = cursor.save_token();
+        assert!(token.is_ascii());
+        s.delete("k5")?;
+        drop(s);
+
+        let mut s = SunsetDB::new(base_dir.path())?;
+        let mut cursor = s.resume_from_token(&token)?;
+        // Writes ahead of the cursor show up, once per key.
+        s.add_new_segment()?;
+        s.insert("k4", "new")?;
+        s.insert("k45", "x")?;
+        let mut keys = Vec::new();
+        loop {
+            let batch = cursor.next_batch(&mut s, 2)?;
+            if batch.is_empty() {
+                break;
+            }
+            keys.extend(batch.into_iter().map(|(k, _)| k));
+        }
+        assert_eq!(keys, ["k4", "k45", "k6", "k7", "k8"]);
+        assert_eq!(
+            s.resume_from_token(&cursor.save_token())?
+                .next_batch(&mut s, 1)?,
+            []
+        );
+
+        // Compaction between batches rewrites the segments under the cursor.
+        let mut cursor = s.cursor(..);
+        let (mut keys, mut rewritten) = (Vec::new(), Vec::new());
+        loop {
+            let batch = cursor.next_batch(&mut s, 4)?;
+            if batch.is_empty() {
+                break;
+            }
+            keys.extend(batch.into_iter().map(|(k, _)| k));
+            s.insert("k55", "ahead")?;
+            rewritten.extend(s.compact()?.segments_rewritten);
+        }
+        let expected = s.scan_filtered(.., |_, _| true)?;
+        assert_eq!(
+            keys,
+            expected.into_iter().map(|(k, _)| k).collect::<Vec<_>>()
+        );
+        assert!(!rewritten.is_empty());
+
+        let corrupted = token.replace("c1.", "c2.");
+        for bad in ["", "0.c1.u.u", &corrupted] {
+            let e = s.resume_from_token(bad).unwrap_err();
+            assert_eq!(e.kind(), ErrorKind::InvalidInput);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,103 @@
+// Per-operation deadlines, see `Options::op_timeout`.
+//
+// Blocking IO can't be interrupted through std, so deadlines are checked
+// between steps: after reads return, before writes reach the disk, and
+// against the time a write would be throttled for.
+
+use std::time::{Duration, SystemTime};
+
+use super::clock::SharedClock;
+use super::error::SunsetDBError;
+
+// The default never expires.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct Deadline(Option<(SystemTime, Duration)>);
+
+impl Deadline {
+    // Starts counting `timeout` from now; `None` never expires.
+    pub(crate) fn start(timeout: Option<Duration>, clock: &SharedClock) -> Deadline {
+        Deadline(timeout.map(|timeout| (clock.now() + timeout, timeout)))
+    }
+
+    // Time left before the deadline, `None` if there is none.
+    pub(crate) fn remaining(&self, clock: &SharedClock) -> Option<Duration> {
+        let (at, _) = self.0?;
+        Some(at.duration_since(clock.now()).unwrap_or(Duration::ZERO))
+    }
+
+    pub(crate) fn check(&self, clock: &SharedClock) -> Result<(), SunsetDBError> {
+        match self.0 {
+            Some((at, timeout)) if clock.now() > at => {
+                Err(SunsetDBError::DeadlineExceeded { timeout })
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error;
+    use std::num::NonZeroU64;
+    use std::time::UNIX_EPOCH;
+
+    use crate::{Clock, ErrorKind, MockClock, Options, RateLimits, SunsetDB, Tunable};
+    use tempfile::tempdir;
+
+    use super::*;
+
+    type TestResult = Result<(), Box<dyn Error>>;
+
+    #[test]
+    fn op_timeout_test() -> TestResult {
+        let base_dir = tempdir()?;
+        let clock = MockClock::new(UNIX_EPOCH);
+        let options = Options {
+            clock: SharedClock::new(clock.clone()),
+            op_timeout: Some(Duration::from_millis(500)),
+            ..Default::default()
+        };
+        let mut s = SunsetDB::with_options(base_dir.path(), options)?;
+        for key in ["a", "b", "c"] {
+            s.insert(key, "v")?;
+        }
+
+        // A write throttled past its deadline fails without waiting.
+        s.set_option(Tunable::RateLimits(RateLimits {
+            ops_per_sec: NonZeroU64::new(1),
+            bytes_per_sec: None,
+            max_stall: None,
+        }));
+        s.insert("d", "v")?;
+        let e = s.insert("e", "v").unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::TimedOut);
+        assert_eq!(clock.now(), UNIX_EPOCH);
+        assert_eq!(s.get("e")?, None);
+        assert_eq!(s.delete("d").unwrap_err().kind(), ErrorKind::TimedOut);
+        s.set_option(Tunable::RateLimits(RateLimits::default()));
+
+        // Batches start their deadline once, before the first item.
+        let batch = [("x", "v"), ("y", "v")].into_iter().inspect(|_| {
+            clock.advance(Duration::from_millis(300));
+        });
+        let e = s.insert_sorted_batch(batch).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::TimedOut);
+        assert_eq!(s.get("x")?, None);
+
+        // Scans check the deadline between the values they read.
+        let e = s
+            .fold(.., 0, |n, _, _| {
+                clock.advance(Duration::from_secs(1));
+                n + 1
+            })
+            .unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::TimedOut);
+        s.set_option(Tunable::OpTimeout(None));
+        let n = s.fold(.., 0, |n, _, _| {
+            clock.advance(Duration::from_secs(1));
+            n + 1
+        })?;
+        assert_eq!(n, 4);
+        Ok(())
+    }
+}
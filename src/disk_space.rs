@@ -0,0 +1,194 @@
+use std::ffi::CString;
+use std::io;
+use std::mem::MaybeUninit;
+use std::os::unix::prelude::OsStrExt;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+use crate::clock::elapsed;
+
+// Free space is only re-read from the filesystem this often; in between,
+// the growth of the segments is subtracted from the last reading, so that
+// writes that failed don't count.
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Free-space low-water marks for the filesystem holding the database, in
+/// bytes; `None` disables the check.
+///
+/// Free space is read by writes, at most once a second, and before merges:
+/// there is no background polling, so an idle database raises no event.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DiskSpaceLimits {
+    /// Below this, an [`Event::LowDiskSpace`](crate::Event::LowDiskSpace) is
+    /// raised, and background merges whose output would bring free space
+    /// below it are not started. [`SunsetDB::compact`](crate::SunsetDB::compact)
+    /// still runs while the output fits.
+    pub warn_below: Option<u64>,
+    /// Below this, writes fail with [`ErrorKind::OutOfSpace`](crate::ErrorKind::OutOfSpace).
+    /// Deletes are still accepted.
+    pub reject_below: Option<u64>,
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum DiskSpace {
+    Ok,
+    /// Crossed below `warn_below` since the previous check.
+    Low {
+        available: u64,
+    },
+    Exhausted {
+        available: u64,
+    },
+}
+
+pub(crate) struct DiskWatchdog {
+    limits: DiskSpaceLimits,
+    available: u64,
+    // Length of the segments when `available` was read.
+    written: u64,
+    last_check: Option<SystemTime>,
+    is_low: bool,
+}
+
+impl DiskWatchdog {
+    pub(crate) fn new(limits: DiskSpaceLimits) -> DiskWatchdog {
+        DiskWatchdog {
+            limits,
+            available: u64::MAX,
+            written: 0,
+            last_check: None,
+            is_low: false,
+        }
+    }
+
+    /// Checks whether a write of `bytes` leaves enough space on `path`,
+    /// where the segments hold `written` bytes.
+    pub(crate) fn check(
+        &mut self,
+        path: &Path,
+        bytes: u64,
+        written: u64,
+        now: SystemTime,
+    ) -> io::Result<DiskSpace> {
+        if self.limits == DiskSpaceLimits::default() {
+            return Ok(DiskSpace::Ok);
+        }
+
+        if self
+            .last_check
+            .map_or(true, |t| elapsed(t, now) >= CHECK_INTERVAL)
+        {
+            self.read(path, written, now)?;
+        }
+
+        let available = self
+            .available
+            .saturating_sub(written.saturating_sub(self.written))
+            .saturating_sub(bytes);
+        if self.limits.reject_below.is_some_and(|l| available < l) {
+            return Ok(DiskSpace::Exhausted { available });
+        }
+
+        let is_low = self.limits.warn_below.is_some_and(|l| available < l);
+        let crossed = is_low && !self.is_low;
+        self.is_low = is_low;
+        if crossed {
+            Ok(DiskSpace::Low { available })
+        } else {
+            Ok(DiskSpace::Ok)
+        }
+    }
+
+    /// Checks that `bytes` fit on `path`, reading the free space again.
+    /// `reject_below` doesn't apply, e.g. a merge frees its inputs once
+    /// done, but with `keep_warn_below`, `warn_below` must remain free.
+    pub(crate) fn check_room(
+        &mut self,
+        path: &Path,
+        bytes: u64,
+        written: u64,
+        keep_warn_below: bool,
+        now: SystemTime,
+    ) -> io::Result<DiskSpace> {
+        self.read(path, written, now)?;
+        let reserve = match self.limits.warn_below {
+            Some(l) if keep_warn_below => l,
+            _ => 0,
+        };
+        if bytes.saturating_add(reserve) > self.available {
+            return Ok(DiskSpace::Exhausted {
+                available: self.available,
+            });
+        }
+        Ok(DiskSpace::Ok)
+    }
+
+    fn read(&mut self, path: &Path, written: u64, now: SystemTime) -> io::Result<()> {
+        self.available = available_space(path)?;
+        self.written = written;
+        self.last_check = Some(now);
+        Ok(())
+    }
+}
+
+/// Bytes available to unprivileged users on the filesystem holding `path`.
+pub(crate) fn available_space(path: &Path) -> io::Result<u64> {
+    #[cfg(feature = "failpoints")]
+    fail::fail_point!(crate::failpoints::DISK_SPACE, |available| {
+        available
+            .and_then(|a| a.parse().ok())
+            .ok_or_else(|| crate::failpoints::error(crate::failpoints::DISK_SPACE, None))
+    });
+
+    let c_path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+    let mut stat = MaybeUninit::<libc::statvfs>::uninit();
+    // SAFETY: `c_path` is NUL-terminated and `stat` is only read on success.
+    let stat = unsafe {
+        if libc::statvfs(c_path.as_ptr(), stat.as_mut_ptr()) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        stat.assume_init()
+    };
+
+    #[allow(clippy::unnecessary_cast)] // Field widths differ across platforms.
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn disk_watchdog_test() -> io::Result<()> {
+        let base_dir = tempdir()?;
+        assert!(available_space(base_dir.path())? > 0);
+
+        let mut watchdog = DiskWatchdog::new(DiskSpaceLimits {
+            warn_below: Some(u64::MAX),
+            reject_below: None,
+        });
+        assert!(matches!(
+            watchdog.check(base_dir.path(), 1, 0, SystemTime::now())?,
+            DiskSpace::Low { .. }
+        ));
+        // Only raised once until space recovers.
+        assert_eq!(
+            watchdog.check(base_dir.path(), 1, 0, SystemTime::now())?,
+            DiskSpace::Ok
+        );
+
+        let mut watchdog = DiskWatchdog::new(DiskSpaceLimits {
+            warn_below: None,
+            reject_below: Some(u64::MAX),
+        });
+        assert!(matches!(
+            watchdog.check(base_dir.path(), 0, 0, SystemTime::now())?,
+            DiskSpace::Exhausted { .. }
+        ));
+
+        Ok(())
+    }
+}
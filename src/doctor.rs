@@ -0,0 +1,375 @@
+// Environment checks for a database directory: most reports of lost or
+// corrupted data trace back to the machine rather than to the database.
+
+use std::fmt;
+use std::fs::read_dir;
+use std::io;
+use std::mem::MaybeUninit;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use super::disk_space::available_space;
+use super::error::*;
+use super::{SegmentID, SEGMENT_EXT};
+
+// File descriptors left, beyond one per segment, for checkpoints, the
+// compactor and the application itself.
+const FD_HEADROOM: u64 = 64;
+
+// Segment timestamps this far ahead of the clock are not blamed on
+// filesystem timestamp granularity.
+const CLOCK_SKEW: Duration = Duration::from_secs(60);
+
+// 2020-09-13: a clock before this was most likely never set.
+const PLAUSIBLE_NOW: Duration = Duration::from_secs(1_600_000_000);
+
+/// Outcome of [`doctor`].
+#[derive(Debug)]
+pub struct DoctorReport {
+    pub segments: u64,
+    /// Total length of all segments but the newest, which compaction may
+    /// rewrite.
+    pub sealed_bytes: u64,
+    /// Soft limit on open files, `None` if unlimited.
+    pub open_files_limit: Option<u64>,
+    /// `None` if not recognized.
+    pub filesystem: Option<Filesystem>,
+    pub available_bytes: u64,
+    pub warnings: Vec<Warning>,
+}
+
+impl DoctorReport {
+    pub fn is_ok(&self) -> bool {
+        self.warnings.is_empty()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Filesystem {
+    pub name: &'static str,
+    pub fsync: Fsync,
+}
+
+/// What a successful `fsync` guarantees on a filesystem.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fsync {
+    /// Synced data survives a crash or power loss.
+    Durable,
+    /// Data is only kept in memory and lost on reboot, synced or not.
+    Volatile,
+    /// Data is sent to a server, which may report write errors late, or to
+    /// another client.
+    Remote,
+    /// Left to a userspace implementation, which may ignore it.
+    UserSpace,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Warning {
+    TooFewFileDescriptors { limit: u64, segments: u64 },
+    WeakFsync(Filesystem),
+    LowCompactionHeadroom { available: u64, needed: u64 },
+    InUse { pid: u32 },
+    ClockNotSet { now: SystemTime },
+    ClockBehind { path: PathBuf, by: Duration },
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Warning::TooFewFileDescriptors { limit, segments } => write!(
+                f,
+                "the open files limit of {} leaves little room for {} segments, each kept open: \
+                 raise it with `ulimit -n` or compact the database",
+                limit, segments
+            ),
+            Warning::WeakFsync(Filesystem {
+                name,
+                fsync: Fsync::Volatile,
+            }) => write!(
+                f,
+                "{} keeps data in memory only: everything is lost on reboot, \
+                 move the database to a disk-backed filesystem",
+                name
+            ),
+            Warning::WeakFsync(Filesystem {
+                name,
+                fsync: Fsync::Remote,
+            }) => write!(
+                f,
+                "{} is a network filesystem: write errors may only be reported on close \
+                 and other clients may see stale data, prefer a local filesystem",
+                name
+            ),
+            Warning::WeakFsync(Filesystem { name, .. }) => write!(
+                f,
+                "{} leaves fsync to a userspace implementation: check that it persists \
+                 data before acknowledging it, or prefer a kernel filesystem",
+                name
+            ),
+            Warning::LowCompactionHeadroom { available, needed } => write!(
+                f,
+                "{} bytes are available but compacting the sealed segments may need up to {}: \
+                 free up space before enabling compaction",
+                available, needed
+            ),
+            Warning::InUse { pid } => write!(
+                f,
+                "process {} has segments open: the database does not lock its directory, \
+                 so make sure only one process writes to it",
+                pid
+            ),
+            Warning::ClockNotSet { now } => write!(
+                f,
+                "the system clock reads {}s since the epoch and looks unset: \
+                 trace timestamps and rate limits will be off, enable time synchronization",
+                now.duration_since(SystemTime::UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs()
+            ),
+            Warning::ClockBehind { path, by } => write!(
+                f,
+                "{} was modified {}s in the future: the clock went backwards or the \
+                 directory was copied from a host with a different clock, check time \
+                 synchronization",
+                path.display(),
+                by.as_secs()
+            ),
+        }
+    }
+}
+
+/// Checks the environment of the database in `base_path` without opening
+/// it: open file limits, the filesystem, free space, other processes using
+/// it and the system clock.
+pub fn doctor(base_path: &Path) -> Result<DoctorReport, Error> {
+    doctor_at(base_path, SystemTime::now(), std::process::id())
+}
+
+// Ignores process `this`, which may well have the database open.
+fn doctor_at(base_path: &Path, now: SystemTime, this: u32) -> Result<DoctorReport, Error> {
+    let with_path = |e: io::Error| Error::from(e).with_path(base_path);
+    let mut warnings = Vec::new();
+
+    let mut segments = Vec::new();
+    for entry in read_dir(base_path).map_err(with_path)? {
+        let entry = entry.map_err(with_path)?;
+        let path = entry.path();
+        if path.extension().and_then(|e| e.to_str()) != Some(SEGMENT_EXT) {
+            continue;
+        }
+        if let Ok(id) = SegmentID::try_from(path.as_path()) {
+            let metadata = entry.metadata().map_err(with_path)?;
+            segments.push((id.0, metadata.len(), metadata.modified().ok(), path));
+        }
+    }
+    segments.sort();
+
+    let open_files_limit = open_files_limit().map_err(with_path)?;
+    if let Some(limit) = open_files_limit {
+        if segments.len() as u64 + FD_HEADROOM > limit {
+            warnings.push(Warning::TooFewFileDescriptors {
+                limit,
+                segments: segments.len() as u64,
+            });
+        }
+    }
+
+    let filesystem = filesystem(base_path).map_err(with_path)?;
+    if let Some(filesystem) = &filesystem {
+        if filesystem.fsync != Fsync::Durable {
+            warnings.push(Warning::WeakFsync(filesystem.clone()));
+        }
+    }
+
+    // Compaction writes its output before removing the inputs.
+    let sealed_bytes = segments.iter().rev().skip(1).map(|s| s.1).sum();
+    let available_bytes = available_space(base_path).map_err(with_path)?;
+    if available_bytes < sealed_bytes {
+        warnings.push(Warning::LowCompactionHeadroom {
+            available: available_bytes,
+            needed: sealed_bytes,
+        });
+    }
+
+    for pid in processes_using(base_path, this) {
+        warnings.push(Warning::InUse { pid });
+    }
+
+    if now < SystemTime::UNIX_EPOCH + PLAUSIBLE_NOW {
+        warnings.push(Warning::ClockNotSet { now });
+    }
+    for (_, _, modified, path) in &segments {
+        let Some(by) = modified.and_then(|m| m.duration_since(now).ok()) else {
+            continue;
+        };
+        if by > CLOCK_SKEW {
+            warnings.push(Warning::ClockBehind {
+                path: path.clone(),
+                by,
+            });
+        }
+    }
+
+    Ok(DoctorReport {
+        segments: segments.len() as u64,
+        sealed_bytes,
+        open_files_limit,
+        filesystem,
+        available_bytes,
+        warnings,
+    })
+}
+
+fn open_files_limit() -> io::Result<Option<u64>> {
+    let mut limit = MaybeUninit::<libc::rlimit>::uninit();
+    // SAFETY: `limit` is only read on success.
+    let limit = unsafe {
+        if libc::getrlimit(libc::RLIMIT_NOFILE, limit.as_mut_ptr()) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        limit.assume_init()
+    };
+
+    if limit.rlim_cur == libc::RLIM_INFINITY {
+        return Ok(None);
+    }
+    #[allow(clippy::unnecessary_cast)] // Field widths differ across platforms.
+    Ok(Some(limit.rlim_cur as u64))
+}
+
+#[cfg(target_os = "linux")]
+fn filesystem(path: &Path) -> io::Result<Option<Filesystem>> {
+    use std::ffi::CString;
+    use std::os::unix::prelude::OsStrExt;
+
+    let c_path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+    let mut stat = MaybeUninit::<libc::statfs>::uninit();
+    // SAFETY: `c_path` is NUL-terminated and `stat` is only read on success.
+    let stat = unsafe {
+        if libc::statfs(c_path.as_ptr(), stat.as_mut_ptr()) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        stat.assume_init()
+    };
+
+    // Magic numbers from statfs(2); the field is signed on some platforms.
+    let (name, fsync) = match stat.f_type as u32 {
+        0xef53 => ("ext4", Fsync::Durable),
+        0x5846_5342 => ("xfs", Fsync::Durable),
+        0x9123_683e => ("btrfs", Fsync::Durable),
+        0x2fc1_2fc1 => ("zfs", Fsync::Durable),
+        0xf2f5_2010 => ("f2fs", Fsync::Durable),
+        0x794c_7630 => ("overlayfs", Fsync::Durable),
+        0x0102_1994 => ("tmpfs", Fsync::Volatile),
+        0x8584_58f6 => ("ramfs", Fsync::Volatile),
+        0x6969 => ("nfs", Fsync::Remote),
+        0xff53_4d42 => ("cifs", Fsync::Remote),
+        0xfe53_4d42 => ("smb2", Fsync::Remote),
+        0x0102_1997 => ("9p", Fsync::Remote),
+        0x6573_5546 => ("fuse", Fsync::UserSpace),
+        _ => return Ok(None),
+    };
+    Ok(Some(Filesystem { name, fsync }))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn filesystem(_: &Path) -> io::Result<Option<Filesystem>> {
+    Ok(None)
+}
+
+// Processes other than `this` with a segment of `base_path` open, as far
+// as they are visible to the current user.
+#[cfg(target_os = "linux")]
+fn processes_using(base_path: &Path, this: u32) -> Vec<u32> {
+    let (Ok(base_path), Ok(processes)) = (base_path.canonicalize(), read_dir("/proc")) else {
+        return Vec::new();
+    };
+
+    let mut pids = Vec::new();
+    for process in processes.flatten() {
+        let Some(pid) = process.file_name().to_str().and_then(|n| n.parse().ok()) else {
+            continue;
+        };
+        let Ok(fds) = read_dir(process.path().join("fd")) else {
+            continue;
+        };
+        let uses_segment = fds.flatten().any(|fd| {
+            std::fs::read_link(fd.path()).is_ok_and(|target| {
+                target.parent() == Some(base_path.as_path())
+                    && target.extension().and_then(|e| e.to_str()) == Some(SEGMENT_EXT)
+            })
+        });
+        if uses_segment && pid != this {
+            pids.push(pid);
+        }
+    }
+    pids.sort();
+    pids
+}
+
+#[cfg(not(target_os = "linux"))]
+fn processes_using(_: &Path, _: u32) -> Vec<u32> {
+    Vec::new()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error;
+
+    use super::*;
+    use crate::SunsetDB;
+    use tempfile::tempdir;
+
+    type TestResult = Result<(), Box<dyn Error>>;
+
+    #[test]
+    fn doctor_test() -> TestResult {
+        let base_dir = tempdir()?;
+        let mut s = SunsetDB::new(base_dir.path())?;
+        s.insert("k", "v")?;
+        s.add_new_segment()?;
+
+        let report = doctor(base_dir.path())?;
+        assert_eq!(report.segments, 2);
+        assert_eq!(report.sealed_bytes, s.segment_stats()[0].len);
+        assert!(report.available_bytes > 0);
+        assert!(report
+            .warnings
+            .iter()
+            .all(|w| !matches!(w, Warning::ClockNotSet { .. } | Warning::InUse { .. })));
+
+        // This process has the database open, and the clock looks reset.
+        let report = doctor_at(base_dir.path(), SystemTime::UNIX_EPOCH, 0)?;
+        let warnings: Vec<_> = report
+            .warnings
+            .iter()
+            .filter(|w| {
+                !matches!(
+                    w,
+                    Warning::TooFewFileDescriptors { .. } | Warning::WeakFsync(_)
+                )
+            })
+            .collect();
+        assert!(matches!(
+            warnings[..],
+            [
+                Warning::InUse { .. },
+                Warning::ClockNotSet { .. },
+                Warning::ClockBehind { .. },
+                Warning::ClockBehind { .. },
+            ]
+        ));
+        assert_eq!(
+            warnings[0],
+            &Warning::InUse {
+                pid: std::process::id()
+            }
+        );
+
+        assert!(doctor(&base_dir.path().join("missing")).is_err());
+        Ok(())
+    }
+}
@@ -1,13 +1,266 @@
+use std::fmt;
 use std::io;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use thiserror::Error;
 
+use crate::options::Quota;
+use crate::record::RecordError;
+
+/// Broad category of an [`Error`], stable across internal changes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ErrorKind {
+    /// The key does not exist.
+    NotFound,
+    /// A key, value, path or file passed by the caller can't be used.
+    InvalidInput,
+    /// Data on disk failed validation (checksum, encoding, truncation).
+    Corruption,
+    /// The filesystem returned an error.
+    Io,
+    /// A write was rejected by one of the configured quotas.
+    QuotaExceeded(Quota),
+    /// A write was rejected to keep free disk space above the configured mark.
+    OutOfSpace,
+    /// An internal invariant does not hold.
+    Internal,
+    /// The operation was cancelled by the caller.
+    Cancelled,
+    /// The operation would have blocked longer than allowed.
+    TimedOut,
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ErrorKind::NotFound => write!(f, "key not found"),
+            ErrorKind::InvalidInput => write!(f, "invalid input"),
+            ErrorKind::Corruption => write!(f, "corruption"),
+            ErrorKind::Io => write!(f, "IO error"),
+            ErrorKind::QuotaExceeded(quota) => write!(f, "{} quota exceeded", quota),
+            ErrorKind::OutOfSpace => write!(f, "out of space"),
+            ErrorKind::Internal => write!(f, "internal error"),
+            ErrorKind::Cancelled => write!(f, "cancelled"),
+            ErrorKind::TimedOut => write!(f, "timed out"),
+        }
+    }
+}
+
+/// The error returned by every public API.
+///
+/// `kind()` tells what went wrong, the accessors tell where, and
+/// `source()` chains down to the underlying cause.
+#[derive(Debug)]
+pub struct Error {
+    kind: ErrorKind,
+    path: Option<PathBuf>,
+    segment_id: Option<u64>,
+    offset: Option<u64>,
+    key: Option<String>,
+    source: Box<dyn std::error::Error + Send + Sync>,
+}
+
+impl Error {
+    fn new(kind: ErrorKind, source: impl std::error::Error + Send + Sync + 'static) -> Error {
+        Error {
+            kind,
+            path: None,
+            segment_id: None,
+            offset: None,
+            key: None,
+            source: Box::new(source),
+        }
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        self.kind
+    }
+
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    pub fn segment_id(&self) -> Option<u64> {
+        self.segment_id
+    }
+
+    pub fn offset(&self) -> Option<u64> {
+        self.offset
+    }
+
+    pub fn key(&self) -> Option<&str> {
+        self.key.as_deref()
+    }
+
+    pub(crate) fn with_path(mut self, path: &Path) -> Error {
+        self.path.get_or_insert_with(|| path.to_path_buf());
+        self
+    }
+
+    pub(crate) fn with_segment(mut self, id: u64, path: &Path) -> Error {
+        self.segment_id.get_or_insert(id);
+        self.with_path(path)
+    }
+
+    pub(crate) fn with_offset(mut self, offset: u64) -> Error {
+        self.offset.get_or_insert(offset);
+        self
+    }
+
+    pub(crate) fn with_key(mut self, key: &str) -> Error {
+        self.key.get_or_insert_with(|| key.to_string());
+        self
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.kind)?;
+
+        let mut context = Vec::new();
+        if let Some(key) = &self.key {
+            context.push(format!("key: {:?}", key));
+        }
+        if let Some(id) = self.segment_id {
+            context.push(format!("segment: {}", id));
+        }
+        if let Some(offset) = self.offset {
+            context.push(format!("offset: {}", offset));
+        }
+        if let Some(path) = &self.path {
+            context.push(format!("path: {}", path.display()));
+        }
+        if !context.is_empty() {
+            write!(f, " ({})", context.join(", "))?;
+        }
+
+        Ok(())
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(self.source.as_ref())
+    }
+}
+
+// The fine-grained enums below are internal: they convert into `Error`,
+// which keeps them reachable through `source()`.
+macro_rules! impl_from_internal {
+    ($($t:ty),*) => {
+        $(
+            impl From<$t> for Error {
+                fn from(e: $t) -> Error {
+                    Error::new(e.kind(), e)
+                }
+            }
+        )*
+    };
+}
+
+impl_from_internal!(
+    SunsetDBError,
+    InsertError,
+    DeleteError,
+    GetError,
+    TraceError,
+    SegmentError,
+    ReadError
+);
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::new(ErrorKind::Io, e)
+    }
+}
+
 #[derive(Error, Debug)]
 pub enum SunsetDBError {
     #[error("there should be at least a segment")]
     NoSegments,
 
+    #[error("destination is not empty: {0}")]
+    DestinationNotEmpty(PathBuf),
+
+    #[error("can't absorb a database into itself")]
+    AbsorbSelf,
+
+    #[error("split points must be ascending and one less than the outputs")]
+    InvalidSplitPoints,
+
+    #[error("key routed to output {output} of {outputs}")]
+    InvalidOutput { output: usize, outputs: usize },
+
+    #[error("segment keys are not sorted")]
+    UnsortedSegment,
+
+    #[error("log position {0} is past the end of the log, or was compacted")]
+    InvalidLogPosition(crate::LogPosition),
+
+    #[error("invalid stream name: {0:?}")]
+    InvalidStreamName(String),
+
+    #[error("stream offset {offset} is outside of {start}..={end}")]
+    StreamOffsetOutOfRange { offset: u64, start: u64, end: u64 },
+
+    #[error("invalid cursor token")]
+    InvalidCursorToken,
+
+    #[error("key already exists")]
+    KeyExists,
+
+    #[error("database opened read-only")]
+    ReadOnly,
+
+    #[error("only a database opened read-only can be refreshed")]
+    NotReadOnly,
+
+    #[error("invalid import record on line {line}: {reason}")]
+    InvalidImportRecord { line: u64, reason: &'static str },
+
+    #[error("key or value is not UTF-8")]
+    NotUtf8,
+
+    #[error("table {0:?} has unsupported types")]
+    UnsupportedTable(String),
+
+    #[error("no segment with ID {0}")]
+    UnknownSegment(u64),
+
+    #[error("invalid compaction marker")]
+    InvalidCompactionMarker,
+
+    #[error("database opened by process {opened_by}, call reopen_after_fork")]
+    Forked { opened_by: u32 },
+
+    #[error("cancelled")]
+    Cancelled,
+
+    #[error("a thread panicked while holding the database")]
+    Poisoned,
+
+    #[cfg(feature = "arrow")]
+    #[error("arrow error")]
+    Arrow(#[from] arrow_schema::ArrowError),
+
+    #[cfg(feature = "sled")]
+    #[error("sled error")]
+    Sled(#[from] sled::Error),
+
+    #[cfg(feature = "redb")]
+    #[error("redb error")]
+    Redb(Box<redb::Error>),
+
+    #[error("write would stall for {wait:?}")]
+    StallTimedOut { wait: std::time::Duration },
+
+    #[error("compaction needs {needed} bytes, {available} available")]
+    CompactionOutOfSpace { needed: u64, available: u64 },
+
+    #[error("operation took longer than {timeout:?}")]
+    DeadlineExceeded { timeout: std::time::Duration },
+
     #[error("segment error")]
     SegmentError(#[from] SegmentError),
 
@@ -15,6 +268,53 @@ pub enum SunsetDBError {
     IOError(#[from] io::Error),
 }
 
+impl SunsetDBError {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            SunsetDBError::NoSegments | SunsetDBError::Poisoned => ErrorKind::Internal,
+            SunsetDBError::DestinationNotEmpty(_)
+            | SunsetDBError::AbsorbSelf
+            | SunsetDBError::InvalidSplitPoints
+            | SunsetDBError::InvalidOutput { .. }
+            | SunsetDBError::UnsortedSegment
+            | SunsetDBError::InvalidLogPosition(_)
+            | SunsetDBError::InvalidStreamName(_)
+            | SunsetDBError::StreamOffsetOutOfRange { .. }
+            | SunsetDBError::InvalidCursorToken
+            | SunsetDBError::KeyExists
+            | SunsetDBError::ReadOnly
+            | SunsetDBError::NotReadOnly
+            | SunsetDBError::InvalidImportRecord { .. }
+            | SunsetDBError::NotUtf8
+            | SunsetDBError::UnsupportedTable(_)
+            | SunsetDBError::UnknownSegment(_)
+            | SunsetDBError::Forked { .. } => ErrorKind::InvalidInput,
+            SunsetDBError::InvalidCompactionMarker => ErrorKind::Corruption,
+            SunsetDBError::StallTimedOut { .. } | SunsetDBError::DeadlineExceeded { .. } => {
+                ErrorKind::TimedOut
+            }
+            SunsetDBError::Cancelled => ErrorKind::Cancelled,
+            SunsetDBError::CompactionOutOfSpace { .. } => ErrorKind::OutOfSpace,
+            #[cfg(feature = "arrow")]
+            SunsetDBError::Arrow(_) => ErrorKind::Internal,
+            #[cfg(feature = "sled")]
+            SunsetDBError::Sled(e) => match e {
+                sled::Error::Io(_) => ErrorKind::Io,
+                sled::Error::Corruption { .. } => ErrorKind::Corruption,
+                _ => ErrorKind::Internal,
+            },
+            #[cfg(feature = "redb")]
+            SunsetDBError::Redb(e) => match **e {
+                redb::Error::Io(_) => ErrorKind::Io,
+                redb::Error::Corrupted(_) => ErrorKind::Corruption,
+                _ => ErrorKind::Internal,
+            },
+            SunsetDBError::SegmentError(e) => e.kind(),
+            SunsetDBError::IOError(_) => ErrorKind::Io,
+        }
+    }
+}
+
 #[derive(Error, Debug)]
 pub enum InsertError {
     #[error("there should be at least a segment")]
@@ -26,10 +326,33 @@ pub enum InsertError {
     #[error("value exceeds max size (expected < {})", u64::MAX)]
     ValueExceedsMaxSize,
 
+    #[error("{quota} quota exceeded (limit {limit})")]
+    QuotaExceeded { quota: Quota, limit: u64 },
+
+    #[error("batch keys are not strictly ascending")]
+    UnsortedBatch,
+
+    #[error("not enough disk space ({available} bytes would be left)")]
+    OutOfSpace { available: u64 },
+
     #[error("IO error")]
     IOError(#[from] io::Error),
 }
 
+impl InsertError {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            InsertError::NoSegments => ErrorKind::Internal,
+            InsertError::KeyExceedsMaxSize
+            | InsertError::ValueExceedsMaxSize
+            | InsertError::UnsortedBatch => ErrorKind::InvalidInput,
+            InsertError::QuotaExceeded { quota, .. } => ErrorKind::QuotaExceeded(*quota),
+            InsertError::OutOfSpace { .. } => ErrorKind::OutOfSpace,
+            InsertError::IOError(_) => ErrorKind::Io,
+        }
+    }
+}
+
 #[derive(Error, Debug)]
 pub enum DeleteError {
     #[error("there should be at least a segment")]
@@ -42,6 +365,16 @@ pub enum DeleteError {
     IOError(#[from] io::Error),
 }
 
+impl DeleteError {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            DeleteError::NoSegments => ErrorKind::Internal,
+            DeleteError::KeyNotFound => ErrorKind::NotFound,
+            DeleteError::IOError(_) => ErrorKind::Io,
+        }
+    }
+}
+
 #[derive(Error, Debug)]
 pub enum GetError {
     #[error("key not found")]
@@ -50,10 +383,50 @@ pub enum GetError {
     #[error("invalid checksum (expected {expected:?}, found {found:?})")]
     InvalidChecksum { expected: u32, found: u32 },
 
+    #[error("index points to a different key")]
+    KeyMismatch,
+
+    #[error("value length does not match the index")]
+    LengthMismatch,
+
     #[error("read error")]
     ReadError(#[from] ReadError),
 }
 
+impl GetError {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            GetError::KeyNotFound => ErrorKind::NotFound,
+            GetError::InvalidChecksum { .. } | GetError::KeyMismatch | GetError::LengthMismatch => {
+                ErrorKind::Corruption
+            }
+            GetError::ReadError(e) => e.kind(),
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum TraceError {
+    #[error("invalid trace entry at line {line}")]
+    InvalidEntry { line: usize },
+
+    #[error("replay failed at line {line}")]
+    Replay { line: usize, source: Box<Error> },
+
+    #[error("IO error")]
+    IOError(#[from] io::Error),
+}
+
+impl TraceError {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            TraceError::InvalidEntry { .. } => ErrorKind::InvalidInput,
+            TraceError::Replay { source, .. } => source.kind(),
+            TraceError::IOError(_) => ErrorKind::Io,
+        }
+    }
+}
+
 #[derive(Error, Debug)]
 pub enum SegmentError {
     #[error("can't create segment from path")]
@@ -71,10 +444,25 @@ pub enum SegmentError {
     #[error("IO error at path: {path}")]
     IOErrorAtPath { path: PathBuf, source: io::Error },
 
+    #[error("cancelled")]
+    Cancelled,
+
     #[error("IO error")]
     IOError(#[from] io::Error),
 }
 
+impl SegmentError {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            SegmentError::InvalidPath(_) => ErrorKind::InvalidInput,
+            SegmentError::InvalidIndexFormat(_) | SegmentError::SeekError => ErrorKind::Corruption,
+            SegmentError::ReadError(e) => e.kind(),
+            SegmentError::IOErrorAtPath { .. } | SegmentError::IOError(_) => ErrorKind::Io,
+            SegmentError::Cancelled => ErrorKind::Cancelled,
+        }
+    }
+}
+
 #[derive(Error, Debug, PartialEq)]
 pub(crate) enum SegmentIDError {
     #[error("ID is not an int")]
@@ -98,9 +486,39 @@ pub enum ReadError {
     #[error("invalid string")]
     InvalidString {
         #[from]
-        source: std::string::FromUtf8Error,
+        source: std::str::Utf8Error,
     },
 
     #[error("invalid int")]
     InvalidInt(#[from] std::num::TryFromIntError),
+
+    #[error("unexpected tombstone")]
+    UnexpectedTombstone,
+}
+
+impl ReadError {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            // A record that ends early is truncated, not an IO failure.
+            ReadError::IOError(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                ErrorKind::Corruption
+            }
+            ReadError::IOError(_) => ErrorKind::Io,
+            ReadError::InvalidChecksum { .. }
+            | ReadError::InvalidString { .. }
+            | ReadError::InvalidInt(_)
+            | ReadError::UnexpectedTombstone => ErrorKind::Corruption,
+        }
+    }
+}
+
+impl From<RecordError> for ReadError {
+    fn from(e: RecordError) -> Self {
+        match e {
+            RecordError::InvalidChecksum { expected, found } => {
+                ReadError::InvalidChecksum { expected, found }
+            }
+            RecordError::InvalidString(source) => ReadError::InvalidString { source },
+        }
+    }
 }
@@ -0,0 +1,91 @@
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::error::{Error, ErrorKind};
+
+/// Something that happened inside the database, delivered to every
+/// [`EventListener`] registered in [`Options::listeners`](crate::Options::listeners).
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub enum Event {
+    /// A new, empty segment was created and now receives writes.
+    SegmentCreated { id: u64 },
+    /// The active segment reached
+    /// [`Options::max_segment_size`](crate::Options::max_segment_size) and
+    /// no longer receives writes.
+    SegmentSealed { id: u64, len: u64 },
+    /// Sealed segments `inputs` started being merged.
+    CompactionStarted { inputs: Vec<u64> },
+    /// The merge of `inputs` was dropped, leaving them as they were:
+    /// `error` is why, or `None` if they changed in the meantime.
+    CompactionAbandoned {
+        inputs: Vec<u64>,
+        error: Option<ErrorKind>,
+    },
+    /// Sealed segments `inputs` were merged into segment `id`, which
+    /// replaced them.
+    SegmentsCompacted {
+        inputs: Vec<u64>,
+        id: u64,
+        bytes_reclaimed: u64,
+    },
+    /// An existing segment was loaded while opening the database.
+    SegmentRecovered { id: u64, len: u64, keys: u64 },
+    /// The newest segment ended with a record left incomplete by a crash,
+    /// which was dropped while opening the database.
+    TornTailDiscarded { segment_id: u64, bytes: u64 },
+    /// A write was delayed by the rate limiter.
+    WriteStalled { duration: Duration },
+    /// Free disk space dropped below the warning mark.
+    LowDiskSpace { available: u64 },
+    /// Data on disk failed validation.
+    CorruptionDetected {
+        segment_id: Option<u64>,
+        offset: Option<u64>,
+        key: Option<String>,
+    },
+}
+
+impl Event {
+    pub(crate) fn corruption(e: &Error) -> Event {
+        Event::CorruptionDetected {
+            segment_id: e.segment_id(),
+            offset: e.offset(),
+            key: e.key().map(str::to_string),
+        }
+    }
+}
+
+/// Receives [`Event`]s. Listeners are called synchronously from the thread
+/// that triggered the event, so they should return quickly.
+pub trait EventListener: Send + Sync {
+    fn on_event(&self, event: &Event);
+}
+
+impl<F: Fn(&Event) + Send + Sync> EventListener for F {
+    fn on_event(&self, event: &Event) {
+        self(event)
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct EventListeners(Vec<Arc<dyn EventListener>>);
+
+impl EventListeners {
+    pub fn push(&mut self, listener: impl EventListener + 'static) {
+        self.0.push(Arc::new(listener));
+    }
+
+    pub(crate) fn emit(&self, event: Event) {
+        for listener in &self.0 {
+            listener.on_event(&event);
+        }
+    }
+}
+
+impl fmt::Debug for EventListeners {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "EventListeners({})", self.0.len())
+    }
+}
@@ -0,0 +1,148 @@
+// Arrow export of the live pairs, behind the `arrow` feature.
+
+use std::ops::RangeBounds;
+use std::sync::Arc;
+
+use arrow_array::builder::{ArrayBuilder, StringBuilder, UInt64Builder};
+use arrow_array::{ArrayRef, RecordBatch};
+use arrow_schema::{DataType, Field, Schema, SchemaRef};
+
+use super::error::*;
+use super::import::identity;
+use super::{PairMap, SunsetDB};
+
+impl SunsetDB {
+    /// Passes the live pairs within `range` to `sink` as Arrow record
+    /// batches of up to `batch_rows` rows, in key order. Returns the number
+    /// of rows exported.
+    ///
+    /// Columns are `key`, `value`, `keyspace` (the part of the key before
+    /// the first `separator`, like [`keyspace_stats`](SunsetDB::keyspace_stats)),
+    /// `value_len` and `segment_id`. Records carry no timestamp to export.
+    pub fn export_arrow<'r>(
+        &mut self,
+        range: impl RangeBounds<&'r str>,
+        separator: char,
+        batch_rows: usize,
+        sink: impl FnMut(RecordBatch),
+    ) -> Result<u64, Error> {
+        self.export_arrow_with(range, separator, batch_rows, identity(), sink)
+    }
+
+    /// Like [`export_arrow`](SunsetDB::export_arrow), passing every pair
+    /// through `map`. `range` applies to the keys before mapping, and rows
+    /// stay in that order.
+    pub fn export_arrow_with<'r>(
+        &mut self,
+        range: impl RangeBounds<&'r str>,
+        separator: char,
+        batch_rows: usize,
+        mut map: PairMap,
+        mut sink: impl FnMut(RecordBatch),
+    ) -> Result<u64, Error> {
+        self.check_fork()?;
+        let schema = export_schema();
+        let mut batch = Batch::default();
+        let mut rows = 0;
+        let keys = self.keys_in_range(range);
+        for chunk in keys.chunks(batch_rows.max(1)) {
+            let keys = chunk.iter().map(|(_, key)| key.clone()).collect();
+            for (i, key, value) in self.read_batch(keys)? {
+                let Some((key, value)) = map(key, value) else {
+                    continue;
+                };
+                batch.key.append_value(&key);
+                batch.value.append_value(&value);
+                batch
+                    .keyspace
+                    .append_value(key.split_once(separator).map_or("", |(k, _)| k));
+                batch.value_len.append_value(value.len() as u64);
+                batch.segment_id.append_value(self.segments[i].id.0);
+                rows += 1;
+
+                if batch.key.len() >= batch_rows {
+                    sink(batch.finish(&schema)?);
+                }
+            }
+        }
+        if batch.key.len() > 0 {
+            sink(batch.finish(&schema)?);
+        }
+        Ok(rows)
+    }
+}
+
+pub(crate) fn export_schema() -> SchemaRef {
+    Arc::new(Schema::new(vec![
+        Field::new("key", DataType::Utf8, false),
+        Field::new("value", DataType::Utf8, false),
+        Field::new("keyspace", DataType::Utf8, false),
+        Field::new("value_len", DataType::UInt64, false),
+        Field::new("segment_id", DataType::UInt64, false),
+    ]))
+}
+
+#[derive(Default)]
+struct Batch {
+    key: StringBuilder,
+    value: StringBuilder,
+    keyspace: StringBuilder,
+    value_len: UInt64Builder,
+    segment_id: UInt64Builder,
+}
+
+impl Batch {
+    // Builds the rows appended so far, leaving the builders empty.
+    fn finish(&mut self, schema: &SchemaRef) -> Result<RecordBatch, SunsetDBError> {
+        let columns: Vec<ArrayRef> = vec![
+            Arc::new(self.key.finish()),
+            Arc::new(self.value.finish()),
+            Arc::new(self.keyspace.finish()),
+            Arc::new(self.value_len.finish()),
+            Arc::new(self.segment_id.finish()),
+        ];
+        Ok(RecordBatch::try_new(schema.clone(), columns)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error;
+
+    use arrow_array::cast::AsArray;
+    use arrow_array::types::UInt64Type;
+
+    use super::*;
+    use tempfile::tempdir;
+
+    type TestResult = Result<(), Box<dyn Error>>;
+
+    #[test]
+    fn export_arrow_test() -> TestResult {
+        let base_dir = tempdir()?;
+        let mut s = SunsetDB::new(base_dir.path())?;
+        for (key, value) in [("a:1", "x"), ("a:2", "yy"), ("b", "zzz")] {
+            s.insert(key, value)?;
+        }
+
+        let mut batches = Vec::new();
+        assert_eq!(s.export_arrow(.., ':', 2, |b| batches.push(b))?, 3);
+        assert_eq!(
+            batches.iter().map(|b| b.num_rows()).collect::<Vec<_>>(),
+            [2, 1]
+        );
+        assert_eq!(batches[0].schema(), export_schema());
+
+        let keyspaces: Vec<_> = batches
+            .iter()
+            .flat_map(|b| b.column(2).as_string::<i32>().iter().flatten())
+            .collect();
+        assert_eq!(keyspaces, ["a", "a", ""]);
+        let lens: Vec<_> = batches
+            .iter()
+            .flat_map(|b| b.column(3).as_primitive::<UInt64Type>().values().to_vec())
+            .collect();
+        assert_eq!(lens, [1, 2, 3]);
+        Ok(())
+    }
+}
@@ -0,0 +1,69 @@
+//! Failpoints at the IO boundaries of the database, compiled in with the
+//! `failpoints` feature and configured through the [`fail`] crate, e.g.
+//! `fail::cfg(failpoints::FSYNC, "return")`.
+//!
+//! On `return`, the guarded IO fails with an [`ErrorKind::Io`](crate::ErrorKind::Io)
+//! error, whose message is the failpoint argument if any.
+
+/// Appending records to the active segment. On `return`, half of the
+/// records are written before failing, as on a full disk, and then
+/// truncated.
+#[cfg(feature = "failpoints")]
+pub const APPEND: &str = "sunset::append";
+
+/// Reading a value, see [`SunsetDB::get`](crate::SunsetDB::get).
+#[cfg(feature = "failpoints")]
+pub const READ: &str = "sunset::read";
+
+/// Flushing a segment to disk, see [`SunsetDB::sync`](crate::SunsetDB::sync).
+#[cfg(feature = "failpoints")]
+pub const FSYNC: &str = "sunset::fsync";
+
+/// Creating the next segment and moving writes to it.
+#[cfg(feature = "failpoints")]
+pub const NEW_SEGMENT: &str = "sunset::new_segment";
+
+/// Moving writes to a new segment once the active one is sealed, see
+/// [`Options::max_segment_size`](crate::Options::max_segment_size).
+#[cfg(feature = "failpoints")]
+pub const ROTATE: &str = "sunset::rotate";
+
+/// Renaming the output of a compaction over its newest input, once the
+/// `compaction` marker is written, see [`SunsetDB::compact`](crate::SunsetDB::compact).
+#[cfg(feature = "failpoints")]
+pub const COMPACTION_SWAP: &str = "sunset::compaction_swap";
+
+/// Removing the other inputs of a compaction and then its marker, once its
+/// output was renamed in.
+#[cfg(feature = "failpoints")]
+pub const COMPACTION_FINISH: &str = "sunset::compaction_finish";
+
+/// Reading the free disk space. On `return(n)`, `n` bytes are reported
+/// available instead of failing.
+#[cfg(feature = "failpoints")]
+pub const DISK_SPACE: &str = "sunset::disk_space";
+
+/// Writing an index checkpoint, see
+/// [`Options::index_checkpoint_bytes`](crate::Options::index_checkpoint_bytes).
+#[cfg(feature = "failpoints")]
+pub const CHECKPOINT: &str = "sunset::checkpoint";
+
+// Returns the error from the enclosing function, which must convert
+// `io::Error` into its error type.
+macro_rules! fail_point {
+    ($name:expr) => {
+        #[cfg(feature = "failpoints")]
+        fail::fail_point!($name, |message| Err($crate::failpoints::error(
+            $name, message
+        )
+        .into()));
+    };
+}
+
+pub(crate) use fail_point;
+
+#[cfg(feature = "failpoints")]
+pub(crate) fn error(name: &str, message: Option<String>) -> std::io::Error {
+    let message = message.unwrap_or_else(|| format!("failpoint {}", name));
+    std::io::Error::new(std::io::ErrorKind::Other, message)
+}
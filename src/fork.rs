@@ -0,0 +1,106 @@
+// A forked child inherits the parent's file descriptors, including their
+// offsets, but none of its threads. Appending from both processes through
+// the same descriptors would interleave records, so a handle refuses to
+// work in a process other than the one that opened it.
+
+use std::mem;
+use std::process;
+
+use super::error::*;
+use super::{Options, SunsetDB};
+
+impl SunsetDB {
+    /// Makes a handle inherited through `fork()` usable in the child, by
+    /// reopening the database from disk. Until then, every operation that
+    /// reads or writes files fails with
+    /// [`ErrorKind::InvalidInput`](crate::ErrorKind::InvalidInput).
+    ///
+    /// The parent may still be writing, so the child's handle is reopened
+    /// [`read_only`](crate::Options::read_only): nothing is recovered or
+    /// truncated, and no background worker is started. An active trace is
+    /// stopped, dropping entries the parent had not flushed yet. To write
+    /// from the child once the parent stopped, open the database again.
+    pub fn reopen_after_fork(&mut self) -> Result<(), Error> {
+        if self.pid == process::id() {
+            return Ok(());
+        }
+
+        // Joining the parent's threads would block forever, and flushing
+        // its buffered trace would write those entries twice.
+        mem::forget(self.scrubber.take());
+        mem::forget(self.compactor.take());
+        mem::forget(self.trace.take());
+
+        let options = Options {
+            read_only: true,
+            ..self.options.clone()
+        };
+        *self = SunsetDB::with_options(&self.base_path.clone(), options)?;
+        Ok(())
+    }
+
+    pub(crate) fn check_fork(&self) -> Result<(), Error> {
+        if self.pid != process::id() {
+            return Err(SunsetDBError::Forked {
+                opened_by: self.pid,
+            }
+            .into());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error;
+
+    use super::*;
+    use crate::ErrorKind;
+    use tempfile::tempdir;
+
+    type TestResult = Result<(), Box<dyn Error>>;
+
+    #[test]
+    fn reopen_after_fork_test() -> TestResult {
+        let base_dir = tempdir()?;
+        let mut s = SunsetDB::new(base_dir.path())?;
+        s.insert("k", "v")?;
+
+        // As seen from a forked child.
+        s.pid = process::id().wrapping_add(1);
+        assert_eq!(s.get("k").unwrap_err().kind(), ErrorKind::InvalidInput);
+        assert_eq!(
+            s.insert("j", "w").unwrap_err().kind(),
+            ErrorKind::InvalidInput
+        );
+        assert_eq!(s.sync().unwrap_err().kind(), ErrorKind::InvalidInput);
+        let forked = |e: crate::Error| e.kind() == ErrorKind::InvalidInput;
+        assert!(s.fold(.., (), |_, _, _| ()).is_err_and(forked));
+        assert!(s.cursor(..).next_batch(&mut s, 1).is_err_and(forked));
+        assert!(s.refresh().is_err_and(forked));
+        assert!(s.clone_to(&base_dir.path().join("copy")).is_err_and(forked));
+        assert!(s.stream("log").is_err_and(forked));
+
+        // The parent may still be writing: the child changes nothing.
+        let files = || -> std::io::Result<Vec<_>> {
+            let mut files = std::fs::read_dir(base_dir.path())?
+                .map(|entry| {
+                    let entry = entry?;
+                    Ok((entry.file_name(), entry.metadata()?.len()))
+                })
+                .collect::<std::io::Result<Vec<_>>>()?;
+            files.sort();
+            Ok(files)
+        };
+        let before = files()?;
+        s.reopen_after_fork()?;
+        assert_eq!(files()?, before);
+        assert_eq!(s.get("k")?.as_deref(), Some("v"));
+        assert_eq!(s.cursor(..).next_batch(&mut s, 1)?.len(), 1);
+        assert_eq!(
+            s.insert("j", "w").unwrap_err().kind(),
+            ErrorKind::InvalidInput
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,227 @@
+// Approximate access counts per key. Counts live in two count-min
+// sketches, for reads and writes, so memory does not grow with the number
+// of keys; only a few candidates for the hottest keys are kept by name.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::num::NonZeroUsize;
+
+use super::trace::TraceOp;
+use super::SunsetDB;
+
+/// Sizes of the access counters, see [`Options::key_heat`](crate::Options::key_heat).
+///
+/// Counts are overestimated by at most `e / width` of all accesses, except
+/// with a probability of `e^-depth`. Both sketches take `16 * width * depth`
+/// bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyHeatOptions {
+    pub width: NonZeroUsize,
+    pub depth: NonZeroUsize,
+    /// Number of keys [`SunsetDB::hottest_keys`] can return.
+    pub tracked_keys: usize,
+}
+
+impl Default for KeyHeatOptions {
+    fn default() -> Self {
+        KeyHeatOptions {
+            width: NonZeroUsize::new(2048).unwrap_or(NonZeroUsize::MIN),
+            depth: NonZeroUsize::new(4).unwrap_or(NonZeroUsize::MIN),
+            tracked_keys: 100,
+        }
+    }
+}
+
+/// Estimated accesses to a key since the database was opened, see
+/// [`SunsetDB::hottest_keys`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyHeat {
+    pub key: String,
+    /// Gets.
+    pub reads: u64,
+    /// Inserts, deletes and removes.
+    pub writes: u64,
+}
+
+impl SunsetDB {
+    /// Up to `n` of the most accessed keys, most accessed first, if
+    /// [`Options::key_heat`](crate::Options::key_heat) is set.
+    ///
+    /// Counts are estimates, and keys only become candidates once accessed
+    /// more than the coldest candidate: a key that was hot long ago may be
+    /// reported over one that just became hot.
+    pub fn hottest_keys(&self, n: usize) -> Vec<KeyHeat> {
+        let Some(heat) = &self.heat else {
+            return Vec::new();
+        };
+        let mut keys: Vec<_> = heat
+            .candidates
+            .keys()
+            .map(|key| KeyHeat {
+                key: key.clone(),
+                reads: heat.reads.estimate(key),
+                writes: heat.writes.estimate(key),
+            })
+            .collect();
+        keys.sort_by(|a, b| {
+            (b.reads + b.writes)
+                .cmp(&(a.reads + a.writes))
+                .then_with(|| a.key.cmp(&b.key))
+        });
+        keys.truncate(n);
+        keys
+    }
+}
+
+pub(crate) struct HeatMap {
+    reads: Sketch,
+    writes: Sketch,
+    // Candidates for the hottest keys, with their accesses when last seen.
+    candidates: HashMap<String, u64>,
+    tracked_keys: usize,
+    // Accesses of the coldest candidate, once there are `tracked_keys`.
+    floor: u64,
+}
+
+impl HeatMap {
+    pub(crate) fn new(options: KeyHeatOptions) -> HeatMap {
+        HeatMap {
+            reads: Sketch::new(options.width.get(), options.depth.get()),
+            writes: Sketch::new(options.width.get(), options.depth.get()),
+            candidates: HashMap::new(),
+            tracked_keys: options.tracked_keys,
+            floor: 0,
+        }
+    }
+
+    pub(crate) fn touch(&mut self, op: TraceOp, key: &str) {
+        let accesses = match op {
+            TraceOp::Get => self.reads.add(key) + self.writes.estimate(key),
+            _ => self.reads.estimate(key) + self.writes.add(key),
+        };
+
+        if let Some(seen) = self.candidates.get_mut(key) {
+            *seen = accesses;
+        } else if self.candidates.len() < self.tracked_keys {
+            self.candidates.insert(key.to_string(), accesses);
+        } else if accesses > self.floor && self.tracked_keys > 0 {
+            // Candidate counts may be stale, so refresh them before
+            // evicting the coldest.
+            for (key, seen) in self.candidates.iter_mut() {
+                *seen = self.reads.estimate(key) + self.writes.estimate(key);
+            }
+            let coldest = self
+                .candidates
+                .iter()
+                .min_by_key(|(_, seen)| **seen)
+                .map(|(key, seen)| (key.clone(), *seen));
+            if let Some((coldest, seen)) = coldest {
+                if accesses > seen {
+                    self.candidates.remove(&coldest);
+                    self.candidates.insert(key.to_string(), accesses);
+                }
+            }
+            self.floor = self.candidates.values().copied().min().unwrap_or(0);
+        }
+    }
+}
+
+// A count-min sketch: `depth` rows of `width` counters, each row indexed
+// by a differently seeded hash of the key.
+struct Sketch {
+    width: usize,
+    depth: usize,
+    counters: Vec<u64>,
+}
+
+impl Sketch {
+    fn new(width: usize, depth: usize) -> Sketch {
+        Sketch {
+            width,
+            depth,
+            counters: vec![0; width * depth],
+        }
+    }
+
+    fn slot(&self, key: &str, row: usize) -> usize {
+        let mut hasher = DefaultHasher::new();
+        (row, key).hash(&mut hasher);
+        row * self.width + (hasher.finish() % self.width as u64) as usize
+    }
+
+    // Counts an access, returning the new estimate.
+    fn add(&mut self, key: &str) -> u64 {
+        let mut estimate = u64::MAX;
+        for row in 0..self.depth {
+            let slot = self.slot(key, row);
+            self.counters[slot] += 1;
+            estimate = estimate.min(self.counters[slot]);
+        }
+        estimate
+    }
+
+    fn estimate(&self, key: &str) -> u64 {
+        (0..self.depth)
+            .map(|row| self.counters[self.slot(key, row)])
+            .min()
+            .unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error;
+
+    use super::*;
+    use crate::Options;
+    use tempfile::tempdir;
+
+    type TestResult = Result<(), Box<dyn Error>>;
+
+    #[test]
+    fn hottest_keys_test() -> TestResult {
+        let base_dir = tempdir()?;
+        let mut s = SunsetDB::new(base_dir.path())?;
+        s.insert("k", "v")?;
+        assert_eq!(s.hottest_keys(1), []);
+        drop(s);
+
+        let options = Options {
+            key_heat: Some(KeyHeatOptions {
+                tracked_keys: 3,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let mut s = SunsetDB::with_options(base_dir.path(), options)?;
+        for i in 0..100 {
+            s.insert(&format!("cold-{}", i), "v")?;
+        }
+        for _ in 0..10 {
+            s.get("hot")?;
+            s.get("warm")?;
+        }
+        s.insert("hot", "v")?;
+        s.delete("hot")?;
+        s.insert("warm", "v")?;
+
+        let hottest = s.hottest_keys(2);
+        assert_eq!(
+            hottest,
+            [
+                KeyHeat {
+                    key: "hot".to_string(),
+                    reads: 10,
+                    writes: 2
+                },
+                KeyHeat {
+                    key: "warm".to_string(),
+                    reads: 10,
+                    writes: 1
+                }
+            ]
+        );
+        Ok(())
+    }
+}
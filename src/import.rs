@@ -0,0 +1,660 @@
+// Imports of key/value pairs exported by other systems, one pair per CSV
+// record or JSON line.
+
+use std::collections::HashMap;
+use std::io::BufRead;
+
+use super::cancel::CancellationToken;
+use super::error::*;
+use super::{ConflictPolicy, PairMap, SunsetDB};
+
+// Progress is reported every this many records.
+const PROGRESS_EVERY: u64 = 10_000;
+
+// Pairs are written in sorted batches of up to this many bytes of keys and
+// values, or at the latest when progress is reported.
+const BATCH_BYTES: usize = 1 << 20;
+
+// Objects and arrays nested deeper than this are rejected.
+const MAX_JSON_DEPTH: usize = 128;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImportFormat {
+    /// `key,value` records without a header row, quoted as in RFC 4180:
+    /// fields holding commas, quotes or line breaks are enclosed in double
+    /// quotes, and quotes within them are doubled.
+    Csv,
+    /// One JSON object per line with a string `key` and a `value`. String
+    /// values are stored as they are, other values as their JSON text.
+    /// Other members are ignored, and so are blank lines.
+    Jsonl,
+}
+
+/// Progress and outcome of [`SunsetDB::import`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ImportReport {
+    pub records: u64,
+    /// Records written to the database, including resolved conflicts.
+    pub imported: u64,
+    /// Records whose key was already in the database.
+    pub conflicts: u64,
+    /// Records dropped by the [`PairMap`](crate::PairMap).
+    pub dropped: u64,
+}
+
+impl SunsetDB {
+    /// Imports the pairs read from `reader`, resolving keys already in the
+    /// database according to `policy`. `progress` is called every 10,000
+    /// records and once done.
+    ///
+    /// Each pair is checked like [`insert`](SunsetDB::insert) would, and
+    /// pairs are written in sorted batches, see
+    /// [`insert_sorted_batch`](SunsetDB::insert_sorted_batch). The database
+    /// is synced at the end. On errors, pairs before the failing record stay
+    /// imported.
+    pub fn import(
+        &mut self,
+        reader: impl BufRead,
+        format: ImportFormat,
+        policy: ConflictPolicy,
+        progress: impl FnMut(&ImportReport),
+    ) -> Result<ImportReport, Error> {
+        let cancel = CancellationToken::default();
+        self.import_with(reader, format, policy, identity(), progress, &cancel)
+    }
+
+    /// Like [`import`](SunsetDB::import), passing every pair through `map`
+    /// before resolving conflicts, and checking `cancel` before each record.
+    pub fn import_with(
+        &mut self,
+        mut reader: impl BufRead,
+        format: ImportFormat,
+        policy: ConflictPolicy,
+        map: PairMap,
+        progress: impl FnMut(&ImportReport),
+        cancel: &CancellationToken,
+    ) -> Result<ImportReport, Error> {
+        self.check_writable()?;
+        let mut importer = Importer::new(policy, map, progress);
+        let mut line = 0;
+        let result = (|| loop {
+            cancel.check()?;
+            let pair = match format {
+                ImportFormat::Csv => read_csv_record(&mut reader, &mut line),
+                ImportFormat::Jsonl => read_json_line(&mut reader, &mut line),
+            };
+            let Some((key, value)) = pair? else {
+                return Ok(());
+            };
+            importer.add(self, key, value)?;
+        })();
+        importer.finish(self, result)
+    }
+}
+
+// The map passing pairs through unchanged.
+pub(crate) fn identity() -> PairMap {
+    Box::new(|key, value| Some((key, value)))
+}
+
+// Maps pairs, resolves conflicts and reports progress for one import.
+pub(crate) struct Importer<P> {
+    policy: ConflictPolicy,
+    map: PairMap,
+    progress: P,
+    report: ImportReport,
+    // Pairs to write with the next batch, and where each key is in it.
+    pending: Vec<(String, String)>,
+    pending_keys: HashMap<String, usize>,
+    pending_bytes: usize,
+}
+
+impl<P: FnMut(&ImportReport)> Importer<P> {
+    pub(crate) fn new(policy: ConflictPolicy, map: PairMap, progress: P) -> Importer<P> {
+        Importer {
+            policy,
+            map,
+            progress,
+            report: ImportReport::default(),
+            pending: Vec::new(),
+            pending_keys: HashMap::new(),
+            pending_bytes: 0,
+        }
+    }
+
+    pub(crate) fn add(
+        &mut self,
+        db: &mut SunsetDB,
+        key: String,
+        value: String,
+    ) -> Result<(), Error> {
+        self.report.records += 1;
+        let Some((key, theirs)) = (self.map)(key, value) else {
+            self.report.dropped += 1;
+            return self.tick(db);
+        };
+        // Keys earlier in the import may not be written yet.
+        let pending = self.pending_keys.get(&key).copied();
+        let ours = match pending {
+            Some(j) => Some(self.pending[j].1.clone()),
+            None => db.lookup(&key)?,
+        };
+        let value = match ours {
+            None => Some(theirs),
+            Some(ours) => {
+                self.report.conflicts += 1;
+                match &mut self.policy {
+                    ConflictPolicy::KeepExisting => None,
+                    ConflictPolicy::Overwrite => Some(theirs),
+                    ConflictPolicy::Resolve(resolve) => Some(resolve(&key, &ours, &theirs)),
+                    ConflictPolicy::Fail => {
+                        return Err(Error::from(SunsetDBError::KeyExists).with_key(&key))
+                    }
+                }
+            }
+        };
+        if let Some(value) = value {
+            self.pending_bytes += key.len() + value.len();
+            match pending {
+                Some(j) => self.pending[j].1 = value,
+                None => {
+                    self.pending_keys.insert(key.clone(), self.pending.len());
+                    self.pending.push((key, value));
+                }
+            }
+            self.report.imported += 1;
+        }
+        if self.pending_bytes >= BATCH_BYTES {
+            self.flush(db)?;
+        }
+        self.tick(db)
+    }
+
+    fn tick(&mut self, db: &mut SunsetDB) -> Result<(), Error> {
+        if self.report.records % PROGRESS_EVERY == 0 {
+            self.flush(db)?;
+            (self.progress)(&self.report);
+        }
+        Ok(())
+    }
+
+    // Writes the pending pairs, whose keys are unique, in key order.
+    fn flush(&mut self, db: &mut SunsetDB) -> Result<(), Error> {
+        let mut pending = std::mem::take(&mut self.pending);
+        self.pending_keys.clear();
+        self.pending_bytes = 0;
+        pending.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
+        db.insert_sorted_batch(pending.iter().map(|(k, v)| (k.as_str(), v.as_str())))?;
+        Ok(())
+    }
+
+    // Writes what is pending whether or not `result` failed, so that pairs
+    // before the failing one stay imported.
+    pub(crate) fn finish(
+        mut self,
+        db: &mut SunsetDB,
+        result: Result<(), Error>,
+    ) -> Result<ImportReport, Error> {
+        let flushed = self.flush(db);
+        result?;
+        flushed?;
+        db.sync()?;
+        (self.progress)(&self.report);
+        Ok(self.report)
+    }
+}
+
+// Reads the next CSV record, which may span several lines. Blank lines
+// between records are skipped, like in JSON Lines.
+fn read_csv_record(
+    reader: &mut impl BufRead,
+    line: &mut u64,
+) -> Result<Option<(String, String)>, Error> {
+    let mut fields = vec![String::new()];
+    let mut quoted = false;
+    // Whether the current field was quoted and the quotes closed.
+    let mut closed = false;
+    let mut buffer = String::new();
+    let mut first_line = *line + 1;
+    let invalid =
+        |line, reason| -> Error { SunsetDBError::InvalidImportRecord { line, reason }.into() };
+    loop {
+        buffer.clear();
+        if reader.read_line(&mut buffer)? == 0 {
+            if quoted {
+                return Err(invalid(first_line, "unterminated quoted field"));
+            }
+            if fields.len() == 1 && fields[0].is_empty() {
+                return Ok(None);
+            }
+            break;
+        }
+        *line += 1;
+        // Only a quoted field continues on the next line.
+        if !quoted && buffer.trim().is_empty() {
+            first_line = *line + 1;
+            continue;
+        }
+
+        let mut chars = buffer.chars().peekable();
+        while let Some(c) = chars.next() {
+            let field = fields
+                .last_mut()
+                .ok_or_else(|| invalid(first_line, "no field"))?;
+            match c {
+                '"' if quoted && chars.peek() == Some(&'"') => {
+                    chars.next();
+                    field.push('"');
+                }
+                '"' if quoted => (quoted, closed) = (false, true),
+                '"' if field.is_empty() && !closed => quoted = true,
+                _ if quoted => field.push(c),
+                ',' => {
+                    fields.push(String::new());
+                    closed = false;
+                }
+                '\r' if chars.peek() == Some(&'\n') => {}
+                '\n' => {}
+                _ if closed => return Err(invalid(first_line, "text after a quoted field")),
+                '"' => return Err(invalid(first_line, "quote in an unquoted field")),
+                _ => field.push(c),
+            }
+        }
+        if !quoted {
+            break;
+        }
+    }
+
+    match <[String; 2]>::try_from(fields) {
+        Ok([key, value]) => Ok(Some((key, value))),
+        Err(_) => Err(invalid(first_line, "expected two fields")),
+    }
+}
+
+// Reads the next non-blank JSON line.
+fn read_json_line(
+    reader: &mut impl BufRead,
+    line: &mut u64,
+) -> Result<Option<(String, String)>, Error> {
+    let mut buffer = String::new();
+    loop {
+        buffer.clear();
+        if reader.read_line(&mut buffer)? == 0 {
+            return Ok(None);
+        }
+        *line += 1;
+        if !buffer.trim().is_empty() {
+            return parse_json_pair(&buffer).map(Some).map_err(|reason| {
+                SunsetDBError::InvalidImportRecord {
+                    line: *line,
+                    reason,
+                }
+                .into()
+            });
+        }
+    }
+}
+
+fn parse_json_pair(line: &str) -> Result<(String, String), &'static str> {
+    let mut json = Json {
+        s: line,
+        pos: 0,
+        depth: 0,
+    };
+    let (mut key, mut value) = (None, None);
+
+    json.expect('{')?;
+    if !json.eat('}') {
+        loop {
+            let name = json.string()?;
+            json.expect(':')?;
+            let start = json.skip_ws();
+            let string = json.value()?;
+            match name.as_str() {
+                "key" => key = Some(string.ok_or("key is not a string")?),
+                "value" => {
+                    value = Some(string.unwrap_or_else(|| json.s[start..json.pos].to_string()))
+                }
+                _ => {}
+            }
+            if json.eat('}') {
+                break;
+            }
+            json.expect(',')?;
+        }
+    }
+    json.skip_ws();
+    if json.pos != json.s.len() {
+        return Err("text after the object");
+    }
+
+    Ok((key.ok_or("missing key")?, value.ok_or("missing value")?))
+}
+
+// Just enough of a JSON parser to pick two members out of an object.
+struct Json<'a> {
+    s: &'a str,
+    pos: usize,
+    // Objects and arrays currently open within a value.
+    depth: usize,
+}
+
+impl Json<'_> {
+    fn peek(&self) -> Option<char> {
+        self.s[self.pos..].chars().next()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += c.len_utf8();
+        Some(c)
+    }
+
+    fn skip_ws(&mut self) -> usize {
+        while self
+            .peek()
+            .is_some_and(|c| matches!(c, ' ' | '\t' | '\r' | '\n'))
+        {
+            self.pos += 1;
+        }
+        self.pos
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        self.skip_ws();
+        let eaten = self.peek() == Some(c);
+        if eaten {
+            self.pos += 1;
+        }
+        eaten
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), &'static str> {
+        match self.eat(c) {
+            true => Ok(()),
+            false => Err("invalid JSON"),
+        }
+    }
+
+    // Parses any value, returning it if it is a string.
+    fn value(&mut self) -> Result<Option<String>, &'static str> {
+        match self.peek() {
+            Some('"') => self.string().map(Some),
+            Some('{' | '[') => {
+                if self.depth == MAX_JSON_DEPTH {
+                    return Err("too deeply nested");
+                }
+                self.depth += 1;
+                let skipped = self.container();
+                self.depth -= 1;
+                skipped.map(|()| None)
+            }
+            _ => {
+                let start = self.pos;
+                while self
+                    .peek()
+                    .is_some_and(|c| c.is_ascii_alphanumeric() || matches!(c, '+' | '-' | '.'))
+                {
+                    self.pos += 1;
+                }
+                let literal = &self.s[start..self.pos];
+                let is_number = literal.parse::<f64>().is_ok()
+                    && literal.starts_with(|c: char| c == '-' || c.is_ascii_digit());
+                match literal {
+                    "true" | "false" | "null" => Ok(None),
+                    _ if is_number => Ok(None),
+                    _ => Err("invalid JSON"),
+                }
+            }
+        }
+    }
+
+    // Skips the object or array starting here.
+    fn container(&mut self) -> Result<(), &'static str> {
+        if self.next() == Some('{') {
+            if !self.eat('}') {
+                loop {
+                    self.string()?;
+                    self.expect(':')?;
+                    self.skip_ws();
+                    self.value()?;
+                    if self.eat('}') {
+                        break;
+                    }
+                    self.expect(',')?;
+                }
+            }
+        } else if !self.eat(']') {
+            loop {
+                self.skip_ws();
+                self.value()?;
+                if self.eat(']') {
+                    break;
+                }
+                self.expect(',')?;
+            }
+        }
+        Ok(())
+    }
+
+    fn string(&mut self) -> Result<String, &'static str> {
+        self.expect('"')?;
+        let mut string = String::new();
+        loop {
+            match self.next().ok_or("unterminated string")? {
+                '"' => return Ok(string),
+                '\\' => {
+                    let c = match self.next().ok_or("unterminated string")? {
+                        '"' => '"',
+                        '\\' => '\\',
+                        '/' => '/',
+                        'b' => '\u{8}',
+                        'f' => '\u{c}',
+                        'n' => '\n',
+                        'r' => '\r',
+                        't' => '\t',
+                        'u' => self.unicode_escape()?,
+                        _ => return Err("invalid escape"),
+                    };
+                    string.push(c);
+                }
+                c if (c as u32) < 0x20 => return Err("control character in string"),
+                c => string.push(c),
+            }
+        }
+    }
+
+    // After `\u`, including surrogate pairs.
+    fn unicode_escape(&mut self) -> Result<char, &'static str> {
+        let high = self.hex4()?;
+        if !(0xd800..0xdc00).contains(&high) {
+            return char::from_u32(high).ok_or("invalid escape");
+        }
+        if self.next() != Some('\\') || self.next() != Some('u') {
+            return Err("unpaired surrogate");
+        }
+        let low = self.hex4()?;
+        if !(0xdc00..0xe000).contains(&low) {
+            return Err("unpaired surrogate");
+        }
+        char::from_u32(0x10000 + ((high - 0xd800) << 10) + (low - 0xdc00)).ok_or("invalid escape")
+    }
+
+    fn hex4(&mut self) -> Result<u32, &'static str> {
+        let digits = self.s.get(self.pos..self.pos + 4).ok_or("invalid escape")?;
+        let n = u32::from_str_radix(digits, 16).map_err(|_| "invalid escape")?;
+        self.pos += 4;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error;
+
+    use super::*;
+    use crate::ErrorKind;
+    use tempfile::tempdir;
+
+    type TestResult = Result<(), Box<dyn Error>>;
+
+    #[test]
+    fn import_csv_test() -> TestResult {
+        let base_dir = tempdir()?;
+        let mut s = SunsetDB::new(base_dir.path())?;
+        s.insert("b", "old")?;
+
+        let csv = "a,1\r\nb,\"new, \"\"quoted\"\"\"\n\"multi\nline\",\n";
+        let report = s.import(
+            csv.as_bytes(),
+            ImportFormat::Csv,
+            ConflictPolicy::Overwrite,
+            |_| {},
+        )?;
+        assert_eq!(
+            report,
+            ImportReport {
+                records: 3,
+                imported: 3,
+                conflicts: 1,
+                dropped: 0
+            }
+        );
+        assert_eq!(s.get("a")?.as_deref(), Some("1"));
+        assert_eq!(s.get("b")?.as_deref(), Some("new, \"quoted\""));
+        assert_eq!(s.get("multi\nline")?.as_deref(), Some(""));
+
+        let e = s.import(
+            "c,1\na,2\n".as_bytes(),
+            ImportFormat::Csv,
+            ConflictPolicy::Fail,
+            |_| {},
+        );
+        let e = e.unwrap_err();
+        assert_eq!((e.kind(), e.key()), (ErrorKind::InvalidInput, Some("a")));
+        assert_eq!(s.get("c")?.as_deref(), Some("1"));
+
+        // Keys repeated within an import conflict with their earlier pair,
+        // even before it is written.
+        let e = s.import(
+            "d,1\nd,2\n".as_bytes(),
+            ImportFormat::Csv,
+            ConflictPolicy::Fail,
+            |_| {},
+        );
+        assert_eq!(e.unwrap_err().key(), Some("d"));
+        assert_eq!(s.get("d")?.as_deref(), Some("1"));
+        let concat = ConflictPolicy::Resolve(Box::new(|_, ours, theirs| ours.to_string() + theirs));
+        s.import("e,1\ne,2\n".as_bytes(), ImportFormat::Csv, concat, |_| {})?;
+        assert_eq!(s.get("e")?.as_deref(), Some("12"));
+
+        let report = s.import(
+            "f,1\n\r\ng,2\n\n".as_bytes(),
+            ImportFormat::Csv,
+            ConflictPolicy::Fail,
+            |_| {},
+        )?;
+        assert_eq!(report.records, 2);
+        assert_eq!(s.get("g")?.as_deref(), Some("2"));
+
+        for bad in ["a\n", "a,b,c\n", "\"a,b\n", "\"a\"b,c\n"] {
+            let e = s.import(
+                bad.as_bytes(),
+                ImportFormat::Csv,
+                ConflictPolicy::Fail,
+                |_| {},
+            );
+            assert_eq!(e.unwrap_err().kind(), ErrorKind::InvalidInput, "{:?}", bad);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn import_jsonl_test() -> TestResult {
+        let base_dir = tempdir()?;
+        let mut s = SunsetDB::new(base_dir.path())?;
+        s.insert("a", "old")?;
+
+        let jsonl = r#"{"key": "a", "value": "new"}
+
+{"id": [1, {"x": null}], "key": "bé\n", "value": {"n": -1.5e3, "t": true}}
+{"value": "😀", "key": "c"}
+"#;
+        let mut reports = Vec::new();
+        let report = s.import(
+            jsonl.as_bytes(),
+            ImportFormat::Jsonl,
+            ConflictPolicy::KeepExisting,
+            |r| reports.push(*r),
+        )?;
+        assert_eq!(
+            (report.records, report.imported, report.conflicts),
+            (3, 2, 1)
+        );
+        assert_eq!(reports, [report]);
+        assert_eq!(s.get("a")?.as_deref(), Some("old"));
+        assert_eq!(
+            s.get("bé\n")?.as_deref(),
+            Some(r#"{"n": -1.5e3, "t": true}"#)
+        );
+        assert_eq!(s.get("c")?.as_deref(), Some("😀"));
+
+        for bad in [
+            "[1]",
+            r#"{"key": 1, "value": "v"}"#,
+            r#"{"key": "k"}"#,
+            r#"{"key": "k", "value": tru}"#,
+            r#"{"key": "k", "value": "v"} x"#,
+            &format!(r#"{{"key": "k", "value": {}}}"#, "[".repeat(1_000_000)),
+        ] {
+            let e = s.import(
+                bad.as_bytes(),
+                ImportFormat::Jsonl,
+                ConflictPolicy::Fail,
+                |_| {},
+            );
+            assert_eq!(
+                e.unwrap_err().kind(),
+                ErrorKind::InvalidInput,
+                "{:.40}",
+                bad
+            );
+        }
+
+        let nested = "[".repeat(MAX_JSON_DEPTH) + &"]".repeat(MAX_JSON_DEPTH);
+        let jsonl = format!(r#"{{"key": "n", "value": {}}}"#, nested);
+        s.import(
+            jsonl.as_bytes(),
+            ImportFormat::Jsonl,
+            ConflictPolicy::Fail,
+            |_| {},
+        )?;
+        assert_eq!(s.get("n")?, Some(nested));
+        Ok(())
+    }
+
+    #[test]
+    fn import_with_test() -> TestResult {
+        let base_dir = tempdir()?;
+        let mut s = SunsetDB::new(base_dir.path())?;
+
+        let map: PairMap = Box::new(|key, value| {
+            let key = key.strip_prefix("old:")?.to_string();
+            Some((format!("new:{}", key), value.to_uppercase()))
+        });
+        let csv = "old:a,x\nskip,y\nold:b,z\n";
+        let report = s.import_with(
+            csv.as_bytes(),
+            ImportFormat::Csv,
+            ConflictPolicy::Fail,
+            map,
+            |_| {},
+            &CancellationToken::default(),
+        )?;
+        assert_eq!((report.records, report.imported, report.dropped), (3, 2, 1));
+        assert_eq!(s.get("new:a")?.as_deref(), Some("X"));
+        assert_eq!(s.get("new:b")?.as_deref(), Some("Z"));
+        assert_eq!(s.get("skip")?, None);
+        Ok(())
+    }
+}
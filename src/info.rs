@@ -0,0 +1,219 @@
+// A summary of a database directory, read without opening the database:
+// nothing is created, truncated or recovered.
+//
+// The times of the last compaction and the last backup are not part of the
+// segments, so they are recorded next to them, in `last-compaction` and
+// `last-backup`, as seconds since the epoch.
+
+use std::fs::{read_dir, read_to_string, rename, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use super::checkpoint;
+use super::compact;
+use super::error::*;
+use super::record::FORMAT_VERSION;
+use super::{count_live_keys, shadow, Segment, SegmentID, SegmentStats, SEGMENT_EXT};
+
+pub(crate) const LAST_COMPACTION: &str = "last-compaction";
+pub(crate) const LAST_BACKUP: &str = "last-backup";
+
+/// Outcome of [`info`].
+#[derive(Debug)]
+pub struct InfoReport {
+    /// Version of the record layout. Segments carry no header: this is the
+    /// layout this build reads and writes, with CRC32 checksums on every key
+    /// and value.
+    pub format_version: u64,
+    /// Version of index checkpoints; checkpoints of another version are
+    /// ignored and their segments replayed.
+    pub checkpoint_version: u64,
+    /// Segments that could be read, from oldest to newest.
+    pub segments: Vec<SegmentStats>,
+    pub live_keys: u64,
+    /// A compaction was interrupted: opening the database finishes it or
+    /// rolls it back.
+    pub compaction_pending: bool,
+    pub last_compaction: Option<SystemTime>,
+    /// Last [`SunsetDB::clone_to`](crate::SunsetDB::clone_to) from this
+    /// database.
+    pub last_backup: Option<SystemTime>,
+    /// Invalid data left out of the figures above.
+    pub quarantined: Vec<Quarantined>,
+}
+
+impl InfoReport {
+    pub fn len(&self) -> u64 {
+        self.segments.iter().map(|s| s.len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn dead_bytes(&self) -> u64 {
+        self.segments.iter().map(|s| s.dead_bytes).sum()
+    }
+
+    /// Share of the segments that compaction would reclaim.
+    pub fn dead_ratio(&self) -> f64 {
+        self.dead_bytes() as f64 / self.len().max(1) as f64
+    }
+}
+
+#[derive(Debug)]
+pub enum Quarantined {
+    /// A segment that could not be read.
+    Segment {
+        path: PathBuf,
+        len: u64,
+        error: Error,
+    },
+    /// An incomplete record ending the newest segment, dropped when the
+    /// database is next opened.
+    TornTail {
+        path: PathBuf,
+        offset: u64,
+        bytes: u64,
+    },
+}
+
+/// Summarizes the database in `base_path` without opening it. Keys are read
+/// as when opening the database, so a segment with an invalid key is
+/// quarantined as a whole; values are not verified, see [`check`](crate::check).
+pub fn info(base_path: &Path) -> Result<InfoReport, Error> {
+    let mut paths = Vec::new();
+    for entry in read_dir(base_path).map_err(|e| Error::from(e).with_path(base_path))? {
+        let path = entry
+            .map_err(|e| Error::from(e).with_path(base_path))?
+            .path();
+        if path.extension().and_then(|e| e.to_str()) != Some(SEGMENT_EXT) {
+            continue;
+        }
+        if let Ok(id) = SegmentID::try_from(path.as_path()) {
+            paths.push((id.0, path));
+        }
+    }
+    // NOTE: Sort by ID, not by path: "10.segment" < "2.segment".
+    paths.sort();
+
+    let mut segments = Vec::new();
+    let mut quarantined = Vec::new();
+    let count = paths.len();
+    for (i, (_, path)) in paths.into_iter().enumerate() {
+        let newest = i + 1 == count;
+        let len = path.metadata().map_or(0, |m| m.len());
+        match Segment::open(&path, false, newest, false, None) {
+            Ok(segment) => {
+                if segment.len < len {
+                    quarantined.push(Quarantined::TornTail {
+                        path,
+                        offset: segment.len,
+                        bytes: len - segment.len,
+                    });
+                }
+                segments.push(segment);
+            }
+            Err(e) => {
+                let error = Error::from(e).with_path(&path);
+                quarantined.push(Quarantined::Segment { path, len, error });
+            }
+        }
+    }
+    shadow(&mut segments);
+
+    Ok(InfoReport {
+        format_version: FORMAT_VERSION,
+        checkpoint_version: checkpoint::VERSION,
+        segments: segments
+            .iter()
+            .map(|s| SegmentStats {
+                id: s.id.0,
+                len: s.len,
+                dead_bytes: s.dead_bytes,
+            })
+            .collect(),
+        live_keys: count_live_keys(&segments),
+        compaction_pending: compact::marker_path(base_path).exists(),
+        last_compaction: read_stamp(base_path, LAST_COMPACTION),
+        last_backup: read_stamp(base_path, LAST_BACKUP),
+        quarantined,
+    })
+}
+
+/// Atomically records `now` in the stamp `name`.
+pub(crate) fn write_stamp(base_path: &Path, name: &str, now: SystemTime) -> io::Result<()> {
+    let secs = now
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let tmp_path = base_path.join(format!("{}.tmp", name));
+    let mut file = File::create(&tmp_path)?;
+    writeln!(file, "{}", secs)?;
+    file.sync_all()?;
+    rename(tmp_path, base_path.join(name))
+}
+
+// Missing or unreadable stamps count as never.
+fn read_stamp(base_path: &Path, name: &str) -> Option<SystemTime> {
+    let secs = read_to_string(base_path.join(name))
+        .ok()?
+        .trim()
+        .parse()
+        .ok()?;
+    Some(SystemTime::UNIX_EPOCH + Duration::from_secs(secs))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error;
+    use std::fs::OpenOptions;
+
+    use super::*;
+    use crate::{encoded_record_len, SunsetDB};
+    use tempfile::tempdir;
+
+    type TestResult = Result<(), Box<dyn Error>>;
+
+    #[test]
+    fn info_test() -> TestResult {
+        let base_dir = tempdir()?;
+        let backup_dir = tempdir()?;
+        let mut s = SunsetDB::new(base_dir.path())?;
+        s.insert("k", "v")?;
+        s.insert("k", "w")?;
+        s.add_new_segment()?;
+        s.insert("j", "v")?;
+        s.delete("j")?;
+
+        let report = info(base_dir.path())?;
+        assert_eq!(report.format_version, FORMAT_VERSION);
+        assert_eq!(report.segments, s.segment_stats());
+        assert_eq!(report.live_keys, 1);
+        assert_eq!(
+            report.dead_bytes(),
+            encoded_record_len("k", Some("v")) + encoded_record_len("j", Some("v"))
+        );
+        assert!(report.last_backup.is_none() && report.quarantined.is_empty());
+
+        s.clone_to(&backup_dir.path().join("copy"))?;
+        assert!(info(base_dir.path())?.last_backup.is_some());
+        drop(s);
+
+        // A torn tail is reported, and left in place.
+        let newest = base_dir.path().join(format!("1.{}", SEGMENT_EXT));
+        let len = newest.metadata()?.len();
+        OpenOptions::new()
+            .append(true)
+            .open(&newest)?
+            .write_all(b"\0")?;
+        let report = info(base_dir.path())?;
+        assert!(matches!(
+            report.quarantined[..],
+            [Quarantined::TornTail { offset, bytes: 1, .. }] if offset == len
+        ));
+        assert_eq!(newest.metadata()?.len(), len + 1);
+        Ok(())
+    }
+}
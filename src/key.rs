@@ -0,0 +1,264 @@
+//! Keys and their order-preserving encodings.
+//!
+//! Every encoding below sorts like the values it encodes, byte by byte,
+//! which is what range scans over keys rely on. Tuples encode part after
+//! part, and every part is self-delimiting: the encoding of a tuple prefix,
+//! such as `(tenant_id,)`, is a prefix of the encoding of every key that
+//! starts with it, such as `(tenant_id, user_id)`, and of no other key.
+//!
+//! Variable length parts are terminated rather than prefixed by their
+//! length, which would sort `"b"` before `"aa"`.
+
+use std::borrow::Cow;
+
+/// A value usable as a database key.
+///
+/// Keys are stored as strings, so encodings must produce valid UTF-8.
+/// Integers, tuples and byte arrays (e.g. the bytes of a UUID) are encoded
+/// so that the order of their encodings is the order of the values: keys
+/// like `(tenant_id, user_id)` sort by tenant, then by user.
+///
+/// Strings are stored as they are, except within tuples where they are
+/// escaped and terminated to keep their order there too.
+pub trait Key {
+    fn encode_into(&self, buffer: &mut String);
+
+    /// Encodes `self` as a part of a tuple, followed by further parts. Only
+    /// encodings of varying length need to override this.
+    fn encode_part_into(&self, buffer: &mut String) {
+        self.encode_into(buffer)
+    }
+
+    fn encode(&self) -> Cow<'_, str> {
+        let mut buffer = String::new();
+        self.encode_into(&mut buffer);
+        Cow::Owned(buffer)
+    }
+}
+
+impl Key for str {
+    fn encode_into(&self, buffer: &mut String) {
+        buffer.push_str(self)
+    }
+
+    fn encode_part_into(&self, buffer: &mut String) {
+        encode_str_part(buffer, self)
+    }
+
+    fn encode(&self) -> Cow<'_, str> {
+        Cow::Borrowed(self)
+    }
+}
+
+impl Key for String {
+    fn encode_into(&self, buffer: &mut String) {
+        self.as_str().encode_into(buffer)
+    }
+
+    fn encode_part_into(&self, buffer: &mut String) {
+        self.as_str().encode_part_into(buffer)
+    }
+
+    fn encode(&self) -> Cow<'_, str> {
+        Cow::Borrowed(self)
+    }
+}
+
+impl<K: Key + ?Sized> Key for &K {
+    fn encode_into(&self, buffer: &mut String) {
+        (**self).encode_into(buffer)
+    }
+
+    fn encode_part_into(&self, buffer: &mut String) {
+        (**self).encode_part_into(buffer)
+    }
+
+    fn encode(&self) -> Cow<'_, str> {
+        (**self).encode()
+    }
+}
+
+/// Encodes an unsigned integer given as big endian bytes, e.g. from
+/// `to_be_bytes`, as hex digits. Encodings only compare with those of
+/// integers of the same width.
+pub fn encode_unsigned(buffer: &mut String, be_bytes: &[u8]) {
+    const DIGITS: &[u8; 16] = b"0123456789abcdef";
+    for byte in be_bytes {
+        buffer.push(DIGITS[(byte >> 4) as usize] as char);
+        buffer.push(DIGITS[(byte & 0xf) as usize] as char);
+    }
+}
+
+/// Like [`encode_unsigned`], for two's complement integers: flipping the
+/// sign bit sorts negative numbers before positive ones.
+pub fn encode_signed(buffer: &mut String, be_bytes: &[u8]) {
+    if let Some((first, rest)) = be_bytes.split_first() {
+        encode_unsigned(buffer, &[first ^ 0x80]);
+        encode_unsigned(buffer, rest);
+    }
+}
+
+/// Encodes the bytes of a UUID, which sort like the UUID itself.
+pub fn encode_uuid(buffer: &mut String, bytes: &[u8; 16]) {
+    encode_unsigned(buffer, bytes)
+}
+
+/// Encodes a string part of a tuple. Two NUL bytes end it, while NUL bytes
+/// within it are followed by 0x01: `"a" < "a\0" < "ab"` also holds for
+/// their encodings.
+pub fn encode_str_part(buffer: &mut String, s: &str) {
+    for c in s.chars() {
+        buffer.push(c);
+        if c == '\0' {
+            buffer.push('\u{1}');
+        }
+    }
+    buffer.push_str("\0\0");
+}
+
+/// Encodes a byte string part of a tuple as hex digits, ended by a NUL
+/// byte which sorts before any digit.
+pub fn encode_bytes_part(buffer: &mut String, bytes: &[u8]) {
+    encode_unsigned(buffer, bytes);
+    buffer.push('\0');
+}
+
+macro_rules! impl_key_unsigned {
+    ($($t:ty),*) => {
+        $(
+            impl Key for $t {
+                fn encode_into(&self, buffer: &mut String) {
+                    encode_unsigned(buffer, &self.to_be_bytes())
+                }
+            }
+        )*
+    };
+}
+
+macro_rules! impl_key_signed {
+    ($($t:ty),*) => {
+        $(
+            impl Key for $t {
+                fn encode_into(&self, buffer: &mut String) {
+                    encode_signed(buffer, &self.to_be_bytes())
+                }
+            }
+        )*
+    };
+}
+
+impl_key_unsigned!(u8, u16, u32, u64, u128);
+impl_key_signed!(i8, i16, i32, i64, i128);
+
+/// Fixed size byte strings, e.g. UUIDs, as hex digits.
+impl<const N: usize> Key for [u8; N] {
+    fn encode_into(&self, buffer: &mut String) {
+        encode_unsigned(buffer, self)
+    }
+}
+
+impl Key for [u8] {
+    fn encode_into(&self, buffer: &mut String) {
+        encode_unsigned(buffer, self)
+    }
+
+    fn encode_part_into(&self, buffer: &mut String) {
+        encode_bytes_part(buffer, self)
+    }
+}
+
+macro_rules! impl_key_tuple {
+    ($($name:ident),+) => {
+        impl<$($name: Key),+> Key for ($($name,)+) {
+            #[allow(non_snake_case)]
+            fn encode_into(&self, buffer: &mut String) {
+                let ($($name,)+) = self;
+                $($name.encode_part_into(buffer);)+
+            }
+        }
+    };
+}
+
+impl_key_tuple!(A);
+impl_key_tuple!(A, B);
+impl_key_tuple!(A, B, C);
+impl_key_tuple!(A, B, C, D);
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error;
+
+    use super::*;
+    use crate::SunsetDB;
+    use tempfile::tempdir;
+
+    type TestResult = Result<(), Box<dyn Error>>;
+
+    fn encode(key: impl Key) -> String {
+        key.encode().into_owned()
+    }
+
+    #[test]
+    fn key_order_test() {
+        let ints = [i64::MIN, -256, -1, 0, 1, 255, 256, i64::MAX];
+        for pair in ints.windows(2) {
+            assert!(encode(pair[0]) < encode(pair[1]), "{:?}", pair);
+        }
+        assert_eq!(encode(1u16), "0001");
+
+        let strings = ["", "\0", "\0\0", "\0a", "a", "a\0", "a\0\0", "ab", "b"];
+        for pair in strings.windows(2) {
+            let (a, b) = ((pair[0], 0u8), (pair[1], 0u8));
+            assert!(encode(a) < encode(b), "{:?}", pair);
+        }
+        assert_eq!(encode("k"), "k");
+
+        assert!(encode((1u32, "b")) < encode((2u32, "a")));
+        assert!(encode(("a", 2u32)) < encode(("ab", 1u32)));
+        assert!(encode(("a", -1i8)) < encode(("a", 0i8)));
+        assert!(encode([0u8; 16]) < encode([0xff; 16]));
+    }
+
+    // Sorting by encoding must sort like the values, including prefixes.
+    #[test]
+    fn key_tuple_order_test() {
+        let strings = ["", "\0", "a", "a\0", "a\u{1}", "aa", "b", "é"];
+        let bytes: [&[u8]; 5] = [&[], &[0], &[0, 0], &[1], &[0xff]];
+        let mut keys = Vec::new();
+        for s in strings {
+            for b in bytes {
+                for i in [i32::MIN, -1, 0, 1, i32::MAX] {
+                    keys.push((s, b, i));
+                }
+            }
+        }
+
+        let mut by_encoding = keys.clone();
+        by_encoding.sort_by_cached_key(|k| encode(*k));
+        keys.sort();
+        assert_eq!(by_encoding, keys);
+
+        let prefix = encode(("a",));
+        for key in &keys {
+            assert_eq!(encode(*key).starts_with(&prefix), key.0 == "a", "{:?}", key);
+        }
+    }
+
+    #[test]
+    fn typed_keys_test() -> TestResult {
+        let base_dir = tempdir()?;
+        let mut s = SunsetDB::new(base_dir.path())?;
+        s.insert(&(7u32, "alice"), "a")?;
+        s.insert(&-3i64, "b")?;
+        s.insert("plain", "c")?;
+
+        assert_eq!(s.get(&(7u32, "alice"))?.as_deref(), Some("a"));
+        assert_eq!(s.get(&(7u32, "alic"))?, None);
+        assert_eq!(s.value_len(&-3i64), Some(1));
+        assert_eq!(s.get(&"plain".to_string())?.as_deref(), Some("c"));
+        assert_eq!(s.remove(&-3i64)?.as_deref(), Some("b"));
+        s.delete(&(7u32, "alice"))?;
+        assert_eq!(s.get(&(7u32, "alice"))?, None);
+        Ok(())
+    }
+}
@@ -0,0 +1,132 @@
+use std::collections::BTreeMap;
+
+use super::error::*;
+use super::segment_io::{Record, SegmentReader};
+use super::{encoded_record_len, SunsetDB};
+
+/// Space used by the keys sharing a prefix, see [`SunsetDB::keyspace_stats`].
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct KeyspaceStats {
+    pub live_keys: u64,
+    /// Size on disk of the current record of every live key.
+    pub live_bytes: u64,
+    /// Sum of the length of live values.
+    pub value_bytes: u64,
+    /// Size on disk of overwritten or deleted records.
+    pub dead_bytes: u64,
+    /// Number of tombstones on disk, whether or not they still hide a value.
+    pub tombstones: u64,
+    /// Live bytes held by each segment, by segment ID.
+    pub segments: BTreeMap<u64, u64>,
+}
+
+impl KeyspaceStats {
+    pub fn average_value_size(&self) -> u64 {
+        self.value_bytes.checked_div(self.live_keys).unwrap_or(0)
+    }
+
+    /// IDs of the segments holding live bytes, most bytes first.
+    pub fn hottest_segments(&self) -> Vec<u64> {
+        let mut segments: Vec<_> = self.segments.iter().collect();
+        segments.sort_by(|(a_id, a), (b_id, b)| b.cmp(a).then(a_id.cmp(b_id)));
+        segments.into_iter().map(|(id, _)| *id).collect()
+    }
+}
+
+impl SunsetDB {
+    /// Breaks space usage down by keyspace, i.e. by the part of keys before
+    /// the first `separator` (the empty string for keys without one).
+    ///
+    /// Unlike [`segment_stats`](SunsetDB::segment_stats), reads every segment.
+    pub fn keyspace_stats(
+        &self,
+        separator: char,
+    ) -> Result<BTreeMap<String, KeyspaceStats>, Error> {
+        let mut stats: BTreeMap<String, KeyspaceStats> = BTreeMap::new();
+        for (i, s) in self.segments.iter().enumerate() {
+            let records = SegmentReader::open_range(&s.path, 0, s.len).map_err(|e| s.error(e))?;
+            for record in records {
+                let (offset, record) = record.map_err(|e| s.error(e))?;
+                let key = record.key();
+                let keyspace = key.split_once(separator).map_or("", |(k, _)| k);
+                let stats = match stats.get_mut(keyspace) {
+                    Some(stats) => stats,
+                    None => stats.entry(keyspace.to_string()).or_default(),
+                };
+
+                let Record::Insert { value, .. } = &record else {
+                    stats.tombstones += 1;
+                    continue;
+                };
+                let len = encoded_record_len(key, Some(value));
+                let current = self.segments[i..]
+                    .iter()
+                    .rev()
+                    .find_map(|s| s.index.get(key).map(|entry| (s.id.0, entry.offset)));
+                if current == Some((s.id.0, offset)) {
+                    stats.live_keys += 1;
+                    stats.live_bytes += len;
+                    stats.value_bytes += value.len() as u64;
+                    *stats.segments.entry(s.id.0).or_default() += len;
+                } else {
+                    stats.dead_bytes += len;
+                }
+            }
+        }
+        Ok(stats)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error;
+
+    use crate::SunsetDB;
+    use tempfile::tempdir;
+
+    type TestResult = Result<(), Box<dyn Error>>;
+
+    #[test]
+    fn keyspace_stats_test() -> TestResult {
+        let base_dir = tempdir()?;
+        let mut s = SunsetDB::new(base_dir.path())?;
+        s.insert("a/1", "xx")?;
+        s.insert("a/2", "xxxx")?;
+        s.insert("b/1", "x")?;
+        s.add_new_segment()?;
+        s.insert("a/1", "yyyyyy")?;
+        s.delete("b/1")?;
+        s.insert("c", "z")?;
+
+        let stats = s.keyspace_stats('/')?;
+        assert_eq!(
+            stats.keys().collect::<Vec<_>>(),
+            ["", "a", "b"].iter().collect::<Vec<_>>()
+        );
+
+        let a = &stats["a"];
+        assert_eq!(a.live_keys, 2);
+        assert_eq!(a.value_bytes, 10);
+        assert_eq!(a.average_value_size(), 5);
+        assert_eq!(
+            a.dead_bytes,
+            s.segment_stats()[0].dead_bytes - stats["b"].dead_bytes
+        );
+        assert_eq!(a.hottest_segments(), [1, 0]);
+
+        let b = &stats["b"];
+        assert_eq!((b.live_keys, b.tombstones), (0, 1));
+        assert_eq!(b.live_bytes, 0);
+        assert_eq!(stats[""].live_keys, 1);
+
+        let live_bytes: u64 = stats.values().map(|k| k.live_bytes).sum();
+        let dead_bytes: u64 = stats.values().map(|k| k.dead_bytes).sum();
+        let tombstones = stats.values().map(|k| k.tombstones).sum::<u64>();
+        let total: u64 = s.segment_stats().iter().map(|s| s.len).sum();
+        assert_eq!(
+            live_bytes + dead_bytes + tombstones * crate::encoded_record_len("b/1", None),
+            total
+        );
+        Ok(())
+    }
+}
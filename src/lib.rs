@@ -1,24 +1,126 @@
+extern crate alloc;
+
+mod batch;
+mod cancel;
+mod check;
+mod checkpoint;
+mod clock;
+mod compact;
+mod cursor;
+mod deadline;
+mod disk_space;
+mod doctor;
 mod error;
-
-use std::collections::HashMap;
+mod events;
+#[cfg(feature = "arrow")]
+mod export;
+pub mod failpoints;
+mod fork;
+mod heat;
+mod import;
+mod info;
+pub mod key;
+mod keyspace;
+mod logging;
+#[cfg(any(feature = "sled", feature = "redb"))]
+mod migrate;
+mod misses;
+mod modified;
+mod options;
+mod profiling;
+mod rate_limit;
+mod record;
+mod recovery;
+mod rotate;
+mod scan;
+#[cfg(feature = "async")]
+mod scan_stream;
+mod scrub;
+mod segment_io;
+mod shadow;
+mod stream;
+mod trace;
+mod transfer;
+
+use std::collections::{HashMap, HashSet};
 use std::ffi::OsStr;
 use std::fs::{read_dir, File, OpenOptions};
 use std::io::{self, Read, Seek, SeekFrom, Write};
-use std::mem::size_of;
+use std::os::unix::fs::{FileExt, MetadataExt};
 use std::os::unix::prelude::OsStrExt;
 use std::path::{Path, PathBuf};
 use std::result::Result;
+use std::time::SystemTime;
 
+use self::batch::Pending;
+use self::checkpoint::Checkpoint;
 use self::error::*;
+use self::logging::{log_debug, log_info, log_warn};
+use self::record::*;
+
+pub use self::cancel::CancellationToken;
+pub use self::clock::{Clock, MockClock, SharedClock, SystemClock};
+pub use self::compact::{
+    CompactionFilter, CompactionOptions, CompactionReport, FilterDecision, SharedCompactionFilter,
+};
+pub use self::cursor::Cursor;
+pub use self::disk_space::DiskSpaceLimits;
+pub use self::doctor::{doctor, DoctorReport, Filesystem, Fsync, Warning};
+pub use self::error::{Error, ErrorKind};
+pub use self::events::{Event, EventListener, EventListeners};
+pub use self::heat::{KeyHeat, KeyHeatOptions};
+pub use self::import::{ImportFormat, ImportReport};
+pub use self::info::{info, InfoReport, Quarantined};
+pub use self::key::Key;
+pub use self::keyspace::KeyspaceStats;
+pub use self::modified::LogPosition;
+pub use self::options::{ChecksumSampling, OpenMode, Options, Quota, Quotas, Tunable};
+#[cfg(feature = "profiling")]
+pub use self::profiling::{CountingAllocator, OpProfile};
+pub use self::rate_limit::RateLimits;
+pub use self::recovery::{RecoveryListener, RecoveryListeners, RecoveryProgress};
+#[cfg(feature = "async")]
+pub use self::scan_stream::ScanStream;
+pub use self::segment_io::{Record, SegmentReader, SegmentWriter};
+pub use self::shadow::{ShadowDB, ShadowStats};
+pub use self::stream::Stream;
+pub use self::transfer::{
+    AbsorbReport, ConflictPolicy, ConflictResolver, IngestOptions, PairMap, SplitBy,
+};
+
+pub use self::check::{check, check_with, CheckReport, Issue, IssueKind, SegmentReport};
+pub use self::trace::{read_trace, replay, ReplayReport, TraceEntry, TraceOp};
+
+use self::compact::Compactor;
+use self::deadline::Deadline;
+use self::disk_space::{DiskSpace, DiskWatchdog};
+use self::heat::HeatMap;
+use self::misses::MissCache;
+use self::rate_limit::RateLimiter;
+use self::recovery::Recovery;
+use self::scrub::Scrubber;
+use self::trace::TraceWriter;
+
+type Index = HashMap<String, IndexEntry>;
+
+// Location of the latest value of a key within a segment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct IndexEntry {
+    // Start of the record, i.e. of the key.
+    offset: u64,
+    value_len: u64,
+}
 
-type Index = HashMap<String, u64>;
+impl IndexEntry {
+    // Size on disk of the record, see `encoded_record_len`.
+    fn record_len(&self, key: &str) -> u64 {
+        (ENCODED_LEN_SIZE + key.len() + CRC32_SIZE + ENCODED_LEN_SIZE + CRC32_SIZE) as u64
+            + self.value_len
+    }
+}
 
 const SEGMENT_EXT: &str = "segment";
 
-// TODO: Switch to using an empty byte string as the tombstone?
-const TOMBSTONE: u64 = 1u64 << 63;
-const ENCODED_TOMBSTONE: [u8; size_of::<u64>()] = (TOMBSTONE).to_be_bytes();
-
 #[derive(Debug)]
 struct SegmentID(u64);
 
@@ -46,33 +148,74 @@ impl TryFrom<&Path> for SegmentID {
 // NOTE: This will hold the file open as long as `Segment` is in memory.
 struct Segment {
     id: SegmentID,
+    path: PathBuf,
     file: File,
     index: Index,
+    // Keys whose latest record in this segment is a tombstone.
+    deleted: HashSet<String>,
+    // Keys whose records here a newer tombstone hides, and that would
+    // resurface without it.
+    hidden: HashSet<String>,
+    // Committed length: it only grows once a whole record was appended, and
+    // the index and every reader stop there.
+    len: u64,
+    // Length covered by the last index checkpoint.
+    checkpointed: u64,
+    // Bytes of values that were overwritten or deleted since.
+    dead_bytes: u64,
 }
 
 impl Segment {
     fn new(path: &Path) -> Result<Segment, SegmentError> {
+        Segment::open(path, true, false, false, None)
+    }
+
+    // Only the active segment is opened `writable`, creating it if needed.
+    // See `replay` for `skip_torn_tail`, `verify` and `recovery`. With
+    // `verify`, the whole segment is replayed, ignoring any checkpoint.
+    fn open(
+        path: &Path,
+        writable: bool,
+        skip_torn_tail: bool,
+        verify: bool,
+        recovery: Option<&mut Recovery>,
+    ) -> Result<Segment, SegmentError> {
         let mut f = OpenOptions::new()
-            .create(true) // TODO: Should not try to create all segments.
+            .create(writable)
+            .truncate(false)
             .read(true)
-            .write(true) // TODO: Only most recent segment should be open for write.
+            .write(writable)
             .open(path)
             .map_err(|e| SegmentError::IOErrorAtPath {
                 path: path.to_path_buf(),
                 source: e,
             })?;
-        let index = Segment::index_from_disk(&mut f);
+        let len = f.metadata()?.len();
+        let checkpoint = if verify {
+            None
+        } else {
+            checkpoint::read(path).filter(|c| c.watermark <= len)
+        };
+        let checkpointed = checkpoint.as_ref().map_or(0, |c| c.watermark);
+        let mut replayed = checkpoint.unwrap_or_default();
+        Segment::replay(&mut f, &mut replayed, skip_torn_tail, verify, recovery)?;
         Ok::<_, _>(Segment {
             id: SegmentID::try_from(path)
                 .map_err(|_| SegmentError::InvalidPath(path.to_path_buf()))?,
+            path: path.to_path_buf(),
             file: f,
-            index: index?,
+            index: replayed.index,
+            deleted: replayed.deleted,
+            hidden: HashSet::new(),
+            len: replayed.watermark,
+            checkpointed,
+            dead_bytes: replayed.dead_bytes,
         })
     }
 
     fn insert(&mut self, key: &str, value: &str) -> Result<(), InsertError> {
-        // `append_string` encodes the `len`, then the string.
-        // `append_deletion` stores `TOMBSTONE` after the key.
+        // `encode_string` encodes the `len`, then the string.
+        // `encode_deletion` stores `TOMBSTONE` after the key.
         // Having a `value` with a `len` equal to the TOMBSTONE would
         // allow confusing it with a deleted entry.
         // Could be a strict `==`, we make it >= so that there's a clear max size.
@@ -84,99 +227,387 @@ impl Segment {
             return Err(InsertError::KeyExceedsMaxSize);
         }
 
-        let offset = self.file.metadata()?.len();
+        let offset = self.len;
 
         // NOTE: We could write the CRC only once per record.
         // NOTE: Writing the `key` isn't strictly required,
         // but it allows us to reconstruct `index` later on.
-        append_string(&mut self.file, key)?;
-        append_string(&mut self.file, value)?;
+        self.append(&encode_record(key, Some(value)))?;
+        self.len += encoded_record_len(key, Some(value));
 
         // TODO: no need for `to_owned` if key already there?
         // https://doc.rust-lang.org/std/collections/hash_map/enum.Entry.html
-        self.index.insert(key.to_owned(), offset);
+        let value_len = value.len() as u64;
+        self.index
+            .insert(key.to_owned(), IndexEntry { offset, value_len });
+        self.deleted.remove(key);
 
         Ok(())
     }
 
+    // Appends a tombstone whether or not `key` is in this segment.
     fn delete(&mut self, key: &str) -> Result<(), DeleteError> {
-        append_string(&mut self.file, key)?;
-        append_deletion(&mut self.file)?;
-        self.index.remove(key).ok_or(DeleteError::KeyNotFound)?;
+        self.append(&encode_record(key, None))?;
+        self.len += encoded_record_len(key, None);
+        self.index.remove(key);
+        self.deleted.insert(key.to_owned());
         Ok(())
     }
 
-    fn get(&mut self, key: &str) -> Result<String, GetError> {
-        let mut offset: u64 = *self.index.get(key).ok_or(GetError::KeyNotFound)?;
-        debug_assert!(
-            read_string_at_offset(&mut self.file, offset)
-                .is_ok_and(|v| v.is_some_and(|s| s == key)),
-            "should find key at offset from index"
-        );
+    // Stops serving `key`, deleted by a newer segment.
+    fn hide(&mut self, key: &str) {
+        if self.index.remove(key).is_some() {
+            self.hidden.insert(key.to_owned());
+        }
+    }
 
-        offset += ENCODED_LEN_SIZE as u64 + key.len() as u64 + CRC32_SIZE as u64;
-        let value = read_string_at_offset(&mut self.file, offset)?;
+    // Whether opening the segment again could serve `key`, were newer
+    // tombstones dropped.
+    fn holds(&self, key: &str) -> bool {
+        self.index.contains_key(key) || self.hidden.contains(key)
+    }
+
+    // Appends `buffer` with a single write. On failure, truncates whatever
+    // part of it was written, so that the next record starts at `len`.
+    fn append(&mut self, buffer: &[u8]) -> io::Result<()> {
+        let result = append_at_end(&mut self.file, buffer);
+        if result.is_err() {
+            let _ = self.file.set_len(self.len);
+        }
+        result
+    }
+
+    // Skips the value checksum unless `verify`.
+    fn get(&self, key: &str, verify: bool) -> Result<String, Error> {
+        let entry = *self
+            .index
+            .get(key)
+            .ok_or_else(|| self.error(GetError::KeyNotFound).with_key(key))?;
+        self.read_value(key, entry, verify)
+            .map_err(|e| self.error(e).with_offset(entry.offset).with_key(key))
+    }
 
-        value.ok_or(GetError::KeyNotFound)
+    // Like `get`, but also validates the key stored at the indexed offset.
+    fn get_verified(&mut self, key: &str) -> Result<String, Error> {
+        let entry = *self
+            .index
+            .get(key)
+            .ok_or_else(|| self.error(GetError::KeyNotFound).with_key(key))?;
+
+        let mut read = || {
+            match read_string_at_offset(&mut self.file, entry.offset)? {
+                Some(k) if k == key => {}
+                _ => return Err(GetError::KeyMismatch),
+            }
+            self.read_value(key, entry, true)
+        };
+        read().map_err(|e| self.error(e).with_offset(entry.offset).with_key(key))
     }
 
-    fn index_from_disk(file: &mut File) -> Result<Index, SegmentError> {
-        let mut index = Index::new();
-        file.rewind()?; // Should not be required.
+    // Reads the value with a single positional read, sized from the index.
+    fn read_value(&self, key: &str, entry: IndexEntry, verify: bool) -> Result<String, GetError> {
+        if entry.offset + entry.record_len(key) > self.len {
+            return Err(GetError::LengthMismatch);
+        }
+        let offset = entry.offset + (ENCODED_LEN_SIZE + key.len() + CRC32_SIZE) as u64;
+        let value_len = usize::try_from(entry.value_len).map_err(ReadError::from)?;
+        let mut buffer = vec![0; ENCODED_LEN_SIZE + value_len + CRC32_SIZE];
+        profiling::io(1, buffer.len() as u64, 0);
+        self.file
+            .read_exact_at(&mut buffer, offset)
+            .map_err(ReadError::from)?;
+        decode_value(&buffer, entry, verify).map(str::to_string)
+    }
 
-        // TODO: If possible, instead of a full disk read from a dump of the HashMap
+    // Persists the index, once every record it points to is on disk.
+    fn checkpoint(&mut self) -> io::Result<()> {
+        failpoints::fail_point!(failpoints::FSYNC);
+        profiling::io(1, 0, 0);
+        self.file.sync_data()?;
+        checkpoint::write(
+            &self.path,
+            self.len,
+            &self.index,
+            &self.deleted,
+            self.dead_bytes,
+        )?;
+        self.checkpointed = self.len;
+        Ok(())
+    }
+
+    // Whether the file at `path` is no longer the one open, e.g. after a
+    // compaction.
+    fn replaced(&self) -> bool {
+        match (self.path.metadata(), self.file.metadata()) {
+            (Ok(on_disk), Ok(open)) => (on_disk.dev(), on_disk.ino()) != (open.dev(), open.ino()),
+            _ => true,
+        }
+    }
+
+    // Truncates the record left incomplete by a crash after the replayed
+    // records, see `replay`. Returns the number of bytes dropped.
+    fn discard_torn_tail(&mut self) -> Result<u64, SegmentError> {
+        let file_len = self.file.metadata()?.len();
+        if file_len == self.len {
+            return Ok(0);
+        }
+
+        match record_len_at(&mut self.file, self.len) {
+            Ok(len) if self.len + len > file_len => {}
+            Err(ReadError::IOError(e)) if e.kind() == io::ErrorKind::UnexpectedEof => {}
+            Ok(_) => {
+                return Err(SegmentError::InvalidIndexFormat(
+                    "complete record after the replayed ones".to_string(),
+                ))
+            }
+            Err(e) => return Err(e.into()),
+        }
+        self.file.set_len(self.len)?;
+        Ok(file_len - self.len)
+    }
+
+    fn error(&self, e: impl Into<Error>) -> Error {
+        e.into().with_segment(self.id.0, &self.path)
+    }
+
+    // Replays the records after `c.watermark` into `c`, one at a time so
+    // that `c` stays consistent on errors. With `skip_torn_tail`, stops
+    // quietly before a record that is still being written. With `verify`,
+    // also validates the checksum of values. Progress is reported to
+    // `recovery`, which may cancel.
+    fn replay(
+        file: &mut File,
+        c: &mut Checkpoint,
+        skip_torn_tail: bool,
+        verify: bool,
+        mut recovery: Option<&mut Recovery>,
+    ) -> Result<(), SegmentError> {
+        file.seek(SeekFrom::Start(c.watermark))?;
 
         let segment_len = file.metadata()?.len();
         loop {
-            let offset = file.stream_position()?;
+            let offset = c.watermark;
             if offset == segment_len {
                 break;
             }
+            if skip_torn_tail {
+                if !record_len_at(file, offset).is_ok_and(|len| offset + len <= segment_len) {
+                    break;
+                }
+                file.seek(SeekFrom::Start(offset))?;
+            }
 
             let key = read_check_string(file)?.ok_or(SegmentError::InvalidIndexFormat(
                 "tombstone in index".to_string(),
             ))?;
 
-            // TODO: Ignore keys for values having an invalid checksum.
+            let encoded_len = decode_len(read_u64_bytes(file)?);
+            if let Some(previous) = c.index.get(&key) {
+                c.dead_bytes += previous.record_len(&key);
+            }
 
-            let encoded_value_len = read_u64_bytes(file)?;
-            if encoded_value_len != ENCODED_TOMBSTONE {
-                index.insert(key, offset);
-                let value_len = parse_u64_bytes(encoded_value_len)?;
-                let end_of_encoded_entry = i64::try_from(value_len + CRC32_SIZE as u64)
-                    .map_err(|_| SegmentError::SeekError)?;
-                file.seek(SeekFrom::Current(end_of_encoded_entry))?;
+            if let EncodedLen::Len(value_len) = encoded_len {
+                if verify {
+                    let mut value = vec![0; usize::try_from(value_len).map_err(ReadError::from)?];
+                    file.read_exact(&mut value)?;
+                    let mut checksum = [0; CRC32_SIZE];
+                    file.read_exact(&mut checksum)?;
+                    decode_string(value, checksum).map_err(ReadError::from)?;
+                } else {
+                    let end_of_encoded_entry = i64::try_from(value_len + CRC32_SIZE as u64)
+                        .map_err(|_| SegmentError::SeekError)?;
+                    file.seek(SeekFrom::Current(end_of_encoded_entry))?;
+                }
+                c.deleted.remove(&key);
+                c.index.insert(key, IndexEntry { offset, value_len });
             } else {
-                index.remove(&key);
+                c.index.remove(&key);
+                c.deleted.insert(key);
+            }
+            c.watermark = file.stream_position()?;
+            if let Some(recovery) = recovery.as_deref_mut() {
+                recovery.at(c.watermark)?;
             }
         }
 
-        Ok(index)
+        Ok(())
     }
+
+    // Replays the complete records appended by another process since the
+    // last replay. Returns the keys this segment didn't hold before, and
+    // the keys it newly deletes.
+    fn tail(&mut self) -> Result<(Vec<String>, Vec<String>), SegmentError> {
+        let mut c = Checkpoint {
+            watermark: self.len,
+            index: std::mem::take(&mut self.index),
+            deleted: std::mem::take(&mut self.deleted),
+            dead_bytes: self.dead_bytes,
+        };
+        let (old_keys, old_deleted): (HashSet<_>, _) =
+            (c.index.keys().cloned().collect(), c.deleted.clone());
+        let result = Segment::replay(&mut self.file, &mut c, true, false, None);
+
+        let new_keys = c
+            .index
+            .keys()
+            .chain(c.deleted.iter())
+            .filter(|k| !old_keys.contains(*k) && !old_deleted.contains(*k))
+            .cloned()
+            .collect();
+        let deleted_keys = c.deleted.difference(&old_deleted).cloned().collect();
+        (self.index, self.deleted) = (c.index, c.deleted);
+        (self.len, self.dead_bytes) = (c.watermark, c.dead_bytes);
+
+        result.map(|_| (new_keys, deleted_keys))
+    }
+}
+
+// Keys touched by records a segment gained behind the write path.
+struct Changes {
+    segment: usize,
+    // Keys the segment didn't hold before.
+    new_keys: Vec<String>,
+    // Keys the segment newly deletes.
+    deleted_keys: Vec<String>,
 }
 
+/// Space used by a segment, see [`SunsetDB::segment_stats`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SegmentStats {
+    pub id: u64,
+    pub len: u64,
+    /// Bytes of records whose value was since overwritten or deleted, which
+    /// compacting the segment would reclaim. Tombstones are not included.
+    pub dead_bytes: u64,
+}
+
+/// A key-value store appending to segment files under a base directory.
+///
+/// Within a handle, a successful `insert` or delete is visible to every
+/// later call. Writes always go to the newest segment, so they shadow the
+/// same key in older ones, including across reopens. Acknowledged writes
+/// only survive a crash once [`SunsetDB::sync`] returns.
 pub struct SunsetDB {
     base_path: PathBuf,
     segments: Vec<Segment>,
     next_index: u64,
+    trace: Option<TraceWriter>,
+    options: Options,
+    live_keys: u64,
+    rate_limiter: RateLimiter,
+    disk_watchdog: DiskWatchdog,
+    reads: u64,
+    // Set once corruption is found; every later read is then verified.
+    verify_all_reads: bool,
+    scrubber: Option<Scrubber>,
+    compactor: Option<Compactor>,
+    maintenance_paused: bool,
+    heat: Option<HeatMap>,
+    misses: Option<MissCache>,
+    // When keys were last deleted, with `tombstone_grace_period`.
+    deleted_at: HashMap<String, SystemTime>,
+    // The process that opened the database, see `reopen_after_fork`.
+    pid: u32,
+    #[cfg(feature = "profiling")]
+    profile: profiling::Profile,
 }
 
 impl SunsetDB {
-    pub fn new(base_path: &Path) -> Result<SunsetDB, SunsetDBError> {
-        let mut paths: Vec<_> = read_dir(base_path)?
+    pub fn new(base_path: &Path) -> Result<SunsetDB, Error> {
+        SunsetDB::with_options(base_path, Options::default())
+    }
+
+    pub fn with_options(base_path: &Path, options: Options) -> Result<SunsetDB, Error> {
+        // Removed once the segments are replayed, see `compact::recover`.
+        let superseded = compact::superseded(base_path)?;
+        let mut paths: Vec<_> = read_dir(base_path)
+            .map_err(|e| Error::from(e).with_path(base_path))?
             // WARNING: This will filter out errors on `read_dir`.
             .filter_map(std::io::Result::ok)
             .map(|e| e.path())
             .filter(|p| p.extension() == Some(OsStr::from_bytes(SEGMENT_EXT.as_bytes())))
+            .filter(|p| {
+                SegmentID::try_from(p.as_path()).map_or(true, |id| !superseded.contains(&id.0))
+            })
             .collect();
 
-        // least to most recent ID
-        paths.sort(); // read_dir does not guarantee sorting
+        // least to most recent ID; read_dir does not guarantee sorting.
+        // NOTE: Sort by ID, not by path: "10.segment" < "2.segment".
+        // Invalid names sort first and fail in `Segment::new`.
+        paths.sort_by_cached_key(|p| SegmentID::try_from(p.as_path()).map(|id| id.0).ok());
 
-        let segments = paths
+        let lens: Vec<u64> = paths
             .iter()
-            .map(|p| Segment::new(p))
-            .collect::<Result<Vec<_>, _>>()?;
+            .map(|p| p.metadata().map_or(0, |m| m.len()))
+            .collect();
+        let mut recovery = Recovery::new(
+            &options.recovery_listeners,
+            &options.clock,
+            lens.iter().sum(),
+        );
+
+        let mut segments = Vec::with_capacity(paths.len());
+        for (i, p) in paths.iter().enumerate() {
+            // Only the newest segment was being written to when a crash
+            // could have torn its last record.
+            let newest = i + 1 == paths.len();
+            let verify = match options.open_mode {
+                OpenMode::Fast => false,
+                OpenMode::VerifyActive => newest,
+                OpenMode::Verified => true,
+            };
+            let writable = newest && !options.read_only;
+            let segment = Segment::open(p, writable, newest, verify, Some(&mut recovery))
+                .and_then(|s| {
+                    recovery.next_segment(lens[i])?;
+                    Ok(s)
+                })
+                .and_then(|mut s| {
+                    if writable {
+                        let bytes = s.discard_torn_tail()?;
+                        if bytes > 0 {
+                            log_warn!(
+                                segment_id = s.id.0,
+                                bytes,
+                                replayed_len = s.len,
+                                "discarded the incomplete record ending the newest segment"
+                            );
+                            options.listeners.emit(Event::TornTailDiscarded {
+                                segment_id: s.id.0,
+                                bytes,
+                            });
+                        }
+                    }
+                    Ok(s)
+                })
+                .map_err(|e| {
+                    let e = Error::from(e).with_path(p);
+                    log_warn!(path = %p.display(), verify, error = %e, "failed to open segment");
+                    if e.kind() == ErrorKind::Corruption {
+                        options.listeners.emit(Event::corruption(&e));
+                    }
+                    e
+                })?;
+            log_debug!(
+                segment_id = segment.id.0,
+                len = segment.len,
+                keys = segment.index.len(),
+                checkpointed = segment.checkpointed,
+                replayed = segment.len - segment.checkpointed,
+                verify,
+                "recovered segment"
+            );
+            options.listeners.emit(Event::SegmentRecovered {
+                id: segment.id.0,
+                len: segment.len,
+                keys: segment.index.len() as u64,
+            });
+            segments.push(segment);
+        }
+        // A read-only handle leaves an interrupted compaction to the writer.
+        if !options.read_only {
+            compact::recover(base_path)?;
+        }
 
         let next_index: u64;
         if let Some(s) = segments.last() {
@@ -185,14 +616,56 @@ impl SunsetDB {
             next_index = 0;
         }
 
+        shadow(&mut segments);
+        let live_keys = count_live_keys(&segments);
+        log_info!(
+            path = %base_path.display(),
+            segments = segments.len(),
+            bytes = lens.iter().sum::<u64>(),
+            live_keys,
+            open_mode = ?options.open_mode,
+            "opened database"
+        );
+        let heat = options.key_heat.map(HeatMap::new);
+        let misses = options.negative_cache_keys.map(MissCache::new);
+        let mut deleted_at = HashMap::new();
+        if options.tombstone_grace_period.is_some() {
+            let now = options.clock.now();
+            for key in segments.iter().flat_map(|s| &s.deleted) {
+                deleted_at.insert(key.clone(), now);
+            }
+        }
+
         let mut sunset = SunsetDB {
             base_path: base_path.to_path_buf(),
             segments,
             next_index,
+            trace: None,
+            rate_limiter: RateLimiter::new(options.rate_limits, options.clock.now()),
+            disk_watchdog: DiskWatchdog::new(options.disk_space),
+            options,
+            live_keys,
+            reads: 0,
+            verify_all_reads: false,
+            scrubber: None,
+            compactor: None,
+            maintenance_paused: false,
+            heat,
+            misses,
+            deleted_at,
+            pid: std::process::id(),
+            #[cfg(feature = "profiling")]
+            profile: Default::default(),
         };
 
-        if sunset.segments.is_empty() {
-            sunset.add_new_segment()?;
+        if sunset.segments.is_empty() && !sunset.options.read_only {
+            sunset
+                .add_new_segment()
+                .map_err(|e| Error::from(e).with_path(base_path))?;
+        }
+
+        if !sunset.options.defer_background_workers {
+            sunset.start_background_workers()?;
         }
 
         Ok(sunset)
@@ -206,116 +679,729 @@ impl SunsetDB {
         // TODO: We take the index, make it a path, then the segment needs to
         // re-parse it to know its own index. Strange.
         let path = self.path_from_id(self.next_index);
+        failpoints::fail_point!(failpoints::NEW_SEGMENT);
         self.segments.push(Segment::new(path.as_path())?);
+        self.options.listeners.emit(Event::SegmentCreated {
+            id: self.next_index,
+        });
         self.next_index += 1;
         Ok(())
     }
 
-    pub fn insert(&mut self, key: &str, value: &str) -> Result<(), InsertError> {
-        let segment = self.segments.get_mut(0).ok_or(InsertError::NoSegments)?; // Created in `::new`
-        segment.insert(key, value)?;
+    /// Picks up the records and segments another process appended since the
+    /// database was opened or last refreshed, replaying only what is new.
+    /// Returns the number of keys that changed.
+    ///
+    /// The database must be opened [`read_only`](Options::read_only), so
+    /// that it changes nothing under the writer. A record still being
+    /// written is picked up by the next refresh.
+    ///
+    /// Once a compaction replaced or removed segments, they are all opened
+    /// again, and every live key counts as changed.
+    pub fn refresh(&mut self) -> Result<usize, Error> {
+        self.check_fork()?;
+        if !self.options.read_only {
+            return Err(SunsetDBError::NotReadOnly.into());
+        }
+        if self.segments.iter().any(Segment::replaced) {
+            let mut reopened = SunsetDB::with_options(&self.base_path, self.options.clone())?;
+            reopened.trace = self.trace.take();
+            *self = reopened;
+            return Ok(self.live_keys as usize);
+        }
+        let mut changes = Vec::new();
+        for (i, s) in self.segments.iter_mut().enumerate() {
+            let (new_keys, deleted_keys) = s.tail().map_err(|e| s.error(e))?;
+            changes.push(Changes {
+                segment: i,
+                new_keys,
+                deleted_keys,
+            });
+        }
+
+        let mut paths: Vec<_> = read_dir(&self.base_path)
+            .map_err(|e| Error::from(e).with_path(&self.base_path))?
+            .filter_map(std::io::Result::ok)
+            .map(|e| e.path())
+            .filter(|p| p.extension() == Some(OsStr::from_bytes(SEGMENT_EXT.as_bytes())))
+            .filter_map(|p| Some((SegmentID::try_from(p.as_path()).ok()?.0, p)))
+            .filter(|(id, _)| *id >= self.next_index)
+            .collect();
+        paths.sort();
+        for (_, p) in paths {
+            let segment = Segment::open(&p, false, true, false, None)
+                .map_err(|e| Error::from(e).with_path(&p))?;
+            changes.push(self.attach(segment));
+        }
+
+        self.apply_changes(changes)
+    }
+
+    // Adds a segment written behind the write path as the newest one.
+    fn attach(&mut self, segment: Segment) -> Changes {
+        self.options.listeners.emit(Event::SegmentRecovered {
+            id: segment.id.0,
+            len: segment.len,
+            keys: segment.index.len() as u64,
+        });
+        let changes = Changes {
+            segment: self.segments.len(),
+            new_keys: segment
+                .index
+                .keys()
+                .chain(segment.deleted.iter())
+                .cloned()
+                .collect(),
+            deleted_keys: segment.deleted.iter().cloned().collect(),
+        };
+        self.next_index = segment.id.0 + 1;
+        self.segments.push(segment);
+        changes
+    }
+
+    // Updates older segments for keys that changed behind the write path,
+    // returning the number of such keys.
+    fn apply_changes(&mut self, changes: Vec<Changes>) -> Result<usize, Error> {
+        let mut changed = HashSet::new();
+        for Changes {
+            segment: i,
+            new_keys,
+            deleted_keys,
+        } in changes
+        {
+            let (older, newer) = self.segments.split_at_mut(i);
+            for key in &new_keys {
+                // Unless a newer segment already shadowed it, the newest
+                // record of `key` in an older segment is now dead.
+                if newer[1..]
+                    .iter()
+                    .any(|s| s.index.contains_key(key) || s.deleted.contains(key))
+                {
+                    continue;
+                }
+                if let Some(s) = older.iter_mut().rev().find(|s| s.index.contains_key(key)) {
+                    s.dead_bytes += s.index[key].record_len(key);
+                }
+            }
+            for key in &deleted_keys {
+                for s in older.iter_mut() {
+                    s.hide(key);
+                }
+                if self.options.tombstone_grace_period.is_some() {
+                    self.deleted_at
+                        .insert(key.clone(), self.options.clock.now());
+                }
+            }
+            for key in &new_keys {
+                self.forget_miss(key);
+            }
+            changed.extend(new_keys.into_iter().chain(deleted_keys));
+        }
+        self.live_keys = count_live_keys(&self.segments);
+
+        Ok(changed.len())
+    }
+
+    /// The effective configuration, including changes from [`SunsetDB::set_option`].
+    pub fn options(&self) -> &Options {
+        &self.options
+    }
+
+    /// Changes a setting, taking effect on the next operation.
+    pub fn set_option(&mut self, option: Tunable) {
+        match option {
+            Tunable::Quotas(quotas) => self.options.quotas = quotas,
+            Tunable::RateLimits(rate_limits) => {
+                self.options.rate_limits = rate_limits;
+                self.rate_limiter = RateLimiter::new(rate_limits, self.options.clock.now());
+            }
+            Tunable::DiskSpace(limits) => {
+                self.options.disk_space = limits;
+                self.disk_watchdog = DiskWatchdog::new(limits);
+            }
+            Tunable::ChecksumSampling(sampling) => self.options.checksum_sampling = sampling,
+            Tunable::OpTimeout(timeout) => self.options.op_timeout = timeout,
+        }
+    }
+
+    /// Records every subsequent single-key operation to the trace at `path`,
+    /// which can later be re-executed with [`replay`]: inserts (batched or
+    /// not), gets, deletes and removes.
+    ///
+    /// Scans, cursors, folds and counts, syncs, compactions, imports and
+    /// ingested segments are not traced, so a replay reproduces the key
+    /// workload only.
+    pub fn start_trace(&mut self, path: &Path) -> Result<(), Error> {
+        self.check_fork()?;
+        self.stop_trace()?;
+        self.trace = Some(TraceWriter::new(path).map_err(|e| Error::from(e).with_path(path))?);
+        Ok(())
+    }
+
+    pub fn stop_trace(&mut self) -> Result<(), Error> {
+        self.check_fork()?;
+        if let Some(mut trace) = self.trace.take() {
+            trace.flush()?;
+        }
+        Ok(())
+    }
+
+    // Every write goes through here first.
+    pub(crate) fn check_writable(&self) -> Result<(), Error> {
+        self.check_fork()?;
+        if self.options.read_only {
+            return Err(SunsetDBError::ReadOnly.into());
+        }
+        Ok(())
+    }
 
-        // TODO: Close segment if it grows too large.
-        // TODO: Merge segments and claim space.
+    fn record(&mut self, op: TraceOp, key: &str, value: Option<&str>) -> Result<(), Error> {
+        self.check_fork()?;
+        if let Some(heat) = self.heat.as_mut() {
+            heat.touch(op, key);
+        }
+        match self.trace.as_mut() {
+            Some(trace) => trace
+                .record(self.options.clock.now(), op, key, value)
+                .map_err(|e| Error::from(TraceError::from(e)).with_key(key)),
+            None => Ok(()),
+        }
+    }
 
+    pub fn insert<K: Key + ?Sized>(&mut self, key: &K, value: &str) -> Result<(), Error> {
+        let key = key.encode();
+        let key = key.as_ref();
+        #[cfg(feature = "profiling")]
+        let _profiled = self.profile.start(TraceOp::Insert);
+        let deadline = self.deadline();
+        self.check_writable().map_err(|e| e.with_key(key))?;
+        self.record(TraceOp::Insert, key, Some(value))?;
+        self.maybe_compact();
+        let is_new_key = !self.contains(key);
+        self.check_quotas(key, value, is_new_key, Pending::default())
+            .map_err(|e| Error::from(e).with_key(key))?;
+        self.check_disk_space(encoded_record_len(key, Some(value)))
+            .map_err(|e| e.with_key(key))?;
+        self.throttle(encoded_record_len(key, Some(value)), deadline)
+            .map_err(|e| Error::from(e).with_key(key))?;
+        let previous = self.current_record(key);
+        self.forget_miss(key);
+        self.maybe_rotate().map_err(|e| e.with_key(key))?;
+        deadline
+            .check(&self.options.clock)
+            .map_err(|e| Error::from(e).with_key(key))?;
+
+        let segment = self.segments.last_mut().ok_or(InsertError::NoSegments)?; // Created in `::new`
+        segment
+            .insert(key, value)
+            .map_err(|e| segment.error(e).with_key(key))?;
+        if is_new_key {
+            self.live_keys += 1;
+        }
+        self.kill(previous);
+        self.maybe_checkpoint();
         Ok(())
     }
 
-    pub fn get(&mut self, key: &str) -> Result<String, GetError> {
-        for s in self.segments.iter_mut().rev() {
-            if let Ok(value) = s.get(key) {
-                return Ok(value);
+    // `pending` are writes accepted but not applied yet, see `insert_sorted_batch`.
+    fn check_quotas(
+        &self,
+        key: &str,
+        value: &str,
+        is_new_key: bool,
+        pending: Pending,
+    ) -> Result<(), InsertError> {
+        let quotas = &self.options.quotas;
+        let exceeded = |quota, limit| Err(InsertError::QuotaExceeded { quota, limit });
+
+        if let Some(limit) = quotas.max_value_size {
+            if value.len() as u64 > limit {
+                return exceeded(Quota::MaxValueSize, limit);
+            }
+        }
+
+        if let Some(limit) = quotas.max_keys {
+            if is_new_key && self.live_keys + pending.keys >= limit {
+                return exceeded(Quota::MaxKeys, limit);
+            }
+        }
+
+        if let Some(limit) = quotas.max_bytes {
+            let total_bytes: u64 = self.segments.iter().map(|s| s.len).sum();
+            if total_bytes + pending.bytes + encoded_record_len(key, Some(value)) > limit {
+                return exceeded(Quota::MaxBytes, limit);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn check_disk_space(&mut self, bytes: u64) -> Result<(), Error> {
+        let written = self.segments.iter().map(|s| s.len).sum();
+        let space = self
+            .disk_watchdog
+            .check(&self.base_path, bytes, written, self.options.clock.now())
+            .map_err(|e| Error::from(e).with_path(&self.base_path))?;
+
+        match space {
+            DiskSpace::Ok => Ok(()),
+            DiskSpace::Low { available } => {
+                log_warn!(
+                    available,
+                    warn_below = self.options.disk_space.warn_below,
+                    "disk space is low"
+                );
+                self.options
+                    .listeners
+                    .emit(Event::LowDiskSpace { available });
+                Ok(())
+            }
+            DiskSpace::Exhausted { available } => {
+                log_warn!(
+                    available,
+                    bytes,
+                    reject_below = self.options.disk_space.reject_below,
+                    "rejecting a write for lack of disk space"
+                );
+                Err(Error::from(InsertError::OutOfSpace { available }).with_path(&self.base_path))
+            }
+        }
+    }
+
+    /// Returns `Ok(None)` if no segment holds `key`. Errors reading a segment
+    /// that does hold it are returned rather than falling back to older values.
+    pub fn get<K: Key + ?Sized>(&mut self, key: &K) -> Result<Option<String>, Error> {
+        let key = key.encode();
+        let key = key.as_ref();
+        #[cfg(feature = "profiling")]
+        let _profiled = self.profile.start(TraceOp::Get);
+        let deadline = self.deadline();
+        self.record(TraceOp::Get, key, None)?;
+        let value = self.lookup(key)?;
+        deadline
+            .check(&self.options.clock)
+            .map_err(|e| Error::from(e).with_key(key))?;
+        Ok(value)
+    }
+
+    // Starts the deadline of an operation, see `Options::op_timeout`.
+    fn deadline(&self) -> Deadline {
+        Deadline::start(self.options.op_timeout, &self.options.clock)
+    }
+
+    fn lookup(&mut self, key: &str) -> Result<Option<String>, Error> {
+        if self.misses.as_ref().is_some_and(|m| m.contains(key)) {
+            return Ok(None);
+        }
+        match self
+            .segments
+            .iter()
+            .rposition(|s| s.index.contains_key(key))
+        {
+            Some(i) => self.read_value(i, key).map(Some),
+            None => {
+                if let Some(misses) = self.misses.as_mut() {
+                    misses.insert(key);
+                }
+                Ok(None)
+            }
+        }
+    }
+
+    // To be called before any write that may add `key`.
+    fn forget_miss(&mut self, key: &str) {
+        if let Some(misses) = self.misses.as_mut() {
+            misses.forget(key);
+        }
+    }
+
+    // Reads the value of `key` from the `i`-th segment, which must hold it.
+    fn read_value(&mut self, i: usize, key: &str) -> Result<String, Error> {
+        failpoints::fail_point!(failpoints::READ);
+        let verify = self.sample_read();
+        let value = self.segments[i].get(key, verify);
+        if let Err(e) = &value {
+            self.note_read_error(e);
+        }
+        value
+    }
+
+    // Verifies every later read once one found corruption.
+    fn note_read_error(&mut self, e: &Error) {
+        if e.kind() == ErrorKind::Corruption {
+            if !self.verify_all_reads {
+                log_warn!(error = %e, "read found corruption, verifying every read from now on");
             }
+            self.verify_all_reads = true;
+            self.options.listeners.emit(Event::corruption(e));
         }
+    }
 
-        Err(GetError::KeyNotFound)
+    // Whether the next read should verify the value checksum.
+    fn sample_read(&mut self) -> bool {
+        self.reads += 1;
+        match self.options.checksum_sampling {
+            _ if self.verify_all_reads => true,
+            ChecksumSampling::Always => true,
+            ChecksumSampling::OneIn(n) => self.reads % n.get() == 0,
+        }
     }
 
-    pub fn delete(&mut self, key: &str) -> Result<(), DeleteError> {
-        let segment = self.segments.get_mut(0).ok_or(DeleteError::NoSegments)?; // Created in `::new`
-        segment.delete(key)?;
+    // Fails rather than waiting past `deadline`.
+    fn throttle(&mut self, bytes: u64, deadline: Deadline) -> Result<(), SunsetDBError> {
+        let remaining = deadline.remaining(&self.options.clock);
+        let duration = self
+            .rate_limiter
+            .throttle(bytes, &self.options.clock, remaining)
+            .map_err(|wait| {
+                log_warn!(
+                    bytes,
+                    ?wait,
+                    max_stall = ?self.options.rate_limits.max_stall,
+                    "rejecting a write that would stall too long"
+                );
+                SunsetDBError::StallTimedOut { wait }
+            })?;
+        if !duration.is_zero() {
+            log_debug!(
+                bytes,
+                ?duration,
+                ops_per_sec = self.options.rate_limits.ops_per_sec.map(|r| r.get()),
+                bytes_per_sec = self.options.rate_limits.bytes_per_sec.map(|r| r.get()),
+                "write stalled by the rate limits"
+            );
+            self.options
+                .listeners
+                .emit(Event::WriteStalled { duration });
+        }
         Ok(())
     }
-}
 
-const ENCODED_LEN_SIZE: usize = size_of::<u64>();
-const CRC32_SIZE: usize = size_of::<u32>();
+    /// Flushes every segment to disk, so that all writes acknowledged so far
+    /// survive a crash.
+    pub fn sync(&mut self) -> Result<(), Error> {
+        self.check_fork()?;
+        for s in self.segments.iter() {
+            failpoints::fail_point!(failpoints::FSYNC);
+            profiling::io(1, 0, 0);
+            s.file
+                .sync_all()
+                .map_err(|e| Error::from(e).with_segment(s.id.0, &s.path))?;
+        }
+        Ok(())
+    }
 
-// -- <TOMBSTONE> --
-fn append_deletion(file: &mut File) -> Result<(), io::Error> {
-    file.seek(io::SeekFrom::End(0))?;
-    file.write_all(&ENCODED_TOMBSTONE)?;
+    // Every live key, along with the index of the newest segment holding it.
+    fn live_keys(&self) -> Vec<(usize, String)> {
+        let mut seen = HashSet::new();
+        let mut keys = Vec::new();
+        for (i, s) in self.segments.iter().enumerate().rev() {
+            for key in s.index.keys() {
+                if seen.insert(key) {
+                    keys.push((i, key.clone()));
+                }
+            }
+        }
+        keys
+    }
+
+    // The segment holding the current value of `key` and the size of its
+    // record, which becomes dead once `key` is overwritten or deleted.
+    fn current_record(&self, key: &str) -> Option<(usize, u64)> {
+        self.segments
+            .iter()
+            .enumerate()
+            .rev()
+            .find_map(|(i, s)| Some((i, s.index.get(key)?.record_len(key))))
+    }
+
+    /// Length of the value of `key`, answered from memory without reading
+    /// it, or `None` if `key` is absent.
+    pub fn value_len<K: Key + ?Sized>(&self, key: &K) -> Option<u64> {
+        let key = key.encode();
+        let key = key.as_ref();
+        self.segments
+            .iter()
+            .rev()
+            .find_map(|s| s.index.get(key))
+            .map(|entry| entry.value_len)
+    }
+
+    fn kill(&mut self, record: Option<(usize, u64)>) {
+        if let Some((i, len)) = record {
+            self.segments[i].dead_bytes += len;
+        }
+    }
 
-    // XXX: Write checkum for TOMBSTONE too?
-    // let checksum = crc32fast::hash(&ENCODED_TOMBSTONE);
-    // write_whole(file, &checksum.to_be_bytes())?;
+    /// Size and garbage of every segment, from oldest to newest.
+    pub fn segment_stats(&self) -> Vec<SegmentStats> {
+        self.segments
+            .iter()
+            .map(|s| SegmentStats {
+                id: s.id.0,
+                len: s.len,
+                dead_bytes: s.dead_bytes,
+            })
+            .collect()
+    }
 
-    Ok(())
+    fn contains(&self, key: &str) -> bool {
+        self.segments.iter().any(|s| s.index.contains_key(key))
+    }
+
+    /// Deletes `key`, failing with [`ErrorKind::NotFound`] if it is absent.
+    pub fn delete<K: Key + ?Sized>(&mut self, key: &K) -> Result<(), Error> {
+        let key = key.encode();
+        let key = key.as_ref();
+        #[cfg(feature = "profiling")]
+        let _profiled = self.profile.start(TraceOp::Delete);
+        let deadline = self.deadline();
+        self.check_writable().map_err(|e| e.with_key(key))?;
+        self.record(TraceOp::Delete, key, None)?;
+        if !self.contains(key) {
+            return Err(Error::from(DeleteError::KeyNotFound).with_key(key));
+        }
+        self.append_tombstone(key, deadline)
+    }
+
+    /// Deletes `key` and returns its previous value, or `None` if it was
+    /// absent. Nothing is written for absent keys.
+    pub fn remove<K: Key + ?Sized>(&mut self, key: &K) -> Result<Option<String>, Error> {
+        let key = key.encode();
+        let key = key.as_ref();
+        #[cfg(feature = "profiling")]
+        let _profiled = self.profile.start(TraceOp::Remove);
+        let deadline = self.deadline();
+        self.check_writable().map_err(|e| e.with_key(key))?;
+        self.record(TraceOp::Remove, key, None)?;
+        let previous = self.lookup(key)?;
+        if previous.is_some() {
+            self.append_tombstone(key, deadline)?;
+        }
+        Ok(previous)
+    }
+
+    /// Writes a tombstone for `key` even if no segment holds it.
+    pub fn force_delete<K: Key + ?Sized>(&mut self, key: &K) -> Result<(), Error> {
+        let key = key.encode();
+        let key = key.as_ref();
+        #[cfg(feature = "profiling")]
+        let _profiled = self.profile.start(TraceOp::ForceDelete);
+        let deadline = self.deadline();
+        self.check_writable().map_err(|e| e.with_key(key))?;
+        self.record(TraceOp::ForceDelete, key, None)?;
+        self.append_tombstone(key, deadline)
+    }
+
+    fn append_tombstone(&mut self, key: &str, deadline: Deadline) -> Result<(), Error> {
+        self.maybe_compact();
+        let was_live = self.contains(key);
+        self.throttle(encoded_record_len(key, None), deadline)
+            .map_err(|e| Error::from(e).with_key(key))?;
+        let previous = self.current_record(key);
+        self.maybe_rotate().map_err(|e| e.with_key(key))?;
+        deadline
+            .check(&self.options.clock)
+            .map_err(|e| Error::from(e).with_key(key))?;
+        let segment = self.segments.last_mut().ok_or(DeleteError::NoSegments)?; // Created in `::new`
+        segment
+            .delete(key)
+            .map_err(|e| segment.error(e).with_key(key))?;
+
+        // Older segments must not serve the deleted key either.
+        for s in self.segments.iter_mut() {
+            s.hide(key);
+        }
+        if self.options.tombstone_grace_period.is_some() {
+            self.deleted_at
+                .insert(key.to_owned(), self.options.clock.now());
+        }
+        if was_live {
+            self.live_keys -= 1;
+        }
+        self.kill(previous);
+        self.maybe_checkpoint();
+        Ok(())
+    }
+
+    // Checkpoints the index of the active segment every
+    // `index_checkpoint_bytes`. Failures are ignored: the segment remains
+    // the source of truth and is replayed from the previous checkpoint.
+    #[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+    fn maybe_checkpoint(&mut self) {
+        let (Some(every), Some(segment)) = (
+            self.options.index_checkpoint_bytes,
+            self.segments.last_mut(),
+        ) else {
+            return;
+        };
+        if segment.len - segment.checkpointed >= every.get() {
+            log_debug!(
+                segment_id = segment.id.0,
+                bytes_since = segment.len - segment.checkpointed,
+                every = every.get(),
+                "checkpointing the index"
+            );
+            if let Err(e) = segment.checkpoint() {
+                log_warn!(segment_id = segment.id.0, error = %e, "index checkpoint failed");
+            }
+        }
+    }
 }
 
-// -- <len> || <string> || <checksum> --
-fn append_string(file: &mut File, b: &str) -> Result<(), io::Error> {
-    file.seek(io::SeekFrom::End(0))?;
+// Tombstones hide the key from every older segment, whose records are
+// dead once a newer segment deletes or overwrites the key.
+fn shadow(segments: &mut [Segment]) {
+    let mut deleted = HashSet::new();
+    let mut shadowed = HashSet::new();
+    for s in segments.iter_mut().rev() {
+        s.dead_bytes += s
+            .index
+            .iter()
+            .filter(|(k, _)| deleted.contains(*k) || shadowed.contains(*k))
+            .map(|(k, entry)| entry.record_len(k))
+            .sum::<u64>();
+
+        s.index.retain(|k, _| {
+            let hidden = deleted.contains(k);
+            if hidden {
+                s.hidden.insert(k.clone());
+            }
+            !hidden
+        });
+        shadowed.extend(s.index.keys().cloned());
+        deleted.extend(s.deleted.iter().cloned());
+    }
+}
 
-    // Cast all to u64 and use big endian to make this portable across machines.
-    let encoded_len = b.len().to_be_bytes();
-    file.write_all(&encoded_len)?;
+fn count_live_keys(segments: &[Segment]) -> u64 {
+    segments
+        .iter()
+        .flat_map(|s| s.index.keys())
+        .collect::<HashSet<_>>()
+        .len() as u64
+}
+
+// Size on disk of a key followed by `value`, or by a tombstone if `None`.
+fn encoded_record_len(key: &str, value: Option<&str>) -> u64 {
+    let value_len = value.map_or(0, |v| v.len() + CRC32_SIZE);
+    (ENCODED_LEN_SIZE + key.len() + CRC32_SIZE + ENCODED_LEN_SIZE + value_len) as u64
+}
 
-    let encoded_b = b.as_bytes();
-    file.write_all(encoded_b)?;
+// The record of `key` and `value`, or of a tombstone if `None`.
+fn encode_record(key: &str, value: Option<&str>) -> Vec<u8> {
+    let mut buffer = Vec::with_capacity(encoded_record_len(key, value) as usize);
+    encode_string(&mut buffer, key);
+    match value {
+        Some(value) => encode_string(&mut buffer, value),
+        None => encode_deletion(&mut buffer),
+    }
+    buffer
+}
 
-    let checksum = crc32fast::hash(encoded_b);
-    file.write_all(&checksum.to_be_bytes())?;
+fn append_at_end(file: &mut File, buffer: &[u8]) -> io::Result<()> {
+    profiling::io(2, 0, buffer.len() as u64);
+    file.seek(io::SeekFrom::End(0))?;
+    // Fails halfway through, as a full disk may.
+    #[cfg(feature = "failpoints")]
+    if let Some(message) = fail::eval(failpoints::APPEND, |message| message) {
+        file.write_all(&buffer[..buffer.len() / 2])?;
+        return Err(failpoints::error(failpoints::APPEND, message));
+    }
+    file.write_all(buffer)
+}
 
-    Ok(())
+// Size on disk of the record at `offset`, which must start with a key.
+fn record_len_at(file: &mut File, offset: u64) -> Result<u64, ReadError> {
+    file.seek(SeekFrom::Start(offset))?;
+    let key_len = match decode_len(read_u64_bytes(file)?) {
+        EncodedLen::Len(len) => len,
+        EncodedLen::Tombstone => return Err(ReadError::UnexpectedTombstone),
+    };
+
+    file.seek(SeekFrom::Current(
+        i64::try_from(key_len)? + CRC32_SIZE as i64,
+    ))?;
+    let value_len = match decode_len(read_u64_bytes(file)?) {
+        EncodedLen::Len(len) => len + CRC32_SIZE as u64,
+        EncodedLen::Tombstone => 0,
+    };
+    Ok((ENCODED_LEN_SIZE + CRC32_SIZE + ENCODED_LEN_SIZE) as u64 + key_len + value_len)
 }
 
-fn read_u64_bytes(file: &mut File) -> Result<[u8; ENCODED_LEN_SIZE], ReadError> {
+fn read_u64_bytes(file: &mut impl Read) -> Result<[u8; ENCODED_LEN_SIZE], ReadError> {
     let mut read_buffer = [0; ENCODED_LEN_SIZE];
     file.read_exact(&mut read_buffer)?;
     Ok(read_buffer)
 }
 
-fn parse_u64_bytes(bytes: [u8; ENCODED_LEN_SIZE]) -> Result<u64, ReadError> {
-    Ok(u64::from_be_bytes(bytes))
+fn read_check_string(file: &mut impl Read) -> Result<Option<String>, ReadError> {
+    read_string(file, true)
 }
 
-fn read_check_string(file: &mut File) -> Result<Option<String>, ReadError> {
+// Like `read_check_string`, but only validates the checksum if `verify`.
+fn read_string(file: &mut impl Read, verify: bool) -> Result<Option<String>, ReadError> {
     // TODO: Would it be faster to read a bigger chunk into a static array?
-    let encoded_string_len = read_u64_bytes(file)?;
-    if encoded_string_len == ENCODED_TOMBSTONE {
-        return Ok(None); // Deleted
+    let string_len = match decode_len(read_u64_bytes(file)?) {
+        EncodedLen::Tombstone => return Ok(None), // Deleted
+        EncodedLen::Len(len) => len,
+    };
+
+    // Grow the buffer as bytes arrive rather than trusting a length read
+    // from disk: a corrupted one could ask for any size.
+    let mut encoded_string = Vec::new();
+    file.take(string_len).read_to_end(&mut encoded_string)?;
+    if encoded_string.len() as u64 != string_len {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
     }
 
-    let string_len = parse_u64_bytes(encoded_string_len)?;
-    let mut encoded_string = vec![0; usize::try_from(string_len)?];
-    file.read_exact(&mut encoded_string)?;
-
     let mut encoded_checksum = [0; CRC32_SIZE];
     file.read_exact(&mut encoded_checksum)?;
-    let checksum = u32::from_be_bytes(encoded_checksum);
-    let expected = crc32fast::hash(&encoded_string);
 
-    if checksum != expected {
-        return Err(ReadError::InvalidChecksum {
-            expected,
-            found: checksum,
-        });
+    if verify {
+        Ok(Some(decode_string(encoded_string, encoded_checksum)?))
+    } else {
+        Ok(Some(decode_unchecked_string(encoded_string)?))
+    }
+}
+
+// Decodes the encoded value of `entry`, which `buffer` holds exactly. The
+// length on disk is checked against the index first.
+fn decode_value(buffer: &[u8], entry: IndexEntry, verify: bool) -> Result<&str, GetError> {
+    if buffer.len() < ENCODED_LEN_SIZE {
+        return Err(GetError::LengthMismatch);
+    }
+    let (encoded_len, rest) = buffer.split_at(ENCODED_LEN_SIZE);
+    let mut len_bytes = [0; ENCODED_LEN_SIZE];
+    len_bytes.copy_from_slice(encoded_len);
+    match decode_len(len_bytes) {
+        EncodedLen::Tombstone => return Err(GetError::KeyNotFound),
+        EncodedLen::Len(len) if len != entry.value_len => return Err(GetError::LengthMismatch),
+        EncodedLen::Len(_) => {}
+    }
+    if rest.len() as u64 != entry.value_len + CRC32_SIZE as u64 {
+        return Err(GetError::LengthMismatch);
     }
 
-    Ok(Some(String::from_utf8(encoded_string)?))
+    let (encoded_value, encoded_checksum) = rest.split_at(rest.len() - CRC32_SIZE);
+    let mut checksum = [0; CRC32_SIZE];
+    checksum.copy_from_slice(encoded_checksum);
+    Ok(decode_str(encoded_value, checksum, verify).map_err(ReadError::from)?)
 }
 
 fn read_string_at_offset(file: &mut File, offset: u64) -> Result<Option<String>, ReadError> {
     // TODO: Maybe use `seek_read`?
+    profiling::io(1, 0, 0);
     file.seek(io::SeekFrom::Start(offset))?;
-    read_check_string(file)
+    read_check_string(&mut profiling::Profiled(file))
 }
 
 #[cfg(test)]
 mod tests {
     use std::error::Error;
 
+    use std::sync::{Arc, Mutex};
+
     use super::*;
     use tempfile::{tempdir, TempDir};
 
@@ -360,15 +1446,512 @@ mod tests {
         let mut s = SunsetDB::new(base_dir.path())?;
 
         s.insert("k", "v")?;
-        assert_eq!(s.get("k")?, "v");
+        assert_eq!(s.get("k")?.as_deref(), Some("v"));
         s.insert("k", "vv")?;
-        assert_eq!(s.get("k")?, "vv");
+        assert_eq!(s.get("k")?.as_deref(), Some("vv"));
         s.delete("k")?;
+        assert_eq!(s.get("k")?, None);
         assert!(s.delete("k").is_err());
 
         Ok(())
     }
 
+    #[test]
+    fn sunsetdb_read_your_writes_test() -> TestResult {
+        let base_dir = new_base()?;
+        let mut s = SunsetDB::new(base_dir.path())?;
+
+        s.insert("k", "v0")?;
+        s.insert("j", "w")?;
+        s.add_new_segment()?;
+        s.insert("k", "v1")?;
+        assert_eq!(s.get("k")?.as_deref(), Some("v1"));
+
+        s.add_new_segment()?;
+        s.delete("j")?;
+        assert_eq!(s.get("j")?, None);
+        assert_eq!(s.get("k")?.as_deref(), Some("v1"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn sunsetdb_deletes_survive_reopen_test() -> TestResult {
+        let base_dir = new_base()?;
+        let mut s = SunsetDB::new(base_dir.path())?;
+
+        s.insert("k", "v")?;
+        s.insert("j", "w")?;
+        s.add_new_segment()?;
+        s.delete("k")?;
+        s.force_delete("j")?;
+        s.insert("j", "ww")?;
+        s.sync()?;
+        drop(s);
+
+        let mut s = SunsetDB::new(base_dir.path())?;
+        assert_eq!(s.get("k")?, None);
+        assert!(s.delete("k").is_err());
+        assert_eq!(s.get("j")?.as_deref(), Some("ww"));
+        assert_eq!(s.live_keys, 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn sunsetdb_segment_order_test() -> TestResult {
+        let base_dir = new_base()?;
+        let mut s = SunsetDB::new(base_dir.path())?;
+
+        // Past 10 segments, lexicographic order of paths breaks down.
+        for i in 0..12 {
+            s.insert("k", &i.to_string())?;
+            s.add_new_segment()?;
+        }
+        s.sync()?;
+        drop(s);
+
+        let mut s = SunsetDB::new(base_dir.path())?;
+        assert_eq!(s.get("k")?.as_deref(), Some("11"));
+        s.insert("k", "12")?;
+        assert_eq!(s.segments.last().map(|s| s.id.0), Some(12));
+        drop(s);
+
+        let mut s = SunsetDB::new(base_dir.path())?;
+        assert_eq!(s.get("k")?.as_deref(), Some("12"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn sunsetdb_remove_test() -> TestResult {
+        let base_dir = new_base()?;
+        let mut s = SunsetDB::new(base_dir.path())?;
+        let segment_path = s.path_from_id(0);
+        let segment_len = || segment_path.metadata().map(|m| m.len());
+
+        s.insert("k", "v")?;
+        let len = segment_len()?;
+        assert_eq!(s.remove("missing")?, None);
+        assert!(s.delete("missing").is_err());
+        assert_eq!(segment_len()?, len, "absent keys should not be written");
+
+        assert_eq!(s.remove("k")?.as_deref(), Some("v"));
+        assert_eq!(s.get("k")?, None);
+        assert_eq!(s.remove("k")?, None);
+
+        let len = segment_len()?;
+        s.force_delete("missing")?;
+        assert_eq!(
+            segment_len()?,
+            len + encoded_len("missing", "") - CRC32_SIZE as u64
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn sunsetdb_quotas_test() -> TestResult {
+        let base_dir = new_base()?;
+        let options = Options {
+            quotas: Quotas {
+                max_keys: Some(2),
+                max_bytes: Some(4 * encoded_len("k", "v")),
+                max_value_size: Some(2),
+            },
+            ..Default::default()
+        };
+        let mut s = SunsetDB::with_options(base_dir.path(), options)?;
+        let quota_exceeded = |r: Result<(), crate::Error>, quota| {
+            r.is_err_and(|e| e.kind() == ErrorKind::QuotaExceeded(quota))
+        };
+
+        assert!(quota_exceeded(s.insert("k", "vvv"), Quota::MaxValueSize));
+        s.insert("k", "v")?;
+        s.insert("j", "v")?;
+        assert!(quota_exceeded(s.insert("l", "v"), Quota::MaxKeys));
+
+        // Overwrites don't add keys, deletes free them up.
+        s.insert("k", "w")?;
+        s.delete("j")?;
+        assert!(quota_exceeded(s.insert("l", "v"), Quota::MaxBytes));
+        assert_eq!(s.get("l")?, None);
+
+        s.set_option(Tunable::Quotas(Quotas::default()));
+        assert_eq!(s.options().quotas, Quotas::default());
+        s.insert("l", "vvv")?;
+
+        drop(s);
+        let s = SunsetDB::new(base_dir.path())?;
+        assert_eq!(s.live_keys, 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn sunsetdb_out_of_space_test() -> TestResult {
+        let base_dir = new_base()?;
+        let mut s = SunsetDB::new(base_dir.path())?;
+        s.insert("k", "v")?;
+
+        s.set_option(Tunable::DiskSpace(DiskSpaceLimits {
+            warn_below: None,
+            reject_below: Some(u64::MAX),
+        }));
+        let e = s.insert("k", "w").unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::OutOfSpace);
+        s.delete("k")?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn sunsetdb_get_corrupted_middle_segment_test() -> TestResult {
+        let base_dir = new_base()?;
+        let segment_path = |id| base_dir.path().join(format!("{}.{}", id, SEGMENT_EXT));
+
+        Segment::new(&segment_path(0))?.insert("k", "old")?;
+        Segment::new(&segment_path(1))?.insert("k", "new")?;
+        Segment::new(&segment_path(2))?.insert("other", "v")?;
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let mut options = Options::default();
+        let listener_events = events.clone();
+        options
+            .listeners
+            .push(move |e: &Event| listener_events.lock().unwrap().push(e.clone()));
+
+        // Corrupt the value of "k" in the middle segment.
+        let mut f = OpenOptions::new().write(true).open(segment_path(1))?;
+        f.seek(SeekFrom::Start(
+            encoded_len("k", "new") - CRC32_SIZE as u64 - 1,
+        ))?;
+        f.write_all(b"X")?;
+
+        let mut s = SunsetDB::with_options(base_dir.path(), options)?;
+        let e = s.get("k").unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::Corruption);
+        assert_eq!(e.segment_id(), Some(1));
+        assert_eq!(e.offset(), Some(0));
+        assert_eq!(e.path(), Some(segment_path(1).as_path()));
+
+        assert_eq!(s.get("other")?.as_deref(), Some("v"));
+        assert_eq!(s.get("missing")?, None);
+
+        let events = events.lock().unwrap();
+        assert_eq!(events.len(), 4);
+        assert!(matches!(
+            events[1],
+            Event::SegmentRecovered { id: 1, keys: 1, .. }
+        ));
+        assert_eq!(
+            events[3],
+            Event::CorruptionDetected {
+                segment_id: Some(1),
+                offset: Some(0),
+                key: Some("k".to_string()),
+            }
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn sunsetdb_checksum_sampling_test() -> TestResult {
+        let base_dir = new_base()?;
+        let mut s = SunsetDB::new(base_dir.path())?;
+        s.insert("k", "vvv")?;
+        s.set_option(Tunable::ChecksumSampling(ChecksumSampling::OneIn(
+            std::num::NonZeroU64::new(2).unwrap(),
+        )));
+
+        // Corrupt the value, keeping it valid UTF-8.
+        let mut f = OpenOptions::new().write(true).open(s.path_from_id(0))?;
+        f.seek(SeekFrom::Start(
+            encoded_len("k", "vvv") - CRC32_SIZE as u64 - 1,
+        ))?;
+        f.write_all(b"X")?;
+
+        assert_eq!(s.get("k")?.as_deref(), Some("vvX"));
+        assert_eq!(s.get("k").unwrap_err().kind(), ErrorKind::Corruption);
+        // Every read is verified after a corruption.
+        assert_eq!(s.get("k").unwrap_err().kind(), ErrorKind::Corruption);
+
+        Ok(())
+    }
+
+    #[test]
+    fn sunsetdb_corrupted_value_len_test() -> TestResult {
+        let base_dir = new_base()?;
+        let mut s = SunsetDB::new(base_dir.path())?;
+        s.insert("k", "v")?;
+
+        // A huge length is reported, not allocated.
+        let f = OpenOptions::new().write(true).open(s.path_from_id(0))?;
+        let at = (ENCODED_LEN_SIZE + "k".len() + CRC32_SIZE) as u64;
+        f.write_all_at(&(1u64 << 60).to_be_bytes(), at)?;
+
+        assert_eq!(s.get("k").unwrap_err().kind(), ErrorKind::Corruption);
+        let e = s.scan_filtered(.., |_, _| true).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::Corruption);
+        Ok(())
+    }
+
+    #[test]
+    fn sunsetdb_index_checkpoint_test() -> TestResult {
+        let base_dir = new_base()?;
+        let options = Options {
+            index_checkpoint_bytes: std::num::NonZeroU64::new(
+                encoded_len("b", "v") + encoded_record_len("a", None),
+            ),
+            ..Default::default()
+        };
+        let mut s = SunsetDB::with_options(base_dir.path(), options.clone())?;
+        s.insert("b", "v")?;
+        s.force_delete("a")?;
+        let watermark = s.segments[0].len;
+        s.insert("c", "v")?; // Only in the replayed tail.
+        drop(s);
+
+        let checkpoint = checkpoint::read(&base_dir.path().join("0.segment")).unwrap();
+        assert_eq!(checkpoint.watermark, watermark);
+        assert!(checkpoint.deleted.contains("a"));
+
+        let mut s = SunsetDB::with_options(base_dir.path(), options)?;
+        assert_eq!(s.segments[0].checkpointed, watermark);
+        assert_eq!(s.get("a")?, None);
+        assert_eq!(s.get("b")?.as_deref(), Some("v"));
+        assert_eq!(s.get("c")?.as_deref(), Some("v"));
+        drop(s);
+
+        // An invalid checkpoint falls back to replaying the whole segment.
+        let checkpoint_path = checkpoint::checkpoint_path(&base_dir.path().join("0.segment"));
+        OpenOptions::new()
+            .write(true)
+            .open(checkpoint_path)?
+            .write_all(b"X")?;
+        let mut s = SunsetDB::new(base_dir.path())?;
+        assert_eq!(s.segments[0].checkpointed, 0);
+        assert_eq!(s.get("c")?.as_deref(), Some("v"));
+        assert_eq!(s.live_keys, 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn sunsetdb_open_mode_test() -> TestResult {
+        let base_dir = new_base()?;
+        let options = |open_mode| Options {
+            index_checkpoint_bytes: std::num::NonZeroU64::new(1),
+            open_mode,
+            ..Default::default()
+        };
+        let mut s = SunsetDB::with_options(base_dir.path(), options(OpenMode::Fast))?;
+        s.insert("k", "vvv")?;
+        s.add_new_segment()?;
+        s.insert("a", "vvv")?;
+        drop(s);
+
+        // Corrupt the values of "k", in a sealed segment, then of "a", in
+        // the active one and covered by its checkpoint.
+        let open = |mode| SunsetDB::with_options(base_dir.path(), options(mode)).err();
+        for (id, key) in [(0, "k"), (1, "a")] {
+            let path = base_dir.path().join(format!("{}.{}", id, SEGMENT_EXT));
+            let mut f = OpenOptions::new().write(true).open(path)?;
+            f.seek(SeekFrom::Start(
+                encoded_len(key, "vvv") - CRC32_SIZE as u64 - 1,
+            ))?;
+            f.write_all(b"X")?;
+
+            assert!(open(OpenMode::Fast).is_none());
+            assert_eq!(
+                open(OpenMode::VerifyActive).map(|e| e.kind()),
+                (id == 1).then_some(ErrorKind::Corruption)
+            );
+            let e = open(OpenMode::Verified).ok_or("opened")?;
+            assert_eq!(e.kind(), ErrorKind::Corruption);
+            assert_eq!(e.path(), Some(base_dir.path().join("0.segment").as_path()));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn sunsetdb_dead_bytes_test() -> TestResult {
+        let base_dir = new_base()?;
+        let mut s = SunsetDB::new(base_dir.path())?;
+        let dead_bytes =
+            |s: &SunsetDB| -> Vec<u64> { s.segment_stats().iter().map(|s| s.dead_bytes).collect() };
+
+        s.insert("k", "v")?;
+        s.insert("k", "vv")?;
+        s.insert("j", "w")?;
+        s.add_new_segment()?;
+        s.insert("j", "ww")?;
+        s.delete("k")?;
+        s.force_delete("missing")?;
+        let expected = vec![
+            encoded_len("k", "v") + encoded_len("k", "vv") + encoded_len("j", "w"),
+            0,
+        ];
+        assert_eq!(dead_bytes(&s), expected);
+        drop(s);
+
+        // Recomputed when replaying the segments.
+        let s = SunsetDB::new(base_dir.path())?;
+        assert_eq!(dead_bytes(&s), expected);
+
+        Ok(())
+    }
+
+    #[test]
+    fn sunsetdb_refresh_test() -> TestResult {
+        let base_dir = new_base()?;
+        let read_only = || Options {
+            read_only: true,
+            ..Default::default()
+        };
+
+        // Opening an empty directory read-only creates nothing.
+        let mut reader = SunsetDB::with_options(base_dir.path(), read_only())?;
+        assert_eq!(read_dir(base_dir.path())?.count(), 0);
+        let e = reader.insert("k", "v").unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidInput);
+
+        let mut writer = SunsetDB::new(base_dir.path())?;
+        writer.insert("k", "v")?;
+        writer.insert("j", "w")?;
+        assert_eq!(reader.get("k")?, None);
+        assert_eq!(reader.refresh()?, 2);
+        assert_eq!(reader.get("k")?.as_deref(), Some("v"));
+        let e = writer.refresh().unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidInput);
+
+        // A record still being written is left to the writer, even by a
+        // reader opened meanwhile, and picked up by the next refresh.
+        let mut f = OpenOptions::new()
+            .append(true)
+            .open(writer.path_from_id(0))?;
+        f.write_all(&[0; 3])?;
+        let mut late_reader = SunsetDB::with_options(base_dir.path(), read_only())?;
+        assert_eq!(f.metadata()?.len(), writer.segments[0].len + 3);
+        assert_eq!(late_reader.get("j")?.as_deref(), Some("w"));
+        f.set_len(writer.segments[0].len)?;
+
+        writer.add_new_segment()?;
+        writer.insert("k", "vv")?;
+        writer.delete("j")?;
+        writer.add_new_segment()?;
+        writer.insert("l", "x")?;
+        let mut f = OpenOptions::new()
+            .append(true)
+            .open(writer.path_from_id(2))?;
+        f.write_all(&[0; 3])?;
+        for reader in [&mut reader, &mut late_reader] {
+            assert_eq!(reader.refresh()?, 3);
+            assert_eq!(reader.get("k")?.as_deref(), Some("vv"));
+            assert_eq!(reader.get("j")?, None);
+            assert_eq!(reader.get("l")?.as_deref(), Some("x"));
+            assert_eq!(reader.refresh()?, 0);
+        }
+        assert_eq!(f.metadata()?.len(), writer.segments[2].len + 3);
+
+        f.set_len(writer.segments[2].len)?;
+        let reopened = SunsetDB::new(base_dir.path())?;
+        assert_eq!(reader.live_keys, reopened.live_keys);
+        assert_eq!(reader.segment_stats(), reopened.segment_stats());
+
+        // Compacted segments are opened again, and the inputs let go.
+        writer.compact()?;
+        assert_eq!(reader.refresh()?, 2);
+        assert_eq!(reader.segment_stats(), writer.segment_stats());
+        assert_eq!(reader.get("k")?.as_deref(), Some("vv"));
+        assert_eq!(reader.get("l")?.as_deref(), Some("x"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn sunsetdb_value_len_test() -> TestResult {
+        let base_dir = new_base()?;
+        let mut s = SunsetDB::new(base_dir.path())?;
+
+        s.insert("k", "vvv")?;
+        assert_eq!(s.value_len("k"), Some(3));
+        s.add_new_segment()?;
+        s.insert("k", "")?;
+        assert_eq!(s.value_len("k"), Some(0));
+        s.delete("k")?;
+        assert_eq!(s.value_len("k"), None);
+        s.insert("j", "vv")?;
+        drop(s);
+
+        let s = SunsetDB::new(base_dir.path())?;
+        assert_eq!(s.value_len("j"), Some(2));
+        assert_eq!(s.value_len("k"), None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn sunsetdb_torn_tail_test() -> TestResult {
+        let base_dir = new_base()?;
+        let mut s = SunsetDB::new(base_dir.path())?;
+        s.insert("k", "v")?;
+        s.add_new_segment()?;
+        s.insert("j", "w")?;
+        drop(s);
+
+        // A crash between writing the key and the value of "l".
+        let path = base_dir.path().join(format!("1.{}", SEGMENT_EXT));
+        let mut f = OpenOptions::new().append(true).open(&path)?;
+        let mut key = Vec::new();
+        encode_string(&mut key, "l");
+        f.write_all(&key)?;
+        let torn = encoded_record_len("l", None) - ENCODED_LEN_SIZE as u64;
+
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let mut options = Options::default();
+        let listener_events = events.clone();
+        options
+            .listeners
+            .push(move |e: &Event| listener_events.lock().unwrap().push(e.clone()));
+        let mut s = SunsetDB::with_options(base_dir.path(), options)?;
+        assert!(events.lock().unwrap().contains(&Event::TornTailDiscarded {
+            segment_id: 1,
+            bytes: torn,
+        }));
+        assert_eq!(s.get("l")?, None);
+        s.insert("l", "x")?;
+        drop(s);
+
+        let mut s = SunsetDB::new(base_dir.path())?;
+        assert_eq!(s.get("j")?.as_deref(), Some("w"));
+        assert_eq!(s.get("l")?.as_deref(), Some("x"));
+
+        // Sealed segments are never torn.
+        let path = base_dir.path().join(format!("0.{}", SEGMENT_EXT));
+        let f = OpenOptions::new().write(true).open(&path)?;
+        f.set_len(f.metadata()?.len() - 1)?;
+        let e = SunsetDB::new(base_dir.path()).err().ok_or("opened")?;
+        assert_eq!(e.kind(), ErrorKind::Corruption);
+
+        Ok(())
+    }
+
+    #[test]
+    fn sunsetdb_error_context_test() -> TestResult {
+        let base_dir = new_base()?;
+        let mut s = SunsetDB::new(base_dir.path())?;
+
+        let e = s.delete("missing").unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::NotFound);
+        assert_eq!(e.key(), Some("missing"));
+        assert_eq!(e.segment_id(), None);
+        assert!(e.source().is_some());
+        assert_eq!(e.to_string(), "key not found (key: \"missing\")");
+
+        Ok(())
+    }
+
     #[test]
     fn segment_e2e_test() -> TestResult {
         let new_base = new_base()?;
@@ -393,11 +1976,11 @@ mod tests {
             let delta = segment_path.metadata()?.len() - f_size;
             assert_eq!(delta, encoded_len(k, v));
 
-            let vv = segment.get(k)?;
+            let vv = segment.get(k, true)?;
             assert_eq!(vv, v);
         }
 
-        let vv = segment.get("biz")?;
+        let vv = segment.get("biz", true)?;
         assert_eq!(vv, "boo2");
 
         let inputs_sum: u64 = inputs.iter().map(|(k, v)| encoded_len(k, v)).sum();
@@ -431,7 +2014,7 @@ mod tests {
         let empty_path = PathBuf::new();
         let maybe_db = SunsetDB::new(empty_path.as_path());
 
-        assert!(matches!(maybe_db, Err(SunsetDBError::IOError(_))));
+        assert!(maybe_db.is_err_and(|e| e.kind() == ErrorKind::Io && e.path() == Some(&empty_path)));
 
         Ok(())
     }
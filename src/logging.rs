@@ -0,0 +1,103 @@
+// Structured records of recovery and maintenance decisions, with the
+// inputs that led to them, so that operators can tell why the database
+// did something without reading the source. Records go through `tracing`,
+// under the target of the module that decided; without the `tracing`
+// feature, the macros compile to nothing.
+
+macro_rules! log_debug {
+    ($($arg:tt)+) => {
+        #[cfg(feature = "tracing")]
+        tracing::debug!($($arg)+);
+    };
+}
+
+macro_rules! log_info {
+    ($($arg:tt)+) => {
+        #[cfg(feature = "tracing")]
+        tracing::info!($($arg)+);
+    };
+}
+
+macro_rules! log_warn {
+    ($($arg:tt)+) => {
+        #[cfg(feature = "tracing")]
+        tracing::warn!($($arg)+);
+    };
+}
+
+pub(crate) use {log_debug, log_info, log_warn};
+
+#[cfg(all(test, feature = "tracing"))]
+mod tests {
+    use std::error::Error;
+    use std::fmt;
+    use std::num::NonZeroU64;
+    use std::sync::{Arc, Mutex};
+
+    use tempfile::tempdir;
+    use tracing::field::{Field, Visit};
+    use tracing::span::{Attributes, Id, Record};
+    use tracing::{Event, Metadata};
+
+    use crate::{encoded_record_len, Options, SunsetDB};
+
+    type TestResult = Result<(), Box<dyn Error>>;
+
+    // Collects the fields of every event, as `name=value`.
+    #[derive(Clone, Default)]
+    struct Collector(Arc<Mutex<Vec<String>>>);
+
+    struct Fields(String);
+
+    impl Visit for Fields {
+        fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+            self.0 += &format!("{}={:?} ", field.name(), value);
+        }
+    }
+
+    impl tracing::Subscriber for Collector {
+        fn enabled(&self, _: &Metadata<'_>) -> bool {
+            true
+        }
+        fn new_span(&self, _: &Attributes<'_>) -> Id {
+            Id::from_u64(1)
+        }
+        fn record(&self, _: &Id, _: &Record<'_>) {}
+        fn record_follows_from(&self, _: &Id, _: &Id) {}
+        fn event(&self, event: &Event<'_>) {
+            let mut fields = Fields(String::new());
+            event.record(&mut fields);
+            self.0.lock().unwrap().push(fields.0);
+        }
+        fn enter(&self, _: &Id) {}
+        fn exit(&self, _: &Id) {}
+    }
+
+    #[test]
+    fn log_decisions_test() -> TestResult {
+        let base_dir = tempdir()?;
+        let collector = Collector::default();
+        let max = encoded_record_len("k", Some("v"));
+        tracing::subscriber::with_default(collector.clone(), || -> TestResult {
+            let options = Options {
+                max_segment_size: NonZeroU64::new(max),
+                ..Default::default()
+            };
+            let mut s = SunsetDB::with_options(base_dir.path(), options)?;
+            s.insert("k", "v")?;
+            s.insert("k", "v")?;
+            Ok(())
+        })?;
+
+        let records = collector.0.lock().unwrap();
+        assert!(records[0].starts_with("message=opened database "));
+        assert_eq!(
+            records[1],
+            format!(
+                "message=sealing the active segment segment_id=0 len={} max_segment_size={} ",
+                max, max
+            )
+        );
+        Ok(())
+    }
+}
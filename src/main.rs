@@ -1,3 +1,350 @@
-fn main() {
-    println!("Hello, world!");
+mod soak;
+
+use std::env;
+use std::fs::{create_dir_all, read_dir, File};
+use std::io::BufReader;
+use std::path::Path;
+use std::process::ExitCode;
+use std::time::SystemTime;
+
+use sunset_db::{CheckReport, ConflictPolicy, Error, ImportFormat, Quarantined, SunsetDB};
+
+use self::soak::{SoakError, SoakOptions};
+
+const USAGE: &str = "usage: sunset check <dir>
+       sunset doctor <dir>
+       sunset info <dir>
+       sunset replay <trace> <dir>
+       sunset import <dir> <file> --format csv|jsonl --on-conflict skip|overwrite|fail
+       sunset soak <dir> [--seconds N] [--ops N] [--seed N] [--keys N]
+                         [--max-value-len N] [--restart-every N] [--verify-every N]";
+
+fn main() -> ExitCode {
+    let args: Vec<String> = env::args().skip(1).collect();
+    match args.iter().map(String::as_str).collect::<Vec<_>>()[..] {
+        ["check", dir] => check(Path::new(dir)),
+        ["doctor", dir] => doctor(Path::new(dir)),
+        ["info", dir] => info(Path::new(dir)),
+        ["replay", trace, dir] => replay(Path::new(trace), Path::new(dir)),
+        ["import", dir, file, "--format", format, "--on-conflict", policy] => {
+            let format = match format {
+                "csv" => ImportFormat::Csv,
+                "jsonl" => ImportFormat::Jsonl,
+                _ => return usage(&format!("unknown format: {}", format)),
+            };
+            let policy = match policy {
+                "skip" => ConflictPolicy::KeepExisting,
+                "overwrite" => ConflictPolicy::Overwrite,
+                "fail" => ConflictPolicy::Fail,
+                _ => return usage(&format!("unknown conflict policy: {}", policy)),
+            };
+            import(Path::new(dir), Path::new(file), format, policy)
+        }
+        ["soak", dir, ref options @ ..] => match SoakOptions::parse(options) {
+            Ok(options) => soak(Path::new(dir), &options),
+            Err(e) => usage(&e),
+        },
+        _ => {
+            eprintln!("{}", USAGE);
+            ExitCode::from(2)
+        }
+    }
+}
+
+// Exits with 0 if the database is consistent, 1 if issues were found
+// and 2 if the check itself could not run.
+fn check(dir: &Path) -> ExitCode {
+    match sunset_db::check(dir) {
+        Ok(report) => {
+            println!("{}", report_to_json(&report));
+            if report.is_ok() {
+                ExitCode::SUCCESS
+            } else {
+                ExitCode::from(1)
+            }
+        }
+        Err(e) => {
+            eprintln!("check failed: {}", describe(&e));
+            ExitCode::from(2)
+        }
+    }
+}
+
+// Prints what was found, then one line per warning. Exits with 0 if there
+// are none, 1 if there are and 2 if the checks could not run.
+fn doctor(dir: &Path) -> ExitCode {
+    let report = match sunset_db::doctor(dir) {
+        Ok(report) => report,
+        Err(e) => {
+            eprintln!("doctor failed: {}", describe(&e));
+            return ExitCode::from(2);
+        }
+    };
+
+    println!(
+        "segments: {} ({} bytes sealed)",
+        report.segments, report.sealed_bytes
+    );
+    match report.open_files_limit {
+        Some(limit) => println!("open files limit: {}", limit),
+        None => println!("open files limit: unlimited"),
+    }
+    match &report.filesystem {
+        Some(filesystem) => println!("filesystem: {}", filesystem.name),
+        None => println!("filesystem: unknown"),
+    }
+    println!("available: {} bytes", report.available_bytes);
+    for warning in &report.warnings {
+        println!("warning: {}", warning);
+    }
+
+    if report.is_ok() {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::from(1)
+    }
+}
+
+// Prints a summary meant for a human, not for parsing. Exits with 2 if the
+// directory could not be read.
+fn info(dir: &Path) -> ExitCode {
+    let report = match sunset_db::info(dir) {
+        Ok(report) => report,
+        Err(e) => {
+            eprintln!("info failed: {}", describe(&e));
+            return ExitCode::from(2);
+        }
+    };
+
+    println!(
+        "format:          version {}, checkpoints version {}, CRC32 on keys and values",
+        report.format_version, report.checkpoint_version
+    );
+    let largest = report.segments.iter().map(|s| s.len).max().unwrap_or(0);
+    match (report.segments.first(), report.segments.last()) {
+        (Some(oldest), Some(newest)) => println!(
+            "segments:        {} (IDs {} to {}), {} bytes, largest {} bytes",
+            report.segments.len(),
+            oldest.id,
+            newest.id,
+            report.len(),
+            largest
+        ),
+        _ => println!("segments:        none"),
+    }
+    println!("live keys:       {}", report.live_keys);
+    println!(
+        "dead bytes:      {} ({:.1}%)",
+        report.dead_bytes(),
+        report.dead_ratio() * 100.0
+    );
+    let pending = if report.compaction_pending {
+        ", one interrupted: finished on next open"
+    } else {
+        ""
+    };
+    println!(
+        "last compaction: {}{}",
+        describe_time(report.last_compaction),
+        pending
+    );
+    println!("last backup:     {}", describe_time(report.last_backup));
+    if report.quarantined.is_empty() {
+        println!("quarantined:     none");
+    }
+    for quarantined in &report.quarantined {
+        match quarantined {
+            Quarantined::Segment { path, len, error } => println!(
+                "quarantined:     {} ({} bytes): {}",
+                path.display(),
+                len,
+                describe(error)
+            ),
+            Quarantined::TornTail {
+                path,
+                offset,
+                bytes,
+            } => println!(
+                "quarantined:     {} bytes at {} of {}: incomplete record",
+                bytes,
+                offset,
+                path.display()
+            ),
+        }
+    }
+    ExitCode::SUCCESS
+}
+
+fn describe_time(time: Option<SystemTime>) -> String {
+    let Some(time) = time else {
+        return "never".to_string();
+    };
+    let secs = time
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    match SystemTime::now().duration_since(time) {
+        Ok(ago) => format!("{}s ago (unix time {})", ago.as_secs(), secs),
+        Err(_) => format!("unix time {}", secs),
+    }
+}
+
+// Replays into `dir`, which is created if needed and should not hold a database.
+fn replay(trace: &Path, dir: &Path) -> ExitCode {
+    let result = create_dir_all(dir)
+        .map_err(Error::from)
+        .and_then(|_| SunsetDB::new(dir))
+        .and_then(|mut db| sunset_db::replay(trace, &mut db));
+
+    match result {
+        Ok(report) => {
+            println!(
+                r#"{{"inserts":{},"gets":{},"deletes":{},"misses":{}}}"#,
+                report.inserts, report.gets, report.deletes, report.misses
+            );
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!("replay failed: {}", describe(&e));
+            ExitCode::from(2)
+        }
+    }
+}
+
+fn usage(error: &str) -> ExitCode {
+    eprintln!("{}\n{}", error, USAGE);
+    ExitCode::from(2)
+}
+
+// Imports into `dir`, which is created if needed, reporting progress on
+// stderr. Exits with 2 on errors, keeping the records imported before them.
+fn import(dir: &Path, file: &Path, format: ImportFormat, policy: ConflictPolicy) -> ExitCode {
+    let result = File::open(file)
+        .and_then(|f| create_dir_all(dir).map(|_| f))
+        .map_err(Error::from)
+        .and_then(|f| {
+            let mut db = SunsetDB::new(dir)?;
+            db.import(BufReader::new(f), format, policy, |progress| {
+                eprintln!(
+                    "imported {} of {} records",
+                    progress.imported, progress.records
+                )
+            })
+        });
+
+    match result {
+        Ok(report) => {
+            println!(
+                r#"{{"records":{},"imported":{},"conflicts":{}}}"#,
+                report.records, report.imported, report.conflicts
+            );
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!("import failed: {}", describe(&e));
+            ExitCode::from(2)
+        }
+    }
+}
+
+// Exits with 1 on a divergence from the model, after describing it, and
+// with 2 if the database failed. `dir` is created if needed and must be empty.
+fn soak(dir: &Path, options: &SoakOptions) -> ExitCode {
+    let empty = create_dir_all(dir)
+        .and_then(|_| read_dir(dir))
+        .map(|mut entries| entries.next().is_none());
+    match empty {
+        Ok(true) => {}
+        Ok(false) => {
+            eprintln!("soak failed: {} is not empty", dir.display());
+            return ExitCode::from(2);
+        }
+        Err(e) => {
+            eprintln!("soak failed: {}", describe(&e));
+            return ExitCode::from(2);
+        }
+    }
+
+    match soak::soak(dir, options) {
+        Ok(report) => {
+            println!(
+                r#"{{"ops":{},"restarts":{},"verifications":{}}}"#,
+                report.ops, report.restarts, report.verifications
+            );
+            ExitCode::SUCCESS
+        }
+        Err(SoakError::Divergence(divergence)) => {
+            eprint!("{}", divergence);
+            eprintln!("seed: {}", options.seed);
+            ExitCode::from(1)
+        }
+        Err(SoakError::Db(e)) => {
+            eprintln!("soak failed: {}", describe(&e));
+            ExitCode::from(2)
+        }
+    }
+}
+
+// Formats `e` followed by its chain of causes.
+fn describe(e: &dyn std::error::Error) -> String {
+    let mut description = e.to_string();
+    let mut source = e.source();
+    while let Some(cause) = source {
+        description.push_str(&format!(": {}", cause));
+        source = cause.source();
+    }
+    description
+}
+
+fn report_to_json(report: &CheckReport) -> String {
+    let segments: Vec<String> = report
+        .segments
+        .iter()
+        .map(|s| {
+            format!(
+                r#"{{"id":{},"path":{},"len":{},"records":{},"tombstones":{},"live_keys":{}}}"#,
+                s.id,
+                json_string(&s.path.to_string_lossy()),
+                s.len,
+                s.records,
+                s.tombstones,
+                s.live_keys
+            )
+        })
+        .collect();
+
+    let issues: Vec<String> = report
+        .issues
+        .iter()
+        .map(|i| {
+            format!(
+                r#"{{"path":{},"offset":{},"issue":{}}}"#,
+                json_string(&i.path.to_string_lossy()),
+                i.offset.map_or("null".to_string(), |o| o.to_string()),
+                json_string(&i.kind.to_string())
+            )
+        })
+        .collect();
+
+    format!(
+        r#"{{"ok":{},"segments":[{}],"issues":[{}]}}"#,
+        report.is_ok(),
+        segments.join(","),
+        issues.join(",")
+    )
+}
+
+fn json_string(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len() + 2);
+    escaped.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
 }
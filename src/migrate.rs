@@ -0,0 +1,348 @@
+// Imports of whole databases of other embedded stores. Their trees or
+// tables map to keyspaces: keys are prefixed with the table name and a
+// separator, see `SunsetDB::keyspace_stats`.
+
+use std::path::Path;
+
+use super::error::*;
+use super::import::{identity, Importer};
+use super::{ConflictPolicy, ImportReport, PairMap, SunsetDB};
+
+impl SunsetDB {
+    /// Imports every tree of the sled database at `path`. Keys of the
+    /// default tree are imported as they are, those of other trees as
+    /// `<tree><separator><key>`. Conflicts and progress are handled as in
+    /// [`import`](SunsetDB::import).
+    ///
+    /// Keys and values must be UTF-8.
+    #[cfg(feature = "sled")]
+    pub fn import_sled(
+        &mut self,
+        path: &Path,
+        separator: char,
+        policy: ConflictPolicy,
+        progress: impl FnMut(&ImportReport),
+    ) -> Result<ImportReport, Error> {
+        self.import_sled_with(path, separator, policy, identity(), progress)
+    }
+
+    /// Like [`import_sled`](SunsetDB::import_sled), passing every pair through
+    /// `map`, after prefixing keys with their tree.
+    #[cfg(feature = "sled")]
+    pub fn import_sled_with(
+        &mut self,
+        path: &Path,
+        separator: char,
+        policy: ConflictPolicy,
+        map: PairMap,
+        progress: impl FnMut(&ImportReport),
+    ) -> Result<ImportReport, Error> {
+        self.check_writable()?;
+        // sled would create a database that does not exist.
+        std::fs::metadata(path).map_err(|e| Error::from(e).with_path(path))?;
+        let source = sled::open(path).map_err(|e| Error::from(SunsetDBError::from(e)))?;
+        self.import_sled_db(&source, separator, policy, map, progress)
+            .map_err(|e| e.with_path(path))
+    }
+
+    /// Like [`import_sled_with`](SunsetDB::import_sled_with), from a sled
+    /// database the caller already opened. sled locks its files while open,
+    /// so this is the way to import from a process that keeps using them.
+    #[cfg(feature = "sled")]
+    pub fn import_sled_db(
+        &mut self,
+        source: &sled::Db,
+        separator: char,
+        policy: ConflictPolicy,
+        map: PairMap,
+        progress: impl FnMut(&ImportReport),
+    ) -> Result<ImportReport, Error> {
+        self.check_writable()?;
+        let error = |e: sled::Error| Error::from(SunsetDBError::from(e));
+
+        let mut importer = Importer::new(policy, map, progress);
+        let result = (|| {
+            for name in source.tree_names() {
+                let prefix = match &*name {
+                    b"__sled__default" => Vec::new(),
+                    _ => [&name[..], separator.to_string().as_bytes()].concat(),
+                };
+                for pair in source.open_tree(&name).map_err(error)?.iter() {
+                    let (key, value) = pair.map_err(error)?;
+                    let key = utf8_key([&prefix[..], &key].concat())?;
+                    let value = utf8_value(&key, value.to_vec())?;
+                    importer.add(self, key, value)?;
+                }
+            }
+            Ok(())
+        })();
+        importer.finish(self, result)
+    }
+
+    /// Imports every table of the redb database at `path`, with keys
+    /// imported as `<table><separator><key>`. Conflicts and progress are
+    /// handled as in [`import`](SunsetDB::import).
+    ///
+    /// Keys and values of tables must be `&str` or `&[u8]`, and bytes must
+    /// be UTF-8. Tables of other types and multimap tables are rejected
+    /// before importing anything.
+    #[cfg(feature = "redb")]
+    pub fn import_redb(
+        &mut self,
+        path: &Path,
+        separator: char,
+        policy: ConflictPolicy,
+        progress: impl FnMut(&ImportReport),
+    ) -> Result<ImportReport, Error> {
+        self.import_redb_with(path, separator, policy, identity(), progress)
+    }
+
+    /// Like [`import_redb`](SunsetDB::import_redb), passing every pair through
+    /// `map`, after prefixing keys with their table.
+    #[cfg(feature = "redb")]
+    pub fn import_redb_with(
+        &mut self,
+        path: &Path,
+        separator: char,
+        policy: ConflictPolicy,
+        map: PairMap,
+        progress: impl FnMut(&ImportReport),
+    ) -> Result<ImportReport, Error> {
+        use redb::{MultimapTableHandle, TableHandle};
+
+        self.check_writable()?;
+        let error = |e: redb::Error| Error::from(SunsetDBError::Redb(Box::new(e))).with_path(path);
+        let source = redb::Database::open(path).map_err(|e| error(e.into()))?;
+        let transaction = source.begin_read().map_err(|e| error(e.into()))?;
+
+        if let Some(table) = transaction
+            .list_multimap_tables()
+            .map_err(|e| error(e.into()))?
+            .next()
+        {
+            return Err(
+                Error::from(SunsetDBError::UnsupportedTable(table.name().to_string()))
+                    .with_path(path),
+            );
+        }
+        let tables: Vec<_> = transaction
+            .list_tables()
+            .map_err(|e| error(e.into()))?
+            .collect();
+
+        let mut readers = Vec::new();
+        for table in &tables {
+            let name = table.name();
+            let mut reader = None;
+            for (matches, read) in REDB_TYPES {
+                if matches(&transaction, name).map_err(|e| error(e.into()))? {
+                    reader = Some(read);
+                    break;
+                }
+            }
+            let read = reader.ok_or_else(|| {
+                Error::from(SunsetDBError::UnsupportedTable(name.to_string())).with_path(path)
+            })?;
+            readers.push((name, read));
+        }
+
+        let mut importer = Importer::new(policy, map, progress);
+        let result = (|| {
+            for (name, read) in readers {
+                let mut add = |key: Vec<u8>, value: Vec<u8>| -> Result<(), Error> {
+                    let key = utf8_key(
+                        [name.as_bytes(), separator.to_string().as_bytes(), &key].concat(),
+                    )?;
+                    let value = utf8_value(&key, value)?;
+                    importer.add(self, key, value)
+                };
+                read(&transaction, name, &mut add).map_err(|e| e.with_path(path))?;
+            }
+            Ok(())
+        })();
+        importer.finish(self, result)
+    }
+}
+
+fn utf8_key(key: Vec<u8>) -> Result<String, Error> {
+    String::from_utf8(key).map_err(|e| {
+        let lossy = String::from_utf8_lossy(e.as_bytes()).into_owned();
+        Error::from(SunsetDBError::NotUtf8).with_key(&lossy)
+    })
+}
+
+fn utf8_value(key: &str, value: Vec<u8>) -> Result<String, Error> {
+    String::from_utf8(value).map_err(|_| Error::from(SunsetDBError::NotUtf8).with_key(key))
+}
+
+#[cfg(feature = "redb")]
+type AddPair<'a> = &'a mut dyn FnMut(Vec<u8>, Vec<u8>) -> Result<(), Error>;
+
+// Whether a table has given types, and how to read it if so.
+#[cfg(feature = "redb")]
+type RedbType = (
+    fn(&redb::ReadTransaction, &str) -> Result<bool, redb::TableError>,
+    fn(&redb::ReadTransaction, &str, AddPair) -> Result<(), Error>,
+);
+
+#[cfg(feature = "redb")]
+const REDB_TYPES: [RedbType; 4] = [
+    (redb_matches::<&str, &str>, redb_read::<&str, &str>),
+    (redb_matches::<&str, &[u8]>, redb_read::<&str, &[u8]>),
+    (redb_matches::<&[u8], &str>, redb_read::<&[u8], &str>),
+    (redb_matches::<&[u8], &[u8]>, redb_read::<&[u8], &[u8]>),
+];
+
+// Key and value types of redb tables that can be imported.
+#[cfg(feature = "redb")]
+trait RedbBytes: redb::Value + 'static {
+    fn to_bytes(value: Self::SelfType<'_>) -> Vec<u8>;
+}
+
+#[cfg(feature = "redb")]
+impl RedbBytes for &'static str {
+    fn to_bytes(value: &str) -> Vec<u8> {
+        value.as_bytes().to_vec()
+    }
+}
+
+#[cfg(feature = "redb")]
+impl RedbBytes for &'static [u8] {
+    fn to_bytes(value: &[u8]) -> Vec<u8> {
+        value.to_vec()
+    }
+}
+
+#[cfg(feature = "redb")]
+fn redb_matches<K: RedbBytes + redb::Key, V: RedbBytes>(
+    transaction: &redb::ReadTransaction,
+    name: &str,
+) -> Result<bool, redb::TableError> {
+    match transaction.open_table(redb::TableDefinition::<K, V>::new(name)) {
+        Ok(_) => Ok(true),
+        Err(redb::TableError::TableTypeMismatch { .. }) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(feature = "redb")]
+fn redb_read<K: RedbBytes + redb::Key, V: RedbBytes>(
+    transaction: &redb::ReadTransaction,
+    name: &str,
+    add: AddPair,
+) -> Result<(), Error> {
+    use redb::ReadableTable;
+
+    let error = |e: redb::Error| Error::from(SunsetDBError::Redb(Box::new(e)));
+    let table = transaction
+        .open_table(redb::TableDefinition::<K, V>::new(name))
+        .map_err(|e| error(e.into()))?;
+    for pair in table.iter().map_err(|e| error(e.into()))? {
+        let (key, value) = pair.map_err(|e| error(e.into()))?;
+        add(K::to_bytes(key.value()), V::to_bytes(value.value()))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error;
+
+    use super::*;
+    use crate::ErrorKind;
+    use tempfile::tempdir;
+
+    type TestResult = Result<(), Box<dyn Error>>;
+
+    #[cfg(feature = "sled")]
+    #[test]
+    fn import_sled_test() -> TestResult {
+        let (base_dir, source_dir) = (tempdir()?, tempdir()?);
+        let source = sled::open(source_dir.path().join("sled"))?;
+        source.insert("a", "1")?;
+        source.insert("b", "2")?;
+        source.open_tree("users")?.insert("alice", "3")?;
+
+        let mut s = SunsetDB::new(base_dir.path())?;
+        s.insert("a", "old")?;
+        let policy = ConflictPolicy::KeepExisting;
+        let report = s.import_sled_db(&source, '/', policy, identity(), |_| {})?;
+        assert_eq!(
+            (report.records, report.imported, report.conflicts),
+            (3, 2, 1)
+        );
+        assert_eq!(s.get("a")?.as_deref(), Some("old"));
+        assert_eq!(s.get("b")?.as_deref(), Some("2"));
+        assert_eq!(s.get("users/alice")?.as_deref(), Some("3"));
+
+        let missing = source_dir.path().join("missing");
+        let e = s.import_sled(&missing, '/', ConflictPolicy::Fail, |_| {});
+        assert_eq!(e.unwrap_err().kind(), ErrorKind::Io);
+        assert!(!missing.exists());
+        Ok(())
+    }
+
+    #[cfg(feature = "redb")]
+    #[test]
+    fn import_redb_test() -> TestResult {
+        use redb::{MultimapTableDefinition, TableDefinition};
+
+        let (base_dir, source_dir) = (tempdir()?, tempdir()?);
+        let source_path = source_dir.path().join("redb");
+        let source = redb::Database::create(&source_path)?;
+        let transaction = source.begin_write()?;
+        {
+            let mut strings = transaction.open_table(TableDefinition::<&str, &str>::new("s"))?;
+            strings.insert("a", "1")?;
+            let mut bytes = transaction.open_table(TableDefinition::<&[u8], &[u8]>::new("b"))?;
+            bytes.insert(b"a".as_slice(), b"2".as_slice())?;
+            bytes.insert(b"bad".as_slice(), [0xff].as_slice())?;
+        }
+        transaction.commit()?;
+        drop(source);
+
+        let mut s = SunsetDB::new(base_dir.path())?;
+        let e = s
+            .import_redb(&source_path, ':', ConflictPolicy::Fail, |_| {})
+            .unwrap_err();
+        assert_eq!(
+            (e.kind(), e.key()),
+            (ErrorKind::InvalidInput, Some("b:bad"))
+        );
+
+        let source = redb::Database::open(&source_path)?;
+        let transaction = source.begin_write()?;
+        transaction
+            .open_table(TableDefinition::<&[u8], &[u8]>::new("b"))?
+            .remove(b"bad".as_slice())?;
+        transaction.commit()?;
+        drop(source);
+        let report = s.import_redb(&source_path, ':', ConflictPolicy::Overwrite, |_| {})?;
+        assert_eq!(
+            (report.records, report.imported, report.conflicts),
+            (2, 2, 1)
+        );
+        assert_eq!(s.get("b:a")?.as_deref(), Some("2"));
+        assert_eq!(s.get("s:a")?.as_deref(), Some("1"));
+
+        for unsupported in ["u64", "multimap"] {
+            let path = source_dir.path().join(unsupported);
+            let source = redb::Database::create(&path)?;
+            let transaction = source.begin_write()?;
+            match unsupported {
+                "u64" => {
+                    transaction.open_table(TableDefinition::<u64, &str>::new("t"))?;
+                }
+                _ => {
+                    transaction
+                        .open_multimap_table(MultimapTableDefinition::<&str, &str>::new("t"))?;
+                }
+            }
+            transaction.commit()?;
+            drop(source);
+            let e = s.import_redb(&path, ':', ConflictPolicy::Overwrite, |_| {});
+            assert_eq!(e.unwrap_err().kind(), ErrorKind::InvalidInput);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,115 @@
+// Keys recently found absent, so that repeated misses skip probing the
+// index of every segment. Any write that may add a key forgets it first.
+
+use std::collections::{HashMap, VecDeque};
+use std::num::NonZeroUsize;
+
+pub(crate) struct MissCache {
+    // Key to the generation it was cached at.
+    keys: HashMap<String, u64>,
+    // Oldest first. Entries whose generation no longer matches were
+    // forgotten, and maybe cached again, since: they are skipped.
+    order: VecDeque<(u64, String)>,
+    generation: u64,
+    capacity: usize,
+}
+
+impl MissCache {
+    pub(crate) fn new(capacity: NonZeroUsize) -> MissCache {
+        MissCache {
+            keys: HashMap::new(),
+            order: VecDeque::new(),
+            generation: 0,
+            capacity: capacity.get(),
+        }
+    }
+
+    pub(crate) fn contains(&self, key: &str) -> bool {
+        self.keys.contains_key(key)
+    }
+
+    pub(crate) fn insert(&mut self, key: &str) {
+        if self.keys.contains_key(key) {
+            return;
+        }
+        self.generation += 1;
+        self.keys.insert(key.to_string(), self.generation);
+        self.order.push_back((self.generation, key.to_string()));
+        while self.keys.len() > self.capacity {
+            let Some((generation, oldest)) = self.order.pop_front() else {
+                break;
+            };
+            if self.keys.get(&oldest) == Some(&generation) {
+                self.keys.remove(&oldest);
+            }
+        }
+
+        // Drop skipped entries once they make up half of `order`.
+        if self.order.len() > 2 * self.capacity {
+            let keys = &self.keys;
+            self.order
+                .retain(|(generation, key)| keys.get(key) == Some(generation));
+        }
+    }
+
+    pub(crate) fn forget(&mut self, key: &str) {
+        self.keys.remove(key);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error;
+
+    use super::*;
+    use crate::{Options, SegmentWriter, SunsetDB};
+    use tempfile::tempdir;
+
+    type TestResult = Result<(), Box<dyn Error>>;
+
+    #[test]
+    fn miss_cache_test() -> TestResult {
+        let (base_dir, staging_dir) = (tempdir()?, tempdir()?);
+        let options = Options {
+            negative_cache_keys: NonZeroUsize::new(2),
+            ..Default::default()
+        };
+        let mut s = SunsetDB::with_options(base_dir.path(), options)?;
+
+        for key in ["a", "b", "c", "d"] {
+            assert_eq!(s.get(key)?, None);
+        }
+        let misses = s.misses.as_ref().ok_or("no cache")?;
+        assert!(!misses.contains("b") && misses.contains("c") && misses.contains("d"));
+
+        s.insert("c", "1")?;
+        s.insert_sorted_batch([("d", "2")])?;
+        let path = staging_dir.path().join("segment");
+        let mut writer = SegmentWriter::create(&path)?;
+        writer.insert("e", "3")?;
+        writer.finish()?;
+        assert_eq!(s.get("e")?, None);
+        s.ingest_segment(&path, Default::default())?;
+
+        assert_eq!(s.get("c")?.as_deref(), Some("1"));
+        assert_eq!(s.get("d")?.as_deref(), Some("2"));
+        assert_eq!(s.get("e")?.as_deref(), Some("3"));
+        s.delete("c")?;
+        assert_eq!(s.get("c")?, None);
+
+        // A forgotten key doesn't take up room, nor is it evicted early once
+        // cached again.
+        for key in ["x", "y"] {
+            assert_eq!(s.get(key)?, None);
+        }
+        s.insert("x", "1")?;
+        s.delete("x")?;
+        for key in ["x", "z"] {
+            assert_eq!(s.get(key)?, None);
+        }
+        let misses = s.misses.as_ref().ok_or("no cache")?;
+        assert!(misses.contains("x") && !misses.contains("y") && misses.contains("z"));
+        assert!(misses.order.len() <= 4);
+        Ok(())
+    }
+}
@@ -0,0 +1,137 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use super::compact;
+use super::error::*;
+use super::segment_io::SegmentReader;
+use super::SunsetDB;
+
+/// A point in the log of a database: a segment, and an offset within it.
+///
+/// Every write lands after the current [`SunsetDB::log_position`], so
+/// positions can be saved and later passed to [`SunsetDB::modified_since`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct LogPosition {
+    pub segment_id: u64,
+    pub offset: u64,
+}
+
+impl fmt::Display for LogPosition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.segment_id, self.offset)
+    }
+}
+
+impl SunsetDB {
+    /// The end of the log, i.e. the position of the next write.
+    pub fn log_position(&self) -> LogPosition {
+        // There is always a segment, created in `::new`.
+        self.segments
+            .last()
+            .map_or(LogPosition::default(), |s| LogPosition {
+                segment_id: s.id.0,
+                offset: s.len,
+            })
+    }
+
+    /// Keys inserted or deleted after `since`, which must come from
+    /// [`log_position`](SunsetDB::log_position), in the order of their last
+    /// change. Only the records written after `since` are read.
+    ///
+    /// Compaction rewrites sealed segments under the ID of the newest one:
+    /// positions within or before a compacted segment are rejected, like
+    /// positions past the end of the log.
+    pub fn modified_since(&self, since: LogPosition) -> Result<Vec<String>, Error> {
+        self.check_fork()?;
+        let compacted = compact::compacted_through(&self.base_path);
+        if since > self.log_position() || compacted.is_some_and(|id| since.segment_id <= id) {
+            return Err(SunsetDBError::InvalidLogPosition(since).into());
+        }
+
+        // Key to the position of its last change.
+        let mut modified = HashMap::new();
+        let mut position = 0;
+        for s in self.segments.iter().filter(|s| s.id.0 >= since.segment_id) {
+            let start = if s.id.0 == since.segment_id {
+                since.offset
+            } else {
+                0
+            };
+            let records = SegmentReader::open_range(&s.path, start, s.len)
+                .map_err(|e| e.with_segment(s.id.0, &s.path))?;
+            for record in records {
+                let (_, record) = record.map_err(|e| e.with_segment(s.id.0, &s.path))?;
+                modified.insert(record.key().to_string(), position);
+                position += 1;
+            }
+        }
+
+        let mut keys: Vec<_> = modified.into_iter().collect();
+        keys.sort_unstable_by_key(|(_, position)| *position);
+        Ok(keys.into_iter().map(|(key, _)| key).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error;
+
+    use crate::{ErrorKind, LogPosition, SunsetDB};
+    use tempfile::tempdir;
+
+    type TestResult = Result<(), Box<dyn Error>>;
+
+    #[test]
+    fn modified_since_test() -> TestResult {
+        let base_dir = tempdir()?;
+        let mut s = SunsetDB::new(base_dir.path())?;
+        s.insert("a", "1")?;
+        s.insert("b", "1")?;
+
+        let since = s.log_position();
+        assert_eq!(s.modified_since(since)?, Vec::<String>::new());
+        s.insert("c", "1")?;
+        s.insert("a", "2")?;
+        s.add_new_segment()?;
+        s.delete("c")?;
+        s.insert("d", "1")?;
+        assert_eq!(s.modified_since(since)?, ["a", "c", "d"]);
+
+        let start = LogPosition {
+            segment_id: 0,
+            offset: 0,
+        };
+        assert_eq!(s.modified_since(start)?, ["b", "a", "c", "d"]);
+
+        // Positions survive reopening.
+        drop(s);
+        let s = SunsetDB::new(base_dir.path())?;
+        assert_eq!(s.modified_since(since)?, ["a", "c", "d"]);
+
+        let future = LogPosition {
+            segment_id: 2,
+            offset: 0,
+        };
+        assert_eq!(
+            s.modified_since(future).unwrap_err().kind(),
+            ErrorKind::InvalidInput
+        );
+
+        // Compacted segments hold other records at the same offsets.
+        let mut s = s;
+        let after = s.log_position();
+        s.insert("e", "1")?;
+        s.compact()?;
+        for position in [since, after] {
+            let e = s.modified_since(position).unwrap_err();
+            assert_eq!(e.kind(), ErrorKind::InvalidInput);
+        }
+        let position = s.log_position();
+        s.insert("f", "1")?;
+        drop(s);
+        let s = SunsetDB::new(base_dir.path())?;
+        assert!(s.modified_since(after).is_err());
+        assert_eq!(s.modified_since(position)?, ["f"]);
+        Ok(())
+    }
+}
@@ -0,0 +1,173 @@
+use std::fmt;
+use std::num::{NonZeroU64, NonZeroUsize};
+use std::time::Duration;
+
+use crate::{
+    CompactionOptions, DiskSpaceLimits, EventListeners, KeyHeatOptions, MockClock, RateLimits,
+    RecoveryListeners, SharedClock, SharedCompactionFilter,
+};
+
+/// Configuration of a [`SunsetDB`](crate::SunsetDB), see
+/// [`SunsetDB::with_options`](crate::SunsetDB::with_options).
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    pub quotas: Quotas,
+    pub rate_limits: RateLimits,
+    pub disk_space: DiskSpaceLimits,
+    pub checksum_sampling: ChecksumSampling,
+    /// Re-read sealed segments in the background at this rate, raising
+    /// [`Event::CorruptionDetected`](crate::Event::CorruptionDetected) for
+    /// invalid records; `None` disables scrubbing.
+    pub scrub_bytes_per_sec: Option<NonZeroU64>,
+    /// Don't start background threads when opening the database, but only
+    /// on [`SunsetDB::start_background_workers`](crate::SunsetDB::start_background_workers),
+    /// e.g. once the process has forked.
+    ///
+    /// They are the compactors, as many as
+    /// [`CompactionOptions::max_parallel_merges`] with [`compaction`](Self::compaction),
+    /// and a scrubber, with [`scrub_bytes_per_sec`](Self::scrub_bytes_per_sec).
+    pub defer_background_workers: bool,
+    /// Names background threads `<prefix>-compactor` and `<prefix>-scrubber`,
+    /// e.g. to tell databases apart in `top`; `None` uses `sunset`. Linux
+    /// truncates thread names to 15 bytes.
+    pub thread_name_prefix: Option<String>,
+    /// Checkpoint the index of the active segment every time this many bytes
+    /// were appended to it, so that opening the database only replays the
+    /// records written since; `None` always replays whole segments.
+    pub index_checkpoint_bytes: Option<NonZeroU64>,
+    /// Seal the active segment and move writes to a new one once it holds
+    /// this many bytes, checked before every write; `None` lets it grow
+    /// unbounded.
+    pub max_segment_size: Option<NonZeroU64>,
+    /// Merge the sealed segments on background threads when they hold too
+    /// many dead bytes or are too many; `None` disables compaction, e.g. for
+    /// single-threaded use.
+    pub compaction: Option<CompactionOptions>,
+    /// Passes every live record copied by a compaction, in the background
+    /// or not, through this filter; `None` keeps them all.
+    pub compaction_filter: Option<SharedCompactionFilter>,
+    /// Keep tombstones through compactions for at least this long after
+    /// the delete, e.g. so that replicas catching up still see it; `None`
+    /// drops them once no older segment holds the key. Tombstones found
+    /// when opening the database count from then.
+    pub tombstone_grace_period: Option<Duration>,
+    /// How much of the segments to verify when opening the database.
+    pub open_mode: OpenMode,
+    /// Count accesses per key for [`SunsetDB::hottest_keys`](crate::SunsetDB::hottest_keys);
+    /// `None` disables counting.
+    pub key_heat: Option<KeyHeatOptions>,
+    /// Remember up to this many keys that reads found absent, until a
+    /// write adds them, so that repeated misses don't probe every segment;
+    /// `None` disables the cache.
+    pub negative_cache_keys: Option<NonZeroUsize>,
+    /// Fail gets, writes and scans that are still running after this long
+    /// with [`ErrorKind::TimedOut`](crate::ErrorKind::TimedOut); `None` lets
+    /// them run to completion.
+    ///
+    /// IO in progress is not interrupted: the deadline is checked after
+    /// reads, before writes reach the disk, and against the time a write
+    /// would be throttled for. A write that timed out was not applied.
+    pub op_timeout: Option<Duration>,
+    /// Only read the database, e.g. next to the process writing to it:
+    /// opening it creates, truncates and recovers nothing, no background
+    /// thread is started, and writes fail with
+    /// [`ErrorKind::InvalidInput`](crate::ErrorKind::InvalidInput). See
+    /// [`SunsetDB::refresh`](crate::SunsetDB::refresh).
+    pub read_only: bool,
+    pub listeners: EventListeners,
+    pub recovery_listeners: RecoveryListeners,
+    pub clock: SharedClock,
+}
+
+impl Options {
+    /// Options for reproducible tests: time only moves with `clock`, and
+    /// background threads are never started, so that compactions only run
+    /// when [`SunsetDB::compact`](crate::SunsetDB::compact) is called.
+    /// Nothing else in the database is random or timed.
+    pub fn deterministic(clock: MockClock) -> Options {
+        Options {
+            clock: SharedClock::new(clock),
+            defer_background_workers: true,
+            ..Default::default()
+        }
+    }
+
+    // Name of the background thread doing `work`.
+    pub(crate) fn thread_name(&self, work: &str) -> String {
+        let prefix = self.thread_name_prefix.as_deref().unwrap_or("sunset");
+        format!("{}-{}", prefix, work)
+    }
+}
+
+/// A setting that can change without reopening the database, see
+/// [`SunsetDB::set_option`](crate::SunsetDB::set_option).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Tunable {
+    Quotas(Quotas),
+    RateLimits(RateLimits),
+    DiskSpace(DiskSpaceLimits),
+    ChecksumSampling(ChecksumSampling),
+    OpTimeout(Option<Duration>),
+}
+
+/// Which reads verify the checksum of the value they return. Keys are always
+/// verified when opening the database.
+///
+/// Once a read finds corruption, every later read on that handle is verified.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ChecksumSampling {
+    #[default]
+    Always,
+    /// Verify one read in `n`, trading detection latency for CPU.
+    OneIn(NonZeroU64),
+}
+
+/// How much opening the database trusts what is on disk. Keys are always
+/// verified for the records that are replayed.
+///
+/// An invalid record found while opening fails it with
+/// [`ErrorKind::Corruption`](crate::ErrorKind::Corruption).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OpenMode {
+    /// Trust index checkpoints, and only replay the records after them.
+    #[default]
+    Fast,
+    /// Like `Fast`, but replay the whole active segment, verifying values,
+    /// since it is the one a crash may have left inconsistent.
+    VerifyActive,
+    /// Replay every segment, verifying values.
+    Verified,
+}
+
+/// Hard ceilings enforced at write time; `None` means unlimited.
+///
+/// Deletes are never rejected, so that callers can always get back under
+/// a quota.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Quotas {
+    /// Maximum number of live keys.
+    pub max_keys: Option<u64>,
+    /// Maximum size of all segments on disk, in bytes.
+    pub max_bytes: Option<u64>,
+    /// Maximum length of a single value, in bytes.
+    pub max_value_size: Option<u64>,
+}
+
+/// The quota rejecting a write, see [`Quotas`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Quota {
+    MaxKeys,
+    MaxBytes,
+    MaxValueSize,
+}
+
+impl fmt::Display for Quota {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Quota::MaxKeys => "max keys",
+            Quota::MaxBytes => "max bytes",
+            Quota::MaxValueSize => "max value size",
+        })
+    }
+}
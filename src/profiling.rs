@@ -0,0 +1,211 @@
+// Counters for the hot path, to catch performance regressions in
+// benchmarks: syscalls, bytes read and written, and allocations, per
+// operation.
+//
+// IO sites report to thread-local counters, and each operation adds what
+// its thread counted while it ran. Without the `profiling` feature, `io`
+// compiles to nothing.
+
+use std::io::{self, Read};
+
+#[cfg(feature = "profiling")]
+pub use self::enabled::*;
+
+#[inline]
+pub(crate) fn io(syscalls: u64, bytes_read: u64, bytes_written: u64) {
+    #[cfg(feature = "profiling")]
+    enabled::count(|c| {
+        c.syscalls += syscalls;
+        c.bytes_read += bytes_read;
+        c.bytes_written += bytes_written;
+    });
+    #[cfg(not(feature = "profiling"))]
+    let _ = (syscalls, bytes_read, bytes_written);
+}
+
+// Reports every read on `R` as a syscall.
+pub(crate) struct Profiled<R>(pub(crate) R);
+
+impl<R: Read> Read for Profiled<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.0.read(buf)?;
+        io(1, read as u64, 0);
+        Ok(read)
+    }
+}
+
+#[cfg(feature = "profiling")]
+mod enabled {
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::cell::Cell;
+    use std::sync::{Arc, Mutex};
+
+    use crate::trace::TraceOp;
+    use crate::SunsetDB;
+
+    const OPS: usize = 5; // Variants of `TraceOp`.
+
+    /// What an operation cost, see [`SunsetDB::profile`].
+    #[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+    pub struct OpProfile {
+        pub calls: u64,
+        pub syscalls: u64,
+        pub bytes_read: u64,
+        pub bytes_written: u64,
+        /// Only counted with [`CountingAllocator`] installed.
+        pub allocations: u64,
+    }
+
+    impl OpProfile {
+        const ZERO: OpProfile = OpProfile {
+            calls: 0,
+            syscalls: 0,
+            bytes_read: 0,
+            bytes_written: 0,
+            allocations: 0,
+        };
+
+        fn add(&mut self, other: &OpProfile) {
+            self.calls += other.calls;
+            self.syscalls += other.syscalls;
+            self.bytes_read += other.bytes_read;
+            self.bytes_written += other.bytes_written;
+            self.allocations += other.allocations;
+        }
+
+        fn since(&self, start: &OpProfile) -> OpProfile {
+            OpProfile {
+                calls: 1,
+                syscalls: self.syscalls - start.syscalls,
+                bytes_read: self.bytes_read - start.bytes_read,
+                bytes_written: self.bytes_written - start.bytes_written,
+                allocations: self.allocations - start.allocations,
+            }
+        }
+    }
+
+    thread_local! {
+        static COUNTS: Cell<OpProfile> = const { Cell::new(OpProfile::ZERO) };
+    }
+
+    // Ignores counts made while the thread is being torn down.
+    pub(super) fn count(f: impl FnOnce(&mut OpProfile)) {
+        let _ = COUNTS.try_with(|counts| {
+            let mut c = counts.get();
+            f(&mut c);
+            counts.set(c);
+        });
+    }
+
+    /// Global allocator counting allocations for [`OpProfile::allocations`],
+    /// to be installed by benchmarks:
+    ///
+    /// ```ignore
+    /// #[global_allocator]
+    /// static ALLOCATOR: sunset_db::CountingAllocator = sunset_db::CountingAllocator;
+    /// ```
+    pub struct CountingAllocator;
+
+    unsafe impl GlobalAlloc for CountingAllocator {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            count(|c| c.allocations += 1);
+            System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout)
+        }
+
+        unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+            count(|c| c.allocations += 1);
+            System.realloc(ptr, layout, new_size)
+        }
+    }
+
+    // Shared with the `OpScope`s in flight, which may outlive a `&mut` borrow
+    // of the database.
+    #[derive(Default)]
+    pub(crate) struct Profile(Arc<Mutex<[OpProfile; OPS]>>);
+
+    impl Profile {
+        pub(crate) fn start(&self, op: TraceOp) -> OpScope {
+            OpScope {
+                profile: self.0.clone(),
+                op,
+                start: COUNTS.with(Cell::get),
+            }
+        }
+    }
+
+    // Adds what the thread counted to `op` when dropped.
+    pub(crate) struct OpScope {
+        profile: Arc<Mutex<[OpProfile; OPS]>>,
+        op: TraceOp,
+        start: OpProfile,
+    }
+
+    impl Drop for OpScope {
+        fn drop(&mut self) {
+            let spent = COUNTS.with(Cell::get).since(&self.start);
+            if let Ok(mut profile) = self.profile.lock() {
+                profile[self.op as usize].add(&spent);
+            }
+        }
+    }
+
+    impl SunsetDB {
+        /// What every call of `op` cost in total, since the database was
+        /// opened or [`reset_profile`](SunsetDB::reset_profile) was called.
+        pub fn profile(&self, op: TraceOp) -> OpProfile {
+            self.profile
+                .0
+                .lock()
+                .map_or(OpProfile::ZERO, |profile| profile[op as usize])
+        }
+
+        pub fn reset_profile(&mut self) {
+            if let Ok(mut profile) = self.profile.0.lock() {
+                *profile = [OpProfile::ZERO; OPS];
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use std::error::Error;
+
+        use super::*;
+        use crate::encoded_record_len;
+        use tempfile::tempdir;
+
+        type TestResult = Result<(), Box<dyn Error>>;
+
+        #[global_allocator]
+        static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+        #[test]
+        fn profile_test() -> TestResult {
+            let base_dir = tempdir()?;
+            let mut s = SunsetDB::new(base_dir.path())?;
+            s.insert("k", "v")?;
+            s.insert("j", "w")?;
+            s.get("k")?;
+
+            let insert = s.profile(TraceOp::Insert);
+            assert_eq!(insert.calls, 2);
+            assert_eq!(insert.syscalls, 4); // A seek and a write per record.
+            assert_eq!(insert.bytes_read, 0);
+            assert_eq!(insert.bytes_written, 2 * encoded_record_len("k", Some("v")));
+
+            let get = s.profile(TraceOp::Get);
+            assert_eq!(get.calls, 1);
+            assert_eq!(get.syscalls, 1);
+            assert_eq!(get.bytes_written, 0);
+            assert!(get.allocations > 0);
+
+            s.reset_profile();
+            assert_eq!(s.profile(TraceOp::Insert), OpProfile::default());
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,164 @@
+use std::num::NonZeroU64;
+use std::time::{Duration, SystemTime};
+
+use crate::clock::{elapsed, SharedClock};
+
+/// Throughput ceilings for foreground writes (inserts and deletes); `None`
+/// means unlimited. Bursts of up to one second worth of either are allowed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RateLimits {
+    pub ops_per_sec: Option<NonZeroU64>,
+    pub bytes_per_sec: Option<NonZeroU64>,
+    /// Writes that would be delayed longer fail with
+    /// [`ErrorKind::TimedOut`](crate::ErrorKind::TimedOut) right away
+    /// instead, without using up the limits; `None` waits as long as needed.
+    pub max_stall: Option<Duration>,
+}
+
+struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    last_refill: SystemTime,
+}
+
+impl TokenBucket {
+    fn new(rate: NonZeroU64, now: SystemTime) -> TokenBucket {
+        let rate = rate.get() as f64;
+        TokenBucket {
+            rate,
+            tokens: rate,
+            last_refill: now,
+        }
+    }
+
+    // Takes `amount` tokens, going into debt if needed, and returns how long
+    // the caller must wait for the debt to be repaid.
+    fn take(&mut self, amount: u64, now: SystemTime) -> Duration {
+        let elapsed = elapsed(self.last_refill, now);
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * self.rate).min(self.rate);
+        self.last_refill = now;
+
+        self.tokens -= amount as f64;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate)
+        }
+    }
+
+    fn refund(&mut self, amount: u64) {
+        self.tokens += amount as f64;
+    }
+}
+
+pub(crate) struct RateLimiter {
+    ops: Option<TokenBucket>,
+    bytes: Option<TokenBucket>,
+    max_stall: Option<Duration>,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(limits: RateLimits, now: SystemTime) -> RateLimiter {
+        RateLimiter {
+            ops: limits.ops_per_sec.map(|r| TokenBucket::new(r, now)),
+            bytes: limits.bytes_per_sec.map(|r| TokenBucket::new(r, now)),
+            max_stall: limits.max_stall,
+        }
+    }
+
+    fn wait_for(&mut self, bytes: u64, now: SystemTime) -> Duration {
+        let ops_wait = self.ops.as_mut().map_or(Duration::ZERO, |b| b.take(1, now));
+        let bytes_wait = self
+            .bytes
+            .as_mut()
+            .map_or(Duration::ZERO, |b| b.take(bytes, now));
+        ops_wait.max(bytes_wait)
+    }
+
+    /// Blocks until a write of `bytes` fits within the limits, returning
+    /// how long it waited. Fails with the wait if it exceeds `max_stall`
+    /// or `remaining`, the time left to the write.
+    pub(crate) fn throttle(
+        &mut self,
+        bytes: u64,
+        clock: &SharedClock,
+        remaining: Option<Duration>,
+    ) -> Result<Duration, Duration> {
+        let wait = self.wait_for(bytes, clock.now());
+        if self
+            .max_stall
+            .into_iter()
+            .chain(remaining)
+            .any(|max| wait > max)
+        {
+            if let Some(b) = self.ops.as_mut() {
+                b.refund(1);
+            }
+            if let Some(b) = self.bytes.as_mut() {
+                b.refund(bytes);
+            }
+            return Err(wait);
+        }
+        if !wait.is_zero() {
+            clock.sleep(wait);
+        }
+        Ok(wait)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Clock, MockClock};
+
+    #[test]
+    fn rate_limiter_test() {
+        let limits = RateLimits {
+            ops_per_sec: NonZeroU64::new(2),
+            bytes_per_sec: NonZeroU64::new(100),
+            max_stall: None,
+        };
+        let now = SystemTime::now();
+        let mut limiter = RateLimiter::new(limits, now);
+
+        // The initial burst is free.
+        assert_eq!(limiter.wait_for(10, now), Duration::ZERO);
+        assert_eq!(limiter.wait_for(10, now), Duration::ZERO);
+
+        // Out of ops: wait half a second for the next one.
+        assert_eq!(limiter.wait_for(10, now), Duration::from_millis(500));
+
+        // A second later, bytes are the bottleneck.
+        let later = now + Duration::from_secs(1);
+        assert_eq!(limiter.wait_for(150, later), Duration::from_millis(500));
+
+        assert_eq!(
+            RateLimiter::new(RateLimits::default(), now).wait_for(u64::MAX, now),
+            Duration::ZERO
+        );
+    }
+
+    #[test]
+    fn rate_limiter_max_stall_test() {
+        let clock = MockClock::new(SystemTime::UNIX_EPOCH);
+        let shared = SharedClock::new(clock.clone());
+        let mut limiter = RateLimiter::new(
+            RateLimits {
+                ops_per_sec: NonZeroU64::new(1),
+                bytes_per_sec: None,
+                max_stall: Some(Duration::from_millis(500)),
+            },
+            clock.now(),
+        );
+
+        assert_eq!(limiter.throttle(1, &shared, None), Ok(Duration::ZERO));
+        assert_eq!(
+            limiter.throttle(1, &shared, None),
+            Err(Duration::from_secs(1))
+        );
+        // The rejected write used up nothing.
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(limiter.throttle(1, &shared, None), Ok(Duration::ZERO));
+        assert_eq!(clock.now(), SystemTime::UNIX_EPOCH + Duration::from_secs(1));
+    }
+}
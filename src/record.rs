@@ -0,0 +1,136 @@
+// Record encoding, independent of where the bytes are stored.
+//
+// This module only relies on `core` and `alloc` so that it can be reused by
+// targets without `std` (e.g. on top of a custom flash driver); file handling
+// stays in the parent module.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::mem::size_of;
+use core::str::Utf8Error;
+
+// Version of the record layout below; segments don't store it.
+pub(crate) const FORMAT_VERSION: u64 = 1;
+
+pub(crate) const ENCODED_LEN_SIZE: usize = size_of::<u64>();
+pub(crate) const CRC32_SIZE: usize = size_of::<u32>();
+
+// TODO: Switch to using an empty byte string as the tombstone?
+pub(crate) const TOMBSTONE: u64 = 1u64 << 63;
+pub(crate) const ENCODED_TOMBSTONE: [u8; ENCODED_LEN_SIZE] = (TOMBSTONE).to_be_bytes();
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum EncodedLen {
+    Tombstone,
+    Len(u64),
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum RecordError {
+    InvalidChecksum { expected: u32, found: u32 },
+    InvalidString(Utf8Error),
+}
+
+// -- <len> || <string> || <checksum> --
+pub(crate) fn encode_string(buffer: &mut Vec<u8>, s: &str) {
+    encode_bytes(buffer, s.as_bytes())
+}
+
+// Same framing as `encode_string`, for arbitrary bytes.
+pub(crate) fn encode_bytes(buffer: &mut Vec<u8>, bytes: &[u8]) {
+    // Cast all to u64 and use big endian to make this portable across machines.
+    buffer.extend_from_slice(&(bytes.len() as u64).to_be_bytes());
+    buffer.extend_from_slice(bytes);
+
+    let checksum = crc32fast::hash(bytes);
+    buffer.extend_from_slice(&checksum.to_be_bytes());
+}
+
+// -- <TOMBSTONE> --
+pub(crate) fn encode_deletion(buffer: &mut Vec<u8>) {
+    buffer.extend_from_slice(&ENCODED_TOMBSTONE);
+
+    // XXX: Write checkum for TOMBSTONE too?
+    // let checksum = crc32fast::hash(&ENCODED_TOMBSTONE);
+    // buffer.extend_from_slice(&checksum.to_be_bytes());
+}
+
+pub(crate) fn decode_len(bytes: [u8; ENCODED_LEN_SIZE]) -> EncodedLen {
+    if bytes == ENCODED_TOMBSTONE {
+        EncodedLen::Tombstone
+    } else {
+        EncodedLen::Len(u64::from_be_bytes(bytes))
+    }
+}
+
+pub(crate) fn decode_string(
+    encoded_string: Vec<u8>,
+    encoded_checksum: [u8; CRC32_SIZE],
+) -> Result<String, RecordError> {
+    decode_unchecked_string(decode_bytes(encoded_string, encoded_checksum)?)
+}
+
+pub(crate) fn decode_bytes(
+    encoded_bytes: Vec<u8>,
+    encoded_checksum: [u8; CRC32_SIZE],
+) -> Result<Vec<u8>, RecordError> {
+    let found = u32::from_be_bytes(encoded_checksum);
+    let expected = crc32fast::hash(&encoded_bytes);
+    if found != expected {
+        return Err(RecordError::InvalidChecksum { expected, found });
+    }
+
+    Ok(encoded_bytes)
+}
+
+// Like `decode_string`, without validating the checksum.
+pub(crate) fn decode_unchecked_string(encoded_string: Vec<u8>) -> Result<String, RecordError> {
+    String::from_utf8(encoded_string).map_err(|e| RecordError::InvalidString(e.utf8_error()))
+}
+
+// Like `decode_string`, borrowing from `encoded_string`. The checksum is
+// only validated if `verify`.
+pub(crate) fn decode_str(
+    encoded_string: &[u8],
+    encoded_checksum: [u8; CRC32_SIZE],
+    verify: bool,
+) -> Result<&str, RecordError> {
+    if verify {
+        let found = u32::from_be_bytes(encoded_checksum);
+        let expected = crc32fast::hash(encoded_string);
+        if found != expected {
+            return Err(RecordError::InvalidChecksum { expected, found });
+        }
+    }
+    core::str::from_utf8(encoded_string).map_err(RecordError::InvalidString)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn record_roundtrip_test() {
+        let mut buffer = Vec::new();
+        encode_string(&mut buffer, "foo");
+        encode_deletion(&mut buffer);
+        assert_eq!(
+            buffer.len(),
+            ENCODED_LEN_SIZE + 3 + CRC32_SIZE + ENCODED_LEN_SIZE
+        );
+
+        let (len, rest) = buffer.split_at(ENCODED_LEN_SIZE);
+        assert_eq!(decode_len(len.try_into().unwrap()), EncodedLen::Len(3));
+
+        let (s, rest) = rest.split_at(3);
+        let (checksum, rest) = rest.split_at(CRC32_SIZE);
+        let checksum: [u8; CRC32_SIZE] = checksum.try_into().unwrap();
+        assert_eq!(decode_string(s.to_vec(), checksum), Ok("foo".to_string()));
+        assert!(matches!(
+            decode_string(b"bar".to_vec(), checksum),
+            Err(RecordError::InvalidChecksum { .. })
+        ));
+
+        assert_eq!(decode_len(rest.try_into().unwrap()), EncodedLen::Tombstone);
+    }
+}
@@ -0,0 +1,184 @@
+// Progress of replaying segments while opening a database.
+
+use std::fmt;
+use std::ops::ControlFlow;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use super::clock::{elapsed, SharedClock};
+use super::error::SegmentError;
+
+// Listeners are called at most once per this many bytes replayed.
+const REPORT_BYTES: u64 = 1 << 20;
+
+/// How far opening a database got, see [`Options::recovery_listeners`](crate::Options::recovery_listeners).
+///
+/// Bytes covered by an index checkpoint count as done without being read,
+/// so progress can jump ahead.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecoveryProgress {
+    pub bytes_done: u64,
+    /// Size of all segments.
+    pub bytes_total: u64,
+    pub elapsed: Duration,
+}
+
+impl RecoveryProgress {
+    /// Time left at the average rate so far, once there is one.
+    pub fn eta(&self) -> Option<Duration> {
+        if self.bytes_done == 0 {
+            return None;
+        }
+        let left = self.bytes_total.saturating_sub(self.bytes_done);
+        Some(self.elapsed.mul_f64(left as f64 / self.bytes_done as f64))
+    }
+}
+
+/// Receives [`RecoveryProgress`] while the database opens, on the opening
+/// thread. Returning [`ControlFlow::Break`] cancels opening, which then
+/// fails with [`ErrorKind::Cancelled`](crate::ErrorKind::Cancelled) and
+/// leaves the files as they were.
+pub trait RecoveryListener: Send + Sync {
+    fn on_progress(&self, progress: &RecoveryProgress) -> ControlFlow<()>;
+}
+
+impl<F: Fn(&RecoveryProgress) -> ControlFlow<()> + Send + Sync> RecoveryListener for F {
+    fn on_progress(&self, progress: &RecoveryProgress) -> ControlFlow<()> {
+        self(progress)
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct RecoveryListeners(Vec<Arc<dyn RecoveryListener>>);
+
+impl RecoveryListeners {
+    pub fn push(&mut self, listener: impl RecoveryListener + 'static) {
+        self.0.push(Arc::new(listener));
+    }
+}
+
+impl fmt::Debug for RecoveryListeners {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "RecoveryListeners({})", self.0.len())
+    }
+}
+
+// Tracks progress across the segments replayed in turn.
+pub(crate) struct Recovery<'a> {
+    listeners: &'a RecoveryListeners,
+    clock: &'a SharedClock,
+    start: SystemTime,
+    total: u64,
+    // Size of the segments already replayed.
+    base: u64,
+    reported: u64,
+}
+
+impl Recovery<'_> {
+    pub(crate) fn new<'a>(
+        listeners: &'a RecoveryListeners,
+        clock: &'a SharedClock,
+        total: u64,
+    ) -> Recovery<'a> {
+        Recovery {
+            listeners,
+            clock,
+            start: clock.now(),
+            total,
+            base: 0,
+            reported: 0,
+        }
+    }
+
+    /// The current segment was replayed up to `offset`.
+    pub(crate) fn at(&mut self, offset: u64) -> Result<(), SegmentError> {
+        if self.base + offset >= self.reported + REPORT_BYTES {
+            self.report(self.base + offset)?;
+        }
+        Ok(())
+    }
+
+    /// The current segment, of `len` bytes, is done.
+    pub(crate) fn next_segment(&mut self, len: u64) -> Result<(), SegmentError> {
+        self.base += len;
+        if self.base > self.reported {
+            self.report(self.base)?;
+        }
+        Ok(())
+    }
+
+    fn report(&mut self, done: u64) -> Result<(), SegmentError> {
+        self.reported = done;
+        let progress = RecoveryProgress {
+            bytes_done: done,
+            bytes_total: self.total.max(done),
+            elapsed: elapsed(self.start, self.clock.now()),
+        };
+        for listener in &self.listeners.0 {
+            if listener.on_progress(&progress).is_break() {
+                return Err(SegmentError::Cancelled);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error;
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::{ErrorKind, Options, SunsetDB};
+    use tempfile::tempdir;
+
+    type TestResult = Result<(), Box<dyn Error>>;
+
+    #[test]
+    fn recovery_progress_test() -> TestResult {
+        let base_dir = tempdir()?;
+        let mut s = SunsetDB::new(base_dir.path())?;
+        let value = "v".repeat(1 << 10);
+        for i in 0..3000 {
+            s.insert(&format!("key-{}", i), &value)?;
+            if i == 1000 {
+                s.add_new_segment()?;
+            }
+        }
+        drop(s);
+
+        let reports = Arc::new(Mutex::new(Vec::new()));
+        let mut options = Options::default();
+        {
+            let reports = reports.clone();
+            options
+                .recovery_listeners
+                .push(move |p: &RecoveryProgress| {
+                    reports.lock().unwrap().push(*p);
+                    ControlFlow::Continue(())
+                });
+        }
+        SunsetDB::with_options(base_dir.path(), options.clone())?;
+
+        let reports = reports.lock().unwrap();
+        let last = reports.last().ok_or("no progress")?;
+        assert_eq!(last.bytes_done, last.bytes_total);
+        assert!(reports.len() > 2);
+        assert!(reports
+            .windows(2)
+            .all(|w| w[0].bytes_done < w[1].bytes_done));
+
+        // Cancelling on the first report.
+        options.recovery_listeners = RecoveryListeners::default();
+        options
+            .recovery_listeners
+            .push(|_: &RecoveryProgress| ControlFlow::Break(()));
+        let e = SunsetDB::with_options(base_dir.path(), options).err();
+        assert_eq!(e.map(|e| e.kind()), Some(ErrorKind::Cancelled));
+        assert_eq!(
+            SunsetDB::new(base_dir.path())?.get("key-2999")?,
+            Some(value)
+        );
+        Ok(())
+    }
+}
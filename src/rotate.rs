@@ -0,0 +1,145 @@
+// Size-based rotation: once the active segment reaches
+// `Options::max_segment_size`, it is sealed and writes move to a new one.
+
+use std::fs::File;
+use std::io;
+
+use super::error::*;
+use super::events::Event;
+use super::failpoints;
+use super::logging::log_info;
+use super::{Segment, SunsetDB};
+
+impl SunsetDB {
+    // Called before every write, so that a failed rotation fails the write
+    // instead of following one that succeeded. A segment left sealed by a
+    // failure is sealed again by the next attempt.
+    pub(crate) fn maybe_rotate(&mut self) -> Result<(), Error> {
+        let (Some(max), Some(segment)) = (self.options.max_segment_size, self.segments.last_mut())
+        else {
+            return Ok(());
+        };
+        if segment.len < max.get() {
+            return Ok(());
+        }
+
+        log_info!(
+            segment_id = segment.id.0,
+            len = segment.len,
+            max_segment_size = max.get(),
+            "sealing the active segment"
+        );
+        self.seal_active()
+    }
+
+    // Seals the active segment and starts a new one.
+    pub(crate) fn seal_active(&mut self) -> Result<(), Error> {
+        self.seal_last()?;
+        let rotate = || -> io::Result<()> {
+            failpoints::fail_point!(failpoints::ROTATE);
+            Ok(())
+        };
+        rotate().map_err(|e| Error::from(e).with_path(&self.base_path))?;
+        self.add_new_segment()
+            .map_err(|e| Error::from(e).with_path(&self.base_path))
+    }
+}
+
+impl SunsetDB {
+    // Seals the active segment, leaving the database without one: the
+    // caller adds the next segment.
+    pub(crate) fn seal_last(&mut self) -> Result<(), Error> {
+        let segment = self.segments.last_mut().ok_or(SunsetDBError::NoSegments)?;
+        let checkpoint = self.options.index_checkpoint_bytes.is_some();
+        segment.seal(checkpoint).map_err(|e| segment.error(e))?;
+        self.options.listeners.emit(Event::SegmentSealed {
+            id: segment.id.0,
+            len: segment.len,
+        });
+        Ok(())
+    }
+}
+
+impl Segment {
+    // Persists the records, and a last checkpoint if `checkpoint`, then
+    // reopens the file read-only.
+    fn seal(&mut self, checkpoint: bool) -> io::Result<()> {
+        if checkpoint && self.len > self.checkpointed {
+            self.checkpoint()?;
+        } else {
+            self.file.sync_data()?;
+        }
+        self.file = File::open(&self.path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error;
+    use std::num::NonZeroU64;
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::{checkpoint, encoded_record_len, Options};
+    use tempfile::tempdir;
+
+    type TestResult = Result<(), Box<dyn Error>>;
+
+    #[test]
+    fn rotate_test() -> TestResult {
+        let base_dir = tempdir()?;
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let mut options = Options {
+            max_segment_size: NonZeroU64::new(2 * encoded_record_len("k0", Some("v"))),
+            index_checkpoint_bytes: NonZeroU64::new(1 << 20),
+            ..Default::default()
+        };
+        let listener_events = events.clone();
+        options
+            .listeners
+            .push(move |e: &Event| listener_events.lock().unwrap().push(e.clone()));
+        let mut s = SunsetDB::with_options(base_dir.path(), options.clone())?;
+
+        for i in 0..5 {
+            s.insert(&format!("k{}", i), "v")?;
+        }
+        s.delete("k0")?;
+        s.insert_sorted_batch([("k5", "v"), ("k6", "v")])?;
+        let lens: Vec<_> = s.segment_stats().iter().map(|s| s.len).collect();
+        assert_eq!(
+            lens,
+            [
+                2 * encoded_record_len("k0", Some("v")),
+                2 * encoded_record_len("k0", Some("v")),
+                3 * encoded_record_len("k0", Some("v")) + encoded_record_len("k0", None),
+            ]
+        );
+        assert!(matches!(
+            events.lock().unwrap()[..],
+            [
+                Event::SegmentCreated { id: 0 },
+                Event::SegmentSealed { id: 0, .. },
+                Event::SegmentCreated { id: 1 },
+                ..
+            ]
+        ));
+
+        // Sealed segments are checkpointed and no longer writable.
+        let sealed = checkpoint::read(&s.path_from_id(0)).ok_or("no checkpoint")?;
+        assert_eq!(sealed.watermark, lens[0]);
+        assert!(s.segments[0].file.set_len(0).is_err());
+        drop(s);
+
+        // Reopened, only the active segment is writable.
+        let mut s = SunsetDB::with_options(base_dir.path(), options)?;
+        let (active, sealed) = s.segments.split_last().ok_or("no segments")?;
+        assert!(sealed.iter().all(|s| s.file.set_len(0).is_err()));
+        active.file.set_len(active.len)?;
+        assert_eq!(s.get("k0")?, None);
+        assert_eq!(s.get("k6")?.as_deref(), Some("v"));
+        s.insert("k7", "v")?;
+        assert_eq!(s.segment_stats().len(), 4);
+        Ok(())
+    }
+}
@@ -0,0 +1,364 @@
+// Reads over ranges of keys. The index is a hash map, so every scan first
+// collects and sorts the live keys of the range.
+
+use std::collections::BinaryHeap;
+use std::ops::{Add, RangeBounds};
+use std::os::unix::fs::FileExt;
+
+use super::cancel::CancellationToken;
+use super::error::*;
+use super::record::*;
+use super::{decode_value, profiling, IndexEntry, Segment, SunsetDB};
+
+// Scans read values this many keys at a time.
+const SCAN_BATCH: usize = 256;
+
+// Largest read covering several adjacent records.
+const RUN_BYTES: u64 = 1 << 20;
+
+impl SunsetDB {
+    /// Returns the pairs within `range` for which `filter` holds, in key
+    /// order. `filter` sees values where they were read, so that only
+    /// matching ones are copied.
+    ///
+    /// Ranges are over encoded keys, e.g. `"a".."b"` or `..`.
+    pub fn scan_filtered<'r>(
+        &mut self,
+        range: impl RangeBounds<&'r str>,
+        filter: impl FnMut(&str, &str) -> bool,
+    ) -> Result<Vec<(String, String)>, Error> {
+        self.scan_filtered_with(range, filter, &CancellationToken::default())
+    }
+
+    /// Like [`scan_filtered`](SunsetDB::scan_filtered), checking `cancel`
+    /// before reading each batch of values.
+    pub fn scan_filtered_with<'r>(
+        &mut self,
+        range: impl RangeBounds<&'r str>,
+        filter: impl FnMut(&str, &str) -> bool,
+        cancel: &CancellationToken,
+    ) -> Result<Vec<(String, String)>, Error> {
+        let scan = |mut matches: Vec<_>, key: String, value| {
+            matches.push((key, value));
+            matches
+        };
+        self.scan(range, Vec::new(), filter, scan, cancel)
+    }
+
+    /// Number of live keys within `range`, without reading values.
+    pub fn count<'r>(&self, range: impl RangeBounds<&'r str>) -> u64 {
+        self.keys_in_range(range).len() as u64
+    }
+
+    /// Folds the live pairs within `range` in key order, reading one value
+    /// at a time.
+    pub fn fold<'r, B>(
+        &mut self,
+        range: impl RangeBounds<&'r str>,
+        init: B,
+        f: impl FnMut(B, &str, &str) -> B,
+    ) -> Result<B, Error> {
+        self.fold_with(range, init, f, &CancellationToken::default())
+    }
+
+    /// Like [`fold`](SunsetDB::fold), checking `cancel` before reading each
+    /// batch of values.
+    pub fn fold_with<'r, B>(
+        &mut self,
+        range: impl RangeBounds<&'r str>,
+        init: B,
+        mut f: impl FnMut(B, &str, &str) -> B,
+        cancel: &CancellationToken,
+    ) -> Result<B, Error> {
+        let scan = |acc, key: String, value: String| f(acc, &key, &value);
+        self.scan(range, init, |_, _| true, scan, cancel)
+    }
+
+    /// Sums the values within `range` as parsed by `parser`, skipping the
+    /// ones it returns `None` for.
+    pub fn sum_values<'r, T: Default + Add<Output = T>>(
+        &mut self,
+        range: impl RangeBounds<&'r str>,
+        mut parser: impl FnMut(&str) -> Option<T>,
+    ) -> Result<T, Error> {
+        self.fold(range, T::default(), |sum, _, value| match parser(value) {
+            Some(n) => sum + n,
+            None => sum,
+        })
+    }
+
+    // Folds the live pairs within `range` for which `filter` holds, in key
+    // order.
+    fn scan<'r, B>(
+        &mut self,
+        range: impl RangeBounds<&'r str>,
+        init: B,
+        mut filter: impl FnMut(&str, &str) -> bool,
+        mut f: impl FnMut(B, String, String) -> B,
+        cancel: &CancellationToken,
+    ) -> Result<B, Error> {
+        self.check_fork()?;
+        let deadline = self.deadline();
+        let mut acc = init;
+        let keys = self.keys_in_range(range);
+        for chunk in keys.chunks(SCAN_BATCH) {
+            cancel.check()?;
+            let keys = chunk.iter().map(|(_, key)| key.clone()).collect();
+            for (_, key, value) in self.read_batch_filtered(keys, &mut filter)? {
+                deadline.check(&self.options.clock)?;
+                acc = f(acc, key, value);
+            }
+        }
+        deadline.check(&self.options.clock)?;
+        Ok(acc)
+    }
+
+    // Reads the current values of `keys`, skipping absent ones, along with
+    // the index of the segment holding them. Records adjacent on disk, e.g.
+    // written by a sorted batch, are read together.
+    pub(crate) fn read_batch(
+        &mut self,
+        keys: Vec<String>,
+    ) -> Result<Vec<(usize, String, String)>, Error> {
+        self.read_batch_filtered(keys, |_, _| true)
+    }
+
+    // Like `read_batch`, only keeping the pairs for which `keep` holds.
+    fn read_batch_filtered(
+        &mut self,
+        keys: Vec<String>,
+        mut keep: impl FnMut(&str, &str) -> bool,
+    ) -> Result<Vec<(usize, String, String)>, Error> {
+        let mut located = Vec::new();
+        for key in keys {
+            if let Some(i) = self
+                .segments
+                .iter()
+                .rposition(|s| s.index.contains_key(&key))
+            {
+                let entry = self.segments[i].index[&key];
+                located.push((i, entry, key, self.sample_read()));
+            }
+        }
+
+        let mut order: Vec<_> = (0..located.len()).collect();
+        order.sort_unstable_by_key(|&j| (located[j].0, located[j].1.offset));
+        let mut values = vec![None; located.len()];
+        let mut run_start = 0;
+        while run_start < order.len() {
+            // Extend the run while records follow each other in a segment.
+            let first = &located[order[run_start]];
+            let mut run_end = run_start + 1;
+            while let Some(&next) = order.get(run_end) {
+                let (i, entry, key, _) = &located[order[run_end - 1]];
+                let end = entry.offset + entry.record_len(key);
+                let (next_i, next_entry, ..) = &located[next];
+                if next_i != i || next_entry.offset != end || end - first.1.offset >= RUN_BYTES {
+                    break;
+                }
+                run_end += 1;
+            }
+
+            let run = &order[run_start..run_end];
+            let records: Vec<_> = run
+                .iter()
+                .map(|&j| (located[j].2.as_str(), located[j].1, located[j].3))
+                .collect();
+            let run_values = match self.segments[first.0].read_run(&records, &mut keep) {
+                Ok(run_values) => run_values,
+                Err(e) => {
+                    self.note_read_error(&e);
+                    return Err(e);
+                }
+            };
+            for (&j, value) in run.iter().zip(run_values) {
+                values[j] = value;
+            }
+            run_start = run_end;
+        }
+
+        Ok(located
+            .into_iter()
+            .zip(values)
+            .filter_map(|((i, _, key, _), value)| Some((i, key, value?)))
+            .collect())
+    }
+
+    // Live keys within `range` in ascending order, along with the index of
+    // the newest segment holding them.
+    pub(crate) fn keys_in_range<'r>(
+        &self,
+        range: impl RangeBounds<&'r str>,
+    ) -> Vec<(usize, String)> {
+        let mut keys: Vec<_> = self
+            .live_keys()
+            .into_iter()
+            .filter(|(_, key)| range.contains(&key.as_str()))
+            .collect();
+        keys.sort_unstable_by(|(_, a), (_, b)| a.cmp(b));
+        keys
+    }
+
+    // The first `n` live keys within `range`, in ascending order. Goes
+    // through every key, but only holds `n` of them.
+    pub(crate) fn first_keys_in_range<'r>(
+        &self,
+        range: impl RangeBounds<&'r str>,
+        n: usize,
+    ) -> Vec<String> {
+        // The largest of the keys kept is on top, to be replaced first.
+        let mut first = BinaryHeap::with_capacity(n);
+        for (i, s) in self.segments.iter().enumerate() {
+            for key in s.index.keys().map(String::as_str) {
+                let newer = &self.segments[i + 1..];
+                if !range.contains(&key) || newer.iter().any(|s| s.index.contains_key(key)) {
+                    continue;
+                }
+                if first.len() < n {
+                    first.push(key);
+                } else if first.peek().is_some_and(|&largest| key < largest) {
+                    first.pop();
+                    first.push(key);
+                }
+            }
+        }
+        first
+            .into_sorted_vec()
+            .into_iter()
+            .map(str::to_string)
+            .collect()
+    }
+}
+
+impl Segment {
+    // Reads the values of `records`, which are adjacent and sorted by
+    // offset, with a single positional read. Values are verified when their
+    // flag is set, and only copied out of the read if `keep` holds.
+    fn read_run(
+        &self,
+        records: &[(&str, IndexEntry, bool)],
+        keep: &mut impl FnMut(&str, &str) -> bool,
+    ) -> Result<Vec<Option<String>>, Error> {
+        let (Some(first), Some(last)) = (records.first(), records.last()) else {
+            return Ok(Vec::new());
+        };
+        let start = first.1.offset;
+        let end = last.1.offset + last.1.record_len(last.0);
+        if end > self.len {
+            return Err(self.error(GetError::LengthMismatch).with_offset(start));
+        }
+        let len = usize::try_from(end - start).map_err(|e| self.error(ReadError::from(e)))?;
+        let mut buffer = vec![0; len];
+        profiling::io(1, len as u64, 0);
+        self.file
+            .read_exact_at(&mut buffer, start)
+            .map_err(|e| self.error(ReadError::from(e)).with_offset(start))?;
+
+        records
+            .iter()
+            .map(|&(key, entry, verify)| {
+                let at =
+                    (entry.offset - start) as usize + ENCODED_LEN_SIZE + key.len() + CRC32_SIZE;
+                let encoded =
+                    buffer.get(at..at + ENCODED_LEN_SIZE + entry.value_len as usize + CRC32_SIZE);
+                let value = match encoded {
+                    Some(encoded) => decode_value(encoded, entry, verify),
+                    None => Err(GetError::LengthMismatch),
+                };
+                let value =
+                    value.map_err(|e| self.error(e).with_offset(entry.offset).with_key(key))?;
+                Ok(keep(key, value).then(|| value.to_string()))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error;
+
+    use super::*;
+    use crate::ErrorKind;
+    use tempfile::tempdir;
+
+    type TestResult = Result<(), Box<dyn Error>>;
+
+    #[test]
+    fn scan_filtered_test() -> TestResult {
+        let base_dir = tempdir()?;
+        let mut s = SunsetDB::new(base_dir.path())?;
+        for (key, value) in [("a", "1"), ("b", "22"), ("c", "3"), ("d", "44"), ("e", "5")] {
+            s.insert(key, value)?;
+        }
+        s.add_new_segment()?;
+        s.insert("b", "2")?;
+        s.delete("d")?;
+
+        let all = s.scan_filtered(.., |_, _| true)?;
+        let keys: Vec<_> = all.iter().map(|(k, _)| k.as_str()).collect();
+        assert_eq!(keys, ["a", "b", "c", "e"]);
+
+        let odd = s.scan_filtered("b".."e", |_, v| v.parse::<u64>().is_ok_and(|n| n % 2 == 1))?;
+        assert_eq!(odd, [("c".to_string(), "3".to_string())]);
+        assert_eq!(s.scan_filtered("x".., |_, _| true)?, []);
+        Ok(())
+    }
+
+    #[test]
+    fn range_aggregates_test() -> TestResult {
+        let base_dir = tempdir()?;
+        let mut s = SunsetDB::new(base_dir.path())?;
+        for (key, value) in [("m:1", "10"), ("m:2", "x"), ("m:3", "5"), ("n:1", "7")] {
+            s.insert(key, value)?;
+        }
+
+        assert_eq!(s.count("m:".."m;"), 3);
+        assert_eq!(s.count(..), 4);
+        assert_eq!(s.sum_values("m:".."m;", |v| v.parse::<u64>().ok())?, 15);
+        let keys = s.fold(.., String::new(), |acc, k, _| acc + k)?;
+        assert_eq!(keys, "m:1m:2m:3n:1");
+        Ok(())
+    }
+
+    #[test]
+    fn read_batch_test() -> TestResult {
+        let base_dir = tempdir()?;
+        let mut s = SunsetDB::new(base_dir.path())?;
+        let values: Vec<_> = (0..10)
+            .map(|i| (format!("k{}", i), "v".repeat(i)))
+            .collect();
+        s.insert_sorted_batch(values.iter().map(|(k, v)| (k.as_str(), v.as_str())))?;
+        s.add_new_segment()?;
+        s.insert("k3", "new")?;
+        s.delete("k5")?;
+
+        let keys = ["k9", "k3", "missing", "k5", "k0", "k1", "k2"];
+        let batch = s.read_batch(keys.iter().map(|k| k.to_string()).collect())?;
+        let pairs: Vec<_> = batch
+            .iter()
+            .map(|(_, k, v)| (k.as_str(), v.as_str()))
+            .collect();
+        assert_eq!(
+            pairs,
+            [
+                ("k9", "vvvvvvvvv"),
+                ("k3", "new"),
+                ("k0", ""),
+                ("k1", "v"),
+                ("k2", "vv")
+            ]
+        );
+        assert_eq!(batch[1].0, 1);
+
+        // Corrupt the last byte of the value of `k2`, in the middle of a run.
+        let entry = s.segments[0].index["k2"];
+        let at = entry.offset + entry.record_len("k2") - CRC32_SIZE as u64 - 1;
+        s.segments[0].file.write_all_at(b"x", at)?;
+        let e = s
+            .read_batch(vec!["k1".to_string(), "k2".to_string()])
+            .unwrap_err();
+        assert_eq!((e.kind(), e.key()), (ErrorKind::Corruption, Some("k2")));
+        assert!(s.verify_all_reads);
+        Ok(())
+    }
+}
@@ -0,0 +1,234 @@
+// An async scan, behind the `async` feature. Reads are blocking, so they
+// run on a thread of their own, which locks the database for one batch at a
+// time and sends batches through a bounded channel: polling never blocks, and
+// the thread stops reading ahead while the channel is full.
+
+use std::ops::RangeBounds;
+use std::pin::Pin;
+use std::sync::mpsc::{self, Receiver, SyncSender, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::thread;
+
+use futures_core::Stream as AsyncStream;
+
+use super::error::*;
+use super::{CancellationToken, Cursor, SunsetDB};
+
+/// A [`futures_core::Stream`] of batches of pairs in key order, see
+/// [`SunsetDB::scan_stream`].
+pub struct ScanStream {
+    batches: Receiver<Message>,
+    waker: Arc<Mutex<Option<Waker>>>,
+    // Stops the reading thread once the stream is dropped.
+    cancel: CancellationToken,
+    // Token of the last batch returned, not of the ones read ahead.
+    token: String,
+    done: bool,
+}
+
+enum Message {
+    // A batch, and the cursor token after it.
+    Batch(Vec<(String, String)>, String),
+    Failed(Error),
+    Done,
+}
+
+impl SunsetDB {
+    /// Streams the live pairs within `range` in batches of up to
+    /// `batch_pairs`, like a [`Cursor`]. The stream ends after
+    /// the last batch or after an error.
+    ///
+    /// Batches are read on a thread of their own, locking `db` while reading
+    /// each, up to `read_ahead` batches ahead of the stream. Dropping the
+    /// stream stops the thread after the batch it is reading.
+    pub fn scan_stream<'r>(
+        db: &Arc<Mutex<SunsetDB>>,
+        range: impl RangeBounds<&'r str>,
+        batch_pairs: usize,
+        read_ahead: usize,
+    ) -> Result<ScanStream, Error> {
+        let mut cursor = Cursor::new(range);
+        let token = cursor.save_token();
+        let (sender, batches) = mpsc::sync_channel(read_ahead.max(1));
+        let waker = Arc::new(Mutex::new(None));
+        let cancel = CancellationToken::new();
+
+        let db = db.clone();
+        let batch_pairs = batch_pairs.max(1);
+        let thread_waker = waker.clone();
+        let thread_cancel = cancel.clone();
+        thread::Builder::new()
+            .name("sunset-scan".to_string())
+            .spawn(move || loop {
+                let message = match db.lock() {
+                    Ok(mut db) => {
+                        match cursor.next_batch_with(&mut db, batch_pairs, &thread_cancel) {
+                            Ok(batch) if batch.is_empty() => Message::Done,
+                            Ok(batch) => Message::Batch(batch, cursor.save_token()),
+                            Err(e) => Message::Failed(e),
+                        }
+                    }
+                    Err(_) => Message::Failed(SunsetDBError::Poisoned.into()),
+                };
+                let last = !matches!(message, Message::Batch(..));
+                if send(&sender, &thread_waker, message).is_err() || last {
+                    break;
+                }
+            })
+            .map_err(SunsetDBError::from)?;
+
+        Ok(ScanStream {
+            batches,
+            waker,
+            cancel,
+            token,
+            done: false,
+        })
+    }
+}
+
+// Blocks while the channel is full, then wakes the task polling the stream.
+fn send(
+    sender: &SyncSender<Message>,
+    waker: &Mutex<Option<Waker>>,
+    message: Message,
+) -> Result<(), mpsc::SendError<Message>> {
+    sender.send(message)?;
+    if let Some(waker) = waker.lock().ok().and_then(|mut waker| waker.take()) {
+        waker.wake();
+    }
+    Ok(())
+}
+
+impl ScanStream {
+    /// Where the stream stands, after the last batch it returned, see
+    /// [`Cursor::save_token`].
+    pub fn save_token(&self) -> String {
+        self.token.clone()
+    }
+}
+
+impl AsyncStream for ScanStream {
+    type Item = Result<Vec<(String, String)>, Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let stream = self.get_mut();
+        if stream.done {
+            return Poll::Ready(None);
+        }
+        // NOTE: Register before receiving, so a batch sent in between wakes us.
+        if let Ok(mut waker) = stream.waker.lock() {
+            *waker = Some(cx.waker().clone());
+        }
+
+        match stream.batches.try_recv() {
+            Ok(Message::Batch(batch, token)) => {
+                stream.token = token;
+                Poll::Ready(Some(Ok(batch)))
+            }
+            Ok(Message::Failed(e)) => {
+                stream.done = true;
+                Poll::Ready(Some(Err(e)))
+            }
+            Ok(Message::Done) => {
+                stream.done = true;
+                Poll::Ready(None)
+            }
+            Err(TryRecvError::Empty) => Poll::Pending,
+            // The thread panicked.
+            Err(TryRecvError::Disconnected) => {
+                stream.done = true;
+                Poll::Ready(Some(Err(SunsetDBError::Poisoned.into())))
+            }
+        }
+    }
+}
+
+impl Drop for ScanStream {
+    fn drop(&mut self) {
+        self.cancel.cancel();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error;
+    use std::task::Wake;
+    use std::thread::Thread;
+
+    use super::*;
+    use tempfile::tempdir;
+
+    type TestResult = Result<(), Box<dyn Error>>;
+
+    struct ThreadWaker(Thread);
+
+    impl Wake for ThreadWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    // Polls `stream` once, and until it is ready if `wait`.
+    fn poll(
+        stream: &mut ScanStream,
+        wait: bool,
+    ) -> Poll<Option<Result<Vec<String>, crate::Error>>> {
+        let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+        let mut cx = Context::from_waker(&waker);
+        loop {
+            match Pin::new(&mut *stream).poll_next(&mut cx) {
+                Poll::Pending if wait => thread::park(),
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(batch) => {
+                    let keys =
+                        |batch: Vec<(String, String)>| batch.into_iter().map(|(k, _)| k).collect();
+                    return Poll::Ready(batch.map(|batch| batch.map(keys)));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn scan_stream_test() -> TestResult {
+        let base_dir = tempdir()?;
+        let mut s = SunsetDB::new(base_dir.path())?;
+        for i in 0..5 {
+            s.insert(&format!("k{}", i), "v")?;
+        }
+        let db = Arc::new(Mutex::new(s));
+
+        // Polling doesn't wait for the database.
+        let guard = db.lock().map_err(|_| "poisoned")?;
+        let mut stream = SunsetDB::scan_stream(&db, "k1".., 2, 1)?;
+        assert!(poll(&mut stream, false).is_pending());
+        drop(guard);
+
+        let mut batches = Vec::new();
+        while let Poll::Ready(Some(batch)) = poll(&mut stream, true) {
+            batches.push(batch?);
+            if batches.len() == 1 {
+                let token = stream.save_token();
+                let mut s = db.lock().map_err(|_| "poisoned")?;
+                let mut cursor = s.resume_from_token(&token)?;
+                let keys: Vec<_> = cursor
+                    .next_batch(&mut s, 1)?
+                    .into_iter()
+                    .map(|(k, _)| k)
+                    .collect();
+                assert_eq!(keys, ["k3"]);
+            }
+        }
+        assert_eq!(batches, [vec!["k1", "k2"], vec!["k3", "k4"]]);
+        assert!(matches!(poll(&mut stream, false), Poll::Ready(None)));
+
+        // Dropping a stream stops its thread, even with the channel full.
+        let stream = SunsetDB::scan_stream(&db, .., 1, 1)?;
+        drop(stream);
+        while Arc::strong_count(&db) > 1 {
+            thread::yield_now();
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,384 @@
+use std::fs::{read_dir, File};
+use std::io::{self, BufReader, Read};
+use std::num::NonZeroU64;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime};
+
+use super::cancel::CancellationToken;
+use super::check::{check_records, IssueKind};
+use super::clock::SharedClock;
+use super::compact::Compactor;
+use super::error::Error;
+use super::events::{Event, EventListeners};
+use super::logging::log_warn;
+use super::rate_limit::{RateLimiter, RateLimits};
+use super::{SegmentID, SunsetDB, SEGMENT_EXT};
+
+// Pause between two passes over all sealed segments.
+const PASS_INTERVAL: Duration = Duration::from_secs(60);
+
+// Reads are split so that each one sleeps for at most about this long,
+// keeping `stop` responsive at low rates.
+const READS_PER_SEC: u64 = 10;
+
+/// Background thread re-reading every sealed segment, i.e. all but the
+/// newest one, at a bounded byte rate and raising
+/// [`Event::CorruptionDetected`] for every invalid record.
+///
+/// Started through [`Options::scrub_bytes_per_sec`](crate::Options::scrub_bytes_per_sec)
+/// and stopped when the database is dropped.
+pub(crate) struct Scrubber {
+    stop: Arc<AtomicBool>,
+    paused: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+    pid: u32,
+}
+
+impl Scrubber {
+    pub(crate) fn start(
+        base_path: &Path,
+        bytes_per_sec: NonZeroU64,
+        listeners: EventListeners,
+        paused: bool,
+        name: String,
+    ) -> io::Result<Scrubber> {
+        let stop = Arc::new(AtomicBool::new(false));
+        let paused = Arc::new(AtomicBool::new(paused));
+        let mut scrub = Scrub {
+            base_path: base_path.to_path_buf(),
+            bytes_per_sec,
+            stop: stop.clone(),
+            paused: paused.clone(),
+            listeners,
+        };
+
+        let handle = thread::Builder::new().name(name).spawn(move || {
+            while scrub.wait_while_paused() {
+                // Errors listing or reading segments are retried on the next pass.
+                let _ = scrub.pass();
+                thread::park_timeout(PASS_INTERVAL);
+            }
+        })?;
+
+        Ok(Scrubber {
+            stop,
+            paused,
+            handle: Some(handle),
+            pid: std::process::id(),
+        })
+    }
+
+    /// Suspends scrubbing before the next read, until `resume`.
+    pub(crate) fn pause(&self) {
+        self.paused.store(true, Ordering::Relaxed);
+    }
+
+    pub(crate) fn resume(&self) {
+        self.paused.store(false, Ordering::Relaxed);
+        if let Some(handle) = &self.handle {
+            handle.thread().unpark();
+        }
+    }
+
+    /// Stops the thread and waits up to `timeout` for it to exit, returning
+    /// whether it did. Otherwise it is left to exit on its own.
+    pub(crate) fn stop(mut self, timeout: Duration) -> bool {
+        self.stop.store(true, Ordering::Relaxed);
+        let Some(handle) = self.handle.take() else {
+            return true;
+        };
+        handle.thread().unpark();
+        join_within(handle, timeout)
+    }
+}
+
+/// Waits up to `timeout` for the thread to exit, returning whether it did.
+pub(crate) fn join_within(handle: JoinHandle<()>, timeout: Duration) -> bool {
+    // `JoinHandle` has no timeout: poll until the thread is done.
+    let deadline = Instant::now() + timeout;
+    while !handle.is_finished() {
+        if Instant::now() >= deadline {
+            return false;
+        }
+        thread::sleep(Duration::from_millis(1));
+    }
+    let _ = handle.join();
+    true
+}
+
+impl Drop for Scrubber {
+    fn drop(&mut self) {
+        if self.pid != std::process::id() {
+            // Forked: the thread only exists in the parent.
+            std::mem::forget(self.handle.take());
+            return;
+        }
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(handle) = self.handle.take() {
+            handle.thread().unpark();
+            let _ = handle.join();
+        }
+    }
+}
+
+impl SunsetDB {
+    /// Starts the background threads enabled in [`Options`](crate::Options)
+    /// that are not running yet. Opening the database calls this unless
+    /// [`Options::defer_background_workers`](crate::Options::defer_background_workers)
+    /// is set. None are started on a [`read_only`](crate::Options::read_only)
+    /// database.
+    pub fn start_background_workers(&mut self) -> Result<(), Error> {
+        self.check_fork()?;
+        if self.options.read_only {
+            return Ok(());
+        }
+        if let (None, Some(options)) = (&self.compactor, self.options.compaction) {
+            let name = self.options.thread_name("compactor");
+            let compactor = Compactor::start(options, name)
+                .map_err(|e| Error::from(e).with_path(&self.base_path))?;
+            self.compactor = Some(compactor);
+        }
+        if let (None, Some(rate)) = (&self.scrubber, self.options.scrub_bytes_per_sec) {
+            let scrubber = Scrubber::start(
+                &self.base_path,
+                rate,
+                self.options.listeners.clone(),
+                self.maintenance_paused,
+                self.options.thread_name("scrubber"),
+            )
+            .map_err(|e| Error::from(e).with_path(&self.base_path))?;
+            self.scrubber = Some(scrubber);
+        }
+        Ok(())
+    }
+
+    /// Suspends background work, including workers started later, until
+    /// [`resume_maintenance`](SunsetDB::resume_maintenance). Threads keep
+    /// running but stay idle, though a running compaction is still
+    /// installed.
+    pub fn pause_maintenance(&mut self) {
+        self.maintenance_paused = true;
+        if let Some(scrubber) = &self.scrubber {
+            scrubber.pause();
+        }
+    }
+
+    pub fn resume_maintenance(&mut self) {
+        self.maintenance_paused = false;
+        if let Some(scrubber) = &self.scrubber {
+            scrubber.resume();
+        }
+    }
+
+    /// Stops every background thread, waiting up to `timeout` for them to
+    /// exit. Returns `false` if one was still running by then: it is left
+    /// to exit on its own. Workers can be started again afterwards.
+    ///
+    /// Dropping the database also stops them, waiting as long as needed.
+    pub fn shutdown(&mut self, timeout: Duration) -> bool {
+        let scrubber = self
+            .scrubber
+            .take()
+            .map_or(true, |scrubber| scrubber.stop(timeout));
+        let compactor = self
+            .compactor
+            .take()
+            .map_or(true, |compactor| compactor.stop(timeout));
+        scrubber && compactor
+    }
+}
+
+struct Scrub {
+    base_path: PathBuf,
+    bytes_per_sec: NonZeroU64,
+    stop: Arc<AtomicBool>,
+    paused: Arc<AtomicBool>,
+    listeners: EventListeners,
+}
+
+impl Scrub {
+    fn stopped(&self) -> bool {
+        self.stop.load(Ordering::Relaxed)
+    }
+
+    // Returns once not paused, or `false` once stopped.
+    fn wait_while_paused(&self) -> bool {
+        wait_while_paused(&self.stop, &self.paused)
+    }
+
+    fn pass(&mut self) -> io::Result<()> {
+        let mut segments = Vec::new();
+        for entry in read_dir(&self.base_path)? {
+            let path = entry?.path();
+            if path.extension().and_then(|e| e.to_str()) != Some(SEGMENT_EXT) {
+                continue;
+            }
+            if let Ok(id) = SegmentID::try_from(path.as_path()) {
+                segments.push((id.0, path));
+            }
+        }
+        segments.sort();
+        segments.pop(); // Still being written to.
+
+        let mut limiter = RateLimiter::new(
+            RateLimits {
+                ops_per_sec: None,
+                bytes_per_sec: Some(self.bytes_per_sec),
+                max_stall: None,
+            },
+            SystemTime::now(),
+        );
+        for (id, path) in segments {
+            if self.stopped() {
+                break;
+            }
+            self.scrub_segment(id, &path, &mut limiter)?;
+        }
+        Ok(())
+    }
+
+    fn scrub_segment(&self, id: u64, path: &Path, limiter: &mut RateLimiter) -> io::Result<()> {
+        let file = File::open(path)?;
+        let len = file.metadata()?.len();
+        let mut reader = BufReader::new(Throttled {
+            inner: file,
+            limiter,
+            // Throttles actual reads, so always on the system clock.
+            clock: SharedClock::default(),
+            chunk: (self.bytes_per_sec.get() / READS_PER_SEC).max(1) as usize,
+            stop: &self.stop,
+            paused: &self.paused,
+        });
+
+        let mut issues = Vec::new();
+        check_records(
+            id,
+            path,
+            len,
+            &mut reader,
+            &mut issues,
+            &CancellationToken::default(),
+        )?;
+        for issue in issues {
+            if let IssueKind::IOError(_) = issue.kind {
+                continue;
+            }
+            log_warn!(
+                segment_id = id,
+                offset = issue.offset,
+                issue = %issue.kind,
+                "scrubbing found an invalid record"
+            );
+            self.listeners.emit(Event::CorruptionDetected {
+                segment_id: Some(id),
+                offset: issue.offset,
+                key: None,
+            });
+        }
+        Ok(())
+    }
+}
+
+// Reads at most `chunk` bytes at a time, waiting on `limiter` after each.
+struct Throttled<'a, R> {
+    inner: R,
+    limiter: &'a mut RateLimiter,
+    clock: SharedClock,
+    chunk: usize,
+    stop: &'a AtomicBool,
+    paused: &'a AtomicBool,
+}
+
+impl<R: Read> Read for Throttled<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if !wait_while_paused(self.stop, self.paused) {
+            return Err(io::Error::new(io::ErrorKind::Other, "scrubber stopped"));
+        }
+
+        let len = buf.len().min(self.chunk);
+        let read = self.inner.read(&mut buf[..len])?;
+        let _ = self.limiter.throttle(read as u64, &self.clock, None);
+        Ok(read)
+    }
+}
+
+fn wait_while_paused(stop: &AtomicBool, paused: &AtomicBool) -> bool {
+    while paused.load(Ordering::Relaxed) && !stop.load(Ordering::Relaxed) {
+        thread::park_timeout(PASS_INTERVAL);
+    }
+    !stop.load(Ordering::Relaxed)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error;
+    use std::fs::OpenOptions;
+    use std::io::{Seek, SeekFrom, Write};
+    use std::sync::mpsc;
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::{Options, SunsetDB};
+    use tempfile::tempdir;
+
+    type TestResult = Result<(), Box<dyn Error>>;
+
+    #[test]
+    fn scrubber_test() -> TestResult {
+        let base_dir = tempdir()?;
+        let mut s = SunsetDB::new(base_dir.path())?;
+        s.insert("k", "v")?;
+        s.add_new_segment()?;
+        s.insert("j", "w")?;
+        drop(s);
+
+        // Corrupt the value of "k" in the sealed segment.
+        let mut f = OpenOptions::new()
+            .write(true)
+            .open(base_dir.path().join(format!("0.{}", SEGMENT_EXT)))?;
+        f.seek(SeekFrom::End(-(crate::record::CRC32_SIZE as i64) - 1))?;
+        f.write_all(b"X")?;
+
+        let (sender, receiver) = mpsc::channel();
+        let sender = Mutex::new(sender);
+        let mut options = Options {
+            scrub_bytes_per_sec: NonZeroU64::new(1 << 20),
+            ..Default::default()
+        };
+        options.defer_background_workers = true;
+        options.thread_name_prefix = Some("scrubtest".to_string());
+        options.listeners.push(move |e: &Event| {
+            if let Event::CorruptionDetected { .. } = e {
+                let _ = sender.lock().unwrap().send(e.clone());
+            }
+        });
+
+        let mut s = SunsetDB::with_options(base_dir.path(), options)?;
+        assert!(receiver.recv_timeout(Duration::from_millis(50)).is_err());
+        s.pause_maintenance();
+        s.start_background_workers()?;
+        assert!(receiver.recv_timeout(Duration::from_millis(50)).is_err());
+        // Threads of other tests may exit meanwhile.
+        let names: Vec<_> = read_dir("/proc/self/task")?
+            .filter_map(|task| std::fs::read_to_string(task.ok()?.path().join("comm")).ok())
+            .collect();
+        assert!(names.iter().any(|name| name.trim() == "scrubtest-scrub"));
+
+        s.resume_maintenance();
+        let event = receiver.recv_timeout(Duration::from_secs(10))?;
+        assert_eq!(
+            event,
+            Event::CorruptionDetected {
+                segment_id: Some(0),
+                offset: Some(0),
+                key: None,
+            }
+        );
+        assert!(s.shutdown(Duration::from_secs(10)));
+        assert!(s.shutdown(Duration::ZERO));
+        Ok(())
+    }
+}
@@ -0,0 +1,280 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use super::error::*;
+use super::read_check_string;
+use super::record::*;
+
+/// A single entry of a segment file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Record {
+    Insert { key: String, value: String },
+    Delete { key: String },
+}
+
+impl Record {
+    pub fn key(&self) -> &str {
+        match self {
+            Record::Insert { key, .. } | Record::Delete { key } => key,
+        }
+    }
+
+    /// Appends the encoding of the record, as stored on disk, to `buffer`.
+    pub fn encode(&self, buffer: &mut Vec<u8>) {
+        encode_string(buffer, self.key());
+        match self {
+            Record::Insert { value, .. } => encode_string(buffer, value),
+            Record::Delete { .. } => encode_deletion(buffer),
+        }
+    }
+
+    /// Decodes the record at the start of `bytes`, returning it along with
+    /// its encoded length, or `None` if `bytes` ends before the record does.
+    pub fn decode(bytes: &[u8]) -> Result<Option<(Record, usize)>, Error> {
+        let mut reader = bytes;
+        match read_record(&mut reader) {
+            Ok(record) => Ok(Some((record, bytes.len() - reader.len()))),
+            Err(ReadError::IOError(e)) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn validate(&self) -> Result<(), InsertError> {
+        match self {
+            // See `Segment::insert`.
+            Record::Insert { value, .. } if value.len() as u64 >= TOMBSTONE => {
+                Err(InsertError::ValueExceedsMaxSize)
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Builds a segment file outside of a database, e.g. in a batch job, to be
+/// attached later.
+///
+/// Records are buffered; nothing is guaranteed to be on disk before
+/// [`finish`](SegmentWriter::finish) returns.
+pub struct SegmentWriter {
+    path: PathBuf,
+    file: BufWriter<File>,
+    len: u64,
+    buffer: Vec<u8>,
+}
+
+impl SegmentWriter {
+    /// Creates a new segment file at `path`, which must not exist yet.
+    pub fn create(path: &Path) -> Result<SegmentWriter, Error> {
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(path)
+            .map_err(|e| Error::from(e).with_path(path))?;
+        Ok(SegmentWriter {
+            path: path.to_path_buf(),
+            file: BufWriter::new(file),
+            len: 0,
+            buffer: Vec::new(),
+        })
+    }
+
+    pub fn insert(&mut self, key: &str, value: &str) -> Result<(), Error> {
+        self.append(&Record::Insert {
+            key: key.to_string(),
+            value: value.to_string(),
+        })
+    }
+
+    pub fn delete(&mut self, key: &str) -> Result<(), Error> {
+        self.append(&Record::Delete {
+            key: key.to_string(),
+        })
+    }
+
+    pub fn append(&mut self, record: &Record) -> Result<(), Error> {
+        record
+            .validate()
+            .map_err(|e| Error::from(e).with_key(record.key()))?;
+
+        self.buffer.clear();
+        record.encode(&mut self.buffer);
+        self.file
+            .write_all(&self.buffer)
+            .map_err(|e| Error::from(e).with_path(&self.path))?;
+        self.len += self.buffer.len() as u64;
+        Ok(())
+    }
+
+    /// Bytes written so far.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Flushes the segment to disk, returning its path.
+    pub fn finish(self) -> Result<PathBuf, Error> {
+        let path = self.path;
+        self.file
+            .into_inner()
+            .map_err(|e| e.into_error())
+            .and_then(|f| f.sync_all())
+            .map_err(|e| Error::from(e).with_path(&path))?;
+        Ok(path)
+    }
+}
+
+/// Iterates over the records of a segment file in the order they were
+/// written, along with their offset. Every checksum is validated.
+pub struct SegmentReader {
+    path: PathBuf,
+    reader: BufReader<File>,
+    offset: u64,
+    len: u64,
+}
+
+impl SegmentReader {
+    pub fn open(path: &Path) -> Result<SegmentReader, Error> {
+        let open = || -> io::Result<_> {
+            let file = File::open(path)?;
+            let len = file.metadata()?.len();
+            Ok((BufReader::new(file), len))
+        };
+        let (reader, len) = open().map_err(|e| Error::from(e).with_path(path))?;
+
+        Ok(SegmentReader {
+            path: path.to_path_buf(),
+            reader,
+            offset: 0,
+            len,
+        })
+    }
+
+    // Only reads the records within `start..end`, `start` being the offset
+    // of a record.
+    pub(crate) fn open_range(path: &Path, start: u64, end: u64) -> Result<SegmentReader, Error> {
+        let mut reader = SegmentReader::open(path)?;
+        reader
+            .reader
+            .seek(SeekFrom::Start(start))
+            .map_err(|e| Error::from(e).with_path(path))?;
+        reader.offset = start;
+        reader.len = end;
+        Ok(reader)
+    }
+}
+
+impl Iterator for SegmentReader {
+    type Item = Result<(u64, Record), Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.offset >= self.len {
+            return None;
+        }
+
+        let offset = self.offset;
+        let mut counted = Counted {
+            inner: &mut self.reader,
+            count: 0,
+        };
+        let record = read_record(&mut counted);
+        self.offset += counted.count;
+
+        match record {
+            Ok(record) => Some(Ok((offset, record))),
+            Err(e) => {
+                // Without a valid record there is no way to find the next one.
+                self.offset = self.len;
+                Some(Err(Error::from(e)
+                    .with_path(&self.path)
+                    .with_offset(offset)))
+            }
+        }
+    }
+}
+
+struct Counted<R> {
+    inner: R,
+    count: u64,
+}
+
+impl<R: Read> Read for Counted<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.count += read as u64;
+        Ok(read)
+    }
+}
+
+fn read_record(reader: &mut impl Read) -> Result<Record, ReadError> {
+    let key = read_check_string(reader)?.ok_or(ReadError::UnexpectedTombstone)?;
+    Ok(match read_check_string(reader)? {
+        Some(value) => Record::Insert { key, value },
+        None => Record::Delete { key },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error;
+
+    use super::*;
+    use crate::{ErrorKind, SunsetDB, SEGMENT_EXT};
+    use tempfile::tempdir;
+
+    type TestResult = Result<(), Box<dyn Error>>;
+
+    #[test]
+    fn segment_writer_reader_test() -> TestResult {
+        let base_dir = tempdir()?;
+        let path = base_dir.path().join(format!("0.{}", SEGMENT_EXT));
+
+        let mut writer = SegmentWriter::create(&path)?;
+        writer.insert("k", "v")?;
+        writer.insert("j", "w")?;
+        writer.delete("k")?;
+        assert!(SegmentWriter::create(&path).is_err());
+        let len = writer.len();
+        assert_eq!(writer.finish()?, path);
+
+        // The database reads what the writer wrote, and vice versa.
+        let mut s = SunsetDB::new(base_dir.path())?;
+        assert_eq!(s.get("k")?, None);
+        assert_eq!(s.get("j")?.as_deref(), Some("w"));
+        s.insert("l", "x")?;
+
+        let records = SegmentReader::open(&path)?.collect::<Result<Vec<_>, _>>()?;
+        let offsets: Vec<u64> = records.iter().map(|(offset, _)| *offset).collect();
+        assert_eq!(offsets[3], len);
+        assert_eq!(
+            records[2].1,
+            Record::Delete {
+                key: "k".to_string()
+            }
+        );
+
+        let mut buffer = Vec::new();
+        records[3].1.encode(&mut buffer);
+        assert_eq!(
+            Record::decode(&buffer)?,
+            Some((records[3].1.clone(), buffer.len()))
+        );
+        assert_eq!(Record::decode(&buffer[..buffer.len() - 1])?, None);
+        buffer[ENCODED_LEN_SIZE] = b'X';
+        assert_eq!(
+            Record::decode(&buffer).unwrap_err().kind(),
+            ErrorKind::Corruption
+        );
+
+        // Readers stop where the segment ended when they were opened, even
+        // as the database keeps appending.
+        let reader = SegmentReader::open(&path)?;
+        s.insert("m", "y")?;
+        assert_eq!(reader.count(), records.len());
+
+        Ok(())
+    }
+}
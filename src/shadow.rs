@@ -0,0 +1,187 @@
+use super::error::{Error, ErrorKind};
+use super::key::Key;
+use super::SunsetDB;
+
+/// Mirrors every write to a secondary database, e.g. one opened with
+/// different options, to migrate online and cut over once both agree.
+///
+/// The primary stays the source of truth: its results are returned, while
+/// failures and disagreements on the secondary are only counted in
+/// [`ShadowStats`]. Writes that fail on the primary are not mirrored.
+/// Outcomes are compared rather than results: e.g. deleting a key the
+/// secondary doesn't hold leaves both without it.
+pub struct ShadowDB {
+    primary: SunsetDB,
+    secondary: SunsetDB,
+    compare_reads: bool,
+    stats: ShadowStats,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ShadowStats {
+    /// Writes or reads that failed on the secondary only, leaving it in a
+    /// different state than the primary.
+    pub secondary_errors: u64,
+    /// Reads where the secondary returned a different value.
+    pub read_mismatches: u64,
+}
+
+impl ShadowDB {
+    /// With `compare_reads`, every `get` is also served by the secondary and
+    /// compared against the primary.
+    pub fn new(primary: SunsetDB, secondary: SunsetDB, compare_reads: bool) -> ShadowDB {
+        ShadowDB {
+            primary,
+            secondary,
+            compare_reads,
+            stats: ShadowStats::default(),
+        }
+    }
+
+    pub fn stats(&self) -> ShadowStats {
+        self.stats
+    }
+
+    /// Stops mirroring, returning `(primary, secondary)`.
+    pub fn into_inner(self) -> (SunsetDB, SunsetDB) {
+        (self.primary, self.secondary)
+    }
+
+    // Runs `f` on the secondary once it succeeded on the primary. Errors of
+    // the secondary for which `same_outcome` holds are not counted.
+    fn mirror<T>(
+        &mut self,
+        f: impl Fn(&mut SunsetDB) -> Result<T, Error>,
+        same_outcome: impl Fn(&Error) -> bool,
+    ) -> Result<T, Error> {
+        let result = f(&mut self.primary)?;
+        if let Err(e) = f(&mut self.secondary) {
+            if !same_outcome(&e) {
+                self.stats.secondary_errors += 1;
+            }
+        }
+        Ok(result)
+    }
+
+    pub fn insert<K: Key + ?Sized>(&mut self, key: &K, value: &str) -> Result<(), Error> {
+        self.mirror(|db| db.insert(key, value), |_| false)
+    }
+
+    /// Like [`SunsetDB::insert_sorted_batch`], collecting `items` first to
+    /// write them to both.
+    pub fn insert_sorted_batch<'a>(
+        &mut self,
+        items: impl IntoIterator<Item = (&'a str, &'a str)>,
+    ) -> Result<u64, Error> {
+        let items: Vec<_> = items.into_iter().collect();
+        self.mirror(
+            |db| db.insert_sorted_batch(items.iter().copied()),
+            |_| false,
+        )
+    }
+
+    pub fn get<K: Key + ?Sized>(&mut self, key: &K) -> Result<Option<String>, Error> {
+        let value = self.primary.get(key)?;
+        if self.compare_reads {
+            match self.secondary.get(key) {
+                Ok(shadow_value) if shadow_value != value => self.stats.read_mismatches += 1,
+                Ok(_) => {}
+                Err(_) => self.stats.secondary_errors += 1,
+            }
+        }
+        Ok(value)
+    }
+
+    pub fn delete<K: Key + ?Sized>(&mut self, key: &K) -> Result<(), Error> {
+        // The key is gone from both either way.
+        self.mirror(|db| db.delete(key), |e| e.kind() == ErrorKind::NotFound)
+    }
+
+    /// Returns the value removed from the primary; the secondary may have
+    /// held another.
+    pub fn remove<K: Key + ?Sized>(&mut self, key: &K) -> Result<Option<String>, Error> {
+        self.mirror(|db| db.remove(key), |_| false)
+    }
+
+    pub fn force_delete<K: Key + ?Sized>(&mut self, key: &K) -> Result<(), Error> {
+        self.mirror(|db| db.force_delete(key), |_| false)
+    }
+
+    pub fn sync(&mut self) -> Result<(), Error> {
+        self.mirror(|db| db.sync(), |_| false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error;
+
+    use super::*;
+    use crate::Options;
+    use tempfile::tempdir;
+
+    type TestResult = Result<(), Box<dyn Error>>;
+
+    #[test]
+    fn shadow_db_test() -> TestResult {
+        let (primary_dir, secondary_dir) = (tempdir()?, tempdir()?);
+        let mut secondary = SunsetDB::new(secondary_dir.path())?;
+        secondary.insert("stale", "v")?;
+
+        let primary = SunsetDB::new(primary_dir.path())?;
+        let mut shadow = ShadowDB::new(primary, secondary, true);
+        shadow.insert("k", "v")?;
+        assert_eq!(shadow.get("k")?.as_deref(), Some("v"));
+        assert_eq!(shadow.get("stale")?, None);
+        assert_eq!(shadow.remove("k")?.as_deref(), Some("v"));
+        assert!(shadow.delete("k").is_err());
+
+        assert_eq!(
+            shadow.stats(),
+            ShadowStats {
+                secondary_errors: 0,
+                read_mismatches: 1,
+            }
+        );
+
+        let (_, mut secondary) = shadow.into_inner();
+        assert_eq!(secondary.get("k")?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn shadow_db_outcomes_test() -> TestResult {
+        let (primary_dir, secondary_dir) = (tempdir()?, tempdir()?);
+        let mut primary = SunsetDB::new(primary_dir.path())?;
+        primary.insert("old", "v")?;
+
+        let secondary = SunsetDB::new(secondary_dir.path())?;
+        let mut shadow = ShadowDB::new(primary, secondary, true);
+        // Deleting a key the secondary doesn't hold yet is no divergence.
+        shadow.delete("old")?;
+        assert_eq!(shadow.insert_sorted_batch([("a", "1"), ("b", "2")])?, 2);
+        shadow.insert(&7u64, "v")?;
+        assert_eq!(shadow.get(&7u64)?.as_deref(), Some("v"));
+        shadow.force_delete("a")?;
+        shadow.sync()?;
+        assert_eq!(shadow.stats(), ShadowStats::default());
+
+        let (mut primary, mut secondary) = shadow.into_inner();
+        for db in [&mut primary, &mut secondary] {
+            assert_eq!(db.get("a")?, None);
+            assert_eq!(db.get("b")?.as_deref(), Some("2"));
+        }
+        drop(secondary);
+
+        let options = Options {
+            read_only: true,
+            ..Default::default()
+        };
+        let secondary = SunsetDB::with_options(secondary_dir.path(), options)?;
+        let mut shadow = ShadowDB::new(primary, secondary, false);
+        shadow.insert("c", "3")?;
+        shadow.delete("b")?;
+        assert_eq!(shadow.stats().secondary_errors, 2);
+        Ok(())
+    }
+}
@@ -0,0 +1,302 @@
+// `sunset soak`: a long running mixed workload, validated against an
+// in-memory model of what the database should hold.
+
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use sunset_db::{Error, ErrorKind, SunsetDB};
+
+// Operations kept to describe a divergence.
+const HISTORY: usize = 32;
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct SoakOptions {
+    pub(crate) duration: Duration,
+    /// Stops after this many operations, if set, even before `duration`.
+    pub(crate) ops: Option<u64>,
+    pub(crate) seed: u64,
+    pub(crate) keys: u64,
+    pub(crate) max_value_len: u64,
+    /// Operations between two reopens of the database.
+    pub(crate) restart_every: u64,
+    /// Operations between two full comparisons with the model.
+    pub(crate) verify_every: u64,
+}
+
+impl Default for SoakOptions {
+    fn default() -> Self {
+        SoakOptions {
+            duration: Duration::from_secs(60),
+            ops: None,
+            seed: 1,
+            keys: 10_000,
+            max_value_len: 1024,
+            restart_every: 100_000,
+            verify_every: 10_000,
+        }
+    }
+}
+
+impl SoakOptions {
+    // Parses `--name value` pairs, e.g. `--seconds 3600 --seed 7`.
+    pub(crate) fn parse(args: &[&str]) -> Result<SoakOptions, String> {
+        let mut options = SoakOptions::default();
+        for pair in args.chunks(2) {
+            let [name, value] = pair else {
+                return Err(format!("missing value for {}", pair[0]));
+            };
+            let value: u64 = value
+                .parse()
+                .map_err(|_| format!("invalid value for {}: {}", name, value))?;
+            match *name {
+                "--seconds" => options.duration = Duration::from_secs(value),
+                "--ops" => options.ops = Some(value),
+                "--seed" => options.seed = value,
+                "--keys" => options.keys = value.max(1),
+                "--max-value-len" => options.max_value_len = value,
+                "--restart-every" => options.restart_every = value.max(1),
+                "--verify-every" => options.verify_every = value.max(1),
+                _ => return Err(format!("unknown option: {}", name)),
+            }
+        }
+        Ok(options)
+    }
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct SoakReport {
+    pub(crate) ops: u64,
+    pub(crate) restarts: u64,
+    pub(crate) verifications: u64,
+}
+
+/// Why a soak run stopped early.
+#[derive(Debug)]
+pub(crate) enum SoakError {
+    Db(Error),
+    /// The database and the model disagree.
+    Divergence(Box<Divergence>),
+}
+
+impl From<Error> for SoakError {
+    fn from(e: Error) -> Self {
+        SoakError::Db(e)
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct Divergence {
+    pub(crate) op: u64,
+    pub(crate) key: String,
+    pub(crate) expected: Option<String>,
+    pub(crate) found: Option<String>,
+    pub(crate) history: Vec<String>,
+    pub(crate) segments: Vec<String>,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "divergence at op {} on key {:?}", self.op, self.key)?;
+        writeln!(f, "  expected: {:?}", self.expected)?;
+        writeln!(f, "  found:    {:?}", self.found)?;
+        writeln!(f, "last operations:")?;
+        for op in &self.history {
+            writeln!(f, "  {}", op)?;
+        }
+        writeln!(f, "segments:")?;
+        for segment in &self.segments {
+            writeln!(f, "  {}", segment)?;
+        }
+        Ok(())
+    }
+}
+
+struct Soak<'a> {
+    dir: &'a Path,
+    options: &'a SoakOptions,
+    db: SunsetDB,
+    model: HashMap<String, String>,
+    rng: u64,
+    history: VecDeque<String>,
+    report: SoakReport,
+}
+
+/// Runs the workload on `dir`, which must not hold a database, as the model
+/// starts empty.
+pub(crate) fn soak(dir: &Path, options: &SoakOptions) -> Result<SoakReport, SoakError> {
+    let mut soak = Soak {
+        dir,
+        options,
+        db: SunsetDB::new(dir)?,
+        model: HashMap::new(),
+        // xorshift64 needs a non-zero state.
+        rng: options.seed | 1,
+        history: VecDeque::with_capacity(HISTORY),
+        report: SoakReport::default(),
+    };
+
+    let start = Instant::now();
+    while start.elapsed() < options.duration
+        && options.ops.map_or(true, |ops| soak.report.ops < ops)
+    {
+        soak.step()?;
+        soak.report.ops += 1;
+        if soak.report.ops % options.verify_every == 0 {
+            soak.verify()?;
+        }
+        if soak.report.ops % options.restart_every == 0 {
+            soak.restart()?;
+        }
+    }
+    soak.restart()?;
+    Ok(soak.report)
+}
+
+impl Soak<'_> {
+    fn next(&mut self) -> u64 {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        self.rng
+    }
+
+    fn step(&mut self) -> Result<(), SoakError> {
+        let key = format!("key-{}", self.next() % self.options.keys);
+        match self.next() % 100 {
+            0..=49 => {
+                let len = self.next() % (self.options.max_value_len + 1);
+                let value = format!("{:016x}", self.next()).repeat(len as usize / 16 + 1);
+                let value = &value[..len as usize];
+                self.log(format!("insert {} ({} bytes)", key, len));
+                self.db.insert(&key, value)?;
+                self.model.insert(key, value.to_string());
+            }
+            50..=79 => {
+                self.log(format!("get {}", key));
+                let found = self.db.get(&key)?;
+                self.compare(&key, found)?;
+            }
+            80..=89 => {
+                self.log(format!("delete {}", key));
+                let deleted = match self.db.delete(&key) {
+                    Ok(()) => true,
+                    Err(e) if e.kind() == ErrorKind::NotFound => false,
+                    Err(e) => return Err(e.into()),
+                };
+                let expected = self.model.remove(&key);
+                if deleted != expected.is_some() {
+                    let found = deleted.then(|| "<deleted>".to_string());
+                    return Err(self.divergence(&key, expected, found));
+                }
+            }
+            _ => {
+                self.log(format!("remove {}", key));
+                let found = self.db.remove(&key)?;
+                let expected = self.model.remove(&key);
+                if found != expected {
+                    return Err(self.divergence(&key, expected, found));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn log(&mut self, op: String) {
+        if self.history.len() == HISTORY {
+            self.history.pop_front();
+        }
+        self.history
+            .push_back(format!("{}: {}", self.report.ops, op));
+    }
+
+    fn compare(&self, key: &str, found: Option<String>) -> Result<(), SoakError> {
+        let expected = self.model.get(key);
+        if found.as_ref() != expected {
+            return Err(self.divergence(key, expected.cloned(), found));
+        }
+        Ok(())
+    }
+
+    // Reads back every key of the keyspace, whether the model holds it or not.
+    fn verify(&mut self) -> Result<(), SoakError> {
+        let keys: Vec<String> = (0..self.options.keys)
+            .map(|i| format!("key-{}", i))
+            .collect();
+        for key in keys {
+            let expected_len = self.model.get(&key).map(|v| v.len() as u64);
+            if self.db.value_len(&key) != expected_len {
+                let found = self
+                    .db
+                    .value_len(&key)
+                    .map(|len| format!("<{} bytes>", len));
+                let expected = expected_len.map(|len| format!("<{} bytes>", len));
+                return Err(self.divergence(&key, expected, found));
+            }
+            let found = self.db.get(&key)?;
+            self.compare(&key, found)?;
+        }
+        self.report.verifications += 1;
+        Ok(())
+    }
+
+    fn restart(&mut self) -> Result<(), SoakError> {
+        self.log("restart".to_string());
+        self.db.sync()?;
+        self.db = SunsetDB::new(self.dir)?;
+        self.report.restarts += 1;
+        self.verify()
+    }
+
+    fn divergence(&self, key: &str, expected: Option<String>, found: Option<String>) -> SoakError {
+        SoakError::Divergence(Box::new(Divergence {
+            op: self.report.ops,
+            key: key.to_string(),
+            expected,
+            found,
+            history: self.history.iter().cloned().collect(),
+            segments: self
+                .db
+                .segment_stats()
+                .iter()
+                .map(|s| format!("{}: {} bytes, {} dead", s.id, s.len, s.dead_bytes))
+                .collect(),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error;
+
+    use super::*;
+    use tempfile::tempdir;
+
+    type TestResult = Result<(), Box<dyn Error>>;
+
+    #[test]
+    fn soak_test() -> TestResult {
+        let base_dir = tempdir()?;
+        let options = SoakOptions::parse(&[
+            "--ops",
+            "5000",
+            "--keys",
+            "100",
+            "--max-value-len",
+            "64",
+            "--restart-every",
+            "1000",
+            "--verify-every",
+            "500",
+        ])?;
+        let report = soak(base_dir.path(), &options).map_err(|e| format!("{:?}", e))?;
+        assert_eq!(report.ops, 5000);
+        assert_eq!(report.restarts, 6);
+        assert_eq!(report.verifications, 16);
+
+        assert!(SoakOptions::parse(&["--seed"]).is_err());
+        assert!(SoakOptions::parse(&["--colour", "blue"]).is_err());
+        Ok(())
+    }
+}
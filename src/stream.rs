@@ -0,0 +1,286 @@
+// Append-only streams of byte entries, stored next to the segments in
+// `streams/<name>/`. A stream is split into chunk files named after the
+// offset of their first entry, so that retention drops whole files.
+//
+// -- (<len> || <bytes> || <checksum>)* --
+
+use std::fs::{create_dir_all, read_dir, remove_file, File, OpenOptions};
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use super::error::*;
+use super::record::*;
+use super::{read_u64_bytes, SunsetDB};
+
+const STREAMS_DIR: &str = "streams";
+const CHUNK_EXT: &str = "chunk";
+
+// Appends move to a new chunk once the active one is this large.
+const CHUNK_BYTES: u64 = 16 << 20;
+
+/// An append-only sequence of byte entries, addressed by offset, see
+/// [`SunsetDB::stream`].
+///
+/// Offsets only grow: an entry keeps its offset until retention drops it.
+/// A stream should only have one open handle at a time.
+pub struct Stream {
+    dir: PathBuf,
+    // Offset of the first entry of every chunk, oldest first. The last
+    // chunk receives appends.
+    chunks: Vec<u64>,
+    file: File,
+    end: u64,
+    chunk_bytes: u64,
+}
+
+impl SunsetDB {
+    /// Opens the stream called `name`, creating it if needed. Names are
+    /// made of ASCII letters, digits, `-` and `_`.
+    pub fn stream(&self, name: &str) -> Result<Stream, Error> {
+        self.check_fork()?;
+        let valid = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_';
+        if name.is_empty() || !name.chars().all(valid) {
+            return Err(SunsetDBError::InvalidStreamName(name.to_string()).into());
+        }
+        Stream::open(&self.base_path.join(STREAMS_DIR).join(name))
+    }
+}
+
+impl Stream {
+    fn open(dir: &Path) -> Result<Stream, Error> {
+        let error = |e: io::Error| Error::from(e).with_path(dir);
+        create_dir_all(dir).map_err(error)?;
+
+        let mut chunks = Vec::new();
+        for entry in read_dir(dir).map_err(error)? {
+            let path = entry.map_err(error)?.path();
+            if path.extension().and_then(|e| e.to_str()) != Some(CHUNK_EXT) {
+                continue;
+            }
+            if let Some(start) = path.file_stem().and_then(|s| s.to_str()?.parse().ok()) {
+                chunks.push(start);
+            }
+        }
+        chunks.sort_unstable();
+        if chunks.is_empty() {
+            chunks.push(0);
+        }
+
+        let start = *chunks.last().unwrap_or(&0);
+        let path = chunk_path(dir, start);
+        let mut file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .read(true)
+            .write(true)
+            .open(&path)
+            .map_err(|e| Error::from(e).with_path(&path))?;
+        let len = recover(&mut file).map_err(|e| e.with_path(&path))?;
+
+        Ok(Stream {
+            dir: dir.to_path_buf(),
+            chunks,
+            file,
+            end: start + len,
+            chunk_bytes: CHUNK_BYTES,
+        })
+    }
+
+    /// Offset of the oldest entry still retained.
+    pub fn start(&self) -> u64 {
+        self.chunks.first().copied().unwrap_or(0)
+    }
+
+    /// Offset of the next entry.
+    pub fn end(&self) -> u64 {
+        self.end
+    }
+
+    /// Appends `entry`, returning its offset.
+    pub fn append(&mut self, entry: &[u8]) -> Result<u64, Error> {
+        if entry.len() as u64 >= TOMBSTONE {
+            return Err(InsertError::ValueExceedsMaxSize.into());
+        }
+
+        let active = self.chunks.last().copied().unwrap_or(0);
+        if self.end - active >= self.chunk_bytes {
+            let path = chunk_path(&self.dir, self.end);
+            self.file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create_new(true)
+                .open(&path)
+                .map_err(|e| Error::from(e).with_path(&path))?;
+            self.chunks.push(self.end);
+        }
+
+        let mut buffer = Vec::with_capacity(ENCODED_LEN_SIZE + entry.len() + CRC32_SIZE);
+        encode_bytes(&mut buffer, entry);
+        let path = chunk_path(&self.dir, self.chunks.last().copied().unwrap_or(0));
+        self.file
+            .seek(SeekFrom::End(0))
+            .and_then(|_| self.file.write_all(&buffer))
+            .map_err(|e| Error::from(e).with_path(&path))?;
+
+        let offset = self.end;
+        self.end += buffer.len() as u64;
+        Ok(offset)
+    }
+
+    /// Reads up to `max_entries` entries from `offset`, which must be the
+    /// offset of an entry or [`end`](Stream::end), along with their offsets.
+    pub fn read_from(&self, offset: u64, max_entries: usize) -> Result<Vec<(u64, Vec<u8>)>, Error> {
+        if offset < self.start() || offset > self.end {
+            return Err(SunsetDBError::StreamOffsetOutOfRange {
+                offset,
+                start: self.start(),
+                end: self.end,
+            }
+            .into());
+        }
+
+        let mut entries = Vec::new();
+        let mut offset = offset;
+        let first = self.chunks.partition_point(|&start| start <= offset) - 1;
+        for (i, &start) in self.chunks.iter().enumerate().skip(first) {
+            let chunk_end = self.chunks.get(i + 1).copied().unwrap_or(self.end);
+            if entries.len() == max_entries || offset == chunk_end {
+                break;
+            }
+
+            let path = chunk_path(&self.dir, start);
+            let mut read = || -> Result<(), ReadError> {
+                let mut file = File::open(&path)?;
+                file.seek(SeekFrom::Start(offset - start))?;
+                let mut reader = BufReader::new(file);
+                while offset < chunk_end && entries.len() < max_entries {
+                    let entry = read_entry(&mut reader)?;
+                    let len = (ENCODED_LEN_SIZE + entry.len() + CRC32_SIZE) as u64;
+                    entries.push((offset, entry));
+                    offset += len;
+                }
+                Ok(())
+            };
+            read().map_err(|e| Error::from(e).with_path(&path).with_offset(offset))?;
+        }
+        Ok(entries)
+    }
+
+    /// Drops the chunks holding only entries before `offset`, returning the
+    /// new [`start`](Stream::start). Entries from `offset` on are kept, as
+    /// may be some before it.
+    pub fn truncate_before(&mut self, offset: u64) -> Result<u64, Error> {
+        while self.chunks.len() > 1 && self.chunks[1] <= offset {
+            let path = chunk_path(&self.dir, self.chunks[0]);
+            remove_file(&path).map_err(|e| Error::from(e).with_path(&path))?;
+            self.chunks.remove(0);
+        }
+        Ok(self.start())
+    }
+
+    /// Drops the oldest chunks while the stream holds more than `bytes`
+    /// besides them, see [`truncate_before`](Stream::truncate_before).
+    pub fn retain_bytes(&mut self, bytes: u64) -> Result<u64, Error> {
+        self.truncate_before(self.end.saturating_sub(bytes))
+    }
+
+    /// Flushes the entries appended so far to disk.
+    pub fn sync(&mut self) -> Result<(), Error> {
+        self.file
+            .sync_data()
+            .map_err(|e| Error::from(e).with_path(&self.dir))
+    }
+}
+
+fn chunk_path(dir: &Path, start: u64) -> PathBuf {
+    dir.join(format!("{}.{}", start, CHUNK_EXT))
+}
+
+fn read_entry(reader: &mut impl Read) -> Result<Vec<u8>, ReadError> {
+    let len = match decode_len(read_u64_bytes(reader)?) {
+        EncodedLen::Len(len) => len,
+        EncodedLen::Tombstone => return Err(ReadError::UnexpectedTombstone),
+    };
+    let mut entry = vec![0; usize::try_from(len)?];
+    reader.read_exact(&mut entry)?;
+    let mut checksum = [0; CRC32_SIZE];
+    reader.read_exact(&mut checksum)?;
+    Ok(decode_bytes(entry, checksum)?)
+}
+
+// Returns the length of the complete entries of the active chunk,
+// truncating an entry left incomplete by a crash.
+fn recover(file: &mut File) -> Result<u64, Error> {
+    let file_len = file.metadata()?.len();
+    let mut reader = BufReader::new(&mut *file);
+    let mut len = 0;
+    while len < file_len {
+        match read_entry(&mut reader) {
+            Ok(entry) => len += (ENCODED_LEN_SIZE + entry.len() + CRC32_SIZE) as u64,
+            Err(ReadError::IOError(e)) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(Error::from(e).with_offset(len)),
+        }
+    }
+    if len < file_len {
+        file.set_len(len)?;
+    }
+    Ok(len)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error;
+
+    use super::*;
+    use crate::ErrorKind;
+    use tempfile::tempdir;
+
+    type TestResult = Result<(), Box<dyn Error>>;
+
+    #[test]
+    fn stream_test() -> TestResult {
+        let base_dir = tempdir()?;
+        let s = SunsetDB::new(base_dir.path())?;
+        assert_eq!(
+            s.stream("../x").err().map(|e| e.kind()),
+            Some(ErrorKind::InvalidInput)
+        );
+
+        let mut stream = s.stream("events")?;
+        stream.chunk_bytes = 64;
+        let mut offsets = Vec::new();
+        for i in 0..10u8 {
+            offsets.push(stream.append(&vec![i; i as usize * 4])?);
+        }
+        assert_eq!(stream.read_from(stream.end(), 10)?, []);
+        let entries = stream.read_from(offsets[2], 3)?;
+        assert_eq!(
+            entries,
+            (2..5u8)
+                .map(|i| (offsets[i as usize], vec![i; i as usize * 4]))
+                .collect::<Vec<_>>()
+        );
+        assert!(stream.chunks.len() > 2);
+
+        let start = stream.truncate_before(offsets[6])?;
+        assert!(start > 0 && start <= offsets[6]);
+        let entries = stream.read_from(start, 100)?;
+        assert_eq!(entries.last().map(|(o, _)| *o), Some(offsets[9]));
+        assert_eq!(
+            stream.read_from(0, 1).unwrap_err().kind(),
+            ErrorKind::InvalidInput
+        );
+
+        // A torn entry is dropped when reopening.
+        let end = stream.end();
+        stream.file.write_all(&[0, 0, 0])?;
+        drop(stream);
+        let mut stream = s.stream("events")?;
+        assert_eq!((stream.start(), stream.end()), (start, end));
+        assert_eq!(stream.append(b"next")?, end);
+        assert_eq!(stream.read_from(end, 1)?, [(end, b"next".to_vec())]);
+
+        assert_eq!(stream.retain_bytes(0)?, *stream.chunks.last().unwrap());
+        Ok(())
+    }
+}
@@ -0,0 +1,337 @@
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::error::*;
+use super::SunsetDB;
+
+// One entry per line, fields separated by a single space:
+// -- <timestamp_us> <op> <hex(key)> <value_len> <value_crc32> --
+// Values are never stored: `-` marks fields that don't apply to `op`.
+//
+// Only operations on a single key are traced, see `SunsetDB::start_trace`.
+
+// Longest value `replay` writes, the trace is not trusted to allocate more.
+const MAX_REPLAYED_VALUE: u64 = 1 << 30;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TraceOp {
+    Insert,
+    Get,
+    Delete,
+    Remove,
+    ForceDelete,
+}
+
+impl fmt::Display for TraceOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            TraceOp::Insert => "insert",
+            TraceOp::Get => "get",
+            TraceOp::Delete => "delete",
+            TraceOp::Remove => "remove",
+            TraceOp::ForceDelete => "force_delete",
+        })
+    }
+}
+
+impl FromStr for TraceOp {
+    type Err = ();
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "insert" => Ok(TraceOp::Insert),
+            "get" => Ok(TraceOp::Get),
+            "delete" => Ok(TraceOp::Delete),
+            "remove" => Ok(TraceOp::Remove),
+            "force_delete" => Ok(TraceOp::ForceDelete),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct TraceEntry {
+    /// Microseconds since the UNIX epoch.
+    pub timestamp: u64,
+    pub op: TraceOp,
+    pub key: String,
+    pub value_len: Option<u64>,
+    pub value_hash: Option<u32>,
+}
+
+impl TraceEntry {
+    fn new(time: SystemTime, op: TraceOp, key: &str, value: Option<&str>) -> TraceEntry {
+        let timestamp = time
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_micros() as u64);
+
+        TraceEntry {
+            timestamp,
+            op,
+            key: key.to_string(),
+            value_len: value.map(|v| v.len() as u64),
+            value_hash: value.map(|v| crc32fast::hash(v.as_bytes())),
+        }
+    }
+}
+
+impl fmt::Display for TraceEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {} ", self.timestamp, self.op)?;
+        if self.key.is_empty() {
+            // Keep the field count stable for empty keys.
+            f.write_str("-")?;
+        }
+        for b in self.key.as_bytes() {
+            write!(f, "{:02x}", b)?;
+        }
+        match (self.value_len, self.value_hash) {
+            (Some(len), Some(hash)) => write!(f, " {} {:08x}", len, hash),
+            _ => write!(f, " - -"),
+        }
+    }
+}
+
+impl FromStr for TraceEntry {
+    type Err = ();
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields: Vec<_> = s.split(' ').collect();
+        let [timestamp, op, key, value_len, value_hash] = fields[..] else {
+            return Err(());
+        };
+
+        let key = match key {
+            "-" => String::new(),
+            _ => String::from_utf8(decode_hex(key).ok_or(())?).map_err(|_| ())?,
+        };
+        let (value_len, value_hash) = match (value_len, value_hash) {
+            ("-", "-") => (None, None),
+            (len, hash) => (
+                Some(len.parse().map_err(|_| ())?),
+                Some(u32::from_str_radix(hash, 16).map_err(|_| ())?),
+            ),
+        };
+
+        Ok(TraceEntry {
+            timestamp: timestamp.parse().map_err(|_| ())?,
+            op: op.parse()?,
+            key,
+            value_len,
+            value_hash,
+        })
+    }
+}
+
+fn decode_hex(s: &str) -> Option<Vec<u8>> {
+    s.as_bytes()
+        .chunks(2)
+        .map(|pair| match pair {
+            [_, _] => u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok(),
+            _ => None,
+        })
+        .collect()
+}
+
+pub(crate) struct TraceWriter {
+    writer: BufWriter<File>,
+}
+
+impl TraceWriter {
+    pub(crate) fn new(path: &Path) -> io::Result<TraceWriter> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(TraceWriter {
+            writer: BufWriter::new(file),
+        })
+    }
+
+    pub(crate) fn record(
+        &mut self,
+        time: SystemTime,
+        op: TraceOp,
+        key: &str,
+        value: Option<&str>,
+    ) -> io::Result<()> {
+        writeln!(self.writer, "{}", TraceEntry::new(time, op, key, value))
+    }
+
+    pub(crate) fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Reads back every entry of a trace recorded with [`SunsetDB::start_trace`].
+pub fn read_trace(path: &Path) -> Result<Vec<TraceEntry>, Error> {
+    let read = || -> Result<Vec<TraceEntry>, TraceError> {
+        BufReader::new(File::open(path)?)
+            .lines()
+            .enumerate()
+            .map(|(n, line)| {
+                line?
+                    .parse()
+                    .map_err(|_| TraceError::InvalidEntry { line: n + 1 })
+            })
+            .collect()
+    };
+    read().map_err(|e| Error::from(e).with_path(path))
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct ReplayReport {
+    pub inserts: u64,
+    pub gets: u64,
+    pub deletes: u64,
+    /// `get`s and `delete`s that found no key.
+    pub misses: u64,
+}
+
+/// Re-executes a trace against `db`.
+///
+/// Values are not part of the trace: each insert writes a synthetic value
+/// of the recorded length, up to 1 GiB.
+pub fn replay(trace_path: &Path, db: &mut SunsetDB) -> Result<ReplayReport, Error> {
+    let mut report = ReplayReport::default();
+
+    for (n, entry) in read_trace(trace_path)?.into_iter().enumerate() {
+        let replay_error = |e: Error| {
+            Error::from(TraceError::Replay {
+                line: n + 1,
+                source: Box::new(e),
+            })
+            .with_path(trace_path)
+        };
+
+        let result = match entry.op {
+            TraceOp::Insert => {
+                report.inserts += 1;
+                let len = entry.value_len.unwrap_or(0);
+                if len > MAX_REPLAYED_VALUE {
+                    let e = Error::from(InsertError::ValueExceedsMaxSize).with_key(&entry.key);
+                    return Err(replay_error(e));
+                }
+                db.insert(&entry.key, &"x".repeat(len as usize))
+            }
+            TraceOp::Get => {
+                report.gets += 1;
+                match db.get(&entry.key) {
+                    Ok(Some(_)) => Ok(()),
+                    Ok(None) => {
+                        report.misses += 1;
+                        Ok(())
+                    }
+                    Err(e) => Err(e),
+                }
+            }
+            TraceOp::Delete => {
+                report.deletes += 1;
+                db.delete(&entry.key)
+            }
+            TraceOp::Remove => {
+                report.deletes += 1;
+                match db.remove(&entry.key) {
+                    Ok(Some(_)) => Ok(()),
+                    Ok(None) => {
+                        report.misses += 1;
+                        Ok(())
+                    }
+                    Err(e) => Err(e),
+                }
+            }
+            TraceOp::ForceDelete => {
+                report.deletes += 1;
+                db.force_delete(&entry.key)
+            }
+        };
+
+        match result {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::NotFound => report.misses += 1,
+            Err(e) => return Err(replay_error(e)),
+        }
+    }
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error;
+
+    use super::*;
+    use tempfile::tempdir;
+
+    type TestResult = Result<(), Box<dyn Error>>;
+
+    #[test]
+    fn trace_entry_roundtrip_test() -> TestResult {
+        for entry in [
+            TraceEntry::new(UNIX_EPOCH, TraceOp::Insert, "k ey\n", Some("value")),
+            TraceEntry::new(UNIX_EPOCH, TraceOp::Get, "", None),
+            TraceEntry::new(UNIX_EPOCH, TraceOp::Delete, "ü", None),
+        ] {
+            assert_eq!(entry.to_string().parse::<TraceEntry>(), Ok(entry));
+        }
+        assert!("1 insert zz - -".parse::<TraceEntry>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn trace_record_replay_test() -> TestResult {
+        let base_dir = tempdir()?;
+        let trace_path = base_dir.path().join("trace");
+
+        {
+            let db_dir = base_dir.path().join("db");
+            std::fs::create_dir(&db_dir)?;
+            let mut s = SunsetDB::new(&db_dir)?;
+            s.start_trace(&trace_path)?;
+            s.insert("k", "value")?;
+            s.get("k")?;
+            s.delete("k")?;
+            assert_eq!(s.get("k")?, None);
+            s.stop_trace()?;
+            s.insert("untraced", "v")?;
+
+            // Batch items are traced once written.
+            s.start_trace(&base_dir.path().join("batch"))?;
+            assert!(s.insert_sorted_batch([("a", "1"), ("b", "")]).is_ok());
+            assert!(s.insert_sorted_batch([("c", "1"), ("b", "2")]).is_err());
+            s.stop_trace()?;
+        }
+        let batch = read_trace(&base_dir.path().join("batch"))?;
+        assert_eq!(
+            batch.iter().map(|e| e.key.as_str()).collect::<Vec<_>>(),
+            ["a", "b"]
+        );
+
+        let entries = read_trace(&trace_path)?;
+        assert_eq!(entries.len(), 4);
+        assert_eq!(entries[0].value_len, Some(5));
+        assert_eq!(entries[0].value_hash, Some(crc32fast::hash(b"value")));
+
+        let replay_dir = base_dir.path().join("replay");
+        std::fs::create_dir(&replay_dir)?;
+        let mut s = SunsetDB::new(&replay_dir)?;
+        let report = replay(&trace_path, &mut s)?;
+        assert_eq!(
+            report,
+            ReplayReport {
+                inserts: 1,
+                gets: 2,
+                deletes: 1,
+                misses: 1,
+            }
+        );
+
+        // Corrupt lengths don't allocate.
+        std::fs::write(
+            &trace_path,
+            format!("1 insert 6b {} 00000000\n", 1u64 << 62),
+        )?;
+        let e = replay(&trace_path, &mut s).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidInput);
+        Ok(())
+    }
+}
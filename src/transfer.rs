@@ -0,0 +1,611 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{canonicalize, copy, create_dir_all, hard_link, metadata, read_dir, File};
+use std::path::{Path, PathBuf};
+
+use super::cancel::CancellationToken;
+use super::compact::sync_dir;
+use super::error::*;
+use super::import::identity;
+use super::info::{self, LAST_BACKUP};
+use super::{Options, Quota, Record, Segment, SegmentReader, SunsetDB};
+
+/// Picks the value to keep given `(key, ours, theirs)`.
+pub type ConflictResolver = Box<dyn FnMut(&str, &str, &str) -> String>;
+
+/// Reshapes a pair on its way through an import, export or copy, e.g. to
+/// rename a prefix or re-encode values: returns the key and value to write,
+/// or `None` to drop the pair.
+pub type PairMap = Box<dyn FnMut(String, String) -> Option<(String, String)>>;
+
+/// How [`SunsetDB::absorb`] and [`SunsetDB::import`] handle keys already
+/// present in this database.
+pub enum ConflictPolicy {
+    /// Keep the value already in this database.
+    KeepExisting,
+    /// Take the value from the absorbed database.
+    Overwrite,
+    /// Store whatever the resolver returns.
+    Resolve(ConflictResolver),
+    /// Stop with an [`ErrorKind::InvalidInput`](crate::ErrorKind::InvalidInput)
+    /// error holding the key. Keys imported before it are kept.
+    Fail,
+}
+
+impl fmt::Debug for ConflictPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConflictPolicy::KeepExisting => write!(f, "KeepExisting"),
+            ConflictPolicy::Overwrite => write!(f, "Overwrite"),
+            ConflictPolicy::Resolve(_) => write!(f, "Resolve(..)"),
+            ConflictPolicy::Fail => write!(f, "Fail"),
+        }
+    }
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct AbsorbReport {
+    /// Keys written to this database, including resolved conflicts.
+    pub imported: u64,
+    /// Keys present in both databases.
+    pub conflicts: u64,
+}
+
+/// Checks run by [`SunsetDB::ingest_segment`] besides record checksums.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct IngestOptions {
+    /// Reject segments whose keys are not in ascending order.
+    pub require_sorted: bool,
+}
+
+/// Picks the output of [`SunsetDB::split`] a key goes to.
+pub enum SplitBy {
+    /// Ascending split points, one less than the number of outputs: output
+    /// `i` holds keys below point `i` and not below the previous one.
+    Range(Vec<String>),
+    /// CRC32 of the key modulo the number of outputs, which stays stable
+    /// across builds.
+    Hash,
+    /// Index of the output, as returned by the callback.
+    Route(Box<dyn FnMut(&str) -> usize>),
+}
+
+impl fmt::Debug for SplitBy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SplitBy::Range(points) => f.debug_tuple("Range").field(points).finish(),
+            SplitBy::Hash => write!(f, "Hash"),
+            SplitBy::Route(_) => write!(f, "Route(..)"),
+        }
+    }
+}
+
+impl SplitBy {
+    fn output(&mut self, key: &str, outputs: usize) -> usize {
+        match self {
+            SplitBy::Range(points) => points.partition_point(|p| p.as_str() <= key),
+            SplitBy::Hash => crc32fast::hash(key.as_bytes()) as usize % outputs,
+            SplitBy::Route(route) => route(key),
+        }
+    }
+}
+
+impl SunsetDB {
+    /// Copies every live record into a new database at `path`, re-validating
+    /// the checksums of both keys and values on the way. The copy holds no
+    /// overwritten or deleted records.
+    ///
+    /// `path` must be empty or not exist yet. Returns the number of keys copied.
+    pub fn clone_to(&mut self, path: &Path) -> Result<u64, Error> {
+        self.clone_to_with(path, identity(), &CancellationToken::default())
+    }
+
+    /// Like [`clone_to`](SunsetDB::clone_to), passing every pair through
+    /// `map`. Keys mapped to the same key overwrite each other in no
+    /// particular order. Returns the number of pairs written.
+    ///
+    /// `cancel` is checked before each key; a cancelled copy is left
+    /// incomplete at `path`.
+    pub fn clone_to_with(
+        &mut self,
+        path: &Path,
+        mut map: PairMap,
+        cancel: &CancellationToken,
+    ) -> Result<u64, Error> {
+        self.check_fork()?;
+        let mut destination = open_empty(path)?;
+
+        let mut copied = 0;
+        for (i, key) in self.live_keys() {
+            cancel.check()?;
+            let value = self.read_verified(i, &key)?;
+            if let Some((key, value)) = map(key, value) {
+                destination.insert(&key, &value)?;
+                copied += 1;
+            }
+        }
+
+        destination.sync()?;
+        // Only informational, see `info`.
+        let _ = info::write_stamp(&self.base_path, LAST_BACKUP, self.options.clock.now());
+        Ok(copied)
+    }
+
+    /// Imports every live key of the database at `other_path`, resolving
+    /// keys present in both according to `policy`. The other database is
+    /// opened read-only.
+    ///
+    /// Records carry no timestamps, so conflicts can't be resolved by age.
+    pub fn absorb(
+        &mut self,
+        other_path: &Path,
+        policy: ConflictPolicy,
+    ) -> Result<AbsorbReport, Error> {
+        self.absorb_with(other_path, policy, &CancellationToken::default())
+    }
+
+    /// Like [`absorb`](SunsetDB::absorb), checking `cancel` before each key.
+    /// Keys imported before it was cancelled stay imported.
+    pub fn absorb_with(
+        &mut self,
+        other_path: &Path,
+        mut policy: ConflictPolicy,
+        cancel: &CancellationToken,
+    ) -> Result<AbsorbReport, Error> {
+        self.check_writable()?;
+        let same_path = canonicalize(other_path)
+            .and_then(|other| Ok(other == canonicalize(&self.base_path)?))
+            .map_err(|e| Error::from(e).with_path(other_path))?;
+        if same_path {
+            return Err(Error::from(SunsetDBError::AbsorbSelf).with_path(other_path));
+        }
+
+        // Read-only, so that the other database is left as it is.
+        let options = Options {
+            read_only: true,
+            ..Default::default()
+        };
+        let mut other = SunsetDB::with_options(other_path, options)?;
+        let mut report = AbsorbReport::default();
+        for (i, key) in other.live_keys() {
+            cancel.check()?;
+            let theirs = other.segments[i]
+                .get_verified(&key)
+                .map_err(|e| self.noted(e))?;
+            let value = match self.lookup(&key)? {
+                None => theirs,
+                Some(ours) => {
+                    report.conflicts += 1;
+                    match &mut policy {
+                        ConflictPolicy::KeepExisting => continue,
+                        ConflictPolicy::Overwrite => theirs,
+                        ConflictPolicy::Resolve(resolve) => resolve(&key, &ours, &theirs),
+                        ConflictPolicy::Fail => {
+                            return Err(Error::from(SunsetDBError::KeyExists).with_key(&key))
+                        }
+                    }
+                }
+            };
+
+            self.insert(&key, &value)?;
+            report.imported += 1;
+        }
+
+        Ok(report)
+    }
+
+    /// Attaches a segment built outside of the database, e.g. with a
+    /// [`SegmentWriter`](crate::SegmentWriter), as the newest segment, so
+    /// that its records take precedence over existing ones. Every record is
+    /// validated first. Returns the ID assigned to the segment.
+    ///
+    /// Quotas and the disk space left apply to the segment as a whole. The
+    /// file is hard-linked into the database when possible, and copied
+    /// otherwise; it must not be modified afterwards. Segments carry no
+    /// format version, so files written by another version of the crate
+    /// are only checked record by record.
+    pub fn ingest_segment(&mut self, path: &Path, options: IngestOptions) -> Result<u64, Error> {
+        self.check_writable()?;
+        let mut previous_key: Option<String> = None;
+        // Whether each key is live once the segment is attached.
+        let mut live = HashMap::new();
+        for record in SegmentReader::open(path)? {
+            let (offset, record) = record?;
+            if options.require_sorted && previous_key.as_deref() > Some(record.key()) {
+                return Err(Error::from(SunsetDBError::UnsortedSegment)
+                    .with_path(path)
+                    .with_offset(offset)
+                    .with_key(record.key()));
+            }
+            previous_key = Some(record.key().to_string());
+            if let (Record::Insert { key, value }, Some(limit)) =
+                (&record, self.options.quotas.max_value_size)
+            {
+                if value.len() as u64 > limit {
+                    let quota = Quota::MaxValueSize;
+                    return Err(Error::from(InsertError::QuotaExceeded { quota, limit })
+                        .with_path(path)
+                        .with_key(key));
+                }
+            }
+            live.insert(
+                record.key().to_string(),
+                matches!(record, Record::Insert { .. }),
+            );
+        }
+
+        let len = metadata(path)
+            .map_err(|e| Error::from(e).with_path(path))?
+            .len();
+        self.check_ingest_quotas(&live, len)
+            .map_err(|e| Error::from(e).with_path(path))?;
+        self.check_disk_space(len)?;
+
+        // The ingested records must win over the active segment's.
+        self.seal_last()?;
+        let id = self.next_index;
+        let destination = self.path_from_id(id);
+        let attach = || -> std::io::Result<()> {
+            hard_link(path, &destination).or_else(|_| copy(path, &destination).map(|_| ()))?;
+            File::open(&destination)?.sync_all()?;
+            sync_dir(&self.base_path)
+        };
+        if let Err(e) = attach() {
+            self.add_new_segment()
+                .map_err(|e| Error::from(e).with_path(&self.base_path))?;
+            return Err(Error::from(e).with_path(&destination));
+        }
+        let segment = Segment::open(&destination, false, false, false, None)
+            .map_err(|e| Error::from(e).with_path(&destination))?;
+        let changes = self.attach(segment);
+        self.apply_changes(vec![changes])?;
+
+        // Keep writes away from the ingested file, which may be linked.
+        self.add_new_segment()
+            .map_err(|e| Error::from(e).with_path(&self.base_path))?;
+        Ok(id)
+    }
+
+    /// Copies every live record into one of the new databases at `paths`,
+    /// chosen by `by`; this database is left untouched. Like
+    /// [`clone_to`](Self::clone_to), every path must be empty or not exist yet.
+    ///
+    /// Returns the number of keys copied to each output.
+    pub fn split(&mut self, paths: &[PathBuf], mut by: SplitBy) -> Result<Vec<u64>, Error> {
+        self.check_fork()?;
+        if let SplitBy::Range(points) = &by {
+            if points.len() + 1 != paths.len() || points.windows(2).any(|w| w[0] >= w[1]) {
+                return Err(SunsetDBError::InvalidSplitPoints.into());
+            }
+        }
+        if paths.is_empty() {
+            return Err(SunsetDBError::InvalidSplitPoints.into());
+        }
+
+        let mut outputs = paths
+            .iter()
+            .map(|path| open_empty(path))
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut copied = vec![0; outputs.len()];
+        for (i, key) in self.live_keys() {
+            let output = by.output(&key, outputs.len());
+            let destination = outputs.get_mut(output).ok_or_else(|| {
+                Error::from(SunsetDBError::InvalidOutput {
+                    output,
+                    outputs: paths.len(),
+                })
+                .with_key(&key)
+            })?;
+
+            let value = self.read_verified(i, &key)?;
+            destination.insert(&key, &value)?;
+            copied[output] += 1;
+        }
+
+        for destination in &mut outputs {
+            destination.sync()?;
+        }
+        Ok(copied)
+    }
+}
+
+impl SunsetDB {
+    // The quotas on keys and bytes, once a segment of `len` bytes leaving
+    // `live` keys live or deleted is attached.
+    fn check_ingest_quotas(
+        &self,
+        live: &HashMap<String, bool>,
+        len: u64,
+    ) -> Result<(), InsertError> {
+        let quotas = &self.options.quotas;
+        let exceeded = |quota, limit| Err(InsertError::QuotaExceeded { quota, limit });
+
+        if let Some(limit) = quotas.max_keys {
+            let (mut added, mut removed) = (0, 0);
+            for (key, &is_live) in live {
+                match (is_live, self.contains(key)) {
+                    (true, false) => added += 1,
+                    (false, true) => removed += 1,
+                    _ => {}
+                }
+            }
+            if added > removed && self.live_keys + added - removed > limit {
+                return exceeded(Quota::MaxKeys, limit);
+            }
+        }
+
+        if let Some(limit) = quotas.max_bytes {
+            let total_bytes: u64 = self.segments.iter().map(|s| s.len).sum();
+            if total_bytes + len > limit {
+                return exceeded(Quota::MaxBytes, limit);
+            }
+        }
+
+        Ok(())
+    }
+
+    // Reads the value of `key` from segment `i`, validating the key as well.
+    fn read_verified(&mut self, i: usize, key: &str) -> Result<String, Error> {
+        self.segments[i]
+            .get_verified(key)
+            .map_err(|e| self.noted(e))
+    }
+
+    // Passes `e` through `note_read_error`, like any other read.
+    fn noted(&mut self, e: Error) -> Error {
+        self.note_read_error(&e);
+        e
+    }
+}
+
+// Opens a new database at `path`, refusing to touch existing data.
+fn open_empty(path: &Path) -> Result<SunsetDB, Error> {
+    let is_empty = create_dir_all(path)
+        .and_then(|_| read_dir(path))
+        .map(|mut entries| entries.next().is_none())
+        .map_err(|e| Error::from(e).with_path(path))?;
+
+    if !is_empty {
+        return Err(
+            Error::from(SunsetDBError::DestinationNotEmpty(path.to_path_buf())).with_path(path),
+        );
+    }
+
+    SunsetDB::new(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error;
+    use std::fs::OpenOptions;
+    use std::io::{Seek, SeekFrom, Write};
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::{ErrorKind, Event, Options, SegmentWriter, SEGMENT_EXT};
+    use tempfile::tempdir;
+
+    type TestResult = Result<(), Box<dyn Error>>;
+
+    #[test]
+    fn clone_to_test() -> TestResult {
+        let base_dir = tempdir()?;
+        let (source, destination) = (base_dir.path().join("src"), base_dir.path().join("dst"));
+        create_dir_all(&source)?;
+
+        let mut s = SunsetDB::new(&source)?;
+        s.insert("k", "v")?;
+        s.insert("k", "vv")?;
+        s.insert("j", "w")?;
+        s.insert("deleted", "x")?;
+        s.delete("deleted")?;
+
+        assert_eq!(s.clone_to(&destination)?, 2);
+        let mut copy = SunsetDB::new(&destination)?;
+        assert_eq!(copy.get("k")?.as_deref(), Some("vv"));
+        assert_eq!(copy.get("j")?.as_deref(), Some("w"));
+        assert_eq!(copy.get("deleted")?, None);
+        assert_eq!(copy.segments.len(), 1);
+        assert!(copy.segments[0].len < s.segments[0].len);
+
+        let e = s.clone_to(&destination).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidInput);
+
+        let mapped = base_dir.path().join("mapped");
+        let map: PairMap = Box::new(|key, value| (key != "j").then(|| (key + "2", value)));
+        assert_eq!(
+            s.clone_to_with(&mapped, map, &CancellationToken::default())?,
+            1
+        );
+        let mut copy = SunsetDB::new(&mapped)?;
+        assert_eq!(copy.get("k2")?.as_deref(), Some("vv"));
+        assert_eq!(copy.get("j2")?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn absorb_test() -> TestResult {
+        let (ours_dir, theirs_dir) = (tempdir()?, tempdir()?);
+        let mut theirs = SunsetDB::new(theirs_dir.path())?;
+        theirs.insert("a", "theirs")?;
+        theirs.insert("b", "theirs")?;
+        theirs.insert("c", "theirs")?;
+        drop(theirs);
+        let files = || -> std::io::Result<Vec<_>> {
+            let mut files = read_dir(theirs_dir.path())?
+                .map(|entry| {
+                    let entry = entry?;
+                    Ok((entry.file_name(), entry.metadata()?.len()))
+                })
+                .collect::<std::io::Result<Vec<_>>>()?;
+            files.sort();
+            Ok(files)
+        };
+        let before = files()?;
+
+        let policies = [
+            (ConflictPolicy::KeepExisting, "ours", 2),
+            (ConflictPolicy::Overwrite, "theirs", 3),
+            (
+                ConflictPolicy::Resolve(Box::new(|_, ours, theirs| format!("{}+{}", ours, theirs))),
+                "ours+theirs",
+                3,
+            ),
+        ];
+        for (policy, expected, imported) in policies {
+            let mut s = SunsetDB::new(ours_dir.path())?;
+            s.insert("a", "ours")?;
+            s.remove("b")?;
+
+            let report = s.absorb(theirs_dir.path(), policy)?;
+            assert_eq!(report.conflicts, 1);
+            assert_eq!(report.imported, imported);
+            assert_eq!(s.get("a")?.as_deref(), Some(expected));
+            assert_eq!(s.get("b")?.as_deref(), Some("theirs"));
+            assert_eq!(files()?, before);
+
+            s.delete("b")?;
+            s.delete("c")?;
+        }
+
+        let mut s = SunsetDB::new(ours_dir.path())?;
+        let e = s
+            .absorb(theirs_dir.path(), ConflictPolicy::Fail)
+            .unwrap_err();
+        assert_eq!((e.kind(), e.key()), (ErrorKind::InvalidInput, Some("a")));
+
+        let e = s
+            .absorb(ours_dir.path(), ConflictPolicy::Overwrite)
+            .unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidInput);
+        Ok(())
+    }
+
+    #[test]
+    fn split_test() -> TestResult {
+        let base_dir = tempdir()?;
+        let source = base_dir.path().join("src");
+        create_dir_all(&source)?;
+        let mut s = SunsetDB::new(&source)?;
+        for key in ["a", "b", "m", "n", "z"] {
+            s.insert(key, key)?;
+        }
+        s.delete("b")?;
+
+        let paths = |name: &str| -> Vec<PathBuf> {
+            (0..3)
+                .map(|i| base_dir.path().join(format!("{}{}", name, i)))
+                .collect()
+        };
+
+        let ranges = paths("range");
+        let by = SplitBy::Range(vec!["m".to_string(), "n".to_string()]);
+        assert_eq!(s.split(&ranges, by)?, vec![1, 1, 2]);
+        let mut low = SunsetDB::new(&ranges[0])?;
+        assert_eq!(low.get("a")?.as_deref(), Some("a"));
+        assert_eq!(low.get("b")?, None);
+        assert_eq!(SunsetDB::new(&ranges[1])?.get("m")?.as_deref(), Some("m"));
+
+        let copied = s.split(&paths("hash"), SplitBy::Hash)?;
+        assert_eq!(copied.iter().sum::<u64>(), 4);
+
+        let e = s
+            .split(&paths("route"), SplitBy::Route(Box::new(|_| 3)))
+            .unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidInput);
+        let e = s
+            .split(&paths("bad"), SplitBy::Range(vec!["m".to_string()]))
+            .unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidInput);
+        Ok(())
+    }
+
+    #[test]
+    fn ingest_segment_test() -> TestResult {
+        let (base_dir, staging_dir) = (tempdir()?, tempdir()?);
+        let mut s = SunsetDB::new(base_dir.path())?;
+        s.insert("a", "old")?;
+        s.insert("b", "old")?;
+
+        let path = staging_dir.path().join("batch");
+        let mut writer = SegmentWriter::create(&path)?;
+        writer.insert("a", "new")?;
+        writer.delete("b")?;
+        writer.insert("c", "new")?;
+        writer.finish()?;
+
+        let sorted = IngestOptions {
+            require_sorted: true,
+        };
+        assert_eq!(s.ingest_segment(&path, sorted)?, 1);
+        assert_eq!(s.get("a")?.as_deref(), Some("new"));
+        assert_eq!(s.get("b")?, None);
+        assert_eq!(s.get("c")?.as_deref(), Some("new"));
+        assert_eq!(s.live_keys, 2);
+
+        // Writes go to a new segment, leaving the ingested file untouched.
+        s.insert("d", "v")?;
+        assert_eq!(s.segments.len(), 3);
+        drop(s);
+        let mut s = SunsetDB::new(base_dir.path())?;
+        assert_eq!(s.get("b")?, None);
+        assert_eq!(s.get("d")?.as_deref(), Some("v"));
+
+        let unsorted = staging_dir.path().join("unsorted");
+        let mut writer = SegmentWriter::create(&unsorted)?;
+        writer.insert("b", "v")?;
+        writer.insert("a", "v")?;
+        writer.finish()?;
+        let e = s.ingest_segment(&unsorted, sorted).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidInput);
+        assert_eq!(e.key(), Some("a"));
+        s.ingest_segment(&unsorted, IngestOptions::default())?;
+
+        // Quotas apply to the segment as a whole, before it is attached.
+        let base_dir = tempdir()?;
+        let mut options = Options::default();
+        options.quotas.max_keys = Some(2);
+        let mut s = SunsetDB::with_options(base_dir.path(), options)?;
+        s.insert("c", "v")?;
+        s.insert("x", "v")?;
+        let e = s.ingest_segment(&path, sorted).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::QuotaExceeded(Quota::MaxKeys));
+        assert_eq!(s.segments.len(), 1);
+        assert_eq!(read_dir(base_dir.path())?.count(), 1);
+        s.delete("x")?;
+        s.insert("b", "v")?;
+        s.ingest_segment(&path, sorted)?; // "b" is deleted.
+        assert_eq!(s.live_keys, 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn clone_to_corrupted_key_test() -> TestResult {
+        let base_dir = tempdir()?;
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let mut options = Options::default();
+        let listener_events = events.clone();
+        options
+            .listeners
+            .push(move |e: &Event| listener_events.lock().unwrap().push(e.clone()));
+        let mut s = SunsetDB::with_options(base_dir.path(), options)?;
+        s.insert("k", "v")?;
+
+        // Corrupt the key, which `get` doesn't read back.
+        let segment_path = base_dir.path().join(format!("0.{}", SEGMENT_EXT));
+        let mut f = OpenOptions::new().write(true).open(segment_path)?;
+        f.seek(SeekFrom::Start(8))?;
+        f.write_all(b"x")?;
+
+        let e = s.clone_to(&base_dir.path().join("copy")).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::Corruption);
+        assert!(s.verify_all_reads);
+        assert!(events.lock().unwrap().contains(&Event::CorruptionDetected {
+            segment_id: Some(0),
+            offset: Some(0),
+            key: Some("k".to_string()),
+        }));
+        Ok(())
+    }
+}
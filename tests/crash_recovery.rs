@@ -0,0 +1,130 @@
+// Kills a writer process at random points and checks that every write it
+// acknowledged after a sync survives reopening.
+//
+// The writer is the ignored `crash_writer` test of this same binary, which
+// the harness runs as a child process.
+
+use std::env;
+use std::error::Error;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use sunset_db::{Event, Options, SunsetDB};
+use tempfile::tempdir;
+
+type TestResult = Result<(), Box<dyn Error>>;
+
+const DIR_VAR: &str = "SUNSET_CRASH_DIR";
+const ROUND_VAR: &str = "SUNSET_CRASH_ROUND";
+const ROUNDS: u64 = 25;
+const SYNC_EVERY: u64 = 256;
+
+fn key(round: u64, i: u64) -> String {
+    format!("r{}-{}", round, i)
+}
+
+fn value(round: u64, i: u64) -> String {
+    format!("{}-{}", round, i).repeat((i % 64 + 1) as usize)
+}
+
+// Every 8th write deletes the key written 4 writes before.
+fn deleted(i: u64) -> bool {
+    (i + 4) % 8 == 0
+}
+
+#[test]
+#[ignore = "run by `crash_recovery_test` as the process to kill"]
+fn crash_writer() -> TestResult {
+    let (Ok(dir), Ok(round)) = (env::var(DIR_VAR), env::var(ROUND_VAR)) else {
+        return Ok(());
+    };
+    let round: u64 = round.parse()?;
+    let mut s = SunsetDB::new(Path::new(&dir))?;
+
+    for i in 0.. {
+        s.insert(&key(round, i), &value(round, i))?;
+        if i % 8 == 0 && i >= 4 {
+            s.delete(&key(round, i - 4))?;
+        }
+        if i % SYNC_EVERY == SYNC_EVERY - 1 {
+            s.sync()?;
+            println!("synced {}", i);
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn crash_recovery_test() -> TestResult {
+    let base_dir = tempdir()?;
+    let mut rng = SystemTime::now().duration_since(UNIX_EPOCH)?.as_nanos() as u64 | 1;
+
+    for round in 0..ROUNDS {
+        let mut child = Command::new(env::current_exe()?)
+            .args(["crash_writer", "--exact", "--ignored", "--nocapture"])
+            .env(DIR_VAR, base_dir.path())
+            .env(ROUND_VAR, round.to_string())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()?;
+
+        let acknowledged = Arc::new(Mutex::new(None));
+        let stdout = child.stdout.take().ok_or("no stdout")?;
+        let reader = {
+            let acknowledged = acknowledged.clone();
+            thread::spawn(move || {
+                for line in BufReader::new(stdout).lines().map_while(Result::ok) {
+                    if let Some(i) = line.strip_prefix("synced ") {
+                        *acknowledged.lock().unwrap() = i.parse::<u64>().ok();
+                    }
+                }
+            })
+        };
+
+        // xorshift64
+        rng ^= rng << 13;
+        rng ^= rng >> 7;
+        rng ^= rng << 17;
+        thread::sleep(Duration::from_millis(10 + rng % 60));
+        child.kill()?; // SIGKILL
+        child.wait()?;
+        reader.join().map_err(|_| "reader panicked")?;
+
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let mut options = Options::default();
+        {
+            let events = events.clone();
+            options
+                .listeners
+                .push(move |e: &Event| events.lock().unwrap().push(e.clone()));
+        }
+        let mut s = SunsetDB::with_options(base_dir.path(), options)?;
+
+        if let Some(last) = *acknowledged.lock().unwrap() {
+            for i in 0..=last {
+                let expected = match (deleted(i), i + 4 <= last) {
+                    (false, _) => Some(value(round, i)),
+                    (true, true) => None,
+                    // The delete may or may not have been written.
+                    (true, false) => continue,
+                };
+                let found = s.get(&key(round, i))?;
+                assert_eq!(found, expected, "round {} key {}", round, i);
+            }
+        }
+
+        assert!(!events
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|e| matches!(e, Event::CorruptionDetected { .. })));
+        drop(s);
+        let report = sunset_db::check(base_dir.path())?;
+        assert!(report.is_ok(), "round {}: {:?}", round, report.issues);
+    }
+    Ok(())
+}
@@ -0,0 +1,236 @@
+// Failpoints are global to the process, hence a separate test binary with
+// a single test.
+#![cfg(feature = "failpoints")]
+
+use std::error::Error;
+use std::num::NonZeroU64;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use sunset_db::{
+    failpoints, info, CompactionOptions, DiskSpaceLimits, ErrorKind, Event, Options, SunsetDB,
+    Tunable,
+};
+use tempfile::tempdir;
+
+type TestResult = Result<(), Box<dyn Error>>;
+
+#[test]
+fn failpoints_test() -> TestResult {
+    let scenario = fail::FailScenario::setup();
+    let base_dir = tempdir()?;
+
+    fail::cfg(failpoints::NEW_SEGMENT, "return")?;
+    let err = SunsetDB::new(base_dir.path()).err().ok_or("opened")?;
+    assert_eq!(err.kind(), ErrorKind::Io);
+    fail::remove(failpoints::NEW_SEGMENT);
+
+    let options = Options {
+        index_checkpoint_bytes: NonZeroU64::new(1),
+        ..Default::default()
+    };
+    let mut s = SunsetDB::with_options(base_dir.path(), options)?;
+
+    // Half-written records are never visible, whether through the index or
+    // the log.
+    let position = s.log_position();
+    fail::cfg(failpoints::APPEND, "return")?;
+    assert_eq!(s.insert("k", "v").unwrap_err().kind(), ErrorKind::Io);
+    assert_eq!(s.get("k")?, None);
+    assert_eq!(s.delete("k").unwrap_err().kind(), ErrorKind::NotFound);
+    assert_eq!(s.force_delete("k").unwrap_err().kind(), ErrorKind::Io);
+    assert!(s.modified_since(position)?.is_empty());
+    assert_eq!(s.log_position(), position);
+    fail::remove(failpoints::APPEND);
+
+    // Checkpoints are best effort: writes go through without them.
+    fail::cfg(failpoints::CHECKPOINT, "return")?;
+    s.insert("k", "v")?;
+    assert!(!base_dir.path().join("0.index").exists());
+    fail::remove(failpoints::CHECKPOINT);
+
+    // The torn writes were truncated: later records are indexed where they are.
+    s.insert("j", "w")?;
+    assert_eq!(s.get("j")?.as_deref(), Some("w"));
+    assert_eq!(s.modified_since(position)?, ["k", "j"]);
+
+    fail::cfg(failpoints::FSYNC, "return(disk gone)")?;
+    let err = s.sync().unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::Io);
+    assert_eq!(
+        err.source().map(|e| e.to_string()).as_deref(),
+        Some("disk gone")
+    );
+    fail::remove(failpoints::FSYNC);
+
+    s.sync()?;
+    assert_eq!(s.get("k")?.as_deref(), Some("v"));
+
+    // Deadlines are checked once slow IO returns.
+    s.set_option(Tunable::OpTimeout(Some(Duration::from_millis(20))));
+    fail::cfg(failpoints::READ, "sleep(100)")?;
+    assert_eq!(s.get("k").unwrap_err().kind(), ErrorKind::TimedOut);
+    fail::remove(failpoints::READ);
+    assert_eq!(s.get("k")?.as_deref(), Some("v"));
+    drop(s);
+
+    let rotating_dir = tempdir()?;
+    let options = Options {
+        max_segment_size: NonZeroU64::new(1),
+        op_timeout: Some(Duration::from_millis(20)),
+        ..Default::default()
+    };
+    let mut s = SunsetDB::with_options(rotating_dir.path(), options)?;
+    s.insert("k", "v")?;
+    fail::cfg(failpoints::NEW_SEGMENT, "sleep(100)")?;
+    assert_eq!(s.insert("j", "w").unwrap_err().kind(), ErrorKind::TimedOut);
+    fail::remove(failpoints::NEW_SEGMENT);
+    assert_eq!(s.get("j")?, None);
+
+    // A rotation failing once the active segment is sealed is retried by
+    // the next write.
+    s.insert("j", "w")?;
+    let segments = s.segment_stats().len();
+    fail::cfg(failpoints::ROTATE, "return")?;
+    assert_eq!(s.insert("i", "v").unwrap_err().kind(), ErrorKind::Io);
+    assert_eq!(s.segment_stats().len(), segments);
+    fail::remove(failpoints::ROTATE);
+    s.insert("i", "v")?;
+    assert_eq!(s.segment_stats().len(), segments + 1);
+    drop(s);
+    let mut s = SunsetDB::new(rotating_dir.path())?;
+    assert_eq!(s.get("j")?.as_deref(), Some("w"));
+    assert_eq!(s.get("i")?.as_deref(), Some("v"));
+    drop(s);
+
+    compaction_test()?;
+    disk_space_test()?;
+    scenario.teardown();
+    Ok(())
+}
+
+// Only writes that went through count against the free space, and
+// background merges leave the low-water mark free.
+fn disk_space_test() -> TestResult {
+    let base_dir = tempdir()?;
+    let started = Arc::new(AtomicBool::new(false));
+    let listener_started = started.clone();
+    let mut options = Options {
+        max_segment_size: NonZeroU64::new(1),
+        compaction: Some(CompactionOptions::default()),
+        disk_space: DiskSpaceLimits {
+            warn_below: Some(2000),
+            reject_below: Some(100),
+        },
+        ..Default::default()
+    };
+    options.listeners.push(move |e: &Event| {
+        if let Event::CompactionStarted { .. } = e {
+            listener_started.store(true, Ordering::Relaxed);
+        }
+    });
+    let mut s = SunsetDB::with_options(base_dir.path(), options)?;
+    fail::cfg(failpoints::DISK_SPACE, "return(1000)")?;
+
+    let value = "v".repeat(500);
+    fail::cfg(failpoints::APPEND, "return")?;
+    for _ in 0..4 {
+        assert_eq!(s.insert("k", &value).unwrap_err().kind(), ErrorKind::Io);
+    }
+    fail::remove(failpoints::APPEND);
+    s.insert("k", &value)?;
+    let e = s.insert("k", &value).unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::OutOfSpace);
+
+    for i in 0..10 {
+        s.insert(&format!("k{}", i), "v")?;
+    }
+    assert!(!started.load(Ordering::Relaxed));
+    // Unlike explicit ones.
+    assert!(!s.compact()?.segments_rewritten.is_empty());
+    fail::remove(failpoints::DISK_SPACE);
+    Ok(())
+}
+
+fn dead_bytes(s: &SunsetDB) -> u64 {
+    s.segment_stats().iter().map(|s| s.dead_bytes).sum()
+}
+
+fn compaction_pending(path: &Path) -> Result<bool, Box<dyn Error>> {
+    Ok(info(path)?.compaction_pending)
+}
+
+// Compactions need room for their output, and swap it in through a marker:
+// failures before the rename keep the inputs, after it they are removed.
+fn compaction_test() -> TestResult {
+    let base_dir = tempdir()?;
+    let options = Options {
+        max_segment_size: NonZeroU64::new(1),
+        ..Default::default()
+    };
+    let mut s = SunsetDB::with_options(base_dir.path(), options.clone())?;
+    for value in ["v", "w"] {
+        s.insert("k", value)?;
+        s.insert("j", value)?;
+    }
+    s.delete("j")?;
+    let dead = dead_bytes(&s);
+
+    fail::cfg(failpoints::DISK_SPACE, "return(0)")?;
+    assert_eq!(s.compact().unwrap_err().kind(), ErrorKind::OutOfSpace);
+    fail::remove(failpoints::DISK_SPACE);
+    assert_eq!(dead_bytes(&s), dead);
+
+    fail::cfg(failpoints::COMPACTION_SWAP, "return")?;
+    assert_eq!(s.compact().unwrap_err().kind(), ErrorKind::Io);
+    fail::remove(failpoints::COMPACTION_SWAP);
+    assert!(!compaction_pending(base_dir.path())?);
+    assert_eq!(dead_bytes(&s), dead);
+
+    // A crash before the rename is rolled back on open.
+    fail::cfg(failpoints::COMPACTION_SWAP, "panic")?;
+    assert!(panic::catch_unwind(AssertUnwindSafe(|| s.compact())).is_err());
+    fail::remove(failpoints::COMPACTION_SWAP);
+    assert!(compaction_pending(base_dir.path())?);
+    drop(s);
+    let mut s = SunsetDB::with_options(base_dir.path(), options.clone())?;
+    assert!(!compaction_pending(base_dir.path())?);
+    assert_eq!(dead_bytes(&s), dead);
+
+    // A crash after it is finished on open.
+    fail::cfg(failpoints::COMPACTION_FINISH, "return")?;
+    s.compact()?;
+    fail::remove(failpoints::COMPACTION_FINISH);
+    assert!(compaction_pending(base_dir.path())?);
+    drop(s);
+    let mut s = SunsetDB::with_options(base_dir.path(), options.clone())?;
+    assert!(!compaction_pending(base_dir.path())?);
+    assert_eq!((s.segment_stats().len(), dead_bytes(&s)), (2, 0));
+    assert_eq!(s.get("k")?.as_deref(), Some("w"));
+    assert_eq!(s.get("j")?, None);
+
+    // Background merges are skipped.
+    let started = Arc::new(AtomicBool::new(false));
+    let listener_started = started.clone();
+    let mut options = Options {
+        compaction: Some(CompactionOptions::default()),
+        ..options
+    };
+    options.listeners.push(move |e: &Event| {
+        if let Event::CompactionStarted { .. } = e {
+            listener_started.store(true, Ordering::Relaxed);
+        }
+    });
+    let background_dir = tempdir()?;
+    let mut s = SunsetDB::with_options(background_dir.path(), options)?;
+    fail::cfg(failpoints::DISK_SPACE, "return(0)")?;
+    for i in 0..10 {
+        s.insert(&format!("k{}", i), "v")?;
+    }
+    fail::remove(failpoints::DISK_SPACE);
+    assert!(!started.load(Ordering::Relaxed));
+    Ok(())
+}